@@ -0,0 +1,34 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use erodedvax::cpu::instrs::{
+    decode_at,
+    decode_instr,
+    encode_instr,
+    DisasmOptions,
+    Disassembler,
+    OperandMode,
+};
+
+fuzz_target!(|data: &[u8]| {
+    // The raw iterator interface must never panic, whatever it's fed.
+    let iter = &mut data.iter().copied();
+    if let Some((_, operiter)) = decode_instr(iter) {
+        for _ in operiter {}
+    }
+
+    let decoded = match decode_at(data, 0x1000) {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+    assert!(decoded.bytes.len() <= data.len());
+    assert_eq!(&decoded.bytes[..], &data[..decoded.bytes.len()]);
+
+    // Anything that decodes has to encode back to the same bytes, CASE tables aside.
+    let operands: Vec<OperandMode> = decoded.operands.iter().map(|o| o.mode.clone()).collect();
+    let encoded = encode_instr(decoded.instr, &operands);
+    assert_eq!(&encoded[..], &data[..encoded.len()]);
+    assert_eq!(decoded.case_table_base(), 0x1000 + encoded.len() as u32);
+
+    let _ = Disassembler::new(DisasmOptions::default()).format_lines(&decoded);
+});
@@ -0,0 +1,30 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use std::cell::Cell;
+
+use erodedvax::cpu::instrs::{OperandMode, OperandWidth};
+
+fuzz_target!(|data: &[u8]| {
+    let (&control, spec) = match data.split_first() {
+        Some(v) => v,
+        None => return,
+    };
+
+    let width = match control % 5 {
+        0 => OperandWidth::Byte,
+        1 => OperandWidth::Word,
+        2 => OperandWidth::Longword,
+        3 => OperandWidth::Quadword,
+        _ => OperandWidth::Octaword,
+    };
+    let allow_indexed = control & 0x80 != 0;
+
+    let consumed = Cell::new(0usize);
+    let iter = &mut spec.iter().copied().inspect(|_| consumed.set(consumed.get() + 1));
+
+    if let Ok(op) = OperandMode::read_operand(iter, width, allow_indexed) {
+        assert!(allow_indexed || !matches!(op, OperandMode::Indexed(..)));
+        assert_eq!(&op.encode(width)[..], &spec[..consumed.get()]);
+    }
+});
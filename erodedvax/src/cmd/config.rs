@@ -0,0 +1,489 @@
+//! Machine configuration files. They're TOML, like this:
+//!
+//! ```toml
+//! model = "ka630"
+//! ram = 16                    # MiB
+//! rom = "ka630.bin"
+//! boot = "dua0"               # for the built-in console, when there's no ROM
+//! time = "virtual"            # or "real", the default
+//! idle = false                # skip idle loops, the default is true
+//!
+//! [[disk]]
+//! type = "rd54"
+//! path = "system.img"
+//! read_only = false
+//!
+//! [[tape]]
+//! path = "backup.tap"
+//!
+//! [[serial]]
+//! line = 1
+//! telnet = 2301               # or tcp = "0.0.0.0:2301", or pty = true
+//!
+//! [ethernet]
+//! mac = "08:00:2b:01:02:03"
+//! socket = "/tmp/vax-a"       # with peer, a link to one other emulator, otherwise loopback
+//! peer = "/tmp/vax-b"
+//! ```
+//!
+//! Errors say which key, and where in the file it is.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+
+use toml::de::{DeTable, DeValue};
+
+use crate::ervax::{
+    cpu::{execution::ExecutionContext, sysclk::TimeMode},
+    devices::{
+        disk::Geometry,
+        ethernet::{Loopback, MacAddress},
+        serial::TcpLine,
+    },
+    machine::{firmware::BootDevice, MachineBuilder, MachineError, Model},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// The file, or the flag, the error is in.
+    pub source: String,
+    pub line: Option<usize>,
+    /// The key, as a path like disk[1].type.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}: {}", self.source, line, self.key, self.message),
+            None => write!(f, "{}: {}: {}", self.source, self.key, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskConfig {
+    pub path: String,
+    pub drive: String,
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TapeConfig {
+    pub path: String,
+    pub read_only: bool,
+}
+
+/// What's on the host end of a terminal line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A localhost port for telnet clients.
+    Telnet(u16),
+    /// Any address to listen on.
+    Tcp(String),
+    Pty,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub line: usize,
+    pub endpoint: Endpoint,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EthernetConfig {
+    pub mac: MacAddress,
+    /// Our socket and the peer's, without which frames are looped back.
+    pub socket: Option<(String, String)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MachineConfig {
+    /// Where the settings came from, for errors.
+    pub source: String,
+    pub model: Option<Model>,
+    pub ram_mib: Option<usize>,
+    pub rom: Option<String>,
+    pub nvram: Option<String>,
+    pub boot: Option<BootDevice>,
+    pub time_mode: TimeMode,
+    pub idle: bool,
+    pub disks: Vec<DiskConfig>,
+    pub tapes: Vec<TapeConfig>,
+    pub serial: Vec<SerialConfig>,
+    pub ethernet: Option<EthernetConfig>,
+    /// Which line each key was on.
+    lines: Vec<(String, usize)>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> MachineConfig {
+        MachineConfig {
+            source: "command line".to_string(),
+            model: None,
+            ram_mib: None,
+            rom: None,
+            nvram: None,
+            boot: None,
+            time_mode: TimeMode::RealTime,
+            idle: true,
+            disks: vec![],
+            tapes: vec![],
+            serial: vec![],
+            ethernet: None,
+            lines: vec![],
+        }
+    }
+}
+
+/// A table in the file, and the path of keys to it.
+struct Table<'a, 'i> {
+    text: &'a str,
+    source: &'a str,
+    table: &'a DeTable<'i>,
+    path: String,
+    /// Where the table starts, for keys that are missing from it.
+    span: Range<usize>,
+}
+
+impl<'a, 'i> Table<'a, 'i> {
+    fn line(&self, at: usize) -> usize {
+        self.text[..at.min(self.text.len())].matches('\n').count() + 1
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<(&'a DeValue<'i>, Range<usize>)> {
+        self.table.iter().find(|(k, _)| k.get_ref() == key).map(|(_, v)| (v.get_ref(), v.span()))
+    }
+
+    fn error(&self, key: &str, message: String) -> ConfigError {
+        let at = self.get(key).map_or(self.span.start, |(_, span)| span.start);
+        ConfigError { source: self.source.to_string(), line: Some(self.line(at)), key: self.key(key), message }
+    }
+
+    /// Rejects keys that aren't any of `known`, which are usually misspellings.
+    fn check_keys(&self, known: &[&str]) -> Result<(), ConfigError> {
+        for (k, _) in self.table.iter() {
+            if !known.contains(&k.get_ref().as_ref()) {
+                let line = self.line(k.span().start);
+                let message = "isn't a setting".to_string();
+                return Err(ConfigError { source: self.source.to_string(), line: Some(line), key: self.key(k.get_ref()), message });
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some((DeValue::String(s), _)) => Ok(Some(s.to_string())),
+            Some((v, _)) => Err(self.error(key, format!("should be a string, not {}", v.type_str()))),
+        }
+    }
+
+    fn integer(&self, key: &str) -> Result<Option<u64>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some((DeValue::Integer(i), _)) => u64::from_str_radix(i.as_str(), i.radix())
+                .map(Some)
+                .map_err(|_| self.error(key, "should be a positive number".to_string())),
+            Some((v, _)) => Err(self.error(key, format!("should be a number, not {}", v.type_str()))),
+        }
+    }
+
+    fn boolean(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some((DeValue::Boolean(b), _)) => Ok(Some(*b)),
+            Some((v, _)) => Err(self.error(key, format!("should be true or false, not {}", v.type_str()))),
+        }
+    }
+
+    fn required(&self, key: &str) -> Result<String, ConfigError> {
+        self.string(key)?.ok_or_else(|| self.error(key, "is missing".to_string()))
+    }
+
+    fn table(&self, key: &str) -> Result<Option<Table<'a, 'i>>, ConfigError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some((DeValue::Table(table), span)) => Ok(Some(self.child(table, self.key(key), span))),
+            Some((v, _)) => Err(self.error(key, format!("should be a table, not {}", v.type_str()))),
+        }
+    }
+
+    /// An array of tables, [[key]] in the file.
+    fn tables(&self, key: &str) -> Result<Vec<Table<'a, 'i>>, ConfigError> {
+        let array = match self.get(key) {
+            None => return Ok(vec![]),
+            Some((DeValue::Array(array), _)) => array,
+            Some((v, _)) => return Err(self.error(key, format!("should be an array of tables, not {}", v.type_str()))),
+        };
+        array.iter().enumerate().map(|(n, v)| match v.get_ref() {
+            DeValue::Table(table) => Ok(self.child(table, format!("{}[{}]", self.key(key), n), v.span())),
+            other => Err(self.error(key, format!("should be an array of tables, not of {}", other.type_str()))),
+        }).collect()
+    }
+
+    fn child(&self, table: &'a DeTable<'i>, path: String, span: Range<usize>) -> Table<'a, 'i> {
+        Table { text: self.text, source: self.source, table, path, span }
+    }
+
+    fn note_line(&self, key: &str, lines: &mut Vec<(String, usize)>) {
+        if let Some((_, span)) = self.get(key) {
+            lines.push((self.key(key), self.line(span.start)));
+        } else if !self.path.is_empty() && key.is_empty() {
+            lines.push((self.path.clone(), self.line(self.span.start)));
+        }
+    }
+}
+
+pub fn parse_model(name: &str) -> Result<Model, String> {
+    Model::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Model::ALL.iter().map(|m| m.name()).collect();
+        format!("unknown model `{}`, it's one of {}", name, names.join(", "))
+    })
+}
+
+pub fn parse_time_mode(mode: &str) -> Result<TimeMode, String> {
+    match mode {
+        "real" => Ok(TimeMode::RealTime),
+        "virtual" => Ok(TimeMode::Virtual),
+        _ => Err(format!("`{}` should be real or virtual", mode)),
+    }
+}
+
+pub fn parse_boot_device(name: &str) -> Result<BootDevice, String> {
+    BootDevice::from_name(name).ok_or_else(|| format!("`{}` isn't a device to boot from, like DUA0, MUA0 or DKA0", name))
+}
+
+pub fn check_drive(drive: &str) -> Result<(), String> {
+    Geometry::by_name(drive).map(|_| ()).ok_or_else(|| format!("unknown drive type `{}`", drive))
+}
+
+/// Parses a station address like 08:00:2b:01:02:03, or with dashes.
+pub fn parse_mac(s: &str) -> Result<MacAddress, String> {
+    let bytes: Vec<u8> = s.split([':', '-'])
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("`{}` isn't a station address like 08:00:2b:01:02:03", s))?;
+    bytes.try_into().map_err(|_| format!("`{}` isn't six bytes", s))
+}
+
+impl MachineConfig {
+    /// Parses the configuration in `text`, read from `source`.
+    pub fn parse(text: &str, source: &str) -> Result<MachineConfig, ConfigError> {
+        let doc = DeTable::parse(text).map_err(|e| {
+            let line = e.span().map(|s| text[..s.start.min(text.len())].matches('\n').count() + 1);
+            ConfigError { source: source.to_string(), line, key: "(syntax)".to_string(), message: e.message().to_string() }
+        })?;
+        let top = Table { text, source, table: doc.get_ref(), path: String::new(), span: 0..0 };
+        top.check_keys(&["model", "ram", "rom", "nvram", "boot", "time", "idle", "disk", "tape", "serial", "ethernet"])?;
+
+        let mut config = MachineConfig { source: source.to_string(), ..MachineConfig::default() };
+        config.model = top.string("model")?.map(|m| parse_model(&m).map_err(|e| top.error("model", e))).transpose()?;
+        config.ram_mib = top.integer("ram")?.map(|n| n as usize);
+        config.rom = top.string("rom")?;
+        config.nvram = top.string("nvram")?;
+        config.boot = top.string("boot")?.map(|d| parse_boot_device(&d).map_err(|e| top.error("boot", e))).transpose()?;
+        if let Some(mode) = top.string("time")? {
+            config.time_mode = parse_time_mode(&mode).map_err(|e| top.error("time", e))?;
+        }
+        config.idle = top.boolean("idle")?.unwrap_or(true);
+        for key in ["model", "ram", "rom", "nvram", "boot"].iter() {
+            top.note_line(key, &mut config.lines);
+        }
+
+        for disk in top.tables("disk")? {
+            disk.check_keys(&["type", "path", "read_only"])?;
+            let drive = disk.required("type")?;
+            check_drive(&drive).map_err(|e| disk.error("type", e))?;
+            let path = disk.required("path")?;
+            disk.note_line("path", &mut config.lines);
+            config.disks.push(DiskConfig { path, drive, read_only: disk.boolean("read_only")?.unwrap_or(false) });
+        }
+        for tape in top.tables("tape")? {
+            tape.check_keys(&["path", "read_only"])?;
+            let path = tape.required("path")?;
+            tape.note_line("path", &mut config.lines);
+            config.tapes.push(TapeConfig { path, read_only: tape.boolean("read_only")?.unwrap_or(false) });
+        }
+        for serial in top.tables("serial")? {
+            serial.check_keys(&["line", "telnet", "tcp", "pty"])?;
+            let line = serial.integer("line")?.ok_or_else(|| serial.error("line", "is missing".to_string()))? as usize;
+            let endpoint = match (serial.integer("telnet")?, serial.string("tcp")?, serial.boolean("pty")?) {
+                (Some(port), None, None | Some(false)) if port <= u16::MAX as u64 => Endpoint::Telnet(port as u16),
+                (Some(_), None, None | Some(false)) => return Err(serial.error("telnet", "isn't a port number".to_string())),
+                (None, Some(addr), None | Some(false)) => Endpoint::Tcp(addr),
+                (None, None, Some(true)) => Endpoint::Pty,
+                _ => return Err(serial.error("line", "needs one of telnet, tcp or pty".to_string())),
+            };
+            serial.note_line("line", &mut config.lines);
+            config.serial.push(SerialConfig { line, endpoint });
+        }
+        if let Some(ethernet) = top.table("ethernet")? {
+            ethernet.check_keys(&["mac", "socket", "peer"])?;
+            let mac = parse_mac(&ethernet.required("mac")?).map_err(|e| ethernet.error("mac", e))?;
+            let socket = match (ethernet.string("socket")?, ethernet.string("peer")?) {
+                (Some(socket), Some(peer)) => Some((socket, peer)),
+                (None, None) => None,
+                (Some(_), None) => return Err(ethernet.error("peer", "is missing, a socket needs a peer".to_string())),
+                (None, Some(_)) => return Err(ethernet.error("socket", "is missing, a peer needs a socket".to_string())),
+            };
+            ethernet.note_line("mac", &mut config.lines);
+            config.ethernet = Some(EthernetConfig { mac, socket });
+        }
+        Ok(config)
+    }
+
+    /// An error about `key`, at its line if it came from the file.
+    pub fn error(&self, key: &str, message: String) -> ConfigError {
+        let line = self.lines.iter().find(|(k, _)| k == key).map(|(_, line)| *line);
+        let source = if line.is_some() { self.source.clone() } else { "command line".to_string() };
+        ConfigError { source, line, key: key.to_string(), message }
+    }
+
+    /// Builds the machine and powers it up, blaming the key responsible if that fails.
+    pub fn build(&self) -> Result<ExecutionContext, ConfigError> {
+        let model = self.model.ok_or_else(|| self.error("model", "isn't given, in the file or with --model".to_string()))?;
+        let io = |key: String, e: MachineError| self.error(&key, e.to_string());
+        let mut builder = MachineBuilder::new(model).time_mode(self.time_mode);
+        if !self.idle {
+            builder = builder.idle(crate::ervax::cpu::execution::IdleConfig::disabled());
+        }
+        if let Some(mib) = self.ram_mib {
+            builder = builder.ram_mib(mib).map_err(|e| io("ram".to_string(), e))?;
+        }
+        if let Some(rom) = &self.rom {
+            builder = builder.rom_file(rom).map_err(|e| io("rom".to_string(), e))?;
+        }
+        if let Some(nvram) = &self.nvram {
+            let bytes = std::fs::read(nvram).map_err(|e| io("nvram".to_string(), MachineError::Io(nvram.clone(), e)))?;
+            builder = builder.nvram(bytes);
+        }
+        if let Some(device) = self.boot {
+            builder = builder.boot(device);
+        }
+        for (n, disk) in self.disks.iter().enumerate() {
+            builder = builder.disk_file(&disk.path, &disk.drive, disk.read_only).map_err(|e| io(format!("disk[{}].path", n), e))?;
+        }
+        for (n, tape) in self.tapes.iter().enumerate() {
+            builder = builder.tape_file(&tape.path, tape.read_only).map_err(|e| io(format!("tape[{}].path", n), e))?;
+        }
+        for (n, serial) in self.serial.iter().enumerate() {
+            let key = format!("serial[{}].line", n);
+            builder = match &serial.endpoint {
+                Endpoint::Telnet(port) => builder.serial_telnet(serial.line, *port).map_err(|e| io(key, e))?,
+                Endpoint::Tcp(addr) => {
+                    let line = TcpLine::listen(addr.as_str()).map_err(|e| io(key, MachineError::Io(addr.clone(), e)))?;
+                    eprintln!("line {} listening on {}", serial.line, addr);
+                    builder.serial_line(serial.line, Box::new(line))
+                }
+                #[cfg(unix)]
+                Endpoint::Pty => {
+                    let line = crate::ervax::devices::serial::PtyLine::open().map_err(|e| io(key, MachineError::Io("pty".to_string(), e)))?;
+                    eprintln!("line {} on {}", serial.line, line.name());
+                    builder.serial_line(serial.line, Box::new(line))
+                }
+                #[cfg(not(unix))]
+                Endpoint::Pty => return Err(self.error(&key, "pseudo-terminals need a Unix host".to_string())),
+            };
+        }
+        if let Some(ethernet) = &self.ethernet {
+            builder = match &ethernet.socket {
+                #[cfg(unix)]
+                Some((socket, peer)) => {
+                    let backend = crate::ervax::devices::ethernet::SocketBackend::new(socket.as_ref(), peer.as_ref())
+                        .map_err(|e| io("ethernet.mac".to_string(), MachineError::Io(socket.clone(), e)))?;
+                    builder.ethernet(ethernet.mac, Box::new(backend))
+                }
+                #[cfg(not(unix))]
+                Some(_) => return Err(self.error("ethernet.mac", "socket links need a Unix host".to_string())),
+                None => builder.ethernet(ethernet.mac, Box::new(Loopback::new())),
+            };
+        }
+
+        builder.build().map_err(|e| {
+            let key = match &e {
+                MachineError::RamSize(_) => "ram".to_string(),
+                MachineError::RomSize(_) => "rom".to_string(),
+                MachineError::TooManyDisks(n) => format!("disk[{}].path", n),
+                MachineError::NoBootDevice(_) | MachineError::NoBootBlock(_) => "boot".to_string(),
+                MachineError::SerialLine(line) => match self.serial.iter().position(|s| s.line == *line) {
+                    Some(n) => format!("serial[{}].line", n),
+                    None => "serial".to_string(),
+                },
+                _ => "model".to_string(),
+            };
+            self.error(&key, e.to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+model = "MicroVAX2"
+ram = 0x8
+time = "virtual"
+
+[[tape]]
+path = "/nonexistent/boot.tap"
+read_only = true
+
+[[serial]]
+line = 2
+pty = true
+
+[[serial]]
+line = 3
+telnet = 2303
+
+[ethernet]
+mac = "08-00-2b-01-02-03"
+"#;
+
+    fn error(text: &str) -> String {
+        MachineConfig::parse(text, "vax.toml").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn machine_config() {
+        let config = MachineConfig::parse(CONFIG, "vax.toml").unwrap();
+        assert_eq!(config.model, Some(Model::Ka630));
+        assert_eq!((config.ram_mib, config.time_mode, config.idle), (Some(8), TimeMode::Virtual, true));
+        assert_eq!(config.tapes, vec![TapeConfig { path: "/nonexistent/boot.tap".to_string(), read_only: true }]);
+        assert_eq!(config.serial[0], SerialConfig { line: 2, endpoint: Endpoint::Pty });
+        assert_eq!(config.serial[1].endpoint, Endpoint::Telnet(2303));
+        assert_eq!(config.ethernet.as_ref().unwrap().mac, [8, 0, 0x2B, 1, 2, 3]);
+
+        // Problems building point at the key.
+        assert_eq!(config.build().err().unwrap().to_string(), "vax.toml:7: tape[0].path: /nonexistent/boot.tap: No such file or directory (os error 2)");
+        let config = MachineConfig::parse("model = \"ka41\"\nram = 64\n", "vax.toml").unwrap();
+        assert_eq!(config.build().err().unwrap().to_string(), "vax.toml:2: ram: unsupported memory size of 67108864 bytes");
+        let config = MachineConfig::parse("model = \"ka41\"\nram = 17592186044416\n", "vax.toml").unwrap();
+        assert_eq!(config.build().err().unwrap().to_string(), "vax.toml:2: ram: memory size of 17592186044416 MiB is too big");
+        let config = MachineConfig::parse("ram = 4\n", "vax.toml").unwrap();
+        assert_eq!(config.build().err().unwrap().to_string(), "command line: model: isn't given, in the file or with --model");
+
+        // And so do problems with the file.
+        assert_eq!(error("model = \"pdp11\""), "vax.toml:1: model: unknown model `pdp11`, it's one of ka630, ka41");
+        assert_eq!(error("\n\nrma = 4"), "vax.toml:3: rma: isn't a setting");
+        assert_eq!(error("[[disk]]\ntype = \"rd99\"\npath = \"x\""), "vax.toml:2: disk[0].type: unknown drive type `rd99`");
+        assert_eq!(error("[[disk]]\ntype = \"rd54\""), "vax.toml:1: disk[0].path: is missing");
+        assert_eq!(error("idle = 1"), "vax.toml:1: idle: should be true or false, not integer");
+        assert_eq!(error("[[serial]]\nline = 1\n"), "vax.toml:2: serial[0].line: needs one of telnet, tcp or pty");
+        assert_eq!(error("[ethernet]\nmac = \"08:00:2b\""), "vax.toml:2: ethernet.mac: `08:00:2b` isn't six bytes");
+        assert!(error("model = ").starts_with("vax.toml:1: (syntax): "));
+    }
+}
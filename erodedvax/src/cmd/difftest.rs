@@ -0,0 +1,38 @@
+use std::fs;
+
+use crate::ervax::cpu::instrs::{check_captures, parse_captures};
+
+pub const USAGE: &str = "\
+usage: erodedvax difftest <capture file>...
+
+Decodes instructions captured from a reference implementation and reports every one
+where the decoder disagrees on the length, mnemonic, or operands.
+
+Each line of a capture file reads `[address:] <hex bytes> | <length> | <mnemonic> [operands]`,
+with a length of `-` for bytes the reference rejected. Lines starting with # are comments.";
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(format!("no capture file given\n\n{}", USAGE));
+    }
+
+    let mut total = 0;
+    let mut failed = 0;
+    for path in args {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let captures = parse_captures(&text).map_err(|e| format!("{}: {}", path, e))?;
+
+        for (c, mismatch) in check_captures(&captures) {
+            let bytes: Vec<String> = c.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("{}:{}: {}: {}", path, c.line, bytes.join(" "), mismatch);
+            failed += 1;
+        }
+        total += captures.len();
+    }
+
+    println!("{} of {} captures match", total - failed, total);
+    if failed > 0 {
+        return Err(format!("{} mismatches", failed));
+    }
+    Ok(())
+}
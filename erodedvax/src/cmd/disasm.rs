@@ -0,0 +1,229 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::cmd::{option_value, parse_number, parse_u32};
+use crate::ervax::{
+    cpu::instrs::{
+        CodeMap,
+        DisasmOptions,
+        Disassembler,
+    },
+    loader::Executable,
+};
+
+pub const USAGE: &str = "\
+usage: erodedvax disasm [options] <file>
+
+Disassembles a raw binary, or a slice of a disk image or memory dump, or the text of an
+a.out or ELF executable.
+
+options:
+  --base ADDR        address of the first disassembled byte (default 0)
+  --offset N         file offset to start at (default 0)
+  --length N         number of bytes to disassemble (default: up to the end of the file)
+  --recursive        follow control flow from the entry points, instead of a linear sweep
+  --linear           a linear sweep, the default, undoing an earlier --recursive
+  --entry ADDR       an entry point for --recursive, may be repeated (default: the base address)
+  --procedure ADDR   a procedure entry mask for --recursive, may be repeated
+  --executable       the file is an a.out or ELF executable: disassemble its text where it's
+                     loaded, named by its symbols, instead of --base, --offset and --length.
+                     --recursive starts from its entry point and procedures
+  --no-address       don't print addresses
+  --no-bytes         don't print raw bytes
+
+Numbers are decimal, or hex with a 0x or ^X prefix.";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisasmArgs {
+    pub file: String,
+    pub base: u32,
+    pub offset: u64,
+    pub length: Option<u64>,
+    pub recursive: bool,
+    pub entries: Vec<u32>,
+    pub procedures: Vec<u32>,
+    pub executable: bool,
+    pub options: DisasmOptions,
+}
+
+impl DisasmArgs {
+    pub fn parse(args: &[String]) -> Result<DisasmArgs, String> {
+        let mut file = None;
+        let mut parsed = DisasmArgs {
+            file: String::new(),
+            base: 0,
+            offset: 0,
+            length: None,
+            recursive: false,
+            entries: vec![],
+            procedures: vec![],
+            executable: false,
+            options: DisasmOptions { show_address: true, show_bytes: true },
+        };
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--base" => parsed.base = parse_u32(option_value(&mut iter, arg)?)?,
+                "--offset" => parsed.offset = parse_number(option_value(&mut iter, arg)?)?,
+                "--length" => parsed.length = Some(parse_number(option_value(&mut iter, arg)?)?),
+                "--recursive" => parsed.recursive = true,
+                "--linear" => parsed.recursive = false,
+                "--entry" => parsed.entries.push(parse_u32(option_value(&mut iter, arg)?)?),
+                "--procedure" => parsed.procedures.push(parse_u32(option_value(&mut iter, arg)?)?),
+                "--executable" => parsed.executable = true,
+                "--no-address" => parsed.options.show_address = false,
+                "--no-bytes" => parsed.options.show_bytes = false,
+                o if o.starts_with("--") => return Err(format!("unknown option `{}`\n\n{}", o, USAGE)),
+                f if file.is_none() => file = Some(f.to_string()),
+                f => return Err(format!("unexpected argument `{}`\n\n{}", f, USAGE)),
+            }
+        }
+
+        parsed.file = file.ok_or_else(|| format!("no file given\n\n{}", USAGE))?;
+        if parsed.recursive && !parsed.executable && parsed.entries.is_empty() && parsed.procedures.is_empty() {
+            parsed.entries.push(parsed.base);
+        }
+        Ok(parsed)
+    }
+}
+
+/// Reads `length` bytes (or everything) from `offset` in the file.
+pub fn read_image(path: &str, offset: u64, length: Option<u64>) -> io::Result<Vec<u8>> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+
+    let mut bytes = vec![];
+    match length {
+        Some(n) => {
+            f.take(n).read_to_end(&mut bytes)?;
+            if (bytes.len() as u64) < n {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ends before the requested range"));
+            }
+        }
+        None => {
+            f.read_to_end(&mut bytes)?;
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn listing(args: &DisasmArgs, bytes: &[u8]) -> Vec<String> {
+    let map = if args.recursive {
+        CodeMap::recursive(bytes, args.base, &args.entries, &args.procedures)
+    } else {
+        CodeMap::linear(bytes, args.base)
+    };
+    let labels = map.labels();
+
+    Disassembler::new(args.options)
+        .with_labels(&labels)
+        .listing(bytes, args.base, &map)
+}
+
+/// Lists each segment of the program's text, with its symbols as labels where they're at.
+pub fn executable_listing(args: &DisasmArgs, program: &Executable) -> Vec<String> {
+    let mut procedures = args.procedures.clone();
+    procedures.push(program.entry);
+    procedures.extend(program.procedures());
+
+    let mut lines = vec![];
+    for segment in program.segments.iter().filter(|s| s.executable) {
+        let (bytes, base) = (&segment.data, segment.address);
+        let map = if args.recursive {
+            CodeMap::recursive(bytes, base, &args.entries, &procedures)
+        } else {
+            CodeMap::linear(bytes, base)
+        };
+        let mut labels = map.labels();
+        labels.extend(program.labels());
+
+        lines.extend(Disassembler::new(args.options).with_labels(&labels).listing(bytes, base, &map));
+    }
+    lines
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = DisasmArgs::parse(args)?;
+    let bad = |e: &dyn std::fmt::Display| format!("{}: {}", args.file, e);
+    let lines = if args.executable {
+        let bytes = std::fs::read(&args.file).map_err(|e| bad(&e))?;
+        executable_listing(&args, &Executable::parse(&bytes).map_err(|e| bad(&e))?)
+    } else {
+        listing(&args, &read_image(&args.file, args.offset, args.length).map_err(|e| bad(&e))?)
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let written = lines.iter()
+        .try_for_each(|l| writeln!(out, "{}", l))
+        .and_then(|_| out.flush());
+
+    match written {
+        // Being piped into head and the like isn't an error.
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::instrs::assemble;
+
+    fn args(s: &str) -> Result<DisasmArgs, String> {
+        let v: Vec<String> = s.split_whitespace().map(String::from).collect();
+        DisasmArgs::parse(&v)
+    }
+
+    #[test]
+    fn parse_args() {
+        let a = args("--base 0x200 --offset 512 --length ^X40 --recursive boot.img").unwrap();
+        assert_eq!(a.file, "boot.img");
+        assert_eq!(a.base, 0x200);
+        assert_eq!(a.offset, 512);
+        assert_eq!(a.length, Some(0x40));
+        assert_eq!(a.entries, vec![0x200]);
+
+        assert!(args("--base").is_err());
+        assert!(args("--frobnicate x.img").is_err());
+        assert!(args("").is_err());
+        assert!(args("a.img b.img").is_err());
+    }
+
+    #[test]
+    fn recursive_listing() {
+        let a = assemble("BRB GO\n.LONG ^XFFFFFFFF\nGO: HALT", 0x200).unwrap();
+        let mut parsed = args("--recursive --no-address --no-bytes x.img").unwrap();
+        parsed.base = 0x200;
+        parsed.entries = vec![0x200];
+
+        assert_eq!(listing(&parsed, &a.bytes), vec![
+            "L_00000200:",
+            "BRB L_00000206",
+            ".BYTE ^XFF, ^XFF, ^XFF, ^XFF",
+            "L_00000206:",
+            "HALT",
+        ]);
+    }
+
+    #[test]
+    fn executable_with_symbols() {
+        use crate::ervax::loader::aout;
+
+        let a = assemble(".WORD ^M<R2>\nCALLS #0, SUB\nRET\nSUB: .WORD 0\nRET", 0).unwrap();
+        let image = aout::tests::image(aout::OMAGIC, &a.bytes, &[], 0, &[("_start", 0x05, 0), ("_sub", 0x04, a.symbols["SUB"])]);
+        let parsed = args("--executable --recursive --no-address --no-bytes a.out").unwrap();
+        assert!(parsed.entries.is_empty());
+
+        assert_eq!(executable_listing(&parsed, &Executable::parse(&image).unwrap()), vec![
+            "_start:",
+            ".WORD ^M<R2>",
+            "CALLS S^#0, B^_sub",
+            "RET",
+            "_sub:",
+            ".WORD ^M<>",
+            "RET",
+        ]);
+    }
+}
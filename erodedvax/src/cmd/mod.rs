@@ -0,0 +1,49 @@
+pub mod config;
+pub mod difftest;
+pub mod disasm;
+pub mod netbsd;
+pub mod run;
+pub mod trace;
+
+/// Parses a number given on the command line. Decimal, or hex with a 0x or ^X prefix.
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    let upper = s.to_ascii_uppercase();
+    let r = if let Some(hex) = upper.strip_prefix("0X").or_else(|| upper.strip_prefix("^X")) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        upper.parse::<u64>()
+    };
+    r.map_err(|_| format!("`{}` isn't a number", s))
+}
+
+pub fn parse_u32(s: &str) -> Result<u32, String> {
+    let v = parse_number(s)?;
+    if v > u32::MAX as u64 {
+        return Err(format!("`{}` doesn't fit in 32 bits", s));
+    }
+    Ok(v as u32)
+}
+
+/// Pulls the value for an option out of the argument list.
+pub fn option_value<'a, I>(args: &mut I, option: &str) -> Result<&'a str, String>
+    where I: Iterator<Item = &'a String>
+{
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| format!("{} needs a value", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("1234"), Ok(1234));
+        assert_eq!(parse_number("0x1F"), Ok(0x1F));
+        assert_eq!(parse_number("^x1f"), Ok(0x1F));
+        assert!(parse_number("12AB").is_err());
+        assert!(parse_u32("0x1_0000_0000").is_err());
+        assert!(parse_u32("0x100000000").is_err());
+    }
+}
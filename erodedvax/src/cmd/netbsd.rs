@@ -0,0 +1,116 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cmd::option_value;
+use crate::ervax::{
+    cpu::{bus::VAXBus, execution::ExecutionContext, sysclk::TimeMode},
+    loader::Executable,
+    netbsd::{sandbox::Sandbox, Process, Termination},
+};
+
+pub const USAGE: &str = "\
+usage: erodedvax netbsd [options] <program> [args]
+
+Runs a NetBSD/VAX program, an ELF or a.out executable, with the host carrying out its system
+calls instead of a kernel. Only a few calls are there: enough for file I/O, memory and the time.
+
+options:
+  --root DIR          the directory the program sees as its root (default: the current one)
+  --ram MIB           memory for the program, its heap and its stack (default 64)
+  --env NAME=VALUE    adds an environment variable, may be repeated
+
+Exits with the program's exit status.";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetbsdArgs {
+    pub program: String,
+    /// The program's arguments, after its name.
+    pub args: Vec<String>,
+    pub root: String,
+    pub ram_mib: usize,
+    pub env: Vec<String>,
+}
+
+impl NetbsdArgs {
+    /// Parses options up to the program, which takes every argument after it.
+    pub fn parse(args: &[String]) -> Result<NetbsdArgs, String> {
+        let mut parsed = NetbsdArgs { program: String::new(), args: vec![], root: ".".to_string(), ram_mib: 64, env: vec![] };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--root" => parsed.root = option_value(&mut iter, arg)?.to_string(),
+                "--ram" => {
+                    let value = option_value(&mut iter, arg)?;
+                    parsed.ram_mib = value.parse().map_err(|_| format!("--ram: `{}` isn't a number", value))?;
+                    if parsed.ram_mib.checked_mul(1 << 20).is_none() {
+                        return Err(format!("--ram: {} MiB is too big", value));
+                    }
+                }
+                "--env" => {
+                    let value = option_value(&mut iter, arg)?;
+                    if !value.contains('=') {
+                        return Err("--env should be NAME=VALUE".to_string());
+                    }
+                    parsed.env.push(value.to_string());
+                }
+                o if o.starts_with("--") => return Err(format!("unknown option `{}`\n\n{}", o, USAGE)),
+                program => {
+                    parsed.program = program.to_string();
+                    parsed.args = iter.cloned().collect();
+                    return Ok(parsed);
+                }
+            }
+        }
+        Err(format!("no program given\n\n{}", USAGE))
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = NetbsdArgs::parse(args)?;
+    let bad = |e: &dyn std::fmt::Display| format!("{}: {}", args.program, e);
+    let bytes = fs::read(&args.program).map_err(|e| bad(&e))?;
+    let program = Executable::parse(&bytes).map_err(|e| bad(&e))?;
+    let sandbox = Sandbox::new(Path::new(&args.root)).map_err(|e| format!("{}: {}", args.root, e))?;
+
+    let mut exec = ExecutionContext::with_bus(VAXBus::new(args.ram_mib << 20), TimeMode::RealTime);
+    let mut process = Process::new(sandbox);
+    let argv: Vec<&str> = std::iter::once(args.program.as_str()).chain(args.args.iter().map(String::as_str)).collect();
+    let env: Vec<&str> = args.env.iter().map(String::as_str).collect();
+    process.start(&mut exec, &program, &argv, &env).map_err(|e| bad(&e))?;
+
+    let end = process.run(&mut exec);
+    let _ = io::stdout().flush();
+    match end {
+        Termination::Exited(0) => Ok(()),
+        Termination::Exited(status) => std::process::exit(status),
+        _ => {
+            eprintln!("erodedvax: {}: {}", args.program, end);
+            std::process::exit(end.status());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<NetbsdArgs, String> {
+        let v: Vec<String> = s.split_whitespace().map(String::from).collect();
+        NetbsdArgs::parse(&v)
+    }
+
+    #[test]
+    fn parse_args() {
+        let a = args("--root /tmp/vax --env HOME=/ --ram 8 ./hello --root x -v").unwrap();
+        assert_eq!((a.program.as_str(), a.root.as_str(), a.ram_mib), ("./hello", "/tmp/vax", 8));
+        assert_eq!(a.args, vec!["--root", "x", "-v"]);
+        assert_eq!(a.env, vec!["HOME=/"]);
+
+        assert_eq!(args("--env HOME").unwrap_err(), "--env should be NAME=VALUE");
+        assert!(args("--ram").is_err());
+        assert_eq!(args("--ram 17592186044416 ./hello").unwrap_err(), "--ram: 17592186044416 MiB is too big");
+        assert!(args("--frobnicate hello").is_err());
+        assert!(args("").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::thread;
+use std::time::Duration;
+
+use crate::cmd::{
+    config::{check_drive, parse_boot_device, parse_model, parse_time_mode, DiskConfig, Endpoint, MachineConfig, SerialConfig, TapeConfig},
+    option_value, parse_u32,
+};
+use crate::ervax::{
+    devices::console::StdinSource,
+    gdb::{Endpoint as GdbEndpoint, GdbStub, Outcome},
+    loader::{push_args, Executable},
+    cpu::{
+        execution::{BinarySink, HaltReason, InstructionClass, RingSink, TextSink, TraceFilter, TraceSink, Tracer},
+        PrivilegeMode,
+    },
+    machine::{debugger::Debugger, monitor::Monitor, Model},
+};
+
+pub const USAGE: &str = "\
+usage: erodedvax run [options] [config file]
+
+Runs a machine, as the configuration file describes it, with the options on top. Options
+replace settings from the file, except that drives and lines are added to its.
+
+options:
+  --model NAME         ka630 or ka41, or another name for one
+  --ram MIB            memory size
+  --rom FILE           console ROM image, without which the built-in console boots --boot
+  --nvram FILE         saved NVRAM contents
+  --boot DEVICE        what the built-in console boots, such as DUA0, MUA0 or DKA0
+  --time MODE          real, or virtual for reproducible runs
+  --no-idle            don't skip idle loops
+  --disk TYPE:FILE     adds a disk drive, such as rd54:system.img
+  --tape FILE          adds a tape drive with a SIMH .tap image in it
+  --telnet LINE:PORT   puts terminal line LINE on a localhost port for telnet
+  --load FILE          loads an a.out or ELF program and starts it instead, with its stack
+                       at the top of memory
+  --gdb PORT|PATH      waits for GDB on a localhost port or a Unix socket, and runs the
+                       machine as it says until it detaches
+  --debug              stops the machine at the debugger's DBG> prompt instead of >>>,
+                       to start with and whenever it halts
+  --trace FILE         writes a line to FILE for each instruction run, with its operands,
+                       PSL and memory writes; what's been traced is written out on halts
+  --trace-binary FILE  the same in binary, much smaller, for `erodedvax trace` to print
+  --trace-ring N       keeps the last N instructions for the debugger's HISTORY command
+  --trace-pc FROM-TO   traces only with PC in this range, may be repeated
+  --trace-mode MODES   traces only in these modes, such as kernel,user or K,U
+  --trace-class LIST   traces only these kinds of instructions: branch, call, privileged
+                       or other
+  --check              check the configuration, and stop there
+
+Type ^P to halt the CPU and get the console's >>> prompt. The configuration file format
+is described in src/cmd/config.rs.";
+
+/// Where the instruction trace goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceOutput {
+    Text(String),
+    Binary(String),
+    Ring(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunArgs {
+    pub config: MachineConfig,
+    /// A standalone program to run.
+    pub load: Option<String>,
+    /// Where to wait for a debugger before running.
+    pub gdb: Option<GdbEndpoint>,
+    /// Whether the debugger stands in for the console monitor.
+    pub debug: bool,
+    pub trace: Option<TraceOutput>,
+    pub trace_filter: TraceFilter,
+    pub check: bool,
+}
+
+fn parse_mode(name: &str) -> Option<PrivilegeMode> {
+    let name = name.to_ascii_lowercase();
+    ["kernel", "executive", "supervisor", "user"]
+        .iter()
+        .position(|m| !name.is_empty() && m.starts_with(name.as_str()))
+        .map(|i| [PrivilegeMode::Kernel, PrivilegeMode::Executive, PrivilegeMode::Supervisor, PrivilegeMode::User][i])
+}
+
+/// Splits a LEFT:RIGHT option value.
+fn pair<'a>(option: &str, value: &'a str, form: &str) -> Result<(&'a str, &'a str), String> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(l), Some(r)) if !l.is_empty() && !r.is_empty() => Ok((l, r)),
+        _ => Err(format!("{} should be {}", option, form)),
+    }
+}
+
+impl RunArgs {
+    /// Parses the arguments, reading the configuration file if there is one.
+    pub fn parse(args: &[String]) -> Result<RunArgs, String> {
+        let mut file = None;
+        let mut check = false;
+        let mut debug = false;
+        let mut trace = None;
+        let mut trace_filter = TraceFilter::default();
+        let mut load = None;
+        let mut gdb = None;
+        // Options apply after the file's read, wherever they are.
+        let mut options = vec![];
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--check" => check = true,
+                "--debug" => debug = true,
+                "--trace" | "--trace-binary" | "--trace-ring" => {
+                    if trace.is_some() {
+                        return Err("only one of --trace, --trace-binary and --trace-ring can be given".to_string());
+                    }
+                    let value = option_value(&mut iter, arg)?;
+                    trace = Some(match arg.as_str() {
+                        "--trace" => TraceOutput::Text(value.to_string()),
+                        "--trace-binary" => TraceOutput::Binary(value.to_string()),
+                        _ => TraceOutput::Ring(value.parse().map_err(|_| format!("--trace-ring: `{}` isn't a number", value))?),
+                    });
+                }
+                "--trace-pc" => {
+                    let value = option_value(&mut iter, arg)?;
+                    let mut ends = value.splitn(2, '-');
+                    let range = match (ends.next().map(parse_u32), ends.next().map(parse_u32)) {
+                        (Some(Ok(from)), Some(Ok(to))) if from <= to => from..=to,
+                        _ => return Err("--trace-pc should be FROM-TO".to_string()),
+                    };
+                    trace_filter.ranges.push(range);
+                }
+                "--trace-mode" => {
+                    for m in option_value(&mut iter, arg)?.split(',') {
+                        trace_filter.modes.push(parse_mode(m).ok_or_else(|| format!("--trace-mode: unknown mode `{}`", m))?);
+                    }
+                }
+                "--trace-class" => {
+                    for c in option_value(&mut iter, arg)?.split(',') {
+                        let class = InstructionClass::from_name(c).ok_or_else(|| format!("--trace-class: unknown class `{}`", c))?;
+                        trace_filter.classes.push(class);
+                    }
+                }
+                "--no-idle" => options.push((arg.as_str(), "")),
+                "--load" => load = Some(option_value(&mut iter, arg)?.to_string()),
+                "--gdb" => gdb = Some(GdbEndpoint::parse(option_value(&mut iter, arg)?)),
+                "--model" | "--ram" | "--rom" | "--nvram" | "--boot" | "--time" | "--disk" | "--tape" | "--telnet" => {
+                    options.push((arg.as_str(), option_value(&mut iter, arg)?));
+                }
+                o if o.starts_with("--") => return Err(format!("unknown option `{}`\n\n{}", o, USAGE)),
+                f if file.is_none() => file = Some(f.to_string()),
+                f => return Err(format!("unexpected argument `{}`\n\n{}", f, USAGE)),
+            }
+        }
+
+        let mut config = match &file {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                MachineConfig::parse(&text, path).map_err(|e| e.to_string())?
+            }
+            None => MachineConfig::default(),
+        };
+        for (option, value) in options {
+            let bad = |e: String| format!("{}: {}", option, e);
+            match option {
+                "--model" => config.model = Some(parse_model(value).map_err(bad)?),
+                "--ram" => config.ram_mib = Some(value.parse().map_err(|_| bad(format!("`{}` isn't a number", value)))?),
+                "--rom" => config.rom = Some(value.to_string()),
+                "--nvram" => config.nvram = Some(value.to_string()),
+                "--boot" => config.boot = Some(parse_boot_device(value).map_err(bad)?),
+                "--time" => config.time_mode = parse_time_mode(value).map_err(bad)?,
+                "--no-idle" => config.idle = false,
+                "--disk" => {
+                    let (drive, path) = pair(option, value, "TYPE:FILE")?;
+                    check_drive(drive).map_err(bad)?;
+                    config.disks.push(DiskConfig { path: path.to_string(), drive: drive.to_string(), read_only: false });
+                }
+                "--tape" => config.tapes.push(TapeConfig { path: value.to_string(), read_only: false }),
+                _ => {
+                    let (line, port) = pair(option, value, "LINE:PORT")?;
+                    let line = line.parse().map_err(|_| bad(format!("`{}` isn't a line number", line)))?;
+                    let port = port.parse().map_err(|_| bad(format!("`{}` isn't a port number", port)))?;
+                    config.serial.push(SerialConfig { line, endpoint: Endpoint::Telnet(port) });
+                }
+            }
+        }
+        Ok(RunArgs { config, load, gdb, debug, trace, trace_filter, check })
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = RunArgs::parse(args)?;
+    let mut exec = args.config.build().map_err(|e| e.to_string())?;
+    let mut debugger = Debugger::new();
+    if let Some(path) = &args.load {
+        let bad = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+        let program = Executable::parse(&fs::read(path).map_err(|e| bad(&e))?).map_err(|e| bad(&e))?;
+        program.load(&mut exec).map_err(|e| bad(&e))?;
+        debugger.set_labels(program.labels());
+        let top = exec.bus().ram_size().min(u32::MAX as usize) as u32;
+        push_args(&mut exec, top, &[path], &[]).map_err(|e| bad(&e))?;
+    }
+    if args.check {
+        return Ok(());
+    }
+    exec.console().set_source(Some(Box::new(StdinSource::spawn())));
+
+    if let Some(output) = &args.trace {
+        let create = |path: &str| File::create(path).map(BufWriter::new).map_err(|e| format!("{}: {}", path, e));
+        let sink: Box<dyn TraceSink> = match output {
+            TraceOutput::Text(path) => Box::new(TextSink::new(create(path)?)),
+            TraceOutput::Binary(path) => Box::new(BinarySink::new(create(path)?).map_err(|e| format!("{}: {}", path, e))?),
+            TraceOutput::Ring(n) => {
+                let ring = RingSink::new(*n);
+                debugger.set_history(ring.clone());
+                Box::new(ring)
+            }
+        };
+        exec.set_tracer(Some(Tracer::new(sink, args.trace_filter.clone())));
+    }
+
+    if let Some(endpoint) = &args.gdb {
+        eprintln!("erodedvax: waiting for gdb on {}", endpoint);
+        let conn = endpoint.accept().map_err(|e| format!("--gdb: {}", e))?;
+        match GdbStub::new(conn).serve(&mut exec) {
+            Ok(Outcome::Killed) => return Ok(()),
+            Ok(_) => {}
+            Err(e) => eprintln!("erodedvax: gdb: {}", e),
+        }
+    }
+
+    if args.debug {
+        if !exec.is_halted() {
+            exec.halt(HaltReason::External);
+        }
+        while !debugger.quit_requested() {
+            if !debugger.service(&mut exec) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        return Ok(());
+    }
+
+    let mut monitor = Monitor::new();
+    if let Some(device) = args.config.boot.or_else(|| args.config.model.map(Model::default_boot_device)) {
+        monitor.set_boot_device(device);
+    }
+    loop {
+        // Halted, there's nothing to do until the operator types something.
+        if !monitor.service(&mut exec) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::sysclk::TimeMode;
+
+    fn args(s: &str) -> Result<RunArgs, String> {
+        let v: Vec<String> = s.split_whitespace().map(String::from).collect();
+        RunArgs::parse(&v)
+    }
+
+    #[test]
+    fn parse_args() {
+        let path = std::env::temp_dir().join(format!("erodedvax-run-{}.toml", std::process::id()));
+        fs::write(&path, "model = \"ka41\"\nram = 8\n[[disk]]\ntype = \"rd54\"\npath = \"a.img\"\n").unwrap();
+        let a = args(&format!("--check --ram 16 {} --load a.out --gdb 1234 --debug --trace-ring 100 --trace-pc 0x1000-0x1FFF --trace-mode k,USER --trace-class call --disk rd53:b.img --time virtual --telnet 1:2301", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(a.check && a.debug);
+        assert_eq!(a.trace, Some(TraceOutput::Ring(100)));
+        assert_eq!(a.trace_filter, TraceFilter {
+            ranges: vec![0x1000..=0x1FFF],
+            modes: vec![PrivilegeMode::Kernel, PrivilegeMode::User],
+            classes: vec![InstructionClass::Call],
+        });
+        assert_eq!(a.load.as_deref(), Some("a.out"));
+        assert_eq!(a.gdb, Some(GdbEndpoint::Tcp(1234)));
+        assert_eq!((a.config.model, a.config.ram_mib, a.config.time_mode), (Some(Model::Ka41), Some(16), TimeMode::Virtual));
+        assert_eq!(a.config.disks.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), vec!["a.img", "b.img"]);
+        assert_eq!(a.config.serial, vec![SerialConfig { line: 1, endpoint: Endpoint::Telnet(2301) }]);
+
+        assert_eq!(args("--model pdp11").unwrap_err(), "--model: unknown model `pdp11`, it's one of ka630, ka41");
+        assert_eq!(args("--telnet 2301").unwrap_err(), "--telnet should be LINE:PORT");
+        assert_eq!(args("--trace-pc 2000-1000").unwrap_err(), "--trace-pc should be FROM-TO");
+        assert_eq!(args("--trace-mode kernel,x").unwrap_err(), "--trace-mode: unknown mode `x`");
+        assert!(args("--trace a --trace-ring 5").is_err());
+        assert!(args("--ram").is_err());
+        assert!(args("--frobnicate").is_err());
+        assert!(args("/nonexistent/vax.toml").unwrap_err().starts_with("/nonexistent/vax.toml: "));
+    }
+}
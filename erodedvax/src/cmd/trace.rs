@@ -0,0 +1,27 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+use crate::ervax::cpu::execution::read_binary;
+
+pub const USAGE: &str = "\
+usage: erodedvax trace <trace file>...
+
+Prints binary traces, as `run --trace-binary` writes them, as text: a line per instruction
+with its PC, operands, PSL and the memory it wrote.";
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(format!("no trace file given\n\n{}", USAGE));
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for path in args {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let records = read_binary(BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+        for r in records {
+            writeln!(out, "{}", r).map_err(|e| e.to_string())?;
+        }
+    }
+    out.flush().map_err(|e| e.to_string())
+}
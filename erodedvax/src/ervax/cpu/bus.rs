@@ -5,30 +5,253 @@
 // Cross-page read/writes may be performed if both pages are
 // on the same device and the device addresses are contiguous.
 
+use std::any::Any;
+
+use crate::ervax::cpu::interrupts::{self, Interrupt, InterruptSource};
+
+/// Something other than RAM that responds to physical addresses: ROM, board registers, bus adapters.
+/// Devices are given the full physical address, so one device can sit at several ranges.
 pub trait VAXBusDevice {
+    /// Short name, for diagnostics.
+    fn name(&self) -> &str;
+
+    /// Reads `size` bytes (1, 2, or 4) at `addr`.
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError>;
+
+    /// Writes the low `size` bytes (1, 2, or 4) of `v` at `addr`.
+    fn write(&mut self, addr: u32, size: u32, v: u32) -> Result<(), BusError>;
+
+    /// Power-up, or a bus reset such as IORESET.
+    fn reset(&mut self) {}
+
+    /// An interrupt the device is requesting, if any.
+    fn interrupt_request(&self) -> Option<Interrupt> {
+        None
+    }
+
+    /// The CPU is taking an interrupt at `vector`, which may or may not be this device's.
+    fn acknowledge_interrupt(&mut self, _vector: u16) {}
+
+    /// Brings the device's idea of time up to date, in microseconds of emulated time. This happens
+    /// every few milliseconds, so it's also where devices poll the host for input.
+    fn set_time(&mut self, _now_us: u64) {}
 
+    /// Called after each access to the device and each time update, with RAM for devices that
+    /// do DMA. Work a register access starts is done here.
+    fn service(&mut self, _ram: &mut [u8]) {}
+
+    /// For getting at a particular device's own interface again.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
-// Implementation detail. Should be replaced eventually, as it's not as efficient as i'd like and
-// significantly restricts memory layout.
-pub struct VAXBusPage {
-    upper: u32, // Upper half of the address fed to the target device.
-    device: u16, // The device, referenced by ID.
+/// Identifies a device on a VAXBus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+/// A range of physical addresses a device responds to.
+struct BusRegion {
+    start: u32,
+    last: u32,
+    device: usize,
 }
 
 // 0x0000_0000 through 0x3FFF_FFFF is considered RAM space.
 pub struct VAXBus {
     devices: Vec<Box<dyn VAXBusDevice>>,
     ram: Vec<u8>,
-    bus_map: Vec<VAXBusPage>, // VERY inefficient.
+    /// Ordered by address, never overlapping each other or RAM.
+    regions: Vec<BusRegion>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusError {
+    /// Nothing responded at the physical address.
+    NonExistent(u32),
 }
 
 impl VAXBus {
-    pub fn new(ram_size: usize, bus_map: Vec<VAXBusPage>) -> VAXBus {
+    pub fn new(ram_size: usize) -> VAXBus {
         VAXBus {
             devices: vec![],
             ram: vec![0; ram_size],
-            bus_map,
+            regions: vec![],
+        }
+    }
+
+    #[inline]
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+}
+
+/// Devices.
+impl VAXBus {
+    /// Adds a device. It doesn't respond to anything until it's mapped.
+    pub fn add_device(&mut self, device: Box<dyn VAXBusDevice>) -> DeviceId {
+        self.devices.push(device);
+        DeviceId(self.devices.len() - 1)
+    }
+
+    /// Has a device respond to `len` bytes of physical addresses from `start`.
+    /// Panics if the range overlaps RAM or another device's, which is a mistake in the board model.
+    pub fn map(&mut self, id: DeviceId, start: u32, len: u32) {
+        assert!(len > 0 && id.0 < self.devices.len());
+        let last = start.checked_add(len - 1).expect("region past the end of the address space");
+        assert!(start as usize >= self.ram.len(), "region at {:#x} overlaps RAM", start);
+
+        let at = self.regions.partition_point(|r| r.start < start);
+        let overlaps_prev = at > 0 && self.regions[at - 1].last >= start;
+        let overlaps_next = at < self.regions.len() && self.regions[at].start <= last;
+        assert!(!overlaps_prev && !overlaps_next, "region at {:#x} overlaps another device", start);
+        self.regions.insert(at, BusRegion { start, last, device: id.0 });
+    }
+
+    /// Adds a device and maps it at a single range.
+    pub fn attach(&mut self, device: Box<dyn VAXBusDevice>, start: u32, len: u32) -> DeviceId {
+        let id = self.add_device(device);
+        self.map(id, start, len);
+        id
+    }
+
+    pub fn device_mut(&mut self, id: DeviceId) -> &mut dyn VAXBusDevice {
+        self.devices[id.0].as_mut()
+    }
+
+    /// The first device of type `T`, for getting at board-specific devices.
+    pub fn find_device<T: 'static>(&mut self) -> Option<&mut T> {
+        self.devices.iter_mut().find_map(|d| d.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// Resets every device, as IORESET does. RAM is left alone.
+    pub fn reset(&mut self) {
+        self.devices.iter_mut().for_each(|d| d.reset());
+    }
+
+    pub fn set_time(&mut self, now_us: u64) {
+        for d in self.devices.iter_mut() {
+            d.set_time(now_us);
+            d.service(&mut self.ram);
+        }
+    }
+
+    /// The device responding to all of `addr` through `addr + size - 1`.
+    fn device_at(&self, addr: u32, size: u32) -> Result<usize, BusError> {
+        let at = self.regions.partition_point(|r| r.start <= addr);
+        match at.checked_sub(1).map(|i| &self.regions[i]) {
+            Some(r) if addr.checked_add(size - 1).is_some_and(|end| end <= r.last) => Ok(r.device),
+            _ => Err(BusError::NonExistent(addr)),
         }
     }
+
+    fn device_read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        let i = self.device_at(addr, size)?;
+        let device = self.devices[i].as_mut();
+        let v = device.read(addr, size);
+        device.service(&mut self.ram);
+        v
+    }
+
+    fn device_write(&mut self, addr: u32, size: u32, v: u32) -> Result<(), BusError> {
+        let i = self.device_at(addr, size)?;
+        let device = self.devices[i].as_mut();
+        let result = device.write(addr, size, v);
+        device.service(&mut self.ram);
+        result
+    }
+}
+
+impl InterruptSource for VAXBus {
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        interrupts::highest(self.devices.iter().map(|d| d.interrupt_request()))
+    }
+
+    fn acknowledge(&mut self, vector: u16) {
+        self.devices.iter_mut().for_each(|d| d.acknowledge_interrupt(vector));
+    }
+}
+
+/// Physical memory access. Anything outside RAM goes to whichever device is mapped there.
+/// Reads take `&mut self` as reading device registers can have side effects.
+impl VAXBus {
+    #[inline]
+    fn ram_range(&self, addr: u32, len: usize) -> Result<std::ops::Range<usize>, BusError> {
+        let start = addr as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.ram.len() => Ok(start..end),
+            _ => Err(BusError::NonExistent(addr)),
+        }
+    }
+
+    pub fn read_u8(&mut self, addr: u32) -> Result<u8, BusError> {
+        match self.ram_range(addr, 1) {
+            Ok(r) => Ok(self.ram[r.start]),
+            Err(_) => self.device_read(addr, 1).map(|v| v as u8),
+        }
+    }
+
+    pub fn read_u16(&mut self, addr: u32) -> Result<u16, BusError> {
+        match self.ram_range(addr, 2) {
+            Ok(r) => {
+                let mut b = [0; 2];
+                b.copy_from_slice(&self.ram[r]);
+                Ok(u16::from_le_bytes(b))
+            }
+            Err(_) => self.device_read(addr, 2).map(|v| v as u16),
+        }
+    }
+
+    pub fn read_u32(&mut self, addr: u32) -> Result<u32, BusError> {
+        match self.ram_range(addr, 4) {
+            Ok(r) => {
+                let mut b = [0; 4];
+                b.copy_from_slice(&self.ram[r]);
+                Ok(u32::from_le_bytes(b))
+            }
+            Err(_) => self.device_read(addr, 4),
+        }
+    }
+
+    pub fn write_u8(&mut self, addr: u32, v: u8) -> Result<(), BusError> {
+        match self.ram_range(addr, 1) {
+            Ok(r) => {
+                self.ram[r.start] = v;
+                Ok(())
+            }
+            Err(_) => self.device_write(addr, 1, v as u32),
+        }
+    }
+
+    pub fn write_u16(&mut self, addr: u32, v: u16) -> Result<(), BusError> {
+        match self.ram_range(addr, 2) {
+            Ok(r) => {
+                self.ram[r].copy_from_slice(&v.to_le_bytes());
+                Ok(())
+            }
+            Err(_) => self.device_write(addr, 2, v as u32),
+        }
+    }
+
+    pub fn write_u32(&mut self, addr: u32, v: u32) -> Result<(), BusError> {
+        match self.ram_range(addr, 4) {
+            Ok(r) => {
+                self.ram[r].copy_from_slice(&v.to_le_bytes());
+                Ok(())
+            }
+            Err(_) => self.device_write(addr, 4, v),
+        }
+    }
+
+    /// Copies a block of bytes out of RAM.
+    pub fn read_bytes(&mut self, addr: u32, bytes: &mut [u8]) -> Result<(), BusError> {
+        let r = self.ram_range(addr, bytes.len())?;
+        bytes.copy_from_slice(&self.ram[r]);
+        Ok(())
+    }
+
+    /// Copies a block of bytes into RAM, for loading images.
+    pub fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), BusError> {
+        let r = self.ram_range(addr, bytes.len())?;
+        self.ram[r].copy_from_slice(bytes);
+        Ok(())
+    }
 }
\ No newline at end of file
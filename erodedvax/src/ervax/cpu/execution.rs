@@ -1,16 +1,56 @@
 use num_traits::{FromPrimitive, ToPrimitive};
 
-use crate::ervax::cpu::{
-    bus::VAXBus,
-    mmu::VAXMMU,
-    PrivilegeMode,
+use crate::ervax::{
+    cpu::{
+        bus::VAXBus,
+        instrs::FieldMode,
+        interrupts::{self, Interrupt, InterruptSource},
+        mmu::VAXMMU,
+        registers::PrivRegisters,
+        sysclk::{EventId, SystemClock, TimeMode},
+        PrivilegeMode,
+        RegID,
+    },
+    devices::{
+        clock::{IntervalClock, TimeOfYear, TODR_BASE},
+        console::{Console, StdoutSink},
+    },
 };
 
+mod exceptions;
+mod idle;
+mod operands;
+mod ops;
+mod trace;
+mod watch;
+
+pub use exceptions::{vectors, ArithmeticTrap, Exception, HaltReason};
+pub use idle::IdleConfig;
+pub use operands::{DebugAccessError, FetchedOperand, Location, Operand};
+pub use trace::{
+    read_binary, BinarySink, InstructionClass, MemoryWrite, RingSink, TextSink, TraceFilter, TraceRecord, TraceSink,
+    Tracer,
+};
+pub use watch::{WatchKind, Watchpoint};
+
+/// Cycles in a SystemClock tick, 10ms of virtual time.
+const CYCLES_PER_TICK: u32 = 10_000;
+
+/// How often the console's input source is polled.
+const CONSOLE_POLL_US: u64 = 1_000;
+
+/// How often bus devices are told the time.
+const BUS_TIME_US: u64 = 10_000;
+
+/// The PSL the CPU powers up with: kernel mode on the interrupt stack, at IPL 31.
+pub const POWER_UP_PSL: u32 = 0x041F_0000;
+
 /// An ExecutionContext is the enviornment within which the emulated system executes, and it handles
 /// all major aspects of the emulated system. Used to create, start, and stop the emulated CPU, it's
 /// memory, and it's attached IO devices.
 pub struct ExecutionContext {
-    halted: bool,
+    /// Why the CPU isn't running, None while it is.
+    halt_reason: Option<HaltReason>,
 
     /// System Control Block Base.
     scbb: u32, 
@@ -43,6 +83,43 @@ pub struct ExecutionContext {
 
     /// System MMU. When enabled, memory reads/writes are passed through it first.
     mmu: VAXMMU,
+
+    /// Counts cycles executed, is the time base for the clocks, and runs device events.
+    sysclk: SystemClock<ExecutionContext>,
+
+    /// Console terminal, accessed through processor registers.
+    console: Console,
+
+    /// Interval clock, accessed through processor registers.
+    interval_clock: IntervalClock,
+
+    /// Time-of-year clock, accessed through processor registers.
+    todr: TimeOfYear,
+
+    /// Brings the interval clock up to date when ICR next overflows.
+    clock_event: Option<EventId>,
+
+    /// System Identification, which CPU this is.
+    sid: u32,
+
+    /// Where the console saved the PC and PSL when the CPU halted.
+    saved_pc: u32,
+    saved_psl: u32,
+
+    /// Counts memory writes, processor register accesses, and events run, anything that could
+    /// take the CPU out of an idle loop.
+    side_effects: u64,
+    idle_config: IdleConfig,
+    idle_watch: Option<idle::IdleWatch>,
+    idle_cycles: u64,
+
+    /// Change mode instructions halt for the host to service, rather than trapping.
+    host_change_mode: bool,
+
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<(Watchpoint, u32)>,
+
+    tracer: Option<Box<Tracer>>,
 }
 
 /// Getters and setters for the Processor Status Longword
@@ -75,6 +152,26 @@ impl ExecutionContext {
         self.psl |= x;
     }
     #[inline]
+    pub fn get_prev_priv_mode(&self) -> PrivilegeMode {
+        PrivilegeMode::from_u32((self.psl & 0x00C0_0000) >> 22).unwrap()
+    }
+
+    #[inline]
+    pub fn set_prev_priv_mode(&mut self, m: PrivilegeMode) {
+        let x = (m.to_u32().unwrap() << 22) & 0x00C0_0000;
+        self.psl &= !0x00C0_0000;
+        self.psl |= x;
+    }
+    #[inline]
+    pub fn get_ipl(&self) -> u8 {
+        ((self.psl & 0x001F_0000) >> 16) as u8
+    }
+    #[inline]
+    pub fn set_ipl(&mut self, ipl: u8) {
+        self.psl &= !0x001F_0000;
+        self.psl |= ((ipl & 0x1F) as u32) << 16;
+    }
+    #[inline]
     pub fn get_trace_pending(&self) -> bool {
         self.get_psl_bit(30)
     }
@@ -140,10 +237,18 @@ impl ExecutionContext {
     }
     #[inline]
     pub fn get_zero(&self) -> bool {
-        self.get_psl_bit(1)
+        self.get_psl_bit(2)
     }
     #[inline]
     pub fn set_zero(&mut self, val: bool) {
+        self.set_psl_bit(2, val)
+    }
+    #[inline]
+    pub fn get_overflow(&self) -> bool {
+        self.get_psl_bit(1)
+    }
+    #[inline]
+    pub fn set_overflow(&mut self, val: bool) {
         self.set_psl_bit(1, val)
     }
     #[inline]
@@ -156,20 +261,419 @@ impl ExecutionContext {
     }
 }
 
+/// Register access.
+impl ExecutionContext {
+    #[inline]
+    pub fn psl(&self) -> u32 {
+        self.psl
+    }
+
+    #[inline]
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    #[inline]
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
+    /// Replaces the whole PSL, which also picks which stack pointer SP is.
+    #[inline]
+    pub fn set_psl(&mut self, psl: u32) {
+        self.psl = psl;
+    }
+
+    /// The stack pointer in use, picked by the interrupt stack flag and current mode.
+    fn sp_mut(&mut self) -> &mut u32 {
+        if self.get_interrupt_stack() {
+            return &mut self.isp;
+        }
+        match self.get_cur_priv_mode() {
+            PrivilegeMode::Kernel => &mut self.ksp,
+            PrivilegeMode::Executive => &mut self.esp,
+            PrivilegeMode::Supervisor => &mut self.ssp,
+            PrivilegeMode::User => &mut self.usp,
+        }
+    }
+
+    pub fn sp(&self) -> u32 {
+        if self.get_interrupt_stack() {
+            return self.isp;
+        }
+        match self.get_cur_priv_mode() {
+            PrivilegeMode::Kernel => self.ksp,
+            PrivilegeMode::Executive => self.esp,
+            PrivilegeMode::Supervisor => self.ssp,
+            PrivilegeMode::User => self.usp,
+        }
+    }
+
+    #[inline]
+    pub fn set_sp(&mut self, sp: u32) {
+        *self.sp_mut() = sp;
+    }
+
+    /// Reads a general register, R0 through PC.
+    pub fn reg(&self, r: RegID) -> u32 {
+        match r {
+            RegID::SP => self.sp(),
+            RegID::PC => self.pc,
+            r => self.gpr[r.id() as usize],
+        }
+    }
+
+    pub fn set_reg(&mut self, r: RegID, v: u32) {
+        match r {
+            RegID::SP => self.set_sp(v),
+            RegID::PC => self.pc = v,
+            r => self.gpr[r.id() as usize] = v,
+        }
+    }
+
+    /// Reads a processor register, as MFPR does. Registers with nothing behind them read as 0.
+    pub fn read_ipr(&mut self, reg: PrivRegisters) -> u32 {
+        use PrivRegisters::*;
+        self.side_effects += 1;
+        match reg {
+            KSP => self.ksp,
+            ESP => self.esp,
+            SSP => self.ssp,
+            USP => self.usp,
+            ISP => self.isp,
+            PCBB => self.pcbb,
+            SCBB => self.scbb,
+            IPL => self.get_ipl() as u32,
+            ICCS => self.interval_clock.read_iccs(self.sysclk.now_us()),
+            ICR => self.interval_clock.read_icr(self.sysclk.now_us()),
+            TODR => self.todr.read(self.sysclk.now_us()),
+            RXCS => self.console.read_rxcs(),
+            RXDB => self.console.read_rxdb(),
+            TXCS => self.console.read_txcs(),
+            SAVPC => self.saved_pc,
+            SAVPSL => self.saved_psl,
+            SID => self.sid,
+            P0BR => self.mmu.p0_base(),
+            POLR => self.mmu.p0_len(),
+            P1BR => self.mmu.p1_base(),
+            P1LR => self.mmu.p1_len(),
+            SBR => self.mmu.sys_base(),
+            SLR => self.mmu.sys_len(),
+            MAPEN => self.mmu.is_enabled() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Writes a processor register, as MTPR does. Writes to registers with nothing behind them are dropped.
+    pub fn write_ipr(&mut self, reg: PrivRegisters, v: u32) {
+        use PrivRegisters::*;
+        self.side_effects += 1;
+        match reg {
+            KSP => self.ksp = v,
+            ESP => self.esp = v,
+            SSP => self.ssp = v,
+            USP => self.usp = v,
+            ISP => self.isp = v,
+            PCBB => self.pcbb = v & !3,
+            SCBB => self.scbb = v & !0x1FF,
+            IPL => self.set_ipl(v as u8),
+            ICCS => {
+                self.interval_clock.write_iccs(self.sysclk.now_us(), v);
+                self.schedule_clock_event();
+            }
+            NICR => self.interval_clock.write_nicr(v),
+            TODR => self.todr.write(self.sysclk.now_us(), v),
+            RXCS => self.console.write_rxcs(v),
+            TXCS => self.console.write_txcs(v),
+            TXDB => self.console.write_txdb(v),
+            P0BR => self.mmu.set_p0_base(v),
+            POLR => self.mmu.set_p0_len(v),
+            P1BR => self.mmu.set_p1_base(v),
+            P1LR => self.mmu.set_p1_len(v),
+            SBR => self.mmu.set_sys_base(v),
+            SLR => self.mmu.set_sys_len(v),
+            MAPEN => self.mmu.set_enabled(v & 1 != 0),
+            IORESET => {
+                self.bus.reset();
+                self.bus.set_time(self.sysclk.now_us());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Devices.
+impl ExecutionContext {
+    #[inline]
+    pub fn bus(&mut self) -> &mut VAXBus {
+        &mut self.bus
+    }
+
+    #[inline]
+    pub fn console(&mut self) -> &mut Console {
+        &mut self.console
+    }
+
+    #[inline]
+    pub fn sysclk(&mut self) -> &mut SystemClock<ExecutionContext> {
+        &mut self.sysclk
+    }
+
+    /// Runs every event that's come due.
+    pub fn run_due_events(&mut self) {
+        while let Some(callback) = self.sysclk.pop_due() {
+            self.side_effects += 1;
+            callback(self);
+        }
+    }
+
+    /// Tells bus devices the time every BUS_TIME_US, for as long as the machine exists.
+    fn schedule_bus_time(&mut self) {
+        self.bus.set_time(self.sysclk.now_us());
+        self.sysclk.schedule_in_us(BUS_TIME_US, Box::new(|exec: &mut ExecutionContext| {
+            exec.schedule_bus_time();
+        }));
+    }
+
+    /// Polls the console's input source every CONSOLE_POLL_US, for as long as the machine exists.
+    fn schedule_console_poll(&mut self) {
+        self.sysclk.schedule_in_us(CONSOLE_POLL_US, Box::new(|exec: &mut ExecutionContext| {
+            exec.console.poll_input();
+            if exec.console.take_halt_request() && !exec.is_halted() {
+                exec.halt(HaltReason::External);
+            }
+            exec.schedule_console_poll();
+        }));
+    }
+
+    /// Schedules an event for when ICR next overflows, replacing any already scheduled.
+    /// The event brings the clock up to date, which requests the interrupt, and schedules the next one.
+    fn schedule_clock_event(&mut self) {
+        if let Some(id) = self.clock_event.take() {
+            self.sysclk.cancel(id);
+        }
+        let now = self.sysclk.now_us();
+        if let Some(us) = self.interval_clock.next_overflow_in(now) {
+            let id = self.sysclk.schedule_in_us(us, Box::new(|exec: &mut ExecutionContext| {
+                exec.clock_event = None;
+                exec.schedule_clock_event();
+            }));
+            self.clock_event = Some(id);
+        }
+    }
+}
+
+/// Interrupts.
+impl ExecutionContext {
+    /// The highest priority interrupt above the current IPL, if any. Devices update their
+    /// requests from scheduled events, so this doesn't poll anything.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let ipl = self.get_ipl();
+        interrupts::highest([
+            self.interval_clock.pending_interrupt(),
+            self.console.pending_interrupt(),
+            self.bus.pending_interrupt(),
+        ]).filter(|i| i.ipl > ipl)
+    }
+
+    /// Tells whichever device requested an interrupt that it's being taken.
+    fn acknowledge_interrupt(&mut self, vector: u16) {
+        self.interval_clock.acknowledge(vector);
+        self.console.acknowledge(vector);
+        self.bus.acknowledge(vector);
+    }
+
+    /// Runs due events, then takes the highest priority pending interrupt if there is one.
+    /// Returns whether one was taken. The CPU halts if the interrupt can't be delivered.
+    pub fn check_interrupts(&mut self) -> bool {
+        self.run_due_events();
+        match self.pending_interrupt() {
+            Some(int) => {
+                if let Err(reason) = self.take_interrupt(int) {
+                    self.halt(reason);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Registers an instruction puts back if it faults.
+#[derive(Clone, PartialEq, Eq)]
+struct SavedRegisters {
+    gpr: [u32; 14],
+    stack: [u32; 5],
+    psl: u32,
+    pc: u32,
+}
+
 /// Execution.
 impl ExecutionContext {
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.halt_reason.is_some()
+    }
+
+    #[inline]
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halt_reason
+    }
+
+    /// Stops the CPU, saving PC and PSL in SAVPC and SAVPSL as the console does.
+    pub fn halt(&mut self, reason: HaltReason) {
+        self.halt_reason = Some(reason);
+        self.saved_pc = self.pc;
+        self.saved_psl = self.psl;
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.flush();
+        }
+    }
+
+    /// Starts (or continues) execution at `pc`.
+    pub fn start(&mut self, pc: u32) {
+        self.pc = pc;
+        self.halt_reason = None;
+    }
+
+    /// Starts the CPU as power coming on does, at `pc` in the power-up PSL. Boards pass where
+    /// their console ROM begins.
+    pub fn power_up(&mut self, pc: u32) {
+        self.psl = POWER_UP_PSL;
+        self.start(pc);
+    }
+
+    fn save_registers(&self) -> SavedRegisters {
+        SavedRegisters {
+            gpr: self.gpr,
+            stack: [self.ksp, self.esp, self.ssp, self.usp, self.isp],
+            psl: self.psl,
+            pc: self.pc,
+        }
+    }
+
+    fn restore_registers(&mut self, saved: &SavedRegisters) {
+        self.gpr = saved.gpr;
+        let [ksp, esp, ssp, usp, isp] = saved.stack;
+        self.ksp = ksp;
+        self.esp = esp;
+        self.ssp = ssp;
+        self.usp = usp;
+        self.isp = isp;
+        self.psl = saved.psl;
+        self.pc = saved.pc;
+    }
+
+    fn fetch_and_execute(&mut self) -> Result<(), Exception> {
+        let (instr, fetched, next) = self.fetch()?;
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.fetched(instr);
+        }
+        let mut operands = Vec::with_capacity(fetched.len());
+        for op in fetched.iter().filter(|op| op.field != FieldMode::VariableLengthTable) {
+            operands.push(self.evaluate(op)?);
+        }
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.evaluated(&operands);
+        }
+        self.pc = next;
+        self.execute(instr, &operands)
+    }
+
+    /// Takes a pending interrupt, or executes one instruction, without looking at the event queue.
+    fn step(&mut self) {
+        if let Some(int) = self.pending_interrupt() {
+            if let Err(reason) = self.take_interrupt(int) {
+                self.halt(reason);
+            }
+        } else {
+            let saved = self.save_registers();
+            let side_effects = self.side_effects;
+            let traced = self.tracer.is_some();
+            if traced {
+                self.trace_begin();
+            }
+            match self.fetch_and_execute() {
+                Ok(()) => {
+                    if self.idle_config.enabled() && self.is_idle(&saved, side_effects) {
+                        self.idle_until_next_event();
+                    }
+                }
+                Err(e) => {
+                    if e.is_fault() {
+                        self.restore_registers(&saved);
+                    }
+                    if let Err(reason) = self.take_exception(e) {
+                        self.halt(reason);
+                    }
+                }
+            }
+            if traced {
+                self.trace_end();
+            }
+        }
+
+        if self.sysclk.consume_cycles(1) {
+            self.sysclk.new_tick();
+        }
+    }
+
     /// Execute one step. Does not necessarily map to a single cycle.
     /// Usually takes however many cycles it needs to execute the next instruction.
+    /// Runs any events that have come due first. Returns whether the CPU is still running.
     pub fn execute_step(&mut self) -> bool {
-        unimplemented!()
+        if self.is_halted() {
+            return false;
+        }
+        self.run_due_events();
+        self.step();
+        !self.is_halted()
+    }
+
+    /// Runs due events, then executes instructions until the next event is due, without checking
+    /// the event queue in between. Returns whether the CPU is still running.
+    pub fn run_until_next_event(&mut self) -> bool {
+        if self.is_halted() {
+            return false;
+        }
+        self.run_due_events();
+        // An instruction can schedule an earlier event, as writing ICCS does, so this is re-read.
+        while !self.is_halted() && self.sysclk.next_event_at().is_none_or(|at| self.sysclk.cycles_all_time() < at) {
+            self.step();
+        }
+        !self.is_halted()
+    }
+
+    /// Runs until the CPU halts, and says why.
+    pub fn run(&mut self) -> HaltReason {
+        while self.run_until_next_event() {}
+        self.halt_reason.unwrap()
     }
 }
 
 /// Creating an ExecutionContext
 impl ExecutionContext {
+    /// A machine keeping real time, for interactive use.
     pub fn new() -> ExecutionContext {
-        ExecutionContext {
-            halted: true,
+        ExecutionContext::with_time_mode(TimeMode::RealTime)
+    }
+
+    /// 512 KiB of RAM and nothing else on the bus.
+    pub fn with_time_mode(mode: TimeMode) -> ExecutionContext {
+        ExecutionContext::with_bus(VAXBus::new(524288), mode)
+    }
+
+    /// A CPU on `bus`, for board models to build on.
+    /// In virtual time the time-of-year clock starts at the beginning of the year,
+    /// in real time it's set from the host.
+    pub fn with_bus(bus: VAXBus, mode: TimeMode) -> ExecutionContext {
+        let todr = match mode {
+            TimeMode::RealTime => TimeOfYear::from_host(0),
+            TimeMode::Virtual => TimeOfYear::new(TODR_BASE, 0),
+        };
+
+        let mut exec = ExecutionContext {
+            halt_reason: Some(HaltReason::PowerUp),
             scbb: 0,
             pcbb: 0,
             ksp: 0,
@@ -180,12 +684,43 @@ impl ExecutionContext {
             psl: 0,
             pc: 0,
             gpr: [0; 14],
-            bus: VAXBus::new(524288, vec![]),
+            bus,
             mmu: VAXMMU::new(),
-        }
+            sysclk: SystemClock::new(CYCLES_PER_TICK, mode),
+            console: Console::new(Box::new(StdoutSink)),
+            interval_clock: IntervalClock::new(),
+            todr,
+            clock_event: None,
+            sid: 0,
+            saved_pc: 0,
+            saved_psl: 0,
+            side_effects: 0,
+            idle_config: IdleConfig::default(),
+            idle_watch: None,
+            idle_cycles: 0,
+            host_change_mode: false,
+            watchpoints: vec![],
+            watch_hit: None,
+            tracer: None,
+        };
+        exec.schedule_console_poll();
+        exec.schedule_bus_time();
+        exec
+    }
+
+    /// Has CHMK, CHME, CHMS and CHMU halt with HaltReason::ChangeMode instead of trapping, for
+    /// running programs with the host standing in for their operating system.
+    pub fn set_host_change_mode(&mut self, enabled: bool) {
+        self.host_change_mode = enabled;
+    }
+
+    /// Sets the System Identification register, which board models do.
+    pub fn set_sid(&mut self, sid: u32) {
+        self.sid = sid;
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
@@ -194,5 +729,367 @@ mod tests {
         assert_eq!(exec.get_cur_priv_mode(), PrivilegeMode::Kernel);
         exec.set_cur_priv_mode(PrivilegeMode::User);
         assert_eq!(exec.get_cur_priv_mode(), PrivilegeMode::User);
+        exec.set_ipl(0x1F);
+        assert_eq!(exec.get_ipl(), 0x1F);
+        assert_eq!(exec.get_cur_priv_mode(), PrivilegeMode::User);
+
+        // The condition codes are N Z V C, from bit 3 down.
+        exec.set_zero(true);
+        assert_eq!(exec.psl() & 0xF, 0x4);
+        exec.set_overflow(true);
+        assert_eq!(exec.psl() & 0xF, 0x6);
+        assert!(exec.get_zero() && exec.get_overflow() && !exec.get_carry());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn power_up_state() {
+        let mut exec = ExecutionContext::new();
+        assert_eq!(exec.halt_reason(), Some(HaltReason::PowerUp));
+        exec.set_cur_priv_mode(PrivilegeMode::User);
+        exec.power_up(0x2004_0000);
+        assert!(!exec.is_halted());
+        assert_eq!((exec.pc(), exec.get_ipl(), exec.get_cur_priv_mode()), (0x2004_0000, 0x1F, PrivilegeMode::Kernel));
+        assert!(exec.get_interrupt_stack());
+    }
+
+    #[test]
+    fn console_registers() {
+        use crate::ervax::devices::console::{MemorySink, CSR_READY};
+
+        let mut exec = ExecutionContext::new();
+        let sink = MemorySink::new();
+        exec.console().set_sink(Box::new(sink.clone()));
+
+        assert_eq!(exec.read_ipr(PrivRegisters::TXCS) & CSR_READY, CSR_READY);
+        exec.write_ipr(PrivRegisters::TXDB, b'!' as u32);
+        assert_eq!(sink.contents(), b"!");
+
+        exec.console().push_input(b"y");
+        assert_eq!(exec.read_ipr(PrivRegisters::RXCS), CSR_READY);
+        assert_eq!(exec.read_ipr(PrivRegisters::RXDB), b'y' as u32);
+        assert_eq!(exec.read_ipr(PrivRegisters::RXCS), 0);
+    }
+
+    #[test]
+    fn console_interrupt() {
+        use crate::ervax::cpu::interrupts::{vectors, IPL_CONSOLE};
+        use crate::ervax::devices::console::CSR_IE;
+
+        let mut exec = ExecutionContext::new();
+        exec.write_ipr(PrivRegisters::SCBB, 0x200);
+        exec.bus().write_u32(0x200 + vectors::CONSOLE_RECEIVE as u32, 0x3001).unwrap();
+        exec.write_ipr(PrivRegisters::ISP, 0x1000);
+        exec.set_cur_priv_mode(PrivilegeMode::User);
+        exec.set_sp(0x8000);
+        exec.set_pc(0x4000);
+        let psl = exec.psl();
+
+        exec.write_ipr(PrivRegisters::RXCS, CSR_IE);
+        assert!(!exec.check_interrupts());
+
+        exec.console().push_input(b"a");
+        exec.write_ipr(PrivRegisters::IPL, IPL_CONSOLE as u32);
+        assert!(!exec.check_interrupts(), "interrupt taken at its own IPL");
+        exec.write_ipr(PrivRegisters::IPL, 0);
+
+        assert!(exec.check_interrupts());
+        assert_eq!(exec.pc(), 0x3000);
+        assert_eq!(exec.get_ipl(), IPL_CONSOLE);
+        assert_eq!(exec.get_cur_priv_mode(), PrivilegeMode::Kernel);
+        assert!(exec.get_interrupt_stack());
+        assert_eq!(exec.sp(), 0x1000 - 8);
+        assert_eq!(exec.bus().read_u32(0x1000 - 8), Ok(0x4000));
+        assert_eq!(exec.bus().read_u32(0x1000 - 4), Ok(psl));
+        assert_eq!(exec.usp, 0x8000);
+
+        // Acknowledged, so not taken again.
+        exec.write_ipr(PrivRegisters::IPL, 0);
+        assert!(!exec.check_interrupts());
+    }
+
+    #[test]
+    fn interval_clock_interrupt() {
+        use crate::ervax::cpu::interrupts::{vectors, IPL_CLOCK};
+        use crate::ervax::devices::clock::{ICCS_IE, ICCS_INT, ICCS_RUN, ICCS_XFR, TODR_BASE};
+
+        let mut exec = ExecutionContext::with_time_mode(TimeMode::Virtual);
+        exec.write_ipr(PrivRegisters::SCBB, 0x200);
+        exec.bus().write_u32(0x200 + vectors::INTERVAL_CLOCK as u32, 0x3000).unwrap();
+        exec.write_ipr(PrivRegisters::ISP, 0x1000);
+        exec.write_ipr(PrivRegisters::NICR, -10_000i32 as u32);
+        exec.write_ipr(PrivRegisters::ICCS, ICCS_XFR | ICCS_IE | ICCS_RUN);
+
+        exec.sysclk().consume_cycles(9_999);
+        assert!(!exec.check_interrupts());
+        assert_eq!(exec.read_ipr(PrivRegisters::ICR), -1i32 as u32);
+        assert_eq!(exec.read_ipr(PrivRegisters::TODR), TODR_BASE);
+
+        exec.sysclk().consume_cycles(1);
+        assert!(exec.check_interrupts());
+        assert_eq!(exec.get_ipl(), IPL_CLOCK);
+        assert_eq!(exec.pc(), 0x3000);
+        assert_eq!(exec.read_ipr(PrivRegisters::ICCS), ICCS_INT | ICCS_IE | ICCS_RUN);
+        assert_eq!(exec.read_ipr(PrivRegisters::TODR), TODR_BASE + 1);
+    }
+
+    /// A machine in virtual time running `source`, assembled at 0x1000, with the SCB at 0x200,
+    /// every vector pointing at a HALT at 0x800, and the kernel stack below 0x4000.
+    fn machine(source: &str) -> ExecutionContext {
+        use crate::ervax::cpu::instrs::assemble;
+
+        let mut exec = ExecutionContext::with_time_mode(TimeMode::Virtual);
+        exec.write_ipr(PrivRegisters::SCBB, 0x200);
+        for v in (0..0x200).step_by(4) {
+            exec.bus().write_u32(0x200 + v, 0x800).unwrap();
+        }
+        exec.bus().write_u8(0x800, 0x00).unwrap();
+        exec.write_ipr(PrivRegisters::ISP, 0x5000);
+        exec.set_sp(0x4000);
+        exec.set_ipl(0x1F);
+
+        let code = assemble(source, 0x1000).unwrap();
+        exec.bus().write_bytes(0x1000, &code.bytes).unwrap();
+        exec.start(0x1000);
+        exec
+    }
+
+    fn run_to_halt(exec: &mut ExecutionContext) -> HaltReason {
+        for _ in 0..100_000 {
+            if !exec.execute_step() {
+                return exec.halt_reason().unwrap();
+            }
+        }
+        panic!("still running at {:#x}", exec.pc());
+    }
+
+    #[test]
+    fn execute_loop() {
+        let mut exec = machine("
+                CLRL R0
+                MOVL #10, R1
+        LOOP:   ADDL2 R1, R0
+                SOBGTR R1, LOOP
+                HALT
+        ");
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(0)), 55);
+        assert_eq!(exec.reg(RegID::new(1)), 0);
+        assert!(exec.get_zero());
+        assert!(!exec.execute_step());
+    }
+
+    #[test]
+    fn execute_calls_ret() {
+        let mut exec = machine("
+                MOVL #7, R2
+                PUSHL #5
+                CALLS #1, DOUBLE
+                HALT
+                .ENTRY DOUBLE, ^M<R2>
+                MOVL 4(AP), R2
+                ADDL3 R2, R2, R0
+                RET
+        ");
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(0)), 10);
+        assert_eq!(exec.reg(RegID::new(2)), 7, "R2 restored");
+        assert_eq!(exec.sp(), 0x4000, "argument popped");
+    }
+
+    #[test]
+    fn execute_case_and_fields() {
+        let mut exec = machine("
+                MOVL #1, R0
+                CASEB R0, #0, #1
+        TABLE:  .WORD A-TABLE, B-TABLE
+        A:      HALT
+        B:      MOVL #^XF0, R3
+                EXTZV #4, #4, R3, R4
+                INSV #5, #8, #3, R3
+                HALT
+        ");
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(4)), 0xF);
+        assert_eq!(exec.reg(RegID::new(3)), 0x5F0);
+
+        // Table offsets wrap around the address space, for selectors and limits this big.
+        let mut exec = machine("
+                MOVL #^X80000000, R0
+                CASEL R0, #0, #-1
+        TABLE:  .WORD A-TABLE
+        A:      MOVL #^X90000000, R0
+                CASEL R0, #0, #^X80000000
+                .WORD 0
+                HALT
+        ");
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.pc(), 0x1023);
+    }
+
+    #[test]
+    fn execute_faults() {
+        // A reserved instruction backs out autoincrements and goes through its vector.
+        let mut exec = machine("
+                MOVL #^X2000, R1
+                ADDF2 (R1)+, R0
+        ");
+        let psl = exec.psl();
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.pc(), 0x801);
+        assert_eq!(exec.reg(RegID::new(1)), 0x2000);
+        assert_eq!(exec.bus().read_u32(0x4000 - 8), Ok(0x1007));
+        assert_eq!(exec.bus().read_u32(0x4000 - 4), Ok(psl));
+
+        // Integer overflow only traps when enabled, after the result is stored.
+        let mut exec = machine("
+                MOVL #^X7FFFFFFF, R0
+                INCL R0
+                BISPSW #^X20
+                INCL R1
+                MOVL #^X7FFFFFFF, R1
+                INCL R1
+                HALT
+        ");
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.pc(), 0x801);
+        assert_eq!(exec.reg(RegID::new(0)), 0x8000_0000);
+        assert_eq!(exec.reg(RegID::new(1)), 0x8000_0000);
+        assert_eq!(exec.bus().read_u32(0x4000 - 12), Ok(ArithmeticTrap::IntegerOverflow as u32));
+    }
+
+    #[test]
+    fn execute_mapped() {
+        // S0 maps the first 16K one to one from a table at 8000, and its next page is invalid.
+        // P0 does the same, its table at 3000 in S0.
+        let mut exec = machine("
+                MOVL @#^X80001000, R0
+                MOVL @#^X80004000, R1
+        ");
+        for page in 0..0x20 {
+            exec.bus().write_u32(0x8000 + page * 4, 0x9000_0000 | page).unwrap();
+            exec.bus().write_u32(0x3000 + page * 4, 0xA000_0000 | page).unwrap();
+        }
+        exec.bus().write_u32(0x8000 + 0x80, 0x1000_0000).unwrap();
+        exec.write_ipr(PrivRegisters::SBR, 0x8000);
+        exec.write_ipr(PrivRegisters::SLR, 0x21);
+        exec.write_ipr(PrivRegisters::P0BR, 0x8000_3000);
+        exec.write_ipr(PrivRegisters::POLR, 0x20);
+        exec.write_ipr(PrivRegisters::MAPEN, 1);
+
+        assert_eq!(run_to_halt(&mut exec), HaltReason::HaltInstruction);
+        assert_eq!(exec.pc(), 0x801);
+        let first = exec.bus().read_u32(0x1000).unwrap();
+        assert_eq!(exec.reg(RegID::new(0)), first);
+        // Translation not valid, with its reason and the address, and the instruction backed out.
+        assert_eq!(exec.bus().read_u32(0x4000 - 16), Ok(0));
+        assert_eq!(exec.bus().read_u32(0x4000 - 12), Ok(0x8000_4000));
+        assert_eq!(exec.bus().read_u32(0x4000 - 8), Ok(0x1007));
+        assert_eq!(exec.read_ipr(PrivRegisters::MAPEN), 1);
+    }
+
+    #[test]
+    fn execute_events() {
+        use std::{cell::Cell, rc::Rc};
+
+        let mut exec = machine("LOOP: BRB LOOP");
+        exec.set_idle_config(IdleConfig::disabled());
+        let start = exec.sysclk().cycles_all_time();
+        let fired = Rc::new(Cell::new(0));
+        let f = fired.clone();
+        exec.sysclk().schedule_in(5, Box::new(move |exec: &mut ExecutionContext| {
+            f.set(exec.sysclk().cycles_all_time());
+        }));
+
+        assert!(exec.run_until_next_event());
+        assert_eq!(exec.sysclk().cycles_all_time(), start + 5);
+        assert_eq!(fired.get(), 0);
+        assert!(exec.execute_step());
+        assert_eq!(fired.get(), start + 5);
+        assert_eq!(exec.pc(), 0x1000);
+    }
+
+    #[test]
+    fn execute_clock_interrupts() {
+        use crate::ervax::cpu::interrupts::vectors;
+        use crate::ervax::devices::clock::{ICCS_IE, ICCS_INT, ICCS_RUN, ICCS_XFR};
+
+        // Counts clock interrupts in R5 until there have been three.
+        // Handlers must be longword aligned, so TICK is at 0x1004.
+        let mut exec = machine(&format!("
+                BRW MAIN
+                .BLKB 1
+        TICK:   INCL R5
+                MTPR #{ack}, #{iccs}
+                REI
+        MAIN:   MOVAL TICK, @#{vector}
+                MTPR #-100, #{nicr}
+                MTPR #{start}, #{iccs}
+                MTPR #0, #{ipl}
+        LOOP:   CMPL R5, #3
+                BNEQ LOOP
+                HALT
+        ",
+            nicr = PrivRegisters::NICR as u32,
+            iccs = PrivRegisters::ICCS as u32,
+            ipl = PrivRegisters::IPL as u32,
+            start = ICCS_XFR | ICCS_IE | ICCS_RUN,
+            ack = ICCS_INT | ICCS_IE | ICCS_RUN,
+            vector = 0x200 + vectors::INTERVAL_CLOCK as u32,
+        ));
+
+        assert_eq!(exec.run(), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(5)), 3);
+        assert!(exec.sysclk().cycles_all_time() >= 300);
+        assert!(!exec.get_interrupt_stack());
+        assert_eq!(exec.get_ipl(), 0);
+    }
+
+    #[test]
+    fn idle_detection() {
+        // Waits at IPL 0 for the clock in a branch to itself. The clock vector points at a HALT.
+        let source = "
+                MTPR #-1000, #25
+                MTPR #^X51, #24
+                MTPR #0, #18
+        WAIT:   BRB WAIT
+        ";
+        let mut exec = machine(source);
+        for _ in 0..10 {
+            exec.execute_step();
+        }
+        assert!(exec.idle_cycles() > 0);
+        assert_eq!(exec.pc(), 0x800 + 1, "clock interrupt taken, then halted");
+
+        let mut exec = machine(source);
+        exec.set_idle_config(IdleConfig::disabled());
+        for _ in 0..10 {
+            exec.execute_step();
+        }
+        assert_eq!(exec.idle_cycles(), 0);
+        assert!(!exec.is_halted());
+
+        let mut exec = machine("
+                CLRL R5
+        SPIN:   TSTL R5
+                BEQL SPIN
+        ");
+        exec.set_idle_config(IdleConfig { branch_to_self: false, ..IdleConfig::default() });
+        let before = exec.sysclk().cycles_all_time();
+        for _ in 0..10 {
+            exec.execute_step();
+        }
+        assert!(exec.idle_cycles() > 0);
+        assert!(exec.sysclk().cycles_all_time() - before > 10);
+
+        // Anything written in the loop means it isn't idle.
+        let mut exec = machine("
+                CLRL R5
+        SPIN:   MOVL R5, @#^X2000
+                BRB SPIN
+        ");
+        for _ in 0..100 {
+            exec.execute_step();
+        }
+        assert_eq!(exec.idle_cycles(), 0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Exceptions, and delivering them and interrupts through the System Control Block.
+
+use num_traits::ToPrimitive;
+
+use crate::ervax::cpu::{
+    bus::BusError,
+    execution::ExecutionContext,
+    interrupts::Interrupt,
+    PrivilegeMode,
+};
+
+/// SCB offsets of the exception vectors.
+pub mod vectors {
+    pub const MACHINE_CHECK: u16 = 0x04;
+    pub const RESERVED_INSTRUCTION: u16 = 0x10;
+    pub const RESERVED_OPERAND: u16 = 0x18;
+    pub const RESERVED_ADDRESSING_MODE: u16 = 0x1C;
+    pub const ACCESS_VIOLATION: u16 = 0x20;
+    pub const TRANSLATION_NOT_VALID: u16 = 0x24;
+    pub const TRACE: u16 = 0x28;
+    pub const BREAKPOINT: u16 = 0x2C;
+    pub const ARITHMETIC: u16 = 0x34;
+    /// CHMK, followed by CHME, CHMS, and CHMU.
+    pub const CHANGE_MODE: u16 = 0x40;
+}
+
+/// Type codes pushed with an arithmetic trap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArithmeticTrap {
+    IntegerOverflow = 1,
+    IntegerDivideByZero = 2,
+    SubscriptRange = 7,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exception {
+    /// Nothing responded at a physical address.
+    MachineCheck(BusError),
+    /// A reserved opcode, or a privileged instruction outside kernel mode.
+    ReservedInstruction,
+    ReservedOperand,
+    ReservedAddressingMode,
+    /// Memory management refused an access, with the reason bits and the virtual address.
+    AccessViolation(u32, u32),
+    /// A page table entry wasn't valid, with the reason bits and the virtual address.
+    TranslationNotValid(u32, u32),
+    Breakpoint,
+    Arithmetic(ArithmeticTrap),
+    /// CHMK, CHME, CHMS, or CHMU, with the code operand.
+    ChangeMode(PrivilegeMode, u16),
+}
+
+impl From<BusError> for Exception {
+    fn from(e: BusError) -> Self {
+        Exception::MachineCheck(e)
+    }
+}
+
+impl Exception {
+    /// Faults back the instruction out, so it can be restarted. Traps happen once it completes.
+    pub fn is_fault(self) -> bool {
+        !matches!(self, Exception::Arithmetic(_) | Exception::ChangeMode(..))
+    }
+
+    pub fn vector(self) -> u16 {
+        match self {
+            Exception::MachineCheck(_) => vectors::MACHINE_CHECK,
+            Exception::ReservedInstruction => vectors::RESERVED_INSTRUCTION,
+            Exception::ReservedOperand => vectors::RESERVED_OPERAND,
+            Exception::ReservedAddressingMode => vectors::RESERVED_ADDRESSING_MODE,
+            Exception::AccessViolation(..) => vectors::ACCESS_VIOLATION,
+            Exception::TranslationNotValid(..) => vectors::TRANSLATION_NOT_VALID,
+            Exception::Breakpoint => vectors::BREAKPOINT,
+            Exception::Arithmetic(_) => vectors::ARITHMETIC,
+            Exception::ChangeMode(m, _) => vectors::CHANGE_MODE + 4 * m.to_u16().unwrap(),
+        }
+    }
+}
+
+/// Why the CPU stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HaltReason {
+    /// Not started yet.
+    PowerUp,
+    /// The operator asked, from the console terminal or the host.
+    External,
+    /// A HALT instruction in kernel mode.
+    HaltInstruction,
+    /// An exception or interrupt couldn't be delivered, as its SCB vector or the stack wasn't there.
+    DoubleError,
+    /// An SCB vector asked for the writable control store, or to halt.
+    BadVector(u16),
+    /// A change mode instruction on the interrupt stack.
+    ChangeModeOnInterruptStack,
+    /// A change mode instruction, with the mode it asked for and its code, when they're set to
+    /// stop for the host instead of trapping. PC is past the instruction, ready to continue.
+    ChangeMode(PrivilegeMode, u16),
+}
+
+/// Where an exception or interrupt is serviced.
+struct Dispatch<'a> {
+    vector: u16,
+    mode: PrivilegeMode,
+    /// Forces the interrupt stack, otherwise the SCB vector picks.
+    interrupt_stack: bool,
+    /// New IPL, unchanged if None.
+    ipl: Option<u8>,
+    /// Pushed after PC and PSL, so the first ends up on top.
+    params: &'a [u32],
+}
+
+impl ExecutionContext {
+    fn dispatch(&mut self, d: Dispatch) -> Result<(), HaltReason> {
+        let handler = self.bus.read_u32(self.scbb.wrapping_add(d.vector as u32))
+            .map_err(|_| HaltReason::DoubleError)?;
+        let interrupt_stack = match handler & 3 {
+            0 => d.interrupt_stack || self.get_interrupt_stack(),
+            1 => true,
+            _ => return Err(HaltReason::BadVector(d.vector)),
+        };
+
+        let old_psl = self.psl;
+        let old_mode = self.get_cur_priv_mode();
+        let ipl = d.ipl.unwrap_or_else(|| self.get_ipl());
+
+        // Stack pointers live in their own per-mode registers, so switching stacks is just a PSL change.
+        self.psl = 0;
+        self.set_cur_priv_mode(d.mode);
+        self.set_prev_priv_mode(if interrupt_stack { PrivilegeMode::Kernel } else { old_mode });
+        self.set_interrupt_stack(interrupt_stack);
+        self.set_ipl(ipl);
+
+        let pushed = self.push_long(old_psl)
+            .and_then(|_| self.push_long(self.pc));
+        let pushed = d.params.iter().rev().fold(pushed, |r, p| r.and_then(|_| self.push_long(*p)));
+        if pushed.is_err() {
+            return Err(HaltReason::DoubleError);
+        }
+
+        self.pc = handler & !3;
+        Ok(())
+    }
+
+    /// Takes an interrupt: saves PC and PSL on the interrupt stack, raises IPL, and
+    /// jumps through the interrupt's SCB vector.
+    pub fn take_interrupt(&mut self, int: Interrupt) -> Result<(), HaltReason> {
+        self.acknowledge_interrupt(int.vector);
+        self.dispatch(Dispatch {
+            vector: int.vector,
+            mode: PrivilegeMode::Kernel,
+            interrupt_stack: true,
+            ipl: Some(int.ipl),
+            params: &[],
+        })
+    }
+
+    /// Takes an exception. For faults, PC and the registers must already be backed up to the
+    /// start of the instruction.
+    pub fn take_exception(&mut self, e: Exception) -> Result<(), HaltReason> {
+        let d = match e {
+            Exception::MachineCheck(BusError::NonExistent(addr)) => Dispatch {
+                vector: e.vector(),
+                mode: PrivilegeMode::Kernel,
+                interrupt_stack: true,
+                ipl: Some(0x1F),
+                // Byte count of the parameters that follow, then the failing address.
+                params: &[4, addr],
+            },
+            Exception::AccessViolation(reason, address) | Exception::TranslationNotValid(reason, address) => {
+                return self.dispatch(Dispatch {
+                    vector: e.vector(),
+                    mode: PrivilegeMode::Kernel,
+                    interrupt_stack: false,
+                    ipl: None,
+                    params: &[reason, address],
+                });
+            }
+            Exception::Arithmetic(code) => Dispatch {
+                vector: e.vector(),
+                mode: PrivilegeMode::Kernel,
+                interrupt_stack: false,
+                ipl: None,
+                params: &[code as u32],
+            },
+            Exception::ChangeMode(target, code) => {
+                if self.host_change_mode {
+                    return Err(HaltReason::ChangeMode(target, code));
+                }
+                if self.get_interrupt_stack() {
+                    return Err(HaltReason::ChangeModeOnInterruptStack);
+                }
+                // The new mode is never less privileged than the current one.
+                let mode = std::cmp::min(target, self.get_cur_priv_mode());
+                return self.dispatch(Dispatch {
+                    vector: e.vector(),
+                    mode,
+                    interrupt_stack: false,
+                    ipl: None,
+                    params: &[code as i16 as i32 as u32],
+                });
+            }
+            _ => Dispatch {
+                vector: e.vector(),
+                mode: PrivilegeMode::Kernel,
+                interrupt_stack: false,
+                ipl: None,
+                params: &[],
+            },
+        };
+        self.dispatch(d)
+    }
+}
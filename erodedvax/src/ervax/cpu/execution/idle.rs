@@ -0,0 +1,137 @@
+//! Idle detection. A guest waiting for an interrupt spins in a loop that changes nothing, and nothing
+//! but a scheduled event can get it out, so time can skip straight to that event.
+//!
+//! A loop counts as idle when it comes back round to the same PC with every register as it was,
+//! and in between nothing was written, no processor register was touched, and no event ran.
+//! Such a loop is deterministic, so skipping it doesn't change what the guest sees.
+
+use std::time::Duration;
+
+use crate::ervax::cpu::{
+    execution::{ExecutionContext, SavedRegisters},
+    sysclk::TimeMode,
+};
+
+/// Which idle loops to skip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IdleConfig {
+    /// A branch or jump to itself, as in `BRB .`.
+    pub branch_to_self: bool,
+    /// A loop of several instructions, such as a null process testing a work queue.
+    /// Checked whenever a branch goes backwards.
+    pub spin_loops: bool,
+    /// Times a spin loop must come round unchanged before it counts as idle.
+    pub spin_threshold: u32,
+    /// Loops above this IPL don't count.
+    pub max_ipl: u8,
+    /// In real time the host sleeps instead of spinning, at most this long at once.
+    pub max_sleep_us: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            branch_to_self: true,
+            spin_loops: true,
+            spin_threshold: 2,
+            max_ipl: 0x1F,
+            max_sleep_us: 10_000,
+        }
+    }
+}
+
+impl IdleConfig {
+    /// No idle detection, every cycle is executed.
+    pub fn disabled() -> IdleConfig {
+        IdleConfig {
+            branch_to_self: false,
+            spin_loops: false,
+            ..IdleConfig::default()
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.branch_to_self || self.spin_loops
+    }
+}
+
+/// The loop currently being watched.
+pub(super) struct IdleWatch {
+    regs: SavedRegisters,
+    side_effects: u64,
+    rounds: u32,
+}
+
+impl ExecutionContext {
+    #[inline]
+    pub fn idle_config(&self) -> &IdleConfig {
+        &self.idle_config
+    }
+
+    pub fn set_idle_config(&mut self, config: IdleConfig) {
+        self.idle_config = config;
+        self.idle_watch = None;
+    }
+
+    /// Total cycles skipped while idle.
+    #[inline]
+    pub fn idle_cycles(&self) -> u64 {
+        self.idle_cycles
+    }
+
+    /// Called after an instruction completes, with the registers and side effect count from
+    /// before it ran. Returns whether the CPU is idle.
+    pub(super) fn is_idle(&mut self, before: &SavedRegisters, side_effects: u64) -> bool {
+        let config = self.idle_config;
+        let start = before.pc;
+        if self.pc > start || self.get_ipl() > config.max_ipl {
+            return false;
+        }
+
+        let now = self.save_registers();
+        if self.pc == start {
+            if config.branch_to_self && self.side_effects == side_effects && now == *before {
+                return true;
+            }
+            if !config.spin_loops {
+                return false;
+            }
+        }
+
+        match self.idle_watch.as_mut() {
+            Some(w) if w.regs == now && w.side_effects == self.side_effects => {
+                w.rounds += 1;
+                w.rounds >= config.spin_threshold
+            }
+            _ => {
+                self.idle_watch = Some(IdleWatch { regs: now, side_effects: self.side_effects, rounds: 0 });
+                false
+            }
+        }
+    }
+
+    /// Skips time forward to the next event. In real time, sleeps the host until it's due, or for
+    /// max_sleep_us if that's sooner. Returns false if nothing is scheduled.
+    pub fn idle_until_next_event(&mut self) -> bool {
+        let next = match self.sysclk.next_event_at() {
+            Some(at) => at,
+            None => return false,
+        };
+        let cycles = next.saturating_sub(self.sysclk.cycles_all_time());
+
+        let skipped = match self.sysclk.mode() {
+            TimeMode::Virtual => cycles,
+            TimeMode::RealTime => {
+                let cycles = cycles.min(self.sysclk.us_to_cycles(self.idle_config.max_sleep_us));
+                let us = cycles / self.sysclk.us_to_cycles(1);
+                std::thread::sleep(Duration::from_micros(us));
+                cycles
+            }
+        };
+        self.sysclk.skip_cycles(skipped);
+        self.idle_cycles += skipped;
+        self.idle_watch = None;
+        true
+    }
+}
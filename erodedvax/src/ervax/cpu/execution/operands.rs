@@ -0,0 +1,499 @@
+//! Fetching instructions, evaluating operand specifiers, and the memory accesses behind them.
+
+use std::{cell::Cell, fmt};
+
+use crate::ervax::cpu::{
+    bus::BusError,
+    execution::{exceptions::Exception, ExecutionContext},
+    instrs::{
+        decode_instr,
+        FieldMode,
+        InstructionType,
+        OperandMode,
+        OperandParseError,
+        OperandWidth,
+    },
+    mmu::{MemoryAccessType, MemoryFault, PAGE_SIZE},
+    PrivilegeMode,
+    RegID,
+};
+
+/// An operand specifier as decoded from memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchedOperand {
+    pub mode: OperandMode,
+    pub field: FieldMode,
+    pub width: OperandWidth,
+    /// Address following the specifier, what PC reads as while evaluating it.
+    pub next: u32,
+}
+
+/// Where an evaluated operand lives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A register, or for operands wider than a longword the registers starting there.
+    Register(RegID),
+    Memory(u32),
+    /// A literal, immediate, or branch displacement.
+    Constant,
+}
+
+/// An evaluated operand. `value` holds what was read for read, modify, and branch displacement
+/// operands, the effective address for address operands, and is 0 for write operands.
+/// Bitfield operands in memory hold their base address, in a register the register's contents.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Operand {
+    pub location: Location,
+    pub width: OperandWidth,
+    pub value: u128,
+}
+
+impl Operand {
+    #[inline]
+    pub fn long(&self) -> u32 {
+        self.value as u32
+    }
+
+    /// The value sign extended from the operand's width.
+    pub fn signed(&self) -> i64 {
+        sign_extend(self.value, self.width)
+    }
+
+    pub fn address(&self) -> u32 {
+        match self.location {
+            Location::Memory(a) => a,
+            _ => self.value as u32,
+        }
+    }
+}
+
+#[inline]
+pub fn width_mask(width: OperandWidth) -> u128 {
+    match width {
+        OperandWidth::Octaword => u128::MAX,
+        w => (1u128 << (w.bytes() * 8)) - 1,
+    }
+}
+
+/// Sign extends the low bits of `v`, up to a quadword.
+pub fn sign_extend(v: u128, width: OperandWidth) -> i64 {
+    match width {
+        OperandWidth::Byte => v as u8 as i8 as i64,
+        OperandWidth::Word => v as u16 as i16 as i64,
+        OperandWidth::Longword => v as u32 as i32 as i64,
+        _ => v as u64 as i64,
+    }
+}
+
+/// Why a debugger couldn't reach a virtual address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugAccessError {
+    /// Memory management is on, and the page tables don't map the address.
+    Unmapped,
+    Bus(BusError),
+}
+
+impl From<Exception> for DebugAccessError {
+    fn from(e: Exception) -> Self {
+        match e {
+            Exception::MachineCheck(e) => DebugAccessError::Bus(e),
+            _ => DebugAccessError::Unmapped,
+        }
+    }
+}
+
+impl From<BusError> for DebugAccessError {
+    fn from(e: BusError) -> Self {
+        DebugAccessError::Bus(e)
+    }
+}
+
+impl fmt::Display for DebugAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugAccessError::Unmapped => write!(f, "the address isn't mapped"),
+            DebugAccessError::Bus(BusError::NonExistent(a)) => write!(f, "no memory at {:08X}", a),
+        }
+    }
+}
+
+/// The exception for a fault translating `addr`.
+fn memory_fault(f: MemoryFault, addr: u32) -> Exception {
+    match f {
+        MemoryFault::AccessViolation(reason) => Exception::AccessViolation(reason, addr),
+        MemoryFault::TranslationNotValid(reason) => Exception::TranslationNotValid(reason, addr),
+        MemoryFault::Bus(e) => Exception::MachineCheck(e),
+    }
+}
+
+/// Memory access, through memory management when it's on.
+impl ExecutionContext {
+    /// The physical address a virtual address maps to, if it maps to one.
+    pub fn physical_address(&mut self, addr: u32) -> Option<u32> {
+        self.mmu.translate(&mut self.bus, addr, None).ok()
+    }
+
+    pub fn read_virt(&mut self, addr: u32, width: OperandWidth) -> Result<u128, Exception> {
+        self.check_watchpoints(addr, width.bytes() as u32, false);
+        let mode = self.get_cur_priv_mode();
+        let span = self.span(addr, width.bytes() as u32, Some((mode, MemoryAccessType::Read)))?;
+        Ok(self.read_span(addr, span, width)?)
+    }
+
+    pub fn write_virt(&mut self, addr: u32, width: OperandWidth, v: u128) -> Result<(), Exception> {
+        self.side_effects += 1;
+        self.check_watchpoints(addr, width.bytes() as u32, true);
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.wrote(addr, width, v);
+        }
+        let mode = self.get_cur_priv_mode();
+        let span = self.span(addr, width.bytes() as u32, Some((mode, MemoryAccessType::Write)))?;
+        Ok(self.write_span(addr, span, width, v)?)
+    }
+
+    /// Reads a virtual address for a debugger, whatever the page's protection, out of sight of
+    /// watchpoints, the tracer, and idle detection.
+    pub fn debug_read(&mut self, addr: u32, width: OperandWidth) -> Result<u128, DebugAccessError> {
+        let span = self.span(addr, width.bytes() as u32, None)?;
+        Ok(self.read_span(addr, span, width)?)
+    }
+
+    /// Writes a virtual address for a debugger, whatever the page's protection, out of sight of
+    /// watchpoints, the tracer, and idle detection.
+    pub fn debug_write(&mut self, addr: u32, width: OperandWidth, v: u128) -> Result<(), DebugAccessError> {
+        let span = self.span(addr, width.bytes() as u32, None)?;
+        Ok(self.write_span(addr, span, width, v)?)
+    }
+
+    /// Where `len` bytes at `addr` are in physical memory: where they start, and where they go
+    /// on if they run onto the next page while memory management is on. Both pages are checked
+    /// before anything is accessed.
+    fn span(
+        &mut self,
+        addr: u32,
+        len: u32,
+        check: Option<(PrivilegeMode, MemoryAccessType)>,
+    ) -> Result<(u32, Option<u32>), Exception> {
+        let start = self.mmu.translate(&mut self.bus, addr, check).map_err(|f| memory_fault(f, addr))?;
+        let offset = addr & (PAGE_SIZE - 1);
+        if !self.mmu.is_enabled() || offset + len <= PAGE_SIZE {
+            return Ok((start, None));
+        }
+        let page = addr.wrapping_add(PAGE_SIZE - offset);
+        let next = self.mmu.translate(&mut self.bus, page, check).map_err(|f| memory_fault(f, page))?;
+        Ok((start, Some(next)))
+    }
+
+    fn read_span(&mut self, addr: u32, (start, next): (u32, Option<u32>), width: OperandWidth) -> Result<u128, BusError> {
+        let next = match next {
+            Some(next) => next,
+            None => return self.read_phys(start, width),
+        };
+        // Split between pages, a byte at a time.
+        let first = PAGE_SIZE - (addr & (PAGE_SIZE - 1));
+        let mut v = 0;
+        for i in (0..width.bytes() as u32).rev() {
+            let at = if i < first { start + i } else { next + (i - first) };
+            v = (v << 8) | self.bus.read_u8(at)? as u128;
+        }
+        Ok(v)
+    }
+
+    fn write_span(&mut self, addr: u32, (start, next): (u32, Option<u32>), width: OperandWidth, v: u128) -> Result<(), BusError> {
+        let next = match next {
+            Some(next) => next,
+            None => return self.write_phys(start, width, v),
+        };
+        let first = PAGE_SIZE - (addr & (PAGE_SIZE - 1));
+        for i in 0..width.bytes() as u32 {
+            let at = if i < first { start + i } else { next + (i - first) };
+            self.bus.write_u8(at, (v >> (i * 8)) as u8)?;
+        }
+        Ok(())
+    }
+
+    fn read_phys(&mut self, addr: u32, width: OperandWidth) -> Result<u128, BusError> {
+        let v = match width {
+            OperandWidth::Byte => self.bus.read_u8(addr)? as u128,
+            OperandWidth::Word => self.bus.read_u16(addr)? as u128,
+            OperandWidth::Longword => self.bus.read_u32(addr)? as u128,
+            w => {
+                let mut v = 0;
+                for i in (0..w.bytes() as u32 / 4).rev() {
+                    v = (v << 32) | self.bus.read_u32(addr.wrapping_add(i * 4))? as u128;
+                }
+                v
+            }
+        };
+        Ok(v)
+    }
+
+    fn write_phys(&mut self, addr: u32, width: OperandWidth, v: u128) -> Result<(), BusError> {
+        match width {
+            OperandWidth::Byte => self.bus.write_u8(addr, v as u8)?,
+            OperandWidth::Word => self.bus.write_u16(addr, v as u16)?,
+            OperandWidth::Longword => self.bus.write_u32(addr, v as u32)?,
+            w => {
+                for i in 0..w.bytes() as u32 / 4 {
+                    self.bus.write_u32(addr.wrapping_add(i * 4), (v >> (i * 32)) as u32)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn read_long(&mut self, addr: u32) -> Result<u32, Exception> {
+        self.read_virt(addr, OperandWidth::Longword).map(|v| v as u32)
+    }
+
+    #[inline]
+    pub fn write_long(&mut self, addr: u32, v: u32) -> Result<(), Exception> {
+        self.write_virt(addr, OperandWidth::Longword, v as u128)
+    }
+
+    pub fn push_long(&mut self, v: u32) -> Result<(), Exception> {
+        let sp = self.sp().wrapping_sub(4);
+        self.write_long(sp, v)?;
+        self.set_sp(sp);
+        Ok(())
+    }
+
+    pub fn pop_long(&mut self) -> Result<u32, Exception> {
+        let sp = self.sp();
+        let v = self.read_long(sp)?;
+        self.set_sp(sp.wrapping_add(4));
+        Ok(v)
+    }
+}
+
+/// Instruction fetch and operand evaluation.
+impl ExecutionContext {
+    /// Decodes the instruction at PC, without executing anything. Also returns the address of
+    /// the next instruction, or for CASE instructions of the displacement table.
+    pub fn fetch(&mut self) -> Result<(InstructionType, Vec<FetchedOperand>, u32), Exception> {
+        let start = self.pc;
+        let fault = Cell::new(None);
+        let consumed = Cell::new(0u32);
+
+        let check = Some((self.get_cur_priv_mode(), MemoryAccessType::Read));
+        let (bus, mmu) = (&mut self.bus, &self.mmu);
+        // The virtual and physical addresses of the page being read from.
+        let mut page = None;
+        let iter = &mut std::iter::from_fn(|| {
+            let addr = start.wrapping_add(consumed.get());
+            let offset = addr & (PAGE_SIZE - 1);
+            let physical = match page {
+                Some((v, p)) if v == addr - offset => p + offset,
+                _ => match mmu.translate(bus, addr, check) {
+                    Ok(p) => {
+                        page = Some((addr - offset, p - offset));
+                        p
+                    }
+                    Err(f) => {
+                        fault.set(Some(memory_fault(f, addr)));
+                        return None;
+                    }
+                },
+            };
+            match bus.read_u8(physical) {
+                Ok(b) => {
+                    consumed.set(consumed.get() + 1);
+                    Some(b)
+                }
+                Err(e) => {
+                    fault.set(Some(e.into()));
+                    None
+                }
+            }
+        });
+
+        let decoded = decode_instr(iter);
+        let (instr, operiter) = match decoded {
+            Some(d) => d,
+            None => return Err(fault.get().unwrap_or(Exception::ReservedInstruction)),
+        };
+
+        let modes = instr.field_modes();
+        let widths = instr.field_widths();
+        let mut operands = Vec::with_capacity(modes.len());
+        for (i, op) in operiter.enumerate() {
+            match op {
+                Ok(mode) => operands.push(FetchedOperand {
+                    mode,
+                    field: modes[i],
+                    width: widths[i],
+                    next: start.wrapping_add(consumed.get()),
+                }),
+                Err(OperandParseError::InvalidMode) => return Err(Exception::ReservedAddressingMode),
+                Err(OperandParseError::OutOfBytes) => {
+                    return Err(fault.get().unwrap_or(Exception::ReservedAddressingMode));
+                }
+            }
+        }
+        Ok((instr, operands, start.wrapping_add(consumed.get())))
+    }
+
+    /// Registers an operand of `width` occupies starting at `r`. Running into PC is reserved.
+    fn register_span(r: RegID, width: OperandWidth) -> Result<RegID, Exception> {
+        let count = width.bytes().div_ceil(4) as u8;
+        if r.id() + count > 15 {
+            return Err(Exception::ReservedAddressingMode);
+        }
+        Ok(r)
+    }
+
+    pub fn read_register_operand(&self, r: RegID, width: OperandWidth) -> u128 {
+        let count = width.bytes().div_ceil(4);
+        let mut v = 0u128;
+        for i in (0..count as u8).rev() {
+            v = (v << 32) | self.reg(RegID::new(r.id() + i)) as u128;
+        }
+        v & width_mask(width)
+    }
+
+    /// Byte and word writes leave the rest of the register alone.
+    pub fn write_register_operand(&mut self, r: RegID, width: OperandWidth, v: u128) {
+        match width {
+            OperandWidth::Byte | OperandWidth::Word => {
+                let mask = width_mask(width) as u32;
+                let old = self.reg(r);
+                self.set_reg(r, (old & !mask) | (v as u32 & mask));
+            }
+            w => {
+                for i in 0..(w.bytes() / 4) as u8 {
+                    self.set_reg(RegID::new(r.id() + i), (v >> (i as u32 * 32)) as u32);
+                }
+            }
+        }
+    }
+
+    /// Reads whatever the operand refers to, at its own width.
+    pub fn load(&mut self, op: &Operand) -> Result<u128, Exception> {
+        match op.location {
+            Location::Register(r) => Ok(self.read_register_operand(r, op.width)),
+            Location::Memory(a) => self.read_virt(a, op.width),
+            Location::Constant => Ok(op.value),
+        }
+    }
+
+    /// Writes a result to a write or modify operand.
+    pub fn store(&mut self, op: &Operand, v: u128) -> Result<(), Exception> {
+        match op.location {
+            Location::Register(r) => {
+                self.write_register_operand(r, op.width, v);
+                Ok(())
+            }
+            Location::Memory(a) => self.write_virt(a, op.width, v & width_mask(op.width)),
+            Location::Constant => Err(Exception::ReservedAddressingMode),
+        }
+    }
+
+    /// The address an operand specifier refers to, applying any autoincrement or autodecrement.
+    /// Register, literal, and immediate modes have no address.
+    fn effective_address(&mut self, mode: &OperandMode, width: OperandWidth, next: u32) -> Result<Option<u32>, Exception> {
+        use OperandMode::*;
+
+        let size = width.bytes() as u32;
+        let base = |exec: &Self, r: RegID| if r == RegID::PC { next } else { exec.reg(r) };
+
+        let ea = match mode {
+            RegisterDeferred(r) => base(self, *r),
+            AutoDecrement(r) => {
+                if *r == RegID::PC {
+                    return Err(Exception::ReservedAddressingMode);
+                }
+                let a = self.reg(*r).wrapping_sub(size);
+                self.set_reg(*r, a);
+                a
+            }
+            AutoIncrement(r) => {
+                let a = self.reg(*r);
+                self.set_reg(*r, a.wrapping_add(size));
+                a
+            }
+            AutoIncrementDeferred(r) => {
+                let a = self.reg(*r);
+                self.set_reg(*r, a.wrapping_add(4));
+                self.read_long(a)?
+            }
+            Absolute(a) => *a,
+            ByteDisplacement(r, d) => base(self, *r).wrapping_add(*d as u32),
+            WordDisplacement(r, d) => base(self, *r).wrapping_add(*d as u32),
+            LongwordDisplacement(r, d) => base(self, *r).wrapping_add(*d as u32),
+            ByteDisplacementDeferred(r, d) => {
+                let p = base(self, *r).wrapping_add(*d as u32);
+                self.read_long(p)?
+            }
+            WordDisplacementDeferred(r, d) => {
+                let p = base(self, *r).wrapping_add(*d as u32);
+                self.read_long(p)?
+            }
+            LongwordDisplacementDeferred(r, d) => {
+                let p = base(self, *r).wrapping_add(*d as u32);
+                self.read_long(p)?
+            }
+            Indexed(x, inner) => {
+                if *x == RegID::PC {
+                    return Err(Exception::ReservedAddressingMode);
+                }
+                let index = self.reg(*x);
+                match self.effective_address(inner, width, next)? {
+                    Some(a) => a.wrapping_add(index.wrapping_mul(size)),
+                    None => return Err(Exception::ReservedAddressingMode),
+                }
+            }
+            // Immediates sit right before the next specifier.
+            Immediate8(_) | Immediate16(_) | Immediate32(_) | Immediate64(_) | Immediate128(_) => {
+                next.wrapping_sub(size)
+            }
+            Literal(_) | Register(_) | DataByte(_) | DataWord(_) | DataLong(_) => return Ok(None),
+        };
+        Ok(Some(ea))
+    }
+
+    /// Evaluates an operand specifier, reading the operand if the field calls for it.
+    pub fn evaluate(&mut self, op: &FetchedOperand) -> Result<Operand, Exception> {
+        use OperandMode::*;
+
+        let width = op.width;
+        let constant = |value: u128| Operand { location: Location::Constant, width, value };
+
+        match (&op.mode, op.field) {
+            (DataByte(d), _) => return Ok(constant(*d as i8 as u32 as u128)),
+            (DataWord(d), _) => return Ok(constant(*d as i16 as u32 as u128)),
+            (DataLong(d), _) => return Ok(constant(*d as u128)),
+            (Literal(l), FieldMode::Read) => return Ok(constant(*l as u128)),
+            (Immediate8(v), FieldMode::Read) => return Ok(constant(*v as u128)),
+            (Immediate16(v), FieldMode::Read) => return Ok(constant(*v as u128)),
+            (Immediate32(v), FieldMode::Read) => return Ok(constant(*v as u128)),
+            (Immediate64(v), FieldMode::Read) => return Ok(constant(*v as u128)),
+            (Immediate128(v), FieldMode::Read) => return Ok(constant(*v)),
+            (Literal(_), _) => return Err(Exception::ReservedAddressingMode),
+            (Register(r), field) => {
+                if *r == RegID::PC || field == FieldMode::Address {
+                    return Err(Exception::ReservedAddressingMode);
+                }
+                if field == FieldMode::Bitfield {
+                    return Ok(Operand { location: Location::Register(*r), width, value: self.reg(*r) as u128 });
+                }
+                let r = Self::register_span(*r, width)?;
+                let mut operand = Operand { location: Location::Register(r), width, value: 0 };
+                if field == FieldMode::Read || field == FieldMode::Modify {
+                    operand.value = self.read_register_operand(r, width);
+                }
+                return Ok(operand);
+            }
+            _ => {}
+        }
+
+        let ea = self.effective_address(&op.mode, width, op.next)?
+            .ok_or(Exception::ReservedAddressingMode)?;
+        let value = match op.field {
+            FieldMode::Read | FieldMode::Modify => self.read_virt(ea, width)?,
+            FieldMode::Address | FieldMode::Bitfield => ea as u128,
+            _ => 0,
+        };
+        Ok(Operand { location: Location::Memory(ea), width, value })
+    }
+}
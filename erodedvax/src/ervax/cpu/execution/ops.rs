@@ -0,0 +1,746 @@
+//! What each instruction does. Integer, control, and processor instructions are implemented,
+//! floating point and string instructions raise a reserved instruction fault.
+
+use num_traits::FromPrimitive;
+
+use crate::ervax::cpu::{
+    execution::{
+        exceptions::{ArithmeticTrap, Exception, HaltReason},
+        operands::{sign_extend, width_mask, Location, Operand},
+        ExecutionContext,
+    },
+    instrs::{InstructionType, OperandWidth},
+    registers::PrivRegisters,
+    PrivilegeMode,
+    RegID,
+};
+
+/// PSL bits a program can change with BISPSW/BICPSW, and that RET restores.
+const PSW_MASK: u32 = 0xFF;
+/// PSL bits REI requires to be clear.
+const PSL_MBZ: u32 = 0x3020_FF00;
+
+#[inline]
+fn sign_bit(width: OperandWidth) -> u128 {
+    1u128 << (width.bytes() * 8 - 1)
+}
+
+/// Condition codes.
+impl ExecutionContext {
+    #[inline]
+    pub(super) fn set_cc(&mut self, n: bool, z: bool, v: bool, c: bool) {
+        self.psl = (self.psl & !0xF) | (n as u32) << 3 | (z as u32) << 2 | (v as u32) << 1 | c as u32;
+    }
+
+    /// N and Z from the result, V cleared, C left alone.
+    #[inline]
+    fn set_nz(&mut self, v: u128, width: OperandWidth) {
+        let v = v & width_mask(width);
+        let c = self.get_carry();
+        self.set_cc(v & sign_bit(width) != 0, v == 0, false, c);
+    }
+
+    /// Sets N, Z, V, and C for a + b + carry.
+    fn add(&mut self, a: u128, b: u128, carry: bool, width: OperandWidth) -> u128 {
+        let mask = width_mask(width);
+        let sum = (a & mask) + (b & mask) + carry as u128;
+        let r = sum & mask;
+        let overflow = (a ^ r) & (b ^ r) & sign_bit(width) != 0;
+        self.set_cc(r & sign_bit(width) != 0, r == 0, overflow, sum > mask);
+        r
+    }
+
+    /// Sets N, Z, V, and C for min - sub - borrow.
+    fn sub(&mut self, min: u128, sub: u128, borrow: bool, width: OperandWidth) -> u128 {
+        let mask = width_mask(width);
+        let (min, sub) = (min & mask, sub & mask);
+        let r = min.wrapping_sub(sub).wrapping_sub(borrow as u128) & mask;
+        let overflow = (min ^ sub) & (min ^ r) & sign_bit(width) != 0;
+        self.set_cc(r & sign_bit(width) != 0, r == 0, overflow, sub + borrow as u128 > min);
+        r
+    }
+
+    fn compare(&mut self, a: u128, b: u128, width: OperandWidth) {
+        let (sa, sb) = (sign_extend(a, width), sign_extend(b, width));
+        let mask = width_mask(width);
+        self.set_cc(sa < sb, sa == sb, false, (a & mask) < (b & mask));
+    }
+
+    /// Traps if the last instruction overflowed and integer overflow traps are enabled.
+    fn overflow_trap(&self) -> Result<(), Exception> {
+        if self.get_overflow() && self.get_integer_overflow_enable() {
+            return Err(Exception::Arithmetic(ArithmeticTrap::IntegerOverflow));
+        }
+        Ok(())
+    }
+
+    /// Stores a signed result, setting N and Z, and V if it doesn't fit the operand. C is cleared.
+    fn store_signed(&mut self, dst: &Operand, v: i64) -> Result<(), Exception> {
+        let fits = sign_extend(v as u128, dst.width) == v;
+        self.store(dst, v as u128)?;
+        let r = v as u128 & width_mask(dst.width);
+        self.set_cc(r & sign_bit(dst.width) != 0, r == 0, !fits, false);
+        Ok(())
+    }
+
+    #[inline]
+    fn branch(&mut self, displ: &Operand) {
+        self.pc = self.pc.wrapping_add(displ.long());
+    }
+
+    fn require_kernel(&self) -> Result<(), Exception> {
+        if self.get_cur_priv_mode() != PrivilegeMode::Kernel {
+            return Err(Exception::ReservedInstruction);
+        }
+        Ok(())
+    }
+}
+
+/// Bit fields.
+impl ExecutionContext {
+    /// Reads a field of `size` bits at `pos` from a bitfield operand, zero extended.
+    fn read_field(&mut self, pos: u32, size: u32, base: &Operand) -> Result<u32, Exception> {
+        if size > 32 {
+            return Err(Exception::ReservedOperand);
+        }
+        if size == 0 {
+            return Ok(0);
+        }
+        let mask = ((1u64 << size) - 1) as u32;
+
+        match base.location {
+            Location::Register(r) => {
+                if pos > 31 {
+                    return Err(Exception::ReservedOperand);
+                }
+                let mut v = self.reg(r) as u64;
+                if pos + size > 32 {
+                    if r.id() >= 14 {
+                        return Err(Exception::ReservedAddressingMode);
+                    }
+                    v |= (self.reg(RegID::new(r.id() + 1)) as u64) << 32;
+                }
+                Ok((v >> pos) as u32 & mask)
+            }
+            _ => {
+                let (addr, bit) = field_address(base.address(), pos);
+                let bytes = (bit + size).div_ceil(8);
+                let mut v = 0u64;
+                for i in (0..bytes).rev() {
+                    v = (v << 8) | self.read_virt(addr.wrapping_add(i), OperandWidth::Byte)? as u64;
+                }
+                Ok((v >> bit) as u32 & mask)
+            }
+        }
+    }
+
+    fn write_field(&mut self, pos: u32, size: u32, base: &Operand, value: u32) -> Result<(), Exception> {
+        if size > 32 {
+            return Err(Exception::ReservedOperand);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let mask = (1u64 << size) - 1;
+        let value = value as u64 & mask;
+
+        match base.location {
+            Location::Register(r) => {
+                if pos > 31 {
+                    return Err(Exception::ReservedOperand);
+                }
+                let wide = pos + size > 32;
+                if wide && r.id() >= 14 {
+                    return Err(Exception::ReservedAddressingMode);
+                }
+                let next = RegID::new((r.id() + 1).min(15));
+                let mut v = self.reg(r) as u64;
+                if wide {
+                    v |= (self.reg(next) as u64) << 32;
+                }
+                v = (v & !(mask << pos)) | (value << pos);
+                self.set_reg(r, v as u32);
+                if wide {
+                    self.set_reg(next, (v >> 32) as u32);
+                }
+                Ok(())
+            }
+            _ => {
+                let (addr, bit) = field_address(base.address(), pos);
+                let bytes = (bit + size).div_ceil(8);
+                let mut v = 0u64;
+                for i in (0..bytes).rev() {
+                    v = (v << 8) | self.read_virt(addr.wrapping_add(i), OperandWidth::Byte)? as u64;
+                }
+                v = (v & !(mask << bit)) | (value << bit);
+                for i in 0..bytes {
+                    self.write_virt(addr.wrapping_add(i), OperandWidth::Byte, (v >> (i * 8)) as u8 as u128)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The byte holding bit `pos` of a field based at `base`, and the bit within it. Positions are signed.
+fn field_address(base: u32, pos: u32) -> (u32, u32) {
+    (base.wrapping_add(((pos as i32) >> 3) as u32), pos & 7)
+}
+
+/// Procedure calls.
+impl ExecutionContext {
+    fn call(&mut self, numarg: Option<u32>, arglist: u32, dst: u32) -> Result<(), Exception> {
+        let mask = self.read_virt(dst, OperandWidth::Word)? as u32;
+        if mask & 0x3000 != 0 {
+            return Err(Exception::ReservedOperand);
+        }
+
+        let ap = match numarg {
+            Some(n) => {
+                self.push_long(n)?;
+                self.sp()
+            }
+            None => arglist,
+        };
+
+        let sp = self.sp();
+        self.set_sp(sp & !3);
+        for i in (0..12).rev() {
+            if mask & (1 << i) != 0 {
+                self.push_long(self.gpr[i])?;
+            }
+        }
+        self.push_long(self.pc)?;
+        self.push_long(self.reg(RegID::FP))?;
+        self.push_long(self.reg(RegID::AP))?;
+        let saved = (sp & 3) << 30 | (numarg.is_some() as u32) << 29 | (mask & 0xFFF) << 16 | (self.psl & 0xFFE0);
+        self.push_long(saved)?;
+        self.push_long(0)?;
+
+        let fp = self.sp();
+        self.set_reg(RegID::FP, fp);
+        self.set_reg(RegID::AP, ap);
+        self.set_cc(false, false, false, false);
+        self.set_decimal_overflow_enable(mask & 0x8000 != 0);
+        self.set_integer_overflow_enable(mask & 0x4000 != 0);
+        self.set_floating_underflow_enable(false);
+        self.pc = dst.wrapping_add(2);
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), Exception> {
+        let fp = self.reg(RegID::FP);
+        self.set_sp(fp.wrapping_add(4));
+        let saved = self.pop_long()?;
+        let ap = self.pop_long()?;
+        let fp = self.pop_long()?;
+        let pc = self.pop_long()?;
+        for i in 0..12 {
+            if saved & (1 << (16 + i)) != 0 {
+                self.gpr[i] = self.pop_long()?;
+            }
+        }
+        let sp = self.sp().wrapping_add(saved >> 30);
+        self.set_sp(sp);
+        if saved & (1 << 29) != 0 {
+            let n = self.pop_long()?;
+            let sp = self.sp().wrapping_add(4 * (n & 0xFF));
+            self.set_sp(sp);
+        }
+
+        self.set_reg(RegID::AP, ap);
+        self.set_reg(RegID::FP, fp);
+        self.psl = (self.psl & !0xFFFF) | (saved & 0xFFFF);
+        self.pc = pc;
+        Ok(())
+    }
+
+    fn rei(&mut self) -> Result<(), Exception> {
+        let sp = self.sp();
+        let pc = self.read_long(sp)?;
+        let psl = self.read_long(sp.wrapping_add(4))?;
+
+        let cur = self.get_cur_priv_mode() as u32;
+        let new_cur = (psl >> 24) & 3;
+        let new_prev = (psl >> 22) & 3;
+        let new_is = psl & (1 << 26) != 0;
+        let new_ipl = (psl >> 16) & 0x1F;
+        let invalid = psl & PSL_MBZ != 0
+            || new_cur < cur
+            || (new_is && (new_cur != 0 || !self.get_interrupt_stack()))
+            || new_ipl > self.get_ipl() as u32
+            || new_prev < new_cur
+            || (new_cur != 0 && new_ipl != 0);
+        if invalid {
+            return Err(Exception::ReservedOperand);
+        }
+
+        self.set_sp(sp.wrapping_add(8));
+        // Trace pending carries over from the current PSL.
+        self.psl = (psl & !(1 << 30)) | (self.psl & (1 << 30));
+        self.pc = pc;
+        Ok(())
+    }
+}
+
+/// Queues.
+impl ExecutionContext {
+    fn insque(&mut self, entry: u32, pred: u32) -> Result<(), Exception> {
+        let succ = self.read_long(pred)?;
+        self.write_long(entry, succ)?;
+        self.write_long(entry.wrapping_add(4), pred)?;
+        self.write_long(succ.wrapping_add(4), entry)?;
+        self.write_long(pred, entry)?;
+        // Z set if the entry is the only one in the queue.
+        self.set_cc((succ as i32) < (pred as i32), succ == pred, false, succ < pred);
+        Ok(())
+    }
+
+    fn remque(&mut self, entry: u32) -> Result<(), Exception> {
+        let succ = self.read_long(entry)?;
+        let pred = self.read_long(entry.wrapping_add(4))?;
+        // V set if the queue was empty to start with, Z if it's empty now.
+        let empty = succ == entry;
+        if !empty {
+            self.write_long(pred, succ)?;
+            self.write_long(succ.wrapping_add(4), pred)?;
+        }
+        self.set_cc((succ as i32) < (pred as i32), succ == pred, empty, succ < pred);
+        Ok(())
+    }
+}
+
+/// Execution of single instructions.
+impl ExecutionContext {
+    /// Executes an instruction whose operands have been evaluated. PC already points past it.
+    pub(super) fn execute(&mut self, instr: InstructionType, op: &[Operand]) -> Result<(), Exception> {
+        use InstructionType::*;
+        use OperandWidth::*;
+
+        match instr {
+            NOP => {}
+            HALT => {
+                self.require_kernel()?;
+                self.halt(HaltReason::HaltInstruction);
+            }
+            BPT => return Err(Exception::Breakpoint),
+
+            // Moves.
+            MOVB | MOVW | MOVL | MOVQ | MOVO => {
+                self.store(&op[1], op[0].value)?;
+                self.set_nz(op[0].value, op[0].width);
+            }
+            CLRB | CLRW | CLRL | CLRQ | CLRO => {
+                self.store(&op[0], 0)?;
+                let c = self.get_carry();
+                self.set_cc(false, true, false, c);
+            }
+            MOVZBW | MOVZBL | MOVZWL => {
+                self.store(&op[1], op[0].value)?;
+                self.set_nz(op[0].value, op[1].width);
+            }
+            MOVAB | MOVAW | MOVAL | MOVAQ | MOVAO => {
+                let a = op[0].address() as u128;
+                self.store(&op[1], a)?;
+                self.set_nz(a, Longword);
+            }
+            PUSHAB | PUSHAW | PUSHAL | PUSHAQ | PUSHAO => {
+                let a = op[0].address();
+                self.push_long(a)?;
+                self.set_nz(a as u128, Longword);
+            }
+            PUSHL => {
+                self.push_long(op[0].long())?;
+                self.set_nz(op[0].value, Longword);
+            }
+            MCOMB | MCOMW | MCOML => {
+                let r = !op[0].value & width_mask(op[0].width);
+                self.store(&op[1], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            MNEGB | MNEGW | MNEGL => {
+                let r = self.sub(0, op[0].value, false, op[0].width);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            CVTBW | CVTBL | CVTWB | CVTWL | CVTLB | CVTLW => {
+                self.store_signed(&op[1], op[0].signed())?;
+                self.overflow_trap()?;
+            }
+
+            // Integer arithmetic.
+            ADDB2 | ADDW2 | ADDL2 => {
+                let r = self.add(op[1].value, op[0].value, false, op[0].width);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            ADDB3 | ADDW3 | ADDL3 => {
+                let r = self.add(op[1].value, op[0].value, false, op[0].width);
+                self.store(&op[2], r)?;
+                self.overflow_trap()?;
+            }
+            ADWC => {
+                let c = self.get_carry();
+                let r = self.add(op[1].value, op[0].value, c, Longword);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            ADAWI => {
+                if let Location::Memory(a) = op[1].location {
+                    if a & 1 != 0 {
+                        return Err(Exception::ReservedOperand);
+                    }
+                }
+                let r = self.add(op[1].value, op[0].value, false, Word);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            INCB | INCW | INCL => {
+                let r = self.add(op[0].value, 1, false, op[0].width);
+                self.store(&op[0], r)?;
+                self.overflow_trap()?;
+            }
+            SUBB2 | SUBW2 | SUBL2 => {
+                let r = self.sub(op[1].value, op[0].value, false, op[0].width);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            SUBB3 | SUBW3 | SUBL3 => {
+                let r = self.sub(op[1].value, op[0].value, false, op[0].width);
+                self.store(&op[2], r)?;
+                self.overflow_trap()?;
+            }
+            SBWC => {
+                let c = self.get_carry();
+                let r = self.sub(op[1].value, op[0].value, c, Longword);
+                self.store(&op[1], r)?;
+                self.overflow_trap()?;
+            }
+            DECB | DECW | DECL => {
+                let r = self.sub(op[0].value, 1, false, op[0].width);
+                self.store(&op[0], r)?;
+                self.overflow_trap()?;
+            }
+            MULB2 | MULW2 | MULL2 | MULB3 | MULW3 | MULL3 => {
+                let dst = if op.len() == 2 { &op[1] } else { &op[2] };
+                self.store_signed(dst, op[0].signed() * op[1].signed())?;
+                self.overflow_trap()?;
+            }
+            DIVB2 | DIVW2 | DIVL2 | DIVB3 | DIVW3 | DIVL3 => {
+                let dst = if op.len() == 2 { &op[1] } else { &op[2] };
+                let (divr, divd) = (op[0].signed(), op[1].signed());
+                if divr == 0 {
+                    // The quotient is left as the dividend, the two operand form leaves it alone.
+                    if op.len() == 3 {
+                        self.store(dst, op[1].value)?;
+                    }
+                    self.set_nz(op[1].value, dst.width);
+                    self.set_overflow(true);
+                    return Err(Exception::Arithmetic(ArithmeticTrap::IntegerDivideByZero));
+                }
+                self.store_signed(dst, divd / divr)?;
+                self.overflow_trap()?;
+            }
+            EMUL => {
+                let r = op[0].signed() * op[1].signed() + op[2].signed();
+                self.store(&op[3], r as u64 as u128)?;
+                self.set_cc(r < 0, r == 0, false, false);
+            }
+            EDIV => {
+                let (divr, divd) = (op[0].signed(), op[1].value as u64 as i64);
+                let quo = if divr == 0 { None } else { divd.checked_div(divr) };
+                match quo {
+                    Some(q) if q as i32 as i64 == q => {
+                        self.store(&op[2], q as u32 as u128)?;
+                        self.store(&op[3], (divd % divr) as u32 as u128)?;
+                        self.set_cc(q < 0, q == 0, false, false);
+                    }
+                    _ => {
+                        // On overflow the quotient is the low half of the dividend, and the remainder 0.
+                        self.store(&op[2], divd as u32 as u128)?;
+                        self.store(&op[3], 0)?;
+                        let q = divd as i32;
+                        self.set_cc(q < 0, q == 0, true, false);
+                        if divr == 0 {
+                            return Err(Exception::Arithmetic(ArithmeticTrap::IntegerDivideByZero));
+                        }
+                        self.overflow_trap()?;
+                    }
+                }
+            }
+            CMPB | CMPW | CMPL => self.compare(op[0].value, op[1].value, op[0].width),
+            TSTB | TSTW | TSTL => {
+                self.set_nz(op[0].value, op[0].width);
+                self.set_carry(false);
+            }
+
+            // Logical.
+            BITB | BITW | BITL => self.set_nz(op[0].value & op[1].value, op[0].width),
+            BISB2 | BISW2 | BISL2 => {
+                let r = op[1].value | op[0].value;
+                self.store(&op[1], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            BISB3 | BISW3 | BISL3 => {
+                let r = op[1].value | op[0].value;
+                self.store(&op[2], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            BICB2 | BICW2 | BICL2 => {
+                let r = op[1].value & !op[0].value;
+                self.store(&op[1], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            BICB3 | BICW3 | BICL3 => {
+                let r = op[1].value & !op[0].value;
+                self.store(&op[2], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            XORB2 | XORW2 | XORL2 => {
+                let r = op[1].value ^ op[0].value;
+                self.store(&op[1], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            XORB3 | XORW3 | XORL3 => {
+                let r = op[1].value ^ op[0].value;
+                self.store(&op[2], r)?;
+                self.set_nz(r, op[0].width);
+            }
+            ASHL | ASHQ => {
+                let cnt = op[0].value as u8 as i8 as i32;
+                let src = op[1].signed();
+                let bits = op[1].width.bytes() as i32 * 8;
+                let r = if cnt >= 0 {
+                    if cnt >= bits { 0 } else { (src as i128) << cnt }
+                } else {
+                    src as i128 >> (-cnt).min(bits - 1).min(63)
+                };
+                let truncated = sign_extend(r as u128, op[1].width);
+                self.store(&op[2], truncated as u128)?;
+                let overflow = truncated as i128 != r || (cnt >= bits && src != 0);
+                let t = truncated as u128 & width_mask(op[1].width);
+                self.set_cc(truncated < 0, t == 0, overflow, false);
+                self.overflow_trap()?;
+            }
+            ROTL => {
+                let r = op[1].long().rotate_left(op[0].value as u8 as u32 % 32);
+                self.store(&op[2], r as u128)?;
+                self.set_nz(r as u128, Longword);
+            }
+
+            // Bit fields.
+            EXTV | EXTZV => {
+                let size = op[1].value as u8 as u32;
+                let mut v = self.read_field(op[0].long(), size, &op[2])? as u128;
+                if instr == EXTV && size > 0 && size < 32 && v & (1 << (size - 1)) != 0 {
+                    v |= (u32::MAX << size) as u128;
+                }
+                self.store(&op[3], v)?;
+                self.set_nz(v, Longword);
+                self.set_carry(false);
+            }
+            CMPV | CMPZV => {
+                let size = op[1].value as u8 as u32;
+                let mut v = self.read_field(op[0].long(), size, &op[2])? as u128;
+                if instr == CMPV && size > 0 && size < 32 && v & (1 << (size - 1)) != 0 {
+                    v |= (u32::MAX << size) as u128;
+                }
+                self.compare(v, op[3].value, Longword);
+            }
+            INSV => {
+                let size = op[2].value as u8 as u32;
+                self.write_field(op[1].long(), size, &op[3], op[0].long())?;
+            }
+            FFS | FFC => {
+                let start = op[0].long();
+                let size = op[1].value as u8 as u32;
+                let mut field = self.read_field(start, size, &op[2])?;
+                if instr == FFC {
+                    field = !field;
+                }
+                if size < 32 {
+                    field &= ((1u64 << size) - 1) as u32;
+                }
+                let (found, pos) = match field {
+                    0 => (false, start.wrapping_add(size)),
+                    f => (true, start.wrapping_add(f.trailing_zeros())),
+                };
+                self.store(&op[3], pos as u128)?;
+                self.set_cc(false, !found, false, false);
+            }
+
+            // Branches.
+            BRB | BRW => self.branch(&op[0]),
+            BNEQ | BEQL | BGTR | BLEQ | BGEQ | BLSS | BGTRU | BLEQU | BVC | BVS | BCC | BCS => {
+                let (n, z, v, c) = (self.get_negative(), self.get_zero(), self.get_overflow(), self.get_carry());
+                let taken = match instr {
+                    BNEQ => !z,
+                    BEQL => z,
+                    BGTR => !(n || z),
+                    BLEQ => n || z,
+                    BGEQ => !n,
+                    BLSS => n,
+                    BGTRU => !(c || z),
+                    BLEQU => c || z,
+                    BVC => !v,
+                    BVS => v,
+                    BCC => !c,
+                    _ => c,
+                };
+                if taken {
+                    self.branch(&op[0]);
+                }
+            }
+            BLBS | BLBC => {
+                if (op[0].value & 1 != 0) == (instr == BLBS) {
+                    self.branch(&op[1]);
+                }
+            }
+            BBS | BBC | BBSS | BBCS | BBSC | BBCC | BBSSI | BBCCI => {
+                let pos = op[0].long();
+                let set = self.read_field(pos, 1, &op[1])? != 0;
+                match instr {
+                    BBSS | BBCS | BBSSI => self.write_field(pos, 1, &op[1], 1)?,
+                    BBSC | BBCC | BBCCI => self.write_field(pos, 1, &op[1], 0)?,
+                    _ => {}
+                }
+                let on_set = matches!(instr, BBS | BBSS | BBSC | BBSSI);
+                if set == on_set {
+                    self.branch(&op[2]);
+                }
+            }
+            BSBB | BSBW => {
+                self.push_long(self.pc)?;
+                self.branch(&op[0]);
+            }
+            JSB => {
+                self.push_long(self.pc)?;
+                self.pc = op[0].address();
+            }
+            JMP => self.pc = op[0].address(),
+            RSB => self.pc = self.pop_long()?,
+            CASEB | CASEW | CASEL => {
+                let width = op[0].width;
+                let tmp = op[0].value.wrapping_sub(op[1].value) & width_mask(width);
+                let limit = op[2].value & width_mask(width);
+                let table = self.pc;
+                self.compare(tmp, limit, width);
+                self.pc = if tmp <= limit {
+                    let disp = self.read_virt(table.wrapping_add((tmp as u32).wrapping_mul(2)), Word)? as u16 as i16;
+                    table.wrapping_add(disp as u32)
+                } else {
+                    table.wrapping_add((limit as u32).wrapping_add(1).wrapping_mul(2))
+                };
+            }
+
+            // Loops.
+            ACBB | ACBW | ACBL => {
+                let c = self.get_carry();
+                let r = self.add(op[2].value, op[1].value, false, op[2].width);
+                self.store(&op[2], r)?;
+                self.set_carry(c);
+                let (index, limit) = (sign_extend(r, op[2].width), op[0].signed());
+                let taken = if op[1].signed() >= 0 { index <= limit } else { index >= limit };
+                if taken {
+                    self.branch(&op[3]);
+                }
+                self.overflow_trap()?;
+            }
+            AOBLSS | AOBLEQ => {
+                let c = self.get_carry();
+                let r = self.add(op[1].value, 1, false, Longword);
+                self.store(&op[1], r)?;
+                self.set_carry(c);
+                let (index, limit) = (r as u32 as i32, op[0].long() as i32);
+                if index < limit || (instr == AOBLEQ && index == limit) {
+                    self.branch(&op[2]);
+                }
+                self.overflow_trap()?;
+            }
+            SOBGEQ | SOBGTR => {
+                let c = self.get_carry();
+                let r = self.sub(op[0].value, 1, false, Longword);
+                self.store(&op[0], r)?;
+                self.set_carry(c);
+                let index = r as u32 as i32;
+                if index > 0 || (instr == SOBGEQ && index == 0) {
+                    self.branch(&op[1]);
+                }
+                self.overflow_trap()?;
+            }
+
+            // Procedures and the stack.
+            CALLS => self.call(Some(op[0].long()), 0, op[1].address())?,
+            CALLG => self.call(None, op[0].address(), op[1].address())?,
+            RET => self.ret()?,
+            PUSHR => {
+                let mask = op[0].long() & 0x7FFF;
+                for i in (0..15).rev() {
+                    if mask & (1 << i) != 0 {
+                        self.push_long(self.reg(RegID::new(i)))?;
+                    }
+                }
+            }
+            POPR => {
+                let mask = op[0].long() & 0x7FFF;
+                for i in 0..15 {
+                    if mask & (1 << i) != 0 {
+                        let v = self.pop_long()?;
+                        self.set_reg(RegID::new(i), v);
+                    }
+                }
+            }
+            INDEX => {
+                let (subscript, low, high) = (op[0].long() as i32, op[1].long() as i32, op[2].long() as i32);
+                let r = (op[4].long().wrapping_add(subscript as u32)).wrapping_mul(op[3].long());
+                self.store(&op[5], r as u128)?;
+                self.set_nz(r as u128, Longword);
+                self.set_carry(false);
+                if subscript < low || subscript > high {
+                    return Err(Exception::Arithmetic(ArithmeticTrap::SubscriptRange));
+                }
+            }
+            INSQUE => self.insque(op[0].address(), op[1].address())?,
+            REMQUE => {
+                let entry = op[0].address();
+                self.remque(entry)?;
+                self.store(&op[1], entry as u128)?;
+            }
+
+            // Processor status.
+            MOVPSL => self.store(&op[0], self.psl as u128)?,
+            BISPSW | BICPSW => {
+                let mask = op[0].long() & 0xFFFF;
+                if mask & !PSW_MASK != 0 {
+                    return Err(Exception::ReservedOperand);
+                }
+                if instr == BISPSW {
+                    self.psl |= mask;
+                } else {
+                    self.psl &= !mask;
+                }
+            }
+            CHMK | CHME | CHMS | CHMU => {
+                let mode = PrivilegeMode::from_u16(instr as u16 - CHMK as u16).unwrap();
+                return Err(Exception::ChangeMode(mode, op[0].value as u16));
+            }
+            REI => self.rei()?,
+            MTPR => {
+                self.require_kernel()?;
+                let reg = PrivRegisters::from_u32(op[1].long()).ok_or(Exception::ReservedOperand)?;
+                self.write_ipr(reg, op[0].long());
+                self.set_nz(op[0].value, Longword);
+            }
+            MFPR => {
+                self.require_kernel()?;
+                let reg = PrivRegisters::from_u32(op[0].long()).ok_or(Exception::ReservedOperand)?;
+                let v = self.read_ipr(reg);
+                self.store(&op[1], v as u128)?;
+                self.set_nz(v as u128, Longword);
+            }
+
+            _ => return Err(Exception::ReservedInstruction),
+        }
+        Ok(())
+    }
+}
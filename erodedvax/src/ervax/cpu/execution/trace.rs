@@ -0,0 +1,500 @@
+//! Tracing: a record of each instruction the CPU runs, with where it was, its operands as they
+//! were evaluated, the PSL before and after, and what it wrote to memory. A filter picks the
+//! instructions by PC, mode and class, and records go to a sink: a ring buffer kept in memory,
+//! text, or a compact binary form that `read_binary` reads back.
+//!
+//! Without a tracer, all this costs is a test of an empty Option per instruction and per
+//! memory write. Instructions that can't be fetched, and interrupts, aren't recorded.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use num_traits::FromPrimitive;
+
+use crate::ervax::cpu::{
+    execution::{operands::width_mask, ExecutionContext, Location, Operand},
+    instrs::{InstructionType, OperandWidth},
+    PrivilegeMode, RegID,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InstructionClass {
+    /// Branches and jumps.
+    Branch,
+    /// Calls and returns, of procedures and subroutines.
+    Call,
+    /// Instructions for the operating system: changing mode, processor registers, contexts.
+    Privileged,
+    Other,
+}
+
+impl InstructionClass {
+    pub fn of(instr: InstructionType) -> InstructionClass {
+        use InstructionType::*;
+        match instr {
+            CALLS | CALLG | RET | JSB | BSBB | BSBW | RSB => InstructionClass::Call,
+            CHMK | CHME | CHMS | CHMU | REI | MTPR | MFPR | LDPCTX | SVPCTX | HALT => InstructionClass::Privileged,
+            JMP | CASEB | CASEW | CASEL => InstructionClass::Branch,
+            i if i.has_branch_displacement() => InstructionClass::Branch,
+            _ => InstructionClass::Other,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<InstructionClass> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "branch" => InstructionClass::Branch,
+            "call" => InstructionClass::Call,
+            "privileged" => InstructionClass::Privileged,
+            "other" => InstructionClass::Other,
+            _ => return None,
+        })
+    }
+}
+
+/// Which instructions are traced. An empty list lets everything through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub ranges: Vec<RangeInclusive<u32>>,
+    pub modes: Vec<PrivilegeMode>,
+    pub classes: Vec<InstructionClass>,
+}
+
+impl TraceFilter {
+    fn wants(&self, pc: u32, mode: PrivilegeMode) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|r| r.contains(&pc)))
+            && (self.modes.is_empty() || self.modes.contains(&mode))
+    }
+
+    fn wants_class(&self, instr: InstructionType) -> bool {
+        self.classes.is_empty() || self.classes.contains(&InstructionClass::of(instr))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub width: OperandWidth,
+    pub value: u128,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u32,
+    pub instr: InstructionType,
+    /// The operands as evaluated, before the instruction ran.
+    pub operands: Vec<Operand>,
+    pub psl_before: u32,
+    pub psl_after: u32,
+    /// Where the CPU went next, which is an exception handler if the instruction took one.
+    pub next_pc: u32,
+    pub writes: Vec<MemoryWrite>,
+}
+
+fn format_value(width: OperandWidth, value: u128) -> String {
+    format!("{:0digits$X}", value, digits = width.bytes() * 2)
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X}  {:?}", self.pc, self.instr)?;
+        for (i, op) in self.operands.iter().enumerate() {
+            let value = format_value(op.width, op.value);
+            let separator = if i == 0 { " " } else { ", " };
+            match op.location {
+                Location::Register(r) => write!(f, "{}{}={}", separator, r.name(), value)?,
+                Location::Memory(a) => write!(f, "{}({:08X})={}", separator, a, value)?,
+                Location::Constant => write!(f, "{}#{}", separator, value)?,
+            }
+        }
+        write!(f, "  PSL {:08X}", self.psl_before)?;
+        if self.psl_after != self.psl_before {
+            write!(f, ">{:08X}", self.psl_after)?;
+        }
+        for w in &self.writes {
+            write!(f, "  ({:08X})<{}", w.address, format_value(w.width, w.value))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where records go.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The last records, kept in memory. Clones share the buffer, so the host can keep one to read.
+#[derive(Clone)]
+pub struct RingSink {
+    records: Arc<Mutex<VecDeque<TraceRecord>>>,
+    capacity: usize,
+}
+
+impl RingSink {
+    pub fn new(capacity: usize) -> RingSink {
+        RingSink { records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), capacity }
+    }
+
+    /// The records kept, oldest first.
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl TraceSink for RingSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        if self.capacity > 0 {
+            records.push_back(record.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A line of text per record.
+pub struct TextSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(out: W) -> TextSink<W> {
+        TextSink { out }
+    }
+}
+
+impl<W: Write> TraceSink for TextSink<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        writeln!(self.out, "{}", record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+const MAGIC: &[u8; 4] = b"ERVT";
+const VERSION: u8 = 1;
+
+/// Records in binary, after a header of MAGIC and VERSION. Each is PC, opcode, the PSL before
+/// and after and the next PC, then a count of operands and a count of writes, each followed by
+/// them. An operand is a byte with its kind (register, memory, constant) and width code in
+/// the high nibble, a longword for the register number or address, and its value in as many
+/// bytes as the width. A write is its address, width code and value. Little endian throughout.
+pub struct BinarySink<W: Write> {
+    out: W,
+}
+
+fn width_code(width: OperandWidth) -> u8 {
+    match width {
+        OperandWidth::Byte => 0,
+        OperandWidth::Word => 1,
+        OperandWidth::Longword => 2,
+        OperandWidth::Quadword => 3,
+        OperandWidth::Octaword => 4,
+    }
+}
+
+fn width_from_code(code: u8) -> io::Result<OperandWidth> {
+    Ok(match code {
+        0 => OperandWidth::Byte,
+        1 => OperandWidth::Word,
+        2 => OperandWidth::Longword,
+        3 => OperandWidth::Quadword,
+        4 => OperandWidth::Octaword,
+        _ => return Err(bad_trace("width")),
+    })
+}
+
+fn bad_trace(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad {} in trace", what))
+}
+
+impl<W: Write> BinarySink<W> {
+    pub fn new(mut out: W) -> io::Result<BinarySink<W>> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(BinarySink { out })
+    }
+}
+
+impl<W: Write> TraceSink for BinarySink<W> {
+    fn record(&mut self, r: &TraceRecord) -> io::Result<()> {
+        let mut b = Vec::with_capacity(32);
+        b.extend_from_slice(&r.pc.to_le_bytes());
+        b.extend_from_slice(&(r.instr as u16).to_le_bytes());
+        for v in &[r.psl_before, r.psl_after, r.next_pc] {
+            b.extend_from_slice(&v.to_le_bytes());
+        }
+        b.push(r.operands.len() as u8);
+        for op in &r.operands {
+            let (kind, at) = match op.location {
+                Location::Register(reg) => (0, reg.id() as u32),
+                Location::Memory(a) => (1, a),
+                Location::Constant => (2, 0),
+            };
+            b.push(kind | width_code(op.width) << 4);
+            b.extend_from_slice(&at.to_le_bytes());
+            b.extend_from_slice(&op.value.to_le_bytes()[..op.width.bytes()]);
+        }
+        b.push(r.writes.len() as u8);
+        for w in &r.writes {
+            b.extend_from_slice(&w.address.to_le_bytes());
+            b.push(width_code(w.width));
+            b.extend_from_slice(&w.value.to_le_bytes()[..w.width.bytes()]);
+        }
+        self.out.write_all(&b)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads back what a BinarySink wrote.
+pub fn read_binary<R: Read>(mut input: R) -> io::Result<Vec<TraceRecord>> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err(bad_trace("header"));
+    }
+
+    let mut records = vec![];
+    loop {
+        let mut pc = [0; 4];
+        match input.read(&mut pc[..1])? {
+            0 => return Ok(records),
+            _ => input.read_exact(&mut pc[1..])?,
+        }
+        let mut fixed = [0; 15];
+        input.read_exact(&mut fixed)?;
+        let long = |at: usize| u32::from_le_bytes([fixed[at], fixed[at + 1], fixed[at + 2], fixed[at + 3]]);
+        let instr = InstructionType::from_u16(u16::from_le_bytes([fixed[0], fixed[1]])).ok_or_else(|| bad_trace("opcode"))?;
+        let mut record = TraceRecord {
+            pc: u32::from_le_bytes(pc),
+            instr,
+            operands: vec![],
+            psl_before: long(2),
+            psl_after: long(6),
+            next_pc: long(10),
+            writes: vec![],
+        };
+
+        let value = |input: &mut R, width: OperandWidth| -> io::Result<u128> {
+            let mut v = [0; 16];
+            input.read_exact(&mut v[..width.bytes()])?;
+            Ok(u128::from_le_bytes(v))
+        };
+        for _ in 0..fixed[14] {
+            let mut op = [0; 5];
+            input.read_exact(&mut op)?;
+            let width = width_from_code(op[0] >> 4)?;
+            let at = u32::from_le_bytes([op[1], op[2], op[3], op[4]]);
+            let location = match op[0] & 0xF {
+                0 if at < 16 => Location::Register(RegID::new(at as u8)),
+                1 => Location::Memory(at),
+                2 => Location::Constant,
+                _ => return Err(bad_trace("operand")),
+            };
+            record.operands.push(Operand { location, width, value: value(&mut input, width)? });
+        }
+        let mut count = [0];
+        input.read_exact(&mut count)?;
+        for _ in 0..count[0] {
+            let mut w = [0; 5];
+            input.read_exact(&mut w)?;
+            let width = width_from_code(w[4])?;
+            let address = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+            record.writes.push(MemoryWrite { address, width, value: value(&mut input, width)? });
+        }
+        records.push(record);
+    }
+}
+
+/// A filter and the sink it feeds, and the record of the instruction being run.
+pub struct Tracer {
+    filter: TraceFilter,
+    sink: Box<dyn TraceSink>,
+    /// The PC and PSL of the instruction being run, when the filter wants it.
+    pending: Option<(u32, u32)>,
+    current: Option<TraceRecord>,
+    /// What stopped the tracing, if something did.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn TraceSink>, filter: TraceFilter) -> Tracer {
+        Tracer { filter, sink, pending: None, current: None, error: None }
+    }
+
+    /// Why records stopped going to the sink, if they did.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = self.sink.flush() {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn begin(&mut self, pc: u32, psl: u32, mode: PrivilegeMode) {
+        self.current = None;
+        self.pending = if self.error.is_none() && self.filter.wants(pc, mode) { Some((pc, psl)) } else { None };
+    }
+
+    pub(super) fn fetched(&mut self, instr: InstructionType) {
+        if let Some((pc, psl)) = self.pending.take() {
+            if self.filter.wants_class(instr) {
+                self.current = Some(TraceRecord {
+                    pc,
+                    instr,
+                    operands: vec![],
+                    psl_before: psl,
+                    psl_after: psl,
+                    next_pc: pc,
+                    writes: vec![],
+                });
+            }
+        }
+    }
+
+    pub(super) fn evaluated(&mut self, operands: &[Operand]) {
+        if let Some(record) = &mut self.current {
+            // Branch displacements are held sign extended.
+            record.operands = operands.iter().map(|op| Operand { value: op.value & width_mask(op.width), ..*op }).collect();
+        }
+    }
+
+    pub(super) fn wrote(&mut self, address: u32, width: OperandWidth, value: u128) {
+        if let Some(record) = &mut self.current {
+            record.writes.push(MemoryWrite { address, width, value: value & width_mask(width) });
+        }
+    }
+
+    fn end(&mut self, next_pc: u32, psl: u32) {
+        self.pending = None;
+        if let Some(mut record) = self.current.take() {
+            record.next_pc = next_pc;
+            record.psl_after = psl;
+            if let Err(e) = self.sink.record(&record) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl ExecutionContext {
+    /// Starts tracing with `tracer`, or stops with None.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        if let Some(old) = self.tracer.as_deref_mut() {
+            old.flush();
+        }
+        self.tracer = tracer.map(Box::new);
+    }
+
+    #[inline]
+    pub fn tracer(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_deref_mut()
+    }
+
+    pub(super) fn trace_begin(&mut self) {
+        let (pc, psl, mode) = (self.pc, self.psl, self.get_cur_priv_mode());
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.begin(pc, psl, mode);
+        }
+    }
+
+    pub(super) fn trace_end(&mut self) {
+        let (pc, psl) = (self.pc, self.psl);
+        if let Some(t) = self.tracer.as_deref_mut() {
+            t.end(pc, psl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::{instrs::assemble, sysclk::TimeMode};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Bytes written, kept where the test can see them after the sink's gone.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traced_instructions() {
+        let code = assemble("
+                MOVL #^X2000, R1
+                CLRL R0
+        LOOP:   INCL R0
+                MOVL R0, (R1)+
+                CMPL R0, #2
+                BLSS LOOP
+                HALT
+        ", 0x1000).unwrap();
+        let run = |filter: TraceFilter, sink: Box<dyn TraceSink>| {
+            let mut exec = ExecutionContext::with_time_mode(TimeMode::Virtual);
+            exec.bus().write_bytes(0x1000, &code.bytes).unwrap();
+            exec.set_tracer(Some(Tracer::new(sink, filter)));
+            exec.start(0x1000);
+            while exec.execute_step() {}
+        };
+
+        let ring = RingSink::new(3);
+        run(TraceFilter::default(), Box::new(ring.clone()));
+        let records = ring.records();
+        let instrs: Vec<_> = records.iter().map(|r| r.instr).collect();
+        assert_eq!(instrs, vec![InstructionType::CMPL, InstructionType::BLSS, InstructionType::HALT]);
+        assert_eq!(records[1].to_string(), "00001011  BLSS #F6  PSL 00000004");
+
+        let text = Shared::default();
+        let filter = TraceFilter { ranges: vec![0x1009..=0x100D], ..TraceFilter::default() };
+        run(filter, Box::new(TextSink::new(text.clone())));
+        assert_eq!(String::from_utf8(text.0.take()).unwrap(), "\
+00001009  INCL R0=00000000  PSL 00000004>00000000
+0000100B  MOVL R0=00000001, (00002000)=00000000  PSL 00000000  (00002000)<00000001
+00001009  INCL R0=00000001  PSL 00000009>00000000
+0000100B  MOVL R0=00000002, (00002004)=00000000  PSL 00000000  (00002004)<00000002
+");
+
+        let binary = Shared::default();
+        let filter = TraceFilter { classes: vec![InstructionClass::Branch, InstructionClass::Privileged], ..TraceFilter::default() };
+        run(filter, Box::new(BinarySink::new(binary.clone()).unwrap()));
+        let records = read_binary(&binary.0.take()[..]).unwrap();
+        let jumps: Vec<_> = records.iter().map(|r| (r.instr, r.pc, r.next_pc)).collect();
+        assert_eq!(jumps, vec![
+            (InstructionType::BLSS, 0x1011, 0x1009),
+            (InstructionType::BLSS, 0x1011, 0x1013),
+            (InstructionType::HALT, 0x1013, 0x1014),
+        ]);
+        assert_eq!(records[0].operands[0], Operand { location: Location::Constant, width: OperandWidth::Byte, value: 0xF6 });
+
+        let filter = TraceFilter { modes: vec![PrivilegeMode::User], ..TraceFilter::default() };
+        let ring = RingSink::new(10);
+        run(filter, Box::new(ring.clone()));
+        assert!(ring.records().is_empty());
+        assert!(read_binary(&b"ERVT\x02"[..]).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Watchpoints, for debuggers: ranges of virtual addresses whose reads or writes are noted as
+//! instructions make them. Nothing stops; a debugger stepping the CPU looks for a hit after
+//! each step. While there are none, checking costs a test of an empty list per data access.
+
+use crate::ervax::cpu::execution::ExecutionContext;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    /// Reads and writes.
+    Access,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn catches(&self, address: u32, len: u32, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+        let (start, end) = (self.address as u64, self.address as u64 + self.len as u64);
+        kind && (address as u64) < end && start < address as u64 + len as u64
+    }
+}
+
+impl ExecutionContext {
+    pub fn add_watchpoint(&mut self, w: Watchpoint) {
+        if !self.watchpoints.contains(&w) {
+            self.watchpoints.push(w);
+        }
+    }
+
+    /// Returns false if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, w: Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|x| *x != w);
+        self.watchpoints.len() != before
+    }
+
+    #[inline]
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The first watchpoint hit since this was last called, and the address accessed.
+    pub fn take_watch_hit(&mut self) -> Option<(Watchpoint, u32)> {
+        self.watch_hit.take()
+    }
+
+    #[inline]
+    pub(super) fn check_watchpoints(&mut self, address: u32, len: u32, write: bool) {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return;
+        }
+        if let Some(w) = self.watchpoints.iter().find(|w| w.catches(address, len, write)) {
+            self.watch_hit = Some((*w, address));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::{instrs::OperandWidth, sysclk::TimeMode};
+
+    #[test]
+    fn watchpoint_hits() {
+        let mut exec = ExecutionContext::with_time_mode(TimeMode::Virtual);
+        let w = Watchpoint { address: 0x1002, len: 2, kind: WatchKind::Write };
+        exec.add_watchpoint(w);
+        exec.read_virt(0x1000, OperandWidth::Longword).unwrap();
+        exec.write_virt(0x1004, OperandWidth::Longword, 0).unwrap();
+        assert_eq!(exec.take_watch_hit(), None);
+        exec.write_virt(0x1000, OperandWidth::Longword, 0).unwrap();
+        assert_eq!(exec.take_watch_hit(), Some((w, 0x1000)));
+
+        assert!(exec.remove_watchpoint(w));
+        assert!(!exec.remove_watchpoint(w));
+        exec.add_watchpoint(Watchpoint { kind: WatchKind::Access, ..w });
+        exec.read_virt(0x1003, OperandWidth::Byte).unwrap();
+        assert_eq!(exec.take_watch_hit().map(|(_, a)| a), Some(0x1003));
+
+        // Debuggers reach memory without tripping them.
+        exec.debug_write(0x1000, OperandWidth::Longword, 0).unwrap();
+        exec.debug_read(0x1000, OperandWidth::Longword).unwrap();
+        assert_eq!(exec.take_watch_hit(), None);
+    }
+}
@@ -0,0 +1,866 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::ervax::cpu::{
+    instrs::{
+        FieldMode,
+        InstructionType,
+        Labels,
+        OperandMode,
+        OperandWidth,
+    },
+    RegID,
+};
+
+// A small assembler for a subset of VAX MACRO-32, mostly for writing CPU tests.
+// It accepts everything the disassembler prints, plus:
+//   labels (`NAME:`), `.BYTE`, `.WORD`, `.LONG`, `.ASCII`, `.ASCIZ`, `.BLKB`, and `.ENTRY name, mask`
+//   numbers in decimal or with a ^X/^O/^B/^D radix prefix, `^M<R2,R3>` register masks
+//   simple expressions, terms joined by + or -, where `.` is the address of the current statement.
+// Symbols are case sensitive, mnemonics and register names are not.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    BadOperand(String),
+    BadExpression(String),
+    /// The instruction expects a different number of operands. Holds the expected count.
+    OperandCount(usize),
+    /// The operand's addressing mode can't be used for this field, like writing to a literal.
+    InvalidMode(String),
+    UndefinedSymbol(String),
+    DuplicateLabel(String),
+    /// A value doesn't fit in the space it has to go in, like a BRB to something 200 bytes away.
+    OutOfRange(i64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based source line the error was found on.
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+/// The output of a successful assembly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assembled {
+    /// Address the first byte is meant to be loaded at.
+    pub base: u32,
+    pub bytes: Vec<u8>,
+    /// Every label defined in the source, by address.
+    pub labels: Labels,
+    /// Every label defined in the source, by name.
+    pub symbols: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Term {
+    Number(i64),
+    Symbol(String),
+    Here,
+}
+
+/// Terms and whether they're subtracted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Expr(Vec<(bool, Term)>);
+
+/// Size of a displacement.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum DispSize {
+    Byte,
+    Word,
+    Long,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OperandSpec {
+    /// Register, register deferred, autoincrement/decrement. Nothing left to resolve.
+    Fixed(OperandMode),
+    /// `S^#n`
+    Literal(Expr),
+    /// `I^#n`
+    Immediate(Expr),
+    /// `#n`, a short literal if it's a constant that fits, an immediate otherwise.
+    AutoImmediate(Expr),
+    /// `@#addr`
+    Absolute(Expr),
+    /// `B^d(Rn)`, `@W^d(Rn)`, `d(Rn)`. `auto` is true when the size wasn't given and may grow.
+    Displacement { reg: RegID, value: Expr, deferred: bool, size: DispSize, auto: bool },
+    /// `B^addr`, `@L^addr`, `addr`. Like Displacement, but off of the PC.
+    Relative { target: Expr, deferred: bool, size: DispSize, auto: bool },
+    /// A branch displacement field. The size is fixed by the instruction.
+    Branch(Expr),
+    /// A data field that isn't a branch, only the BUG checks have these.
+    Data(Expr),
+    Indexed(RegID, Box<OperandSpec>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Stmt {
+    Label(String),
+    Instr(InstructionType, Vec<OperandSpec>),
+    Data(OperandWidth, Vec<Expr>),
+    Ascii(Vec<u8>),
+    Block(Expr),
+}
+
+fn err(line: usize, kind: AsmErrorKind) -> AsmError {
+    AsmError { line, kind }
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '$' || c == '.'
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.'
+}
+
+fn parse_number(digits: &str, radix: u32) -> Option<i64> {
+    if digits.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(digits, radix).ok().map(|v| v as i64)
+}
+
+/// Parses the inside of a `^M<...>` register save mask.
+fn parse_mask(regs: &str) -> Option<i64> {
+    let mut mask = 0;
+    for r in regs.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        mask |= match r.to_ascii_uppercase().as_str() {
+            "IV" => 1 << 14,
+            "DV" => 1 << 15,
+            _ => match RegID::from_name(r) {
+                Some(id) if id.id() < 12 => 1 << id.id(),
+                _ => return None,
+            },
+        };
+    }
+    Some(mask)
+}
+
+fn parse_term(s: &str) -> Option<Term> {
+    let upper = s.to_ascii_uppercase();
+    if s == "." {
+        return Some(Term::Here);
+    }
+    if let Some(rest) = upper.strip_prefix('^') {
+        let mut chars = rest.chars();
+        let radix = chars.next();
+        let digits = chars.as_str();
+        return match radix {
+            Some('X') => parse_number(digits, 16),
+            Some('O') => parse_number(digits, 8),
+            Some('B') => parse_number(digits, 2),
+            Some('D') => parse_number(digits, 10),
+            Some('M') => digits.strip_prefix('<')
+                .and_then(|d| d.strip_suffix('>'))
+                .and_then(parse_mask),
+            _ => None,
+        }.map(Term::Number);
+    }
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_number(s, 10).map(Term::Number);
+    }
+    if s.starts_with(is_symbol_start) && s.chars().all(is_symbol_char) {
+        return Some(Term::Symbol(s.to_string()));
+    }
+    None
+}
+
+fn parse_expr(s: &str) -> Result<Expr, AsmErrorKind> {
+    let bad = || AsmErrorKind::BadExpression(s.to_string());
+    let mut terms = vec![];
+    let mut negate = false;
+    let mut current = String::new();
+    let mut in_mask = false;
+
+    for c in s.trim().chars() {
+        match c {
+            '<' => { in_mask = true; current.push(c); }
+            '>' => { in_mask = false; current.push(c); }
+            '+' | '-' if !in_mask => {
+                if current.trim().is_empty() {
+                    // Unary sign, only allowed before the first term.
+                    if !terms.is_empty() {
+                        return Err(bad());
+                    }
+                    negate ^= c == '-';
+                } else {
+                    terms.push((negate, parse_term(current.trim()).ok_or_else(bad)?));
+                    current.clear();
+                    negate = c == '-';
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    let t = current.trim();
+    if t.is_empty() {
+        return Err(bad());
+    }
+    terms.push((negate, parse_term(t).ok_or_else(bad)?));
+    Ok(Expr(terms))
+}
+
+impl Expr {
+    fn constant(&self) -> Option<i64> {
+        self.0.iter().try_fold(0i64, |acc, (neg, t)| match t {
+            Term::Number(v) if *neg => Some(acc.wrapping_sub(*v)),
+            Term::Number(v) => Some(acc.wrapping_add(*v)),
+            _ => None,
+        })
+    }
+
+    fn symbols(&self) -> impl Iterator<Item = &String> {
+        self.0.iter().filter_map(|(_, t)| match t {
+            Term::Symbol(s) => Some(s),
+            _ => None,
+        })
+    }
+
+    fn eval(&self, symbols: &HashMap<String, u32>, here: u32) -> i64 {
+        self.0.iter().fold(0i64, |acc, (neg, t)| {
+            let v = match t {
+                Term::Number(v) => *v,
+                // Undefined symbols are reported before layout starts.
+                Term::Symbol(s) => symbols.get(s).copied().unwrap_or(0) as i64,
+                Term::Here => here as i64,
+            };
+            if *neg { acc.wrapping_sub(v) } else { acc.wrapping_add(v) }
+        })
+    }
+}
+
+fn strip_size(s: &str) -> (Option<DispSize>, &str) {
+    let upper = s.get(..2).map(|p| p.to_ascii_uppercase());
+    match upper.as_deref() {
+        Some("B^") => (Some(DispSize::Byte), &s[2..]),
+        Some("W^") => (Some(DispSize::Word), &s[2..]),
+        Some("L^") => (Some(DispSize::Long), &s[2..]),
+        _ => (None, s),
+    }
+}
+
+fn parse_reg(s: &str) -> Result<RegID, AsmErrorKind> {
+    RegID::from_name(s.trim()).ok_or_else(|| AsmErrorKind::BadOperand(s.to_string()))
+}
+
+fn parse_operand(s: &str) -> Result<OperandSpec, AsmErrorKind> {
+    let s = s.trim();
+    let bad = || AsmErrorKind::BadOperand(s.to_string());
+    let upper = s.to_ascii_uppercase();
+
+    if s.ends_with(']') {
+        let open = s.rfind('[').ok_or_else(bad)?;
+        let index = parse_reg(&s[open + 1..s.len() - 1])?;
+        let base = parse_operand(&s[..open])?;
+        return Ok(OperandSpec::Indexed(index, Box::new(base)));
+    }
+
+    if upper.starts_with("S^#") {
+        return Ok(OperandSpec::Literal(parse_expr(&s[3..])?));
+    }
+    if upper.starts_with("I^#") {
+        return Ok(OperandSpec::Immediate(parse_expr(&s[3..])?));
+    }
+    if let Some(rest) = s.strip_prefix('#') {
+        return Ok(OperandSpec::AutoImmediate(parse_expr(rest)?));
+    }
+    if let Some(rest) = s.strip_prefix("@#") {
+        return Ok(OperandSpec::Absolute(parse_expr(rest)?));
+    }
+
+    let (deferred, s) = match s.strip_prefix('@') {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, s),
+    };
+
+    if let Some(inner) = s.strip_prefix("-(").and_then(|r| r.strip_suffix(')')) {
+        return match deferred {
+            false => Ok(OperandSpec::Fixed(OperandMode::AutoDecrement(parse_reg(inner)?))),
+            true => Err(bad()),
+        };
+    }
+    if let Some(inner) = s.strip_prefix('(').and_then(|r| r.strip_suffix(")+")) {
+        let reg = parse_reg(inner)?;
+        return Ok(OperandSpec::Fixed(match deferred {
+            false => OperandMode::AutoIncrement(reg),
+            true => OperandMode::AutoIncrementDeferred(reg),
+        }));
+    }
+    if let Some(inner) = s.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        let reg = parse_reg(inner)?;
+        return Ok(OperandSpec::Fixed(match deferred {
+            false => OperandMode::RegisterDeferred(reg),
+            true => OperandMode::ByteDisplacementDeferred(reg, 0),
+        }));
+    }
+
+    let (size, s) = strip_size(s);
+
+    if s.ends_with(')') {
+        let open = s.rfind('(').ok_or_else(bad)?;
+        let reg = parse_reg(&s[open + 1..s.len() - 1])?;
+        let value = parse_expr(&s[..open])?;
+        // Constant displacements can be sized right away.
+        let fitted = value.constant().map(|v| match v {
+            -0x80..=0x7F => DispSize::Byte,
+            -0x8000..=0x7FFF => DispSize::Word,
+            _ => DispSize::Long,
+        });
+        return Ok(OperandSpec::Displacement {
+            reg,
+            value,
+            deferred,
+            size: size.or(fitted).unwrap_or(DispSize::Byte),
+            auto: size.is_none() && fitted.is_none(),
+        });
+    }
+
+    if !deferred && size.is_none() {
+        if let Some(reg) = RegID::from_name(s) {
+            return Ok(OperandSpec::Fixed(OperandMode::Register(reg)));
+        }
+    }
+
+    Ok(OperandSpec::Relative {
+        target: parse_expr(s)?,
+        deferred,
+        size: size.unwrap_or(DispSize::Byte),
+        auto: size.is_none(),
+    })
+}
+
+/// Splits on commas, except those inside `<...>`.
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn strip_comment(s: &str) -> &str {
+    match s.find(';') {
+        Some(i) => &s[..i],
+        None => s,
+    }
+}
+
+/// Parses a delimited string like `/text/` or `"text"`.
+fn parse_ascii(s: &str) -> Result<Vec<u8>, AsmErrorKind> {
+    let s = s.trim_start();
+    let bad = || AsmErrorKind::BadOperand(s.to_string());
+    let delim = s.chars().next().ok_or_else(bad)?;
+    let body = &s[delim.len_utf8()..];
+    let end = body.find(delim).ok_or_else(bad)?;
+    if !strip_comment(&body[end + delim.len_utf8()..]).trim().is_empty() {
+        return Err(bad());
+    }
+    Ok(body.as_bytes()[..end].to_vec())
+}
+
+fn parse_instr(instr: InstructionType, args: &str) -> Result<Stmt, AsmErrorKind> {
+    let modes: Vec<FieldMode> = instr.field_modes().iter()
+        .copied()
+        .filter(|m| *m != FieldMode::VariableLengthTable)
+        .collect();
+
+    let args = args.trim();
+    let parts = if args.is_empty() { vec![] } else { split_operands(args) };
+    if parts.len() != modes.len() {
+        return Err(AsmErrorKind::OperandCount(modes.len()));
+    }
+
+    let mut operands = vec![];
+    for (part, mode) in parts.into_iter().zip(modes) {
+        operands.push(match mode {
+            FieldMode::Data if instr.has_branch_displacement() => OperandSpec::Branch(parse_expr(part)?),
+            FieldMode::Data => OperandSpec::Data(parse_expr(part)?),
+            _ => parse_operand(part)?,
+        });
+    }
+    Ok(Stmt::Instr(instr, operands))
+}
+
+fn parse_line(line: &str, mnemonics: &HashMap<String, InstructionType>) -> Result<Vec<Stmt>, AsmErrorKind> {
+    let mut stmts = vec![];
+    let mut rest = line.trim();
+
+    // Labels, any number of them.
+    while let Some(colon) = rest.find(':') {
+        let name = rest[..colon].trim();
+        if name.is_empty() || !name.starts_with(is_symbol_start) || !name.chars().all(is_symbol_char) {
+            break;
+        }
+        stmts.push(Stmt::Label(name.to_string()));
+        rest = rest[colon + 1..].trim_start();
+    }
+
+    let (word, args) = match rest.find(char::is_whitespace) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let word = strip_comment(word);
+    if word.is_empty() {
+        return Ok(stmts);
+    }
+    let upper = word.to_ascii_uppercase();
+
+    if upper == ".ASCII" || upper == ".ASCIZ" {
+        let mut text = parse_ascii(args)?;
+        if upper == ".ASCIZ" {
+            text.push(0);
+        }
+        stmts.push(Stmt::Ascii(text));
+        return Ok(stmts);
+    }
+
+    let args = strip_comment(args).trim();
+    let exprs = || -> Result<Vec<Expr>, AsmErrorKind> {
+        split_operands(args).into_iter().map(parse_expr).collect()
+    };
+
+    match upper.as_str() {
+        ".BYTE" => stmts.push(Stmt::Data(OperandWidth::Byte, exprs()?)),
+        ".WORD" => stmts.push(Stmt::Data(OperandWidth::Word, exprs()?)),
+        ".LONG" => stmts.push(Stmt::Data(OperandWidth::Longword, exprs()?)),
+        ".BLKB" => stmts.push(Stmt::Block(parse_expr(args)?)),
+        ".ENTRY" => {
+            let parts = split_operands(args);
+            if parts.len() != 2 {
+                return Err(AsmErrorKind::OperandCount(2));
+            }
+            let name = parts[0].trim();
+            if !name.starts_with(is_symbol_start) || !name.chars().all(is_symbol_char) {
+                return Err(AsmErrorKind::BadOperand(name.to_string()));
+            }
+            stmts.push(Stmt::Label(name.to_string()));
+            stmts.push(Stmt::Data(OperandWidth::Word, vec![parse_expr(parts[1])?]));
+        }
+        d if d.starts_with('.') => return Err(AsmErrorKind::UnknownDirective(word.to_string())),
+        m => match mnemonics.get(m) {
+            Some(instr) => stmts.push(parse_instr(*instr, args)?),
+            None => return Err(AsmErrorKind::UnknownMnemonic(word.to_string())),
+        },
+    }
+    Ok(stmts)
+}
+
+fn fits(v: i64, width: OperandWidth) -> bool {
+    match width {
+        OperandWidth::Byte => (-0x80..=0xFF).contains(&v),
+        OperandWidth::Word => (-0x8000..=0xFFFF).contains(&v),
+        OperandWidth::Longword => (-0x8000_0000..=0xFFFF_FFFF).contains(&v),
+        OperandWidth::Quadword | OperandWidth::Octaword => true,
+    }
+}
+
+fn fits_signed(v: i64, size: DispSize) -> bool {
+    match size {
+        DispSize::Byte => (-0x80..=0x7F).contains(&v),
+        DispSize::Word => (-0x8000..=0x7FFF).contains(&v),
+        DispSize::Long => (-0x8000_0000..=0x7FFF_FFFF).contains(&v),
+    }
+}
+
+fn grow(size: DispSize) -> DispSize {
+    match size {
+        DispSize::Byte => DispSize::Word,
+        _ => DispSize::Long,
+    }
+}
+
+fn disp_len(size: DispSize) -> u32 {
+    match size {
+        DispSize::Byte => 1,
+        DispSize::Word => 2,
+        DispSize::Long => 4,
+    }
+}
+
+fn displacement(reg: RegID, d: i64, deferred: bool, size: DispSize) -> OperandMode {
+    use OperandMode::*;
+    match (size, deferred) {
+        (DispSize::Byte, false) => ByteDisplacement(reg, d as i8),
+        (DispSize::Byte, true) => ByteDisplacementDeferred(reg, d as i8),
+        (DispSize::Word, false) => WordDisplacement(reg, d as i16),
+        (DispSize::Word, true) => WordDisplacementDeferred(reg, d as i16),
+        (DispSize::Long, false) => LongwordDisplacement(reg, d as i32),
+        (DispSize::Long, true) => LongwordDisplacementDeferred(reg, d as i32),
+    }
+}
+
+fn data(v: i64, width: OperandWidth) -> OperandMode {
+    match width {
+        OperandWidth::Byte => OperandMode::DataByte(v as u8),
+        OperandWidth::Word => OperandMode::DataWord(v as u16),
+        _ => OperandMode::DataLong(v as u32),
+    }
+}
+
+/// State shared by a single layout pass.
+struct Pass<'a> {
+    symbols: &'a HashMap<String, u32>,
+    /// Whether auto-sized displacements may grow. Off for the first pass, which only finds
+    /// where labels are when everything is as small as it can be.
+    grow: bool,
+    /// Whether any auto-sized displacement grew during this pass.
+    grew: bool,
+    /// Report out of range values. Only done on the final pass, earlier ones may be using stale addresses.
+    check: bool,
+}
+
+impl<'a> Pass<'a> {
+    fn check_range(&self, ok: bool, v: i64) -> Result<(), AsmErrorKind> {
+        if self.check && !ok {
+            Err(AsmErrorKind::OutOfRange(v))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Resolves an operand located at `at` to a concrete mode, growing auto-sized displacements as needed.
+    fn resolve(&mut self, spec: &mut OperandSpec, width: OperandWidth, here: u32, at: u32)
+        -> Result<OperandMode, AsmErrorKind>
+    {
+        match spec {
+            OperandSpec::Fixed(m) => Ok(m.clone()),
+            OperandSpec::Literal(e) => {
+                let v = e.eval(self.symbols, here);
+                self.check_range((0..=63).contains(&v), v)?;
+                Ok(OperandMode::Literal(v as u8))
+            }
+            OperandSpec::Immediate(e) => {
+                let v = e.eval(self.symbols, here);
+                self.check_range(fits(v, width), v)?;
+                Ok(OperandMode::immediate(v as i128 as u128, width))
+            }
+            OperandSpec::AutoImmediate(e) => {
+                let v = e.eval(self.symbols, here);
+                self.check_range(fits(v, width), v)?;
+                match e.constant() {
+                    // Symbols always get an immediate, their value isn't known while sizing.
+                    Some(_) => Ok(OperandMode::constant(v as i128 as u128, width)),
+                    None => Ok(OperandMode::immediate(v as i128 as u128, width)),
+                }
+            }
+            OperandSpec::Absolute(e) => Ok(OperandMode::Absolute(e.eval(self.symbols, here) as u32)),
+            OperandSpec::Displacement { reg, value, deferred, size, auto } => {
+                let v = value.eval(self.symbols, here);
+                while self.grow && *auto && !fits_signed(v, *size) && *size != DispSize::Long {
+                    *size = grow(*size);
+                    self.grew = true;
+                }
+                self.check_range(fits_signed(v, *size), v)?;
+                Ok(displacement(*reg, v, *deferred, *size))
+            }
+            OperandSpec::Relative { target, deferred, size, auto } => {
+                let t = target.eval(self.symbols, here);
+                let disp = |size| t.wrapping_sub(at as i64 + 1 + disp_len(size) as i64) as i32 as i64;
+                while self.grow && *auto && !fits_signed(disp(*size), *size) && *size != DispSize::Long {
+                    *size = grow(*size);
+                    self.grew = true;
+                }
+                self.check_range(fits_signed(disp(*size), *size), disp(*size))?;
+                Ok(displacement(RegID::PC, disp(*size), *deferred, *size))
+            }
+            OperandSpec::Branch(e) => {
+                let len = match width {
+                    OperandWidth::Byte => 1,
+                    OperandWidth::Word => 2,
+                    _ => 4,
+                };
+                let d = e.eval(self.symbols, here).wrapping_sub(at as i64 + len) as i32 as i64;
+                let size = match width {
+                    OperandWidth::Byte => DispSize::Byte,
+                    OperandWidth::Word => DispSize::Word,
+                    _ => DispSize::Long,
+                };
+                self.check_range(fits_signed(d, size), d)?;
+                Ok(data(d, width))
+            }
+            OperandSpec::Data(e) => {
+                let v = e.eval(self.symbols, here);
+                self.check_range(fits(v, width), v)?;
+                Ok(data(v, width))
+            }
+            OperandSpec::Indexed(x, base) => {
+                let base = self.resolve(base, width, here, at.wrapping_add(1))?;
+                if !base.is_valid_indexed() {
+                    return Err(AsmErrorKind::InvalidMode(format!("{:?}", base)));
+                }
+                Ok(OperandMode::Indexed(*x, Box::new(base)))
+            }
+        }
+    }
+
+    fn encode(&mut self, stmt: &mut Stmt, here: u32, out: &mut Vec<u8>) -> Result<(), AsmErrorKind> {
+        match stmt {
+            Stmt::Label(_) => {}
+            Stmt::Instr(instr, operands) => {
+                let modes = instr.field_modes();
+                let widths = instr.field_widths();
+                let start = out.len();
+                out.extend(instr.opcode_bytes());
+                for (i, spec) in operands.iter_mut().enumerate() {
+                    let at = here.wrapping_add((out.len() - start) as u32);
+                    let mode = self.resolve(spec, widths[i], here, at)?;
+                    if modes[i] != FieldMode::Data && !mode.is_valid_in_fieldmode(modes[i]) {
+                        return Err(AsmErrorKind::InvalidMode(format!("{:?}", mode)));
+                    }
+                    mode.encode_into(widths[i], out);
+                }
+            }
+            Stmt::Data(width, values) => {
+                for e in values.iter() {
+                    let v = e.eval(self.symbols, here);
+                    self.check_range(fits(v, *width), v)?;
+                    data(v, *width).encode_into(*width, out);
+                }
+            }
+            Stmt::Ascii(text) => out.extend(text.iter()),
+            Stmt::Block(e) => {
+                let n = e.constant().ok_or_else(|| AsmErrorKind::BadExpression(format!("{:?}", e)))?;
+                if !(0..=0x100_0000).contains(&n) {
+                    return Err(AsmErrorKind::OutOfRange(n));
+                }
+                out.resize(out.len() + n as usize, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expr_symbols(stmt: &Stmt) -> Vec<&String> {
+    fn spec_symbols<'a>(spec: &'a OperandSpec, out: &mut Vec<&'a String>) {
+        match spec {
+            OperandSpec::Fixed(_) => {}
+            OperandSpec::Literal(e) | OperandSpec::Immediate(e) | OperandSpec::AutoImmediate(e)
+            | OperandSpec::Absolute(e) | OperandSpec::Branch(e) | OperandSpec::Data(e)
+            | OperandSpec::Displacement { value: e, .. } | OperandSpec::Relative { target: e, .. } =>
+                out.extend(e.symbols()),
+            OperandSpec::Indexed(_, base) => spec_symbols(base, out),
+        }
+    }
+
+    let mut out = vec![];
+    match stmt {
+        Stmt::Instr(_, operands) => operands.iter().for_each(|o| spec_symbols(o, &mut out)),
+        Stmt::Data(_, values) => values.iter().for_each(|e| out.extend(e.symbols())),
+        Stmt::Block(e) => out.extend(e.symbols()),
+        Stmt::Label(_) | Stmt::Ascii(_) => {}
+    }
+    out
+}
+
+/// Assembles `source`, to be loaded at `base`.
+pub fn assemble(source: &str, base: u32) -> Result<Assembled, AsmError> {
+    let mnemonics: HashMap<String, InstructionType> = InstructionType::all()
+        .map(|i| (format!("{:?}", i), i))
+        .collect();
+
+    let mut stmts: Vec<(usize, Stmt)> = vec![];
+    for (n, line) in source.lines().enumerate() {
+        let parsed = parse_line(line, &mnemonics).map_err(|k| err(n + 1, k))?;
+        stmts.extend(parsed.into_iter().map(|s| (n + 1, s)));
+    }
+
+    let mut symbols: HashMap<String, u32> = HashMap::new();
+    for (line, stmt) in stmts.iter() {
+        if let Stmt::Label(name) = stmt {
+            if symbols.insert(name.clone(), 0).is_some() {
+                return Err(err(*line, AsmErrorKind::DuplicateLabel(name.clone())));
+            }
+        }
+    }
+    for (line, stmt) in stmts.iter() {
+        if let Some(s) = expr_symbols(stmt).into_iter().find(|s| !symbols.contains_key(*s)) {
+            return Err(err(*line, AsmErrorKind::UndefinedSymbol(s.clone())));
+        }
+    }
+
+    // Lay everything out until label addresses stop moving. Auto-sized displacements only ever grow,
+    // so this always settles. Then one last pass with range checking turned on.
+    let mut check = false;
+    let mut first = true;
+    loop {
+        let previous = symbols.clone();
+        let mut pass = Pass { symbols: &previous, grow: !first, grew: false, check };
+        first = false;
+        let mut bytes = vec![];
+
+        for (line, stmt) in stmts.iter_mut() {
+            let here = base.wrapping_add(bytes.len() as u32);
+            if let Stmt::Label(name) = stmt {
+                symbols.insert(name.clone(), here);
+            }
+            pass.encode(stmt, here, &mut bytes).map_err(|k| err(*line, k))?;
+        }
+
+        if check {
+            let symbols: BTreeMap<String, u32> = symbols.into_iter().collect();
+            let mut labels = Labels::new();
+            for (name, addr) in symbols.iter() {
+                // With several labels on one address, the alphabetically first one wins.
+                labels.entry(*addr).or_insert_with(|| name.clone());
+            }
+            return Ok(Assembled { base, bytes, labels, symbols });
+        }
+        if pass.grow && !pass.grew && symbols == previous {
+            check = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::instrs::{
+        DisasmOptions,
+        Disassembler,
+    };
+
+    fn asm(source: &str) -> Vec<u8> {
+        assemble(source, 0x1000).unwrap().bytes
+    }
+
+    fn asm_err(source: &str) -> AsmErrorKind {
+        assemble(source, 0x1000).unwrap_err().kind
+    }
+
+    #[test]
+    fn asm_decode_tests() {
+        // Same byte sequences as the decoder tests in instrparse.rs
+        assert_eq!(asm("ADDB2 I^#2, R1"), vec![0x80, 0x8F, 0x02, 0x51]);
+        assert_eq!(asm("BUGW 2"), vec![0xFF, 0xFE, 0x02, 0x00]);
+        assert_eq!(asm("ret"), vec![0x04]);
+    }
+
+    #[test]
+    fn asm_operand_modes() {
+        assert_eq!(asm("MOVL #2, R1"), vec![0xD0, 0x02, 0x51]);
+        assert_eq!(asm("MOVL #100, R1"), vec![0xD0, 0x8F, 100, 0, 0, 0, 0x51]);
+        assert_eq!(asm("MOVL @(R3)+, R0"), vec![0xD0, 0x93, 0x50]);
+        assert_eq!(asm("MOVL B^4(R5)[R2], R0"), vec![0xD0, 0x42, 0xA5, 0x04, 0x50]);
+        assert_eq!(asm("MOVL 4(R5), -(SP)"), vec![0xD0, 0xA5, 0x04, 0x7E]);
+        assert_eq!(asm("MOVL -300(R5), (R6)"), vec![0xD0, 0xC5, 0xD4, 0xFE, 0x66]);
+        assert_eq!(asm("MOVL @(R5), @#^X2000"), vec![0xD0, 0xB5, 0x00, 0x9F, 0x00, 0x20, 0x00, 0x00]);
+        assert_eq!(asm("PUSHL #^M<R2,R3,IV>"), vec![0xDD, 0x8F, 0x0C, 0x40, 0x00, 0x00]);
+        assert_eq!(asm("CLRO R0"), vec![0xFD, 0x7C, 0x50]);
+    }
+
+    #[test]
+    fn asm_labels_and_branches() {
+        let source = "
+        START:  CLRL R0          ; 1000
+        LOOP:   INCL R0          ; 1002
+                CMPL R0, #10     ; 1004
+                BNEQ LOOP        ; 1007
+                BRW DONE         ; 1009
+                .BLKB 16         ; 100C
+        DONE:   HALT             ; 101C
+        ";
+        let a = assemble(source, 0x1000).unwrap();
+        assert_eq!(a.symbols["DONE"], 0x101C);
+        assert_eq!(&a.bytes[..12], &[
+            0xD4, 0x50,
+            0xD6, 0x50,
+            0xD1, 0x50, 0x0A,
+            0x12, 0xF9,
+            0x31, 0x10, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn asm_relative_sizing() {
+        // Near targets get a byte displacement, far ones grow to a word.
+        let a = assemble("MOVAB NEAR, R0\nNEAR: MOVAB FAR, R0\n.BLKB 200\nFAR: .BYTE 0", 0).unwrap();
+        assert_eq!(&a.bytes[..4], &[0x9E, 0xAF, 0x01, 0x50]);
+        assert_eq!(&a.bytes[4..9], &[0x9E, 0xCF, 0xC9, 0x00, 0x50]);
+
+        // But a forced size is kept, even when it's bigger than needed.
+        assert_eq!(asm("JMP L^X\nX: HALT"), vec![0x17, 0xEF, 0, 0, 0, 0, 0x00]);
+    }
+
+    #[test]
+    fn asm_directives() {
+        let a = assemble(".ENTRY MAIN, ^M<R2,R3>\n.BYTE 1, -1\n.WORD MAIN\n.LONG ^X12345678\n.ASCIZ /hi;/", 0x200)
+            .unwrap();
+        assert_eq!(a.symbols["MAIN"], 0x200);
+        assert_eq!(a.bytes, vec![
+            0x0C, 0x00,
+            0x01, 0xFF,
+            0x00, 0x02,
+            0x78, 0x56, 0x34, 0x12,
+            b'h', b'i', b';', 0,
+        ]);
+    }
+
+    #[test]
+    fn asm_case_table() {
+        let source = "
+                CASEB R0, #0, #1
+        TABLE:  .WORD A-TABLE, B-TABLE
+        A:      NOP
+        B:      HALT
+        ";
+        assert_eq!(asm(source), vec![0x8F, 0x50, 0x00, 0x01, 0x04, 0x00, 0x05, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn asm_errors() {
+        assert_eq!(asm_err("MOVX R0, R1"), AsmErrorKind::UnknownMnemonic("MOVX".to_string()));
+        assert_eq!(asm_err("MOVL R0"), AsmErrorKind::OperandCount(2));
+        assert_eq!(asm_err("BRB NOWHERE"), AsmErrorKind::UndefinedSymbol("NOWHERE".to_string()));
+        assert_eq!(asm_err("A: NOP\nA: NOP"), AsmErrorKind::DuplicateLabel("A".to_string()));
+        assert_eq!(asm_err("MOVL R0, #1"), AsmErrorKind::InvalidMode("Literal(1)".to_string()));
+        assert_eq!(asm_err("BRB FAR\n.BLKB 200\nFAR: HALT"), AsmErrorKind::OutOfRange(200));
+        assert_eq!(assemble("NOP\n.FOO", 0).unwrap_err().line, 2);
+    }
+
+    #[test]
+    fn asm_disasm_round_trip() {
+        let source = "
+        START:  MOVL S^#12, R1
+                MOVL @(R3)+, R0
+                MOVL B^4(R5)[R2], R0
+                MOVL W^-300(SP), -(SP)
+                MOVL @#^X2000, (FP)+
+                MOVL #^X12345678, R0
+                ADDB2 I^#2, R1
+                MOVAB B^START, R0
+                JMP @L^START
+                SOBGTR R0, START
+                BUGW 2
+                RET
+        ";
+        let a = assemble(source, 0x1000).unwrap();
+        let lines = Disassembler::new(DisasmOptions::default())
+            .with_labels(&a.labels)
+            .disassemble(&a.bytes, 0x1000);
+
+        let expected: Vec<String> = source.lines()
+            .map(|l| l.trim().trim_start_matches("START:").trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert_eq!(lines[0], "START:");
+        assert_eq!(&lines[1..], &expected[..]);
+
+        // And the printed text assembles right back into the same bytes.
+        assert_eq!(assemble(&lines.join("\n"), 0x1000).unwrap().bytes, a.bytes);
+    }
+}
@@ -0,0 +1,232 @@
+//! Differential testing of the decoder against instructions captured from a reference
+//! implementation, such as real hardware or another emulator.
+//!
+//! A capture file holds one instruction per line:
+//!
+//! ```text
+//! [address:] <hex bytes> | <length> | <mnemonic> [operands]
+//! ```
+//!
+//! For example `00001000: D0 02 51 | 3 | MOVL S^#2, R1`. The address defaults to 0 and only
+//! matters for PC-relative operands. A length of `-` means the reference rejected the bytes
+//! as a reserved instruction, and the mnemonic is left out. Operands are written in the
+//! disassembler's syntax and compared ignoring case and whitespace; leave them out to only
+//! check the length and mnemonic. Blank lines and lines starting with `#` are skipped.
+
+use std::fmt;
+
+use crate::ervax::cpu::instrs::{
+    decode_at,
+    DisasmError,
+    DisasmOptions,
+    Disassembler,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capture {
+    /// Line of the capture file this came from, 1-based.
+    pub line: usize,
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    /// Instruction length according to the reference, None if it was rejected.
+    pub length: Option<u32>,
+    pub mnemonic: Option<String>,
+    pub operands: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureErrorKind {
+    MissingField,
+    BadAddress,
+    BadBytes,
+    BadLength,
+    /// The length is longer than the captured bytes.
+    LengthOutOfRange,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureError {
+    pub line: usize,
+    pub kind: CaptureErrorKind,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {:?}", self.line, self.kind)
+    }
+}
+
+/// How the decoder disagreed with a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The reference decoded the bytes, we didn't.
+    Rejected(DisasmError),
+    /// We decoded bytes the reference rejected.
+    Accepted(String),
+    Length { expected: u32, actual: u32 },
+    Mnemonic { expected: String, actual: String },
+    Operands { expected: String, actual: String },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Rejected(e) => write!(f, "reference decoded it, we failed with {:?}", e),
+            Mismatch::Accepted(s) => write!(f, "reference rejected it, we decoded `{}`", s),
+            Mismatch::Length { expected, actual } => write!(f, "length {}, expected {}", actual, expected),
+            Mismatch::Mnemonic { expected, actual } => write!(f, "mnemonic {}, expected {}", actual, expected),
+            Mismatch::Operands { expected, actual } => write!(f, "operands `{}`, expected `{}`", actual, expected),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 16).ok()
+}
+
+fn parse_capture(line: usize, text: &str) -> Result<Capture, CaptureError> {
+    let err = |kind| CaptureError { line, kind };
+
+    let mut fields = text.splitn(3, '|');
+    let (bytes_field, length_field) = match (fields.next(), fields.next()) {
+        (Some(b), Some(l)) => (b, l.trim()),
+        _ => return Err(err(CaptureErrorKind::MissingField)),
+    };
+    let instr_field = fields.next().map(str::trim).unwrap_or("");
+
+    let (address, bytes_field) = match bytes_field.find(':') {
+        Some(i) => (parse_hex(&bytes_field[..i]).ok_or_else(|| err(CaptureErrorKind::BadAddress))?, &bytes_field[i + 1..]),
+        None => (0, bytes_field),
+    };
+
+    let bytes = bytes_field.split_whitespace()
+        .map(|b| if b.len() == 2 { u8::from_str_radix(b, 16).ok() } else { None })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| err(CaptureErrorKind::BadBytes))?;
+    if bytes.is_empty() {
+        return Err(err(CaptureErrorKind::BadBytes));
+    }
+
+    let length = match length_field {
+        "-" => None,
+        l => Some(l.parse::<u32>().map_err(|_| err(CaptureErrorKind::BadLength))?),
+    };
+    if length.is_some_and(|l| l == 0 || l as usize > bytes.len()) {
+        return Err(err(CaptureErrorKind::LengthOutOfRange));
+    }
+
+    let (mnemonic, operands) = match instr_field.split_once(char::is_whitespace) {
+        Some((m, o)) => (Some(m.to_string()), Some(o.trim().to_string())),
+        None if instr_field.is_empty() => (None, None),
+        None => (Some(instr_field.to_string()), None),
+    };
+    if length.is_some() && mnemonic.is_none() {
+        return Err(err(CaptureErrorKind::MissingField));
+    }
+
+    Ok(Capture { line, address, bytes, length, mnemonic, operands })
+}
+
+/// Parses a capture file, see the module docs for the format.
+pub fn parse_captures(text: &str) -> Result<Vec<Capture>, CaptureError> {
+    text.lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .map(|(i, l)| parse_capture(i, l))
+        .collect()
+}
+
+fn normalize(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
+}
+
+impl Capture {
+    /// Decodes the captured bytes and compares the result with the reference.
+    pub fn check(&self) -> Result<(), Mismatch> {
+        let dis = Disassembler::new(DisasmOptions { show_address: false, show_bytes: false });
+
+        let decoded = match (decode_at(&self.bytes, self.address), self.length) {
+            (Ok(d), Some(_)) => d,
+            (Ok(d), None) => return Err(Mismatch::Accepted(dis.format_instr(&d))),
+            (Err(e), Some(_)) => return Err(Mismatch::Rejected(e)),
+            (Err(_), None) => return Ok(()),
+        };
+
+        let expected = self.length.unwrap();
+        if decoded.length() != expected {
+            return Err(Mismatch::Length { expected, actual: decoded.length() });
+        }
+
+        let actual = format!("{:?}", decoded.instr);
+        let mnemonic = self.mnemonic.as_deref().unwrap_or("");
+        if !actual.eq_ignore_ascii_case(mnemonic) {
+            return Err(Mismatch::Mnemonic { expected: mnemonic.to_string(), actual });
+        }
+
+        if let Some(expected) = &self.operands {
+            let text = dis.format_instr(&decoded);
+            let actual = text[actual.len()..].trim();
+            if normalize(actual) != normalize(expected) {
+                return Err(Mismatch::Operands { expected: expected.clone(), actual: actual.to_string() });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks every capture, returning the ones that disagree with the decoder.
+pub fn check_captures(captures: &[Capture]) -> Vec<(&Capture, Mismatch)> {
+    captures.iter()
+        .filter_map(|c| c.check().err().map(|m| (c, m)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPTURES: &str = "\
+# Hand-written, in the format a capture script would produce.
+D0 02 51 | 3 | MOVL S^#2, R1
+00001000: 11 FE | 2 | BRB ^X1000
+9A 8F FF 53 FF | 4 | movzbl #255,r3
+FF FF | - |
+";
+
+    #[test]
+    fn difftest_parse() {
+        let c = parse_captures(CAPTURES).unwrap();
+        assert_eq!(c.len(), 4);
+        assert_eq!(c[0].line, 2);
+        assert_eq!(c[1].address, 0x1000);
+        assert_eq!(c[2].bytes, vec![0x9A, 0x8F, 0xFF, 0x53, 0xFF]);
+        assert_eq!(c[2].mnemonic.as_deref(), Some("movzbl"));
+        assert_eq!(c[3].length, None);
+
+        let kind = |s: &str| parse_captures(s).unwrap_err().kind;
+        assert_eq!(kind("D0 02 51"), CaptureErrorKind::MissingField);
+        assert_eq!(kind("D0 02 5 | 3 | MOVL"), CaptureErrorKind::BadBytes);
+        assert_eq!(kind("D0 | x | MOVL"), CaptureErrorKind::BadLength);
+        assert_eq!(kind("D0 | 2 | MOVL"), CaptureErrorKind::LengthOutOfRange);
+        assert_eq!(kind("D0 02 51 | 3 |"), CaptureErrorKind::MissingField);
+        assert_eq!(kind("G:D0 | 1 | MOVL"), CaptureErrorKind::BadAddress);
+    }
+
+    #[test]
+    fn difftest_check() {
+        let c = parse_captures(CAPTURES).unwrap();
+        assert!(check_captures(&c).is_empty());
+
+        let mismatch = |s: &str| parse_captures(s).unwrap()[0].check().unwrap_err();
+        assert_eq!(mismatch("D0 02 51 | 2 | MOVL"), Mismatch::Length { expected: 2, actual: 3 });
+        assert_eq!(mismatch("D0 02 51 | 3 | MOVW"), Mismatch::Mnemonic { expected: "MOVW".into(), actual: "MOVL".into() });
+        assert_eq!(
+            mismatch("D0 02 51 | 3 | MOVL S^#2, R2"),
+            Mismatch::Operands { expected: "S^#2, R2".into(), actual: "S^#2, R1".into() },
+        );
+        assert_eq!(mismatch("D0 02 51 | - |"), Mismatch::Accepted("MOVL S^#2, R1".into()));
+        assert_eq!(mismatch("FF FF | 2 | BUGW"), Mismatch::Rejected(DisasmError::InvalidOpcode));
+    }
+}
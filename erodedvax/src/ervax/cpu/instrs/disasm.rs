@@ -0,0 +1,661 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ervax::cpu::{
+    instrs::{
+        decode_instr,
+        get_u16_from_stream,
+        FieldMode,
+        InstructionType,
+        OperandMode,
+        OperandParseError,
+        OperandWidth,
+    },
+    RegID,
+};
+
+/// Names for addresses, used in place of raw addresses when printing branch targets
+/// and PC-relative operands.
+pub type Labels = BTreeMap<u32, String>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisasmError {
+    /// The opcode did not decode to a known instruction.
+    InvalidOpcode,
+    /// One of the operand specifiers failed to decode.
+    Operand(OperandParseError),
+}
+
+impl From<OperandParseError> for DisasmError {
+    fn from(e: OperandParseError) -> Self {
+        DisasmError::Operand(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedOperand {
+    pub mode: OperandMode,
+    pub field: FieldMode,
+    pub width: OperandWidth,
+    /// Address of the byte following this operand.
+    /// PC-relative specifiers and branch displacements are relative to this.
+    pub next: u32,
+}
+
+/// A single decoded instruction, along with where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstr {
+    pub address: u32,
+    pub instr: InstructionType,
+    pub operands: Vec<DecodedOperand>,
+    /// The displacement table following a CASE instruction, relative to the start of the table.
+    /// Empty for every other instruction, and for CASE instructions with a non-constant limit.
+    pub case_table: Vec<i16>,
+    /// Raw bytes of the instruction, including the CASE table if there is one.
+    pub bytes: Vec<u8>,
+}
+
+impl OperandMode {
+    /// Returns the value of a literal or immediate operand.
+    pub fn constant_value(&self) -> Option<u128> {
+        use OperandMode::*;
+        match *self {
+            Literal(v) => Some(v as u128),
+            Immediate8(v) => Some(v as u128),
+            Immediate16(v) => Some(v as u128),
+            Immediate32(v) => Some(v as u128),
+            Immediate64(v) => Some(v as u128),
+            Immediate128(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the address this operand refers to, if it can be known without running anything.
+    /// That is absolute mode and the non-deferred PC-relative modes.
+    /// `next` is the address of the byte after the operand.
+    pub fn static_address(&self, next: u32) -> Option<u32> {
+        use OperandMode::*;
+        match *self {
+            Absolute(a) => Some(a),
+            ByteDisplacement(RegID::PC, d) => Some(next.wrapping_add(d as u32)),
+            WordDisplacement(RegID::PC, d) => Some(next.wrapping_add(d as u32)),
+            LongwordDisplacement(RegID::PC, d) => Some(next.wrapping_add(d as u32)),
+            _ => None,
+        }
+    }
+}
+
+impl InstructionType {
+    /// True if the instruction's data fields are branch displacements.
+    /// The only instructions with data fields that aren't are the BUG checks.
+    #[inline]
+    pub fn has_branch_displacement(self) -> bool {
+        use InstructionType::*;
+        match self {
+            BUGW | BUGL => false,
+            _ => self.field_modes().contains(&FieldMode::Data),
+        }
+    }
+
+    #[inline]
+    pub fn is_case(self) -> bool {
+        use InstructionType::*;
+        matches!(self, CASEB | CASEW | CASEL)
+    }
+
+    /// False for instructions that never continue on to the next instruction in memory.
+    pub fn falls_through(self) -> bool {
+        use InstructionType::*;
+        !matches!(self, BRB | BRW | JMP | RSB | RET | REI | HALT | BUGW | BUGL)
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which is assumed to be located at `address`.
+pub fn decode_at(bytes: &[u8], address: u32) -> Result<DecodedInstr, DisasmError> {
+    match bytes {
+        [] | [0xFD..=0xFF] => return Err(DisasmError::Operand(OperandParseError::OutOfBytes)),
+        _ => {}
+    }
+
+    let consumed = Cell::new(0u32);
+    let iter = &mut bytes.iter().copied().inspect(|_| consumed.set(consumed.get() + 1));
+
+    let (instr, operiter) = decode_instr(iter).ok_or(DisasmError::InvalidOpcode)?;
+    let modes = instr.field_modes();
+    let widths = instr.field_widths();
+
+    let mut operands = vec![];
+    for (i, op) in operiter.enumerate() {
+        operands.push(DecodedOperand {
+            mode: op?,
+            field: modes[i],
+            width: widths[i],
+            next: address.wrapping_add(consumed.get()),
+        });
+    }
+
+    let mut case_table = vec![];
+    if instr.is_case() {
+        // The table holds limit + 1 entries. Without a constant limit there's no telling how long it is.
+        if let Some(limit) = operands.get(2).and_then(|o| o.mode.constant_value()) {
+            for _ in 0..=limit {
+                match get_u16_from_stream(iter) {
+                    Some(v) => case_table.push(v as i16),
+                    None => return Err(DisasmError::Operand(OperandParseError::OutOfBytes)),
+                }
+            }
+        }
+    }
+
+    Ok(DecodedInstr {
+        address,
+        instr,
+        operands,
+        case_table,
+        bytes: bytes[..consumed.get() as usize].to_vec(),
+    })
+}
+
+impl DecodedInstr {
+    #[inline]
+    pub fn length(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    /// Address of the next instruction in memory.
+    #[inline]
+    pub fn end(&self) -> u32 {
+        self.address.wrapping_add(self.length())
+    }
+
+    /// Address of the first CASE table entry, which is where the operands end.
+    #[inline]
+    pub fn case_table_base(&self) -> u32 {
+        self.end().wrapping_sub(self.case_table.len() as u32 * 2)
+    }
+
+    /// Computes the branch target for a data field, if it is a branch displacement.
+    pub fn displacement_target(&self, op: &DecodedOperand) -> Option<u32> {
+        if !self.instr.has_branch_displacement() {
+            return None;
+        }
+        match op.mode {
+            OperandMode::DataByte(d) => Some(op.next.wrapping_add(d as i8 as u32)),
+            OperandMode::DataWord(d) => Some(op.next.wrapping_add(d as i16 as u32)),
+            OperandMode::DataLong(d) => Some(op.next.wrapping_add(d)),
+            _ => None,
+        }
+    }
+
+    /// Every address this instruction may transfer control to, other than by falling through
+    /// or by calling a procedure. Targets that depend on register or memory contents are left out.
+    pub fn branch_targets(&self) -> Vec<u32> {
+        use InstructionType::*;
+        let mut targets: Vec<u32> = self.operands.iter()
+            .filter_map(|op| self.displacement_target(op))
+            .collect();
+
+        if let JMP | JSB = self.instr {
+            if let Some(t) = self.operands[0].mode.static_address(self.operands[0].next) {
+                targets.push(t);
+            }
+        }
+
+        let base = self.case_table_base();
+        targets.extend(self.case_table.iter().map(|d| base.wrapping_add(*d as u32)));
+        targets
+    }
+
+    /// The procedure entry mask address for CALLS/CALLG, when it is statically known.
+    pub fn call_target(&self) -> Option<u32> {
+        use InstructionType::*;
+        match self.instr {
+            CALLS | CALLG => self.operands[1].mode.static_address(self.operands[1].next),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisasmOptions {
+    /// Prefix each line with the address it was decoded from.
+    pub show_address: bool,
+    /// Prefix each line with the raw bytes it was decoded from.
+    pub show_bytes: bool,
+}
+
+/// Width of the raw byte column, enough for 8 bytes.
+const BYTES_COLUMN: usize = 8 * 3;
+
+/// Formats instructions in DEC MACRO-32 syntax.
+pub struct Disassembler<'a> {
+    options: DisasmOptions,
+    labels: Option<&'a Labels>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(options: DisasmOptions) -> Self {
+        Disassembler {
+            options,
+            labels: None,
+        }
+    }
+
+    /// Use `labels` to name addresses instead of printing them as numbers.
+    pub fn with_labels(mut self, labels: &'a Labels) -> Self {
+        self.labels = Some(labels);
+        self
+    }
+
+    pub fn format_address(&self, addr: u32) -> String {
+        match self.labels.and_then(|l| l.get(&addr)) {
+            Some(name) => name.clone(),
+            None => format!("^X{:X}", addr),
+        }
+    }
+
+    fn format_immediate(v: u128) -> String {
+        // Values that fit a short literal need the I^ to avoid being assembled as one.
+        match v {
+            0..=63 => format!("I^#{}", v),
+            64..=0xFFFF => format!("#{}", v),
+            _ => format!("#^X{:X}", v),
+        }
+    }
+
+    pub fn format_operand(&self, d: &DecodedInstr, op: &DecodedOperand) -> String {
+        self.format_mode(&op.mode, op.next, d.displacement_target(op))
+    }
+
+    fn format_mode(&self, mode: &OperandMode, next: u32, target: Option<u32>) -> String {
+        use OperandMode::*;
+
+        let displaced = |prefix: &str, deferred: bool, r: RegID, d: i32| {
+            let at = if deferred { "@" } else { "" };
+            if r == RegID::PC {
+                format!("{}{}{}", at, prefix, self.format_address(next.wrapping_add(d as u32)))
+            } else {
+                format!("{}{}{}({})", at, prefix, d, r.name())
+            }
+        };
+
+        match mode {
+            Literal(v) => format!("S^#{}", v),
+            Register(r) => r.name().to_string(),
+            RegisterDeferred(r) => format!("({})", r.name()),
+            AutoDecrement(r) => format!("-({})", r.name()),
+            AutoIncrement(r) => format!("({})+", r.name()),
+            AutoIncrementDeferred(r) => format!("@({})+", r.name()),
+            ByteDisplacement(r, d) => displaced("B^", false, *r, *d as i32),
+            ByteDisplacementDeferred(r, d) => displaced("B^", true, *r, *d as i32),
+            WordDisplacement(r, d) => displaced("W^", false, *r, *d as i32),
+            WordDisplacementDeferred(r, d) => displaced("W^", true, *r, *d as i32),
+            LongwordDisplacement(r, d) => displaced("L^", false, *r, *d),
+            LongwordDisplacementDeferred(r, d) => displaced("L^", true, *r, *d),
+            Absolute(a) => format!("@#{}", self.format_address(*a)),
+            Indexed(x, base) => format!("{}[{}]", self.format_mode(base, next, None), x.name()),
+            Immediate8(_) | Immediate16(_) | Immediate32(_) | Immediate64(_) | Immediate128(_) =>
+                Disassembler::format_immediate(mode.constant_value().unwrap()),
+            DataByte(v) => target.map_or_else(|| v.to_string(), |t| self.format_address(t)),
+            DataWord(v) => target.map_or_else(|| v.to_string(), |t| self.format_address(t)),
+            DataLong(v) => target.map_or_else(|| v.to_string(), |t| self.format_address(t)),
+        }
+    }
+
+    /// Formats the instruction itself, without address, bytes, or CASE table.
+    pub fn format_instr(&self, d: &DecodedInstr) -> String {
+        let operands: Vec<String> = d.operands.iter()
+            .map(|op| self.format_operand(d, op))
+            .collect();
+
+        if operands.is_empty() {
+            format!("{:?}", d.instr)
+        } else {
+            format!("{:?} {}", d.instr, operands.join(", "))
+        }
+    }
+
+    fn prefix(&self, address: u32, bytes: &[u8]) -> String {
+        let mut s = String::new();
+        if self.options.show_address {
+            s += &format!("{:08X}:  ", address);
+        }
+        if self.options.show_bytes {
+            let b: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            s += &format!("{:<width$} ", b.join(" "), width = BYTES_COLUMN);
+        }
+        s
+    }
+
+    /// Formats a decoded instruction as one or more lines.
+    /// CASE tables are printed as a `.WORD` line per entry after the instruction.
+    pub fn format_lines(&self, d: &DecodedInstr) -> Vec<String> {
+        let table_start = d.bytes.len() - d.case_table.len() * 2;
+        let mut lines = vec![
+            format!("{}{}", self.prefix(d.address, &d.bytes[..table_start]), self.format_instr(d))
+        ];
+
+        let base = d.case_table_base();
+        for (i, disp) in d.case_table.iter().enumerate() {
+            let at = base.wrapping_add(i as u32 * 2);
+            let bytes = &d.bytes[table_start + i * 2..table_start + i * 2 + 2];
+            lines.push(format!(
+                "{}.WORD {} ; {}",
+                self.prefix(at, bytes),
+                disp,
+                self.format_address(base.wrapping_add(*disp as u32)),
+            ));
+        }
+        lines
+    }
+
+    /// Formats bytes that aren't code as data.
+    pub fn format_data(&self, address: u32, bytes: &[u8]) -> String {
+        let values: Vec<String> = bytes.iter().map(|b| format!("^X{:02X}", b)).collect();
+        format!("{}.BYTE {}", self.prefix(address, bytes), values.join(", "))
+    }
+
+    /// Formats a procedure entry mask.
+    pub fn format_entry_mask(&self, address: u32, mask: u16) -> String {
+        format!("{}.WORD {}", self.prefix(address, &mask.to_le_bytes()), format_mask(mask))
+    }
+
+    /// Disassembles `bytes`, which start at `base`, printing the code found in `map`.
+    /// Labelled addresses get a label line, and everything that isn't code is printed as `.BYTE`.
+    pub fn listing(&self, bytes: &[u8], base: u32, map: &CodeMap) -> Vec<String> {
+        let mut lines = vec![];
+        let mut offset = 0;
+        let labelled = |address: u32| self.labels.is_some_and(|l| l.contains_key(&address));
+
+        while offset < bytes.len() {
+            let address = base.wrapping_add(offset as u32);
+            if let Some(name) = self.labels.and_then(|l| l.get(&address)) {
+                lines.push(format!("{}:", name));
+            }
+
+            if map.entry_masks.contains(&address) && offset + 2 <= bytes.len() {
+                let mask = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+                lines.push(self.format_entry_mask(address, mask));
+                offset += 2;
+                continue;
+            }
+
+            if map.instructions.contains(&address) {
+                if let Ok(d) = decode_at(&bytes[offset..], address) {
+                    lines.extend(self.format_lines(&d));
+                    offset += d.bytes.len();
+                    continue;
+                }
+            }
+
+            // Group data up to the next thing of interest, 8 bytes per line at most.
+            let mut end = offset + 1;
+            while end < bytes.len() && end - offset < 8 {
+                let a = base.wrapping_add(end as u32);
+                if labelled(a) || map.instructions.contains(&a) || map.entry_masks.contains(&a) {
+                    break;
+                }
+                end += 1;
+            }
+            lines.push(self.format_data(address, &bytes[offset..end]));
+            offset = end;
+        }
+        lines
+    }
+
+    /// Linear sweep disassembly of `bytes`, which start at `base`.
+    /// Labelled addresses get a label line, and bytes that fail to decode are printed as `.BYTE`.
+    pub fn disassemble(&self, bytes: &[u8], base: u32) -> Vec<String> {
+        self.listing(bytes, base, &CodeMap::linear(bytes, base))
+    }
+}
+
+/// Formats a procedure entry mask as `^M<...>`, or as a plain number if it has reserved bits set.
+pub fn format_mask(mask: u16) -> String {
+    if mask & 0x3000 != 0 {
+        return format!("^X{:04X}", mask);
+    }
+
+    let mut names: Vec<&str> = (0..12)
+        .filter(|r| mask & (1 << r) != 0)
+        .map(|r| RegID(r).name())
+        .collect();
+    if mask & 0x4000 != 0 {
+        names.push("IV");
+    }
+    if mask & 0x8000 != 0 {
+        names.push("DV");
+    }
+    format!("^M<{}>", names.join(","))
+}
+
+/// Which parts of an image are code, as found by one of the sweeps below.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CodeMap {
+    /// Addresses instructions were decoded at.
+    pub instructions: BTreeSet<u32>,
+    /// Procedure entry masks, from CALLS/CALLG targets or given as procedure entry points.
+    pub entry_masks: BTreeSet<u32>,
+    /// Entry points and branch, jump and call targets that land inside the image.
+    pub targets: BTreeSet<u32>,
+}
+
+impl CodeMap {
+    /// Decodes everything from start to end, skipping a byte whenever decoding fails.
+    pub fn linear(bytes: &[u8], base: u32) -> CodeMap {
+        let mut map = CodeMap::default();
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let address = base.wrapping_add(offset as u32);
+            match decode_at(&bytes[offset..], address) {
+                Ok(d) => {
+                    map.instructions.insert(address);
+                    map.targets.extend(d.branch_targets().into_iter().chain(d.call_target())
+                        .filter(|t| in_image(*t, bytes, base)));
+                    offset += d.bytes.len();
+                }
+                Err(_) => offset += 1,
+            }
+        }
+        map
+    }
+
+    /// Follows control flow from `entries` (addresses of instructions) and `procedures` (addresses of
+    /// entry masks), so only reachable code is decoded. Indirect jumps and returns end a path.
+    pub fn recursive(bytes: &[u8], base: u32, entries: &[u32], procedures: &[u32]) -> CodeMap {
+        let mut map = CodeMap::default();
+        let mut work: Vec<u32> = vec![];
+
+        let add_procedure = |map: &mut CodeMap, work: &mut Vec<u32>, p: u32| {
+            if in_image(p, bytes, base) && map.entry_masks.insert(p) {
+                map.targets.insert(p);
+                work.push(p.wrapping_add(2));
+            }
+        };
+
+        for p in procedures {
+            add_procedure(&mut map, &mut work, *p);
+        }
+        for e in entries.iter().filter(|e| in_image(**e, bytes, base)) {
+            map.targets.insert(*e);
+            work.push(*e);
+        }
+
+        while let Some(address) = work.pop() {
+            if !in_image(address, bytes, base) || map.instructions.contains(&address) {
+                continue;
+            }
+            let d = match decode_at(&bytes[address.wrapping_sub(base) as usize..], address) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+            map.instructions.insert(address);
+
+            for t in d.branch_targets().into_iter().filter(|t| in_image(*t, bytes, base)) {
+                map.targets.insert(t);
+                work.push(t);
+            }
+            if let Some(p) = d.call_target() {
+                add_procedure(&mut map, &mut work, p);
+            }
+            if d.instr.falls_through() {
+                work.push(d.end());
+            }
+        }
+        map
+    }
+
+    /// Generated names for every target, `P_` and the address for procedures, `L_` and the address otherwise.
+    pub fn labels(&self) -> Labels {
+        self.targets.iter()
+            .map(|t| {
+                let prefix = if self.entry_masks.contains(t) { "P" } else { "L" };
+                (*t, format!("{}_{:08X}", prefix, t))
+            })
+            .collect()
+    }
+}
+
+#[inline]
+fn in_image(address: u32, bytes: &[u8], base: u32) -> bool {
+    (address.wrapping_sub(base) as usize) < bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(bytes: &[u8]) -> String {
+        let d = decode_at(bytes, 0x1000).unwrap();
+        assert_eq!(d.length() as usize, bytes.len());
+        Disassembler::new(DisasmOptions::default()).format_instr(&d)
+    }
+
+    #[test]
+    fn disasm_decode_tests() {
+        // Same byte sequences as the decoder tests in instrparse.rs
+        assert_eq!(dis(&[0x80, 0x8F, 0x02, 0x51]), "ADDB2 I^#2, R1");
+        assert_eq!(dis(&[0xFF, 0xFE, 0x02, 0x00]), "BUGW 2");
+        assert_eq!(dis(&[0x04]), "RET");
+        assert_eq!(decode_at(&[0xFF, 0xFF], 0), Err(DisasmError::InvalidOpcode));
+    }
+
+    #[test]
+    fn disasm_operand_modes() {
+        assert_eq!(dis(&[0xD0, 0x0C, 0x51]), "MOVL S^#12, R1");
+        assert_eq!(dis(&[0xD0, 0x93, 0x50]), "MOVL @(R3)+, R0");
+        assert_eq!(dis(&[0xD0, 0x42, 0xA5, 0x04, 0x50]), "MOVL B^4(R5)[R2], R0");
+        assert_eq!(dis(&[0xD0, 0xCE, 0xFC, 0xFF, 0x7E]), "MOVL W^-4(SP), -(SP)");
+        assert_eq!(dis(&[0xD0, 0x9F, 0x00, 0x20, 0x00, 0x00, 0x8D]), "MOVL @#^X2000, (FP)+");
+        assert_eq!(dis(&[0xD0, 0x8F, 0x78, 0x56, 0x34, 0x12, 0x50]), "MOVL #^X12345678, R0");
+    }
+
+    #[test]
+    fn disasm_pc_relative() {
+        // MOVAB B^^X1010, R0 at 0x1000. The displacement is relative to the end of the operand.
+        assert_eq!(dis(&[0x9E, 0xAF, 0x0D, 0x50]), "MOVAB B^^X1010, R0");
+        assert_eq!(dis(&[0x17, 0xEF, 0x00, 0x01, 0x00, 0x00]), "JMP L^^X1106");
+        assert_eq!(dis(&[0x17, 0xFF, 0x00, 0x01, 0x00, 0x00]), "JMP @L^^X1106");
+
+        let d = decode_at(&[0x17, 0xEF, 0x00, 0x01, 0x00, 0x00], 0x1000).unwrap();
+        assert_eq!(d.branch_targets(), vec![0x1106]);
+    }
+
+    #[test]
+    fn disasm_branch_targets() {
+        let d = decode_at(&[0x11, 0xFE], 0x1000).unwrap();
+        assert_eq!(d.branch_targets(), vec![0x1000]);
+        assert!(!d.instr.falls_through());
+        assert_eq!(Disassembler::new(DisasmOptions::default()).format_instr(&d), "BRB ^X1000");
+
+        let mut labels = Labels::new();
+        labels.insert(0x1000, "LOOP".to_string());
+        let dis = Disassembler::new(DisasmOptions::default()).with_labels(&labels);
+        assert_eq!(dis.format_instr(&d), "BRB LOOP");
+
+        // SOBGTR R0, back 3 bytes to itself
+        let d = decode_at(&[0xF5, 0x50, 0xFD], 0x1000).unwrap();
+        assert_eq!(dis.format_instr(&d), "SOBGTR R0, LOOP");
+        assert!(d.instr.falls_through());
+    }
+
+    #[test]
+    fn disasm_case_table() {
+        // CASEB R0, S^#0, S^#1 with two entries.
+        let bytes = [0x8F, 0x50, 0x00, 0x01, 0x04, 0x00, 0x06, 0x00];
+        let d = decode_at(&bytes, 0x1000).unwrap();
+        assert_eq!(d.case_table, vec![4, 6]);
+        assert_eq!(d.length(), 8);
+        assert_eq!(d.branch_targets(), vec![0x1008, 0x100A]);
+
+        let lines = Disassembler::new(DisasmOptions::default()).format_lines(&d);
+        assert_eq!(lines, vec![
+            "CASEB R0, S^#0, S^#1",
+            ".WORD 4 ; ^X1008",
+            ".WORD 6 ; ^X100A",
+        ]);
+
+        // The table runs past the top of the address space.
+        let d = decode_at(&bytes, 0xFFFF_FFFC).unwrap();
+        let lines = Disassembler::new(DisasmOptions::default()).format_lines(&d);
+        assert_eq!(lines[1..], [".WORD 4 ; ^X4", ".WORD 6 ; ^X6"]);
+    }
+
+    #[test]
+    fn disasm_listing() {
+        let mut labels = Labels::new();
+        labels.insert(0x200, "START".to_string());
+        let options = DisasmOptions { show_address: true, show_bytes: true };
+        let lines = Disassembler::new(options)
+            .with_labels(&labels)
+            .disassemble(&[0xD0, 0x02, 0x51, 0xFF, 0x01], 0x200);
+
+        assert_eq!(lines, vec![
+            "START:".to_string(),
+            format!("00000200:  {:<24} MOVL S^#2, R1", "D0 02 51"),
+            format!("00000203:  {:<24} .BYTE ^XFF", "FF"),
+            format!("00000204:  {:<24} NOP", "01"),
+        ]);
+    }
+
+    #[test]
+    fn disasm_recursive_descent() {
+        use crate::ervax::cpu::instrs::assemble;
+
+        let source = "
+        START:  BRB SKIP
+                .BYTE ^XFF, ^XFF
+        SKIP:   CALLS #0, PROC
+                JMP @(R0)
+        PROC:   .WORD ^M<R2,R3>
+                RET
+        ";
+        let a = assemble(source, 0x1000).unwrap();
+        let map = CodeMap::recursive(&a.bytes, 0x1000, &[0x1000], &[]);
+        assert_eq!(map.entry_masks.iter().copied().collect::<Vec<_>>(), vec![a.symbols["PROC"]]);
+        assert_eq!(map.instructions.len(), 4);
+
+        let labels = map.labels();
+        let lines = Disassembler::new(DisasmOptions::default())
+            .with_labels(&labels)
+            .listing(&a.bytes, 0x1000, &map);
+        assert_eq!(lines, vec![
+            "L_00001000:",
+            "BRB L_00001004",
+            ".BYTE ^XFF, ^XFF",
+            "L_00001004:",
+            "CALLS S^#0, B^P_0000100B",
+            "JMP @B^0(R0)",
+            "P_0000100B:",
+            ".WORD ^M<R2,R3>",
+            "RET",
+        ]);
+
+        // A linear sweep finds the same targets, but decodes the entry mask as if it were code.
+        let linear = CodeMap::linear(&a.bytes, 0x1000);
+        assert!(linear.entry_masks.is_empty());
+        assert!(linear.instructions.contains(&0x100C));
+        assert!(linear.targets.contains(&0x100B));
+    }
+}
@@ -68,13 +68,13 @@ impl<'a, I: Iterator> Iterator for OperandIter<'a, I>
             return None;
         }
 
-        if curfield >= self.fm.len() {
-            self.set_done();
-            return None;
-        }
-
-        let curfm = unsafe { *self.fm.get_unchecked(curfield) };
-        let curfw = unsafe { *self.fw.get_unchecked(curfield) };
+        let (curfm, curfw) = match (self.fm.get(curfield), self.fw.get(curfield)) {
+            (Some(fm), Some(fw)) => (*fm, *fw),
+            _ => {
+                self.set_done();
+                return None;
+            }
+        };
 
         self.field_id += 1;
 
@@ -133,7 +133,22 @@ pub fn decode_instr<'a, I>(bytes: &'a mut I) -> Option<(InstructionType, Operand
     }
 }
 
+/// Encodes an instruction, the inverse of decode_instr.
+/// Operands are given in field order, leaving out the displacement table of CASE instructions.
+pub fn encode_instr(instr: InstructionType, operands: &[OperandMode]) -> Vec<u8> {
+    debug_assert_eq!(
+        operands.len(),
+        instr.field_modes().iter().filter(|m| **m != FieldMode::VariableLengthTable).count()
+    );
+
+    let mut out = instr.opcode_bytes();
+    for (op, width) in operands.iter().zip(instr.field_widths()) {
+        op.encode_into(*width, &mut out);
+    }
+    out
+}
 
+#[cfg(test)]
 mod tests {
     use crate::ervax::cpu::{
         instrs::{
@@ -142,7 +157,9 @@ mod tests {
             OperandWidth,
             OperandIter,
             InstructionType,
+            FieldMode,
             decode_instr,
+            encode_instr,
         },
         RegID,
     };
@@ -189,4 +206,48 @@ mod tests {
 
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn encode_g_floating() {
+        let operands = vec![OperandMode::Immediate64(2), OperandMode::Register(RegID(1))];
+        let bytes = encode_instr(InstructionType::ADDG2, &operands);
+        assert_eq!(&bytes[..3], &[0xFD, 0x40, 0x8F]);
+
+        let iter = &mut bytes.iter().copied();
+        let (instr, operiter) = decode_instr(iter).unwrap();
+        assert_eq!(instr, InstructionType::ADDG2);
+        assert_eq!(operiter.collect::<Result<Vec<_>, _>>(), Ok(operands));
+    }
+
+    #[test]
+    /// Encodes and decodes every instruction with an operand suited to each of its fields.
+    fn encode_round_trip_all_instructions() {
+        for instr in InstructionType::all() {
+            let operands: Vec<OperandMode> = instr.field_modes().iter()
+                .zip(instr.field_widths())
+                .filter(|(m, _)| **m != FieldMode::VariableLengthTable)
+                .enumerate()
+                .map(|(i, (m, w))| match m {
+                    FieldMode::Read => OperandMode::immediate(0x0102_0304_0506_0708_090A_0B0C_0D0E_0F10, *w),
+                    FieldMode::Write | FieldMode::Modify => OperandMode::Register(RegID(i as u8)),
+                    FieldMode::Address => OperandMode::WordDisplacement(RegID(i as u8), -2),
+                    FieldMode::Bitfield => OperandMode::RegisterDeferred(RegID(i as u8)),
+                    FieldMode::Data => match w {
+                        OperandWidth::Byte => OperandMode::DataByte(0x80),
+                        OperandWidth::Word => OperandMode::DataWord(0x8001),
+                        OperandWidth::Longword => OperandMode::DataLong(0x8000_0001),
+                        _ => panic!("{:?} has a {:?} data field, which the decoder can't read", instr, w),
+                    },
+                    FieldMode::VariableLengthTable => unreachable!(),
+                })
+                .collect();
+
+            let bytes = encode_instr(instr, &operands);
+            let iter = &mut bytes.iter().copied();
+            let (decoded, operiter) = decode_instr(iter).unwrap();
+            assert_eq!(decoded, instr);
+            assert_eq!(operiter.collect::<Result<Vec<_>, _>>(), Ok(operands), "{:?}", instr);
+            assert_eq!(iter.next(), None, "{:?} left bytes unread", instr);
+        }
+    }
+}
@@ -403,6 +403,25 @@ impl InstructionType {
         }
     }
 
+    /// The opcode as it appears in memory, one byte or two for the 0xFD/0xFE/0xFF extended opcodes.
+    pub fn opcode_bytes(self) -> Vec<u8> {
+        let op = self.to_u16().unwrap();
+        if op > 0xFF {
+            op.to_le_bytes().to_vec()
+        } else {
+            vec![op as u8]
+        }
+    }
+
+    /// Every defined instruction, in opcode order.
+    pub fn all() -> impl Iterator<Item = InstructionType> {
+        let short = (0x00..0xFDu16).filter_map(InstructionType::from_u16);
+        let long = (0xFDu8..=0xFF)
+            .flat_map(|p| (0x00..=0xFFu8).map(move |c| u16::from_le_bytes([p, c])))
+            .filter_map(InstructionType::from_u16);
+        short.chain(long)
+    }
+
     #[inline]
     pub fn field_count(self) -> u32 {
         // don't repeat yourself.
@@ -624,7 +643,7 @@ impl InstructionType {
         const FW_WQ: &'static [OperandWidth] = 
             &[OW::Word, OW::Quadword];
         const FW_WO: &'static [OperandWidth] = 
-            &[OW::Word, OW::Quadword];
+            &[OW::Word, OW::Octaword];
         
         const FW_LB: &'static [OperandWidth] = 
             &[OW::Longword, OW::Byte];
@@ -655,7 +674,7 @@ impl InstructionType {
         const FW_OL: &'static [OperandWidth] = 
             &[OW::Octaword, OW::Longword];
         const FW_OQ: &'static [OperandWidth] = 
-            &[OW::Octaword, OW::Longword];
+            &[OW::Octaword, OW::Quadword];
         const FW_OO: &'static [OperandWidth] = 
             &[OW::Octaword, OW::Octaword];
 
@@ -893,7 +912,7 @@ impl InstructionType {
             EMODF => &[OW::Longword, OW::Byte, OW::Longword, OW::Longword, OW::Longword],
             EMODD => &[OW::Quadword, OW::Byte, OW::Quadword, OW::Longword, OW::Quadword],
             EMODG => &[OW::Quadword, OW::Word, OW::Quadword, OW::Longword, OW::Quadword],
-            EMODH => &[OW::Octaword, OW::Byte, OW::Octaword, OW::Octaword, OW::Octaword],
+            EMODH => &[OW::Octaword, OW::Word, OW::Octaword, OW::Longword, OW::Octaword],
             MNEGF => FW_LL,
             MNEGD | MNEGG => FW_QQ,
             MNEGH => FW_OO,
@@ -944,7 +963,7 @@ mod tests {
     /// Tests to make sure field_modes and field_widths return the same length arrays for all instructions
     fn all_operand_list_lens_equal() {
 
-        for i in 0..252 {
+        for i in 0..253 {
             let v = vec![i as u8];
             let iter = &mut (v.iter().map(|x| *x));
             if let Some(i) = InstructionType::from_instrid(iter) {
@@ -954,10 +973,9 @@ mod tests {
             }
         }
 
-        for i in 252..256 {
+        for i in 253..256 {
             for j in 0..256 {
                 let v = vec![i as u8, j as u8];
-                let v = vec![i as u8];
                 let iter = &mut (v.iter().map(|x| *x));
                 if let Some(i) = InstructionType::from_instrid(iter) {
                     if i.field_widths().len() != i.field_modes().len() {
@@ -967,4 +985,41 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn opcode_round_trip() {
+        for i in InstructionType::all() {
+            let bytes = i.opcode_bytes();
+            let iter = &mut bytes.iter().copied();
+            assert_eq!(InstructionType::from_instrid(iter), Some(i));
+        }
+        assert_eq!(InstructionType::CLRO.opcode_bytes(), vec![0xFD, 0x7C]);
+    }
+
+    #[test]
+    /// Checks the operand widths of the conversion and move-zero-extended instructions against their mnemonics,
+    /// CVTxy and MOVZxy read an x and write a y.
+    fn convert_widths_match_mnemonics() {
+        use crate::ervax::cpu::instrs::OperandWidth as OW;
+
+        let width = |c: char| match c {
+            'B' => OW::Byte,
+            'W' => OW::Word,
+            'L' | 'F' => OW::Longword,
+            'D' | 'G' => OW::Quadword,
+            'H' => OW::Octaword,
+            _ => unreachable!(),
+        };
+
+        for i in InstructionType::all() {
+            let name = format!("{:?}", i);
+            let types = match name.strip_prefix("CVTR").or_else(|| name.strip_prefix("CVT")).or_else(|| name.strip_prefix("MOVZ")) {
+                Some(t) if t.len() == 2 => t,
+                _ => continue,
+            };
+            let mut chars = types.chars();
+            let expected = [width(chars.next().unwrap()), width(chars.next().unwrap())];
+            assert_eq!(i.field_widths(), &expected[..], "{}", name);
+        }
+    }
+}
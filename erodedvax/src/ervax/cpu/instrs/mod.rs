@@ -9,6 +9,18 @@ mod instrparse;
 
 pub use instrparse::*;
 
+mod disasm;
+
+pub use disasm::*;
+
+mod asm;
+
+pub use asm::*;
+
+mod difftest;
+
+pub use difftest::*;
+
 use crate::ervax::cpu::{RegID};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -20,6 +32,20 @@ pub enum OperandWidth {
     Octaword, // u128
 }
 
+impl OperandWidth {
+    /// Size of the width in bytes.
+    #[inline]
+    pub fn bytes(self) -> usize {
+        match self {
+            OperandWidth::Byte => 1,
+            OperandWidth::Word => 2,
+            OperandWidth::Longword => 4,
+            OperandWidth::Quadword => 8,
+            OperandWidth::Octaword => 16,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum OperandMode {
     Literal(u8),
@@ -35,7 +61,7 @@ pub enum OperandMode {
     LongwordDisplacement(RegID, i32),
     LongwordDisplacementDeferred(RegID, i32),
     Absolute(u32),
-    Indexed(Box<OperandMode>), // TODO: find some way to pretty this up
+    Indexed(RegID, Box<OperandMode>), // Index register, then the base operand.
     Immediate8(u8), // Needs to handle all possible value sizes, up to i128...
     Immediate16(u16),
     Immediate32(u32),
@@ -51,7 +77,7 @@ impl OperandMode {
     pub fn is_valid_indexed(&self) -> bool {
         use OperandMode::*;
         match self {
-            Literal(_) | Indexed(_) | Register(_) |
+            Literal(_) | Indexed(_, _) | Register(_) |
             AutoDecrement(_) | AutoIncrement(_) | AutoIncrementDeferred(_)
             | Immediate8(_) | Immediate16(_) | Immediate32(_) | Immediate64(_) | Immediate128(_) => 
                 false,
@@ -175,7 +201,7 @@ impl OperandMode {
                             Err(v)
                         },
                         Ok(op) if op.is_valid_indexed() => {
-                            Ok(OperandMode::Indexed(Box::new(op)))
+                            Ok(OperandMode::Indexed(reg, Box::new(op)))
                         }
                         _ => {
                             Err(OperandParseError::InvalidMode)
@@ -250,34 +276,34 @@ impl OperandMode {
                 }
 
                 11 => {
-                    match get_u16_from_stream(bytes) {
-                        Some(v) => Ok(OperandMode::WordDisplacement(reg, v as i16)),
+                    match bytes.next() {
+                        Some(v) => Ok(OperandMode::ByteDisplacementDeferred(reg, v as i8)),
                         None => Err(OperandParseError::OutOfBytes),
                     }
                 }
 
                 12 => {
-                    match get_u32_from_stream(bytes) {
-                        Some(v) => Ok(OperandMode::LongwordDisplacement(reg, v as i32)),
+                    match get_u16_from_stream(bytes) {
+                        Some(v) => Ok(OperandMode::WordDisplacement(reg, v as i16)),
                         None => Err(OperandParseError::OutOfBytes),
                     }
                 }
 
                 13 => {
-                    match bytes.next() {
-                        Some(v) => Ok(OperandMode::ByteDisplacementDeferred(reg, v as i8)),
+                    match get_u16_from_stream(bytes) {
+                        Some(v) => Ok(OperandMode::WordDisplacementDeferred(reg, v as i16)),
                         None => Err(OperandParseError::OutOfBytes),
                     }
                 }
 
-                14  => {
-                    match get_u16_from_stream(bytes) {
-                        Some(v) => Ok(OperandMode::WordDisplacementDeferred(reg, v as i16)),
+                14 => {
+                    match get_u32_from_stream(bytes) {
+                        Some(v) => Ok(OperandMode::LongwordDisplacement(reg, v as i32)),
                         None => Err(OperandParseError::OutOfBytes),
                     }
                 }
 
-                15  => {
+                15 => {
                     match get_u32_from_stream(bytes) {
                         Some(v) => Ok(OperandMode::LongwordDisplacementDeferred(reg, v as i32)),
                         None => Err(OperandParseError::OutOfBytes),
@@ -293,6 +319,73 @@ impl OperandMode {
     }
 }
 
+/// Encoding, the inverse of read_operand.
+impl OperandMode {
+    /// An immediate holding `v` truncated to `width`.
+    pub fn immediate(v: u128, width: OperandWidth) -> OperandMode {
+        match width {
+            OperandWidth::Byte => OperandMode::Immediate8(v as u8),
+            OperandWidth::Word => OperandMode::Immediate16(v as u16),
+            OperandWidth::Longword => OperandMode::Immediate32(v as u32),
+            OperandWidth::Quadword => OperandMode::Immediate64(v as u64),
+            OperandWidth::Octaword => OperandMode::Immediate128(v),
+        }
+    }
+
+    /// The shortest way to encode a constant: a short literal if it fits in 6 bits, an immediate otherwise.
+    /// Note short literals are interpreted as floating point by floating point instructions.
+    pub fn constant(v: u128, width: OperandWidth) -> OperandMode {
+        match v {
+            0..=63 => OperandMode::Literal(v as u8),
+            _ => OperandMode::immediate(v, width),
+        }
+    }
+
+    /// Encodes the operand as it would appear in a field of the given width.
+    /// Immediates and data are written at `width` no matter which variant holds them.
+    ///
+    /// Autoincrement and autoincrement deferred of PC are how immediate and absolute modes are encoded,
+    /// so they won't decode back to themselves. Use Immediate*/Absolute instead.
+    pub fn encode(&self, width: OperandWidth) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(width, &mut out);
+        out
+    }
+
+    pub fn encode_into(&self, width: OperandWidth, out: &mut Vec<u8>) {
+        use OperandMode::*;
+        let spec = |out: &mut Vec<u8>, m: u8, r: &RegID| out.push((m << 4) | r.0);
+        let sized = |out: &mut Vec<u8>, v: u128| out.extend(&v.to_le_bytes()[..width.bytes()]);
+
+        match self {
+            Literal(v) => out.push(v & 0x3F),
+            Indexed(x, base) => {
+                spec(out, 4, x);
+                base.encode_into(width, out);
+            }
+            Register(r) => spec(out, 5, r),
+            RegisterDeferred(r) => spec(out, 6, r),
+            AutoDecrement(r) => spec(out, 7, r),
+            AutoIncrement(r) => spec(out, 8, r),
+            AutoIncrementDeferred(r) => spec(out, 9, r),
+            ByteDisplacement(r, d) => { spec(out, 0xA, r); out.push(*d as u8); }
+            ByteDisplacementDeferred(r, d) => { spec(out, 0xB, r); out.push(*d as u8); }
+            WordDisplacement(r, d) => { spec(out, 0xC, r); out.extend(&d.to_le_bytes()); }
+            WordDisplacementDeferred(r, d) => { spec(out, 0xD, r); out.extend(&d.to_le_bytes()); }
+            LongwordDisplacement(r, d) => { spec(out, 0xE, r); out.extend(&d.to_le_bytes()); }
+            LongwordDisplacementDeferred(r, d) => { spec(out, 0xF, r); out.extend(&d.to_le_bytes()); }
+            Absolute(a) => { out.push(0x9F); out.extend(&a.to_le_bytes()); }
+            Immediate8(_) | Immediate16(_) | Immediate32(_) | Immediate64(_) | Immediate128(_) => {
+                out.push(0x8F);
+                sized(out, self.constant_value().unwrap());
+            }
+            DataByte(v) => sized(out, *v as u128),
+            DataWord(v) => sized(out, *v as u128),
+            DataLong(v) => sized(out, *v as u128),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum FieldMode {
     Read,
@@ -304,6 +397,7 @@ pub enum FieldMode {
     VariableLengthTable, // CASE why.
 }
 
+#[cfg(test)]
 mod tests {
     use crate::ervax::cpu::{
         instrs::{
@@ -412,6 +506,33 @@ mod tests {
         assert_eq!(r, Ok(OperandMode::AutoIncrementDeferred(RegID(5))));
     }
 
+    #[test]
+    fn decode_displacements() {
+        let cases: Vec<(Vec<u8>, OperandMode)> = vec![
+            (vec![0xA5, 0xFC], OperandMode::ByteDisplacement(RegID(5), -4)),
+            (vec![0xB5, 0x04], OperandMode::ByteDisplacementDeferred(RegID(5), 4)),
+            (vec![0xC5, 0x00, 0x01], OperandMode::WordDisplacement(RegID(5), 0x100)),
+            (vec![0xD5, 0x00, 0x01], OperandMode::WordDisplacementDeferred(RegID(5), 0x100)),
+            (vec![0xE5, 0x00, 0x00, 0x01, 0x00], OperandMode::LongwordDisplacement(RegID(5), 0x10000)),
+            (vec![0xF5, 0x00, 0x00, 0x01, 0x00], OperandMode::LongwordDisplacementDeferred(RegID(5), 0x10000)),
+        ];
+
+        for (bytes, mode) in cases {
+            let iter = &mut bytes.iter().copied();
+            let r = OperandMode::read_operand(iter, OperandWidth::Byte, true);
+            assert_eq!(r, Ok(mode));
+        }
+    }
+
+    #[test]
+    fn decode_indexed() {
+        let literal: Vec<u8> = vec![0x42, 0xA5, 0x04];
+        let iter = &mut literal.iter().copied();
+
+        let r = OperandMode::read_operand(iter, OperandWidth::Longword, true);
+        assert_eq!(r, Ok(OperandMode::Indexed(RegID(2), Box::new(OperandMode::ByteDisplacement(RegID(5), 4)))));
+    }
+
     #[test]
     fn decode_absolute() {
         let mut literal: Vec<u8> = vec![0x9F];
@@ -423,6 +544,87 @@ mod tests {
         assert_eq!(r, Ok(OperandMode::Absolute(0x1234_5678)));
     }
 
+    /// A sample of every operand mode that read_operand can produce at the given width.
+    fn all_modes(width: OperandWidth) -> Vec<OperandMode> {
+        let mut modes = vec![];
+        let edges = [0u128, 1, 63, 64, 0x7F, 0x80, 0xFF, 0x7FFF, 0xFFFF, u32::MAX as u128, u64::MAX as u128, u128::MAX];
+
+        for v in 0..64 {
+            modes.push(OperandMode::Literal(v));
+        }
+        for v in edges.iter() {
+            modes.push(OperandMode::immediate(*v, width));
+        }
+        for a in [0u32, 0x1234_5678, u32::MAX].iter() {
+            modes.push(OperandMode::Absolute(*a));
+        }
+
+        for r in 0..16 {
+            let reg = RegID(r);
+            modes.push(OperandMode::Register(reg));
+            modes.push(OperandMode::RegisterDeferred(reg));
+            modes.push(OperandMode::AutoDecrement(reg));
+            if r != 15 {
+                modes.push(OperandMode::AutoIncrement(reg));
+                modes.push(OperandMode::AutoIncrementDeferred(reg));
+            }
+            for d in [i8::MIN, -1, 0, 1, i8::MAX].iter() {
+                modes.push(OperandMode::ByteDisplacement(reg, *d));
+                modes.push(OperandMode::ByteDisplacementDeferred(reg, *d));
+            }
+            for d in [i16::MIN, -1, 0, 1, i16::MAX].iter() {
+                modes.push(OperandMode::WordDisplacement(reg, *d));
+                modes.push(OperandMode::WordDisplacementDeferred(reg, *d));
+            }
+            for d in [i32::MIN, -1, 0, 1, i32::MAX].iter() {
+                modes.push(OperandMode::LongwordDisplacement(reg, *d));
+                modes.push(OperandMode::LongwordDisplacementDeferred(reg, *d));
+            }
+        }
+
+        let bases: Vec<OperandMode> = modes.iter().filter(|m| m.is_valid_indexed()).cloned().collect();
+        for x in 0..16 {
+            for base in bases.iter() {
+                modes.push(OperandMode::Indexed(RegID(x), Box::new(base.clone())));
+            }
+        }
+        modes
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let widths = [
+            OperandWidth::Byte,
+            OperandWidth::Word,
+            OperandWidth::Longword,
+            OperandWidth::Quadword,
+            OperandWidth::Octaword,
+        ];
+
+        for width in widths.iter() {
+            for mode in all_modes(*width) {
+                let bytes = mode.encode(*width);
+                let iter = &mut bytes.iter().copied();
+                let r = OperandMode::read_operand(iter, *width, true);
+                assert_eq!(r, Ok(mode.clone()), "{:?} at {:?} encoded as {:02X?}", mode, width, bytes);
+                assert_eq!(iter.next(), None, "{:?} at {:?} left bytes unread", mode, width);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_constant_selection() {
+        assert_eq!(OperandMode::constant(63, OperandWidth::Longword), OperandMode::Literal(63));
+        assert_eq!(OperandMode::constant(64, OperandWidth::Longword), OperandMode::Immediate32(64));
+        assert_eq!(OperandMode::constant(64, OperandWidth::Byte).encode(OperandWidth::Byte), vec![0x8F, 64]);
+        assert_eq!(OperandMode::Immediate8(2).encode(OperandWidth::Word), vec![0x8F, 2, 0]);
+        assert_eq!(
+            OperandMode::Indexed(RegID(2), Box::new(OperandMode::ByteDisplacement(RegID(5), 4)))
+                .encode(OperandWidth::Longword),
+            vec![0x42, 0xA5, 0x04],
+        );
+    }
+
     // -----
     // Operand decoding failure tests
     // -----
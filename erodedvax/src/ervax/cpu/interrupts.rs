@@ -0,0 +1,51 @@
+//! Hardware interrupt requests.
+//!
+//! Devices don't push interrupts into the CPU, it asks them. Before each instruction the CPU
+//! polls every InterruptSource, takes the highest priority request above the current IPL,
+//! and acknowledges it so the device can drop the request.
+
+/// IPL the console terminal interrupts at.
+pub const IPL_CONSOLE: u8 = 0x14;
+/// IPL the interval clock interrupts at.
+pub const IPL_CLOCK: u8 = 0x18;
+
+/// SCB offsets of the interrupt vectors the CPU itself defines.
+pub mod vectors {
+    pub const INTERVAL_CLOCK: u16 = 0xC0;
+    pub const CONSOLE_RECEIVE: u16 = 0xF8;
+    pub const CONSOLE_TRANSMIT: u16 = 0xFC;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Interrupt {
+    /// Interrupt priority level, 0x10 through 0x1F for hardware interrupts.
+    pub ipl: u8,
+    /// Offset of the interrupt's vector in the System Control Block.
+    pub vector: u16,
+}
+
+impl Interrupt {
+    pub fn new(ipl: u8, vector: u16) -> Interrupt {
+        debug_assert!(ipl < 0x20);
+        debug_assert!(vector & 3 == 0);
+        Interrupt { ipl, vector }
+    }
+}
+
+pub trait InterruptSource {
+    /// The highest priority interrupt being requested, if any.
+    fn pending_interrupt(&self) -> Option<Interrupt>;
+
+    /// Called when the CPU takes the interrupt with the given vector.
+    fn acknowledge(&mut self, vector: u16);
+}
+
+/// Picks the highest priority interrupt out of several, earlier ones winning ties.
+pub fn highest(interrupts: impl IntoIterator<Item = Option<Interrupt>>) -> Option<Interrupt> {
+    interrupts.into_iter()
+        .flatten()
+        .fold(None, |best: Option<Interrupt>, i| match best {
+            Some(b) if b.ipl >= i.ipl => Some(b),
+            _ => Some(i),
+        })
+}
@@ -4,7 +4,8 @@ use num_traits::{FromPrimitive, ToPrimitive};
 
 use crate::ervax::{
     cpu::{
-        PrivilegeMode
+        bus::{BusError, VAXBus},
+        PrivilegeMode,
     },
     utils::addr_lw_trim,
 };
@@ -50,42 +51,52 @@ pub enum MemoryAccessType {
 }
 
 impl PTEProtectionCode {
+    /// Whether `mode` may make an access of this kind to a page with this protection.
     pub fn can_access(self, mode: PrivilegeMode, access: MemoryAccessType) -> bool {
-        let m = self.to_u8().unwrap();
-        let rm = ((m & 0b1100) >> 2) as u8;
-        let wm = !(m & 0b0011) as u8;
-
-
-        match mode.to_u8().unwrap() {
-            16..=u8::MAX => unreachable!(),
-
-            0 => false,
-            4 => true,
+        // The least privileged mode that can write, and that can read, by protection code.
+        // Codes 0 and 1 allow nothing, and -1 is no mode at all.
+        const WRITE: [i8; 16] = [-1, -1, 0, -1, 3, 1, 0, -1, 2, 1, 0, -1, 2, 1, 0, -1];
+        const READ: [i8; 16] = [-1, -1, 0, 0, 3, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3];
+        let least = match access {
+            MemoryAccessType::Read => READ,
+            MemoryAccessType::Write => WRITE,
+        };
+        mode.to_i8().unwrap() <= least[self.to_usize().unwrap()]
+    }
+}
 
-            v if v < wm => {
-                true
-            },
+/// Bytes in a page.
+pub const PAGE_SIZE: u32 = 512;
 
-            v if (access == MemoryAccessType::Read) && (v <= rm)  => {
-                true
-            },
+/// Page table entry fields.
+pub const PTE_VALID: u32 = 0x8000_0000;
+pub const PTE_MODIFIED: u32 = 0x0400_0000;
+pub const PTE_PFN: u32 = 0x001F_FFFF;
 
-            _ => false,
+/// Bits of the reason a memory management fault pushes with the virtual address.
+pub const FAULT_LENGTH: u32 = 0x1;
+/// The fault was on the page table entry of a process page, in system space.
+pub const FAULT_PTE_REFERENCE: u32 = 0x2;
+pub const FAULT_WRITE: u32 = 0x4;
 
-        }
-    }
+/// Why a virtual address couldn't be translated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryFault {
+    /// Beyond the page table's length, or not allowed by the page's protection, with the
+    /// reason bits.
+    AccessViolation(u32),
+    /// The page table entry isn't valid, with the reason bits.
+    TranslationNotValid(u32),
+    /// A page table entry wasn't in physical memory.
+    Bus(BusError),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct PFN(u32);
-
-impl From<u32> for PFN {
-    fn from(v: u32) -> Self {
-        PFN(v & 0x3FFF_F800 >> 11)
+impl From<BusError> for MemoryFault {
+    fn from(e: BusError) -> Self {
+        MemoryFault::Bus(e)
     }
 }
 
-
 pub struct VAXMMU {
     /// P0BR
     p0_base: u32,
@@ -101,6 +112,9 @@ pub struct VAXMMU {
     sys_base: u32,
     /// SLR
     sys_len: u32,
+
+    /// MAPEN
+    enabled: bool,
 }
 
 /// Initialization
@@ -113,94 +127,151 @@ impl VAXMMU {
             p1_len: 0,
             sys_base: 0,
             sys_len: 0,
+            enabled: false,
         }
     }
 }
 
-/// Setters/getters for region controls
+/// Setters/getters for region controls. Lengths count page table entries.
 impl VAXMMU {
     #[inline]
     pub fn set_p0_len(&mut self, len: u32) {
-        debug_assert!(len < 8_388_609);
-        self.p0_len = len;
+        self.p0_len = len & 0x3F_FFFF;
     }
     #[inline]
-    pub fn get_p0_len(&mut self) -> u32 {
-        self.p0_len * 512
+    pub fn p0_len(&self) -> u32 {
+        self.p0_len
     }
 
     #[inline]
     pub fn set_p1_len(&mut self, len: u32) {
-        debug_assert!(len < 8_388_609);
-        self.p1_len = len;
+        self.p1_len = len & 0x3F_FFFF;
     }
     #[inline]
-    pub fn get_p1_len(&mut self) -> u32 {
-        self.p1_len * 512
+    pub fn p1_len(&self) -> u32 {
+        self.p1_len
     }
 
     #[inline]
     pub fn set_sys_len(&mut self, len: u32) {
-        debug_assert!(len < 8_388_609);
-        self.sys_len = len;
+        self.sys_len = len & 0x3F_FFFF;
     }
     #[inline]
-    pub fn get_sys_len(&mut self) -> u32 {
-        self.sys_len * 512
+    pub fn sys_len(&self) -> u32 {
+        self.sys_len
     }
 
     #[inline]
     pub fn set_p0_base(&mut self, base: u32) {
         self.p0_base = addr_lw_trim(base);
     }
+    #[inline]
+    pub fn p0_base(&self) -> u32 {
+        self.p0_base
+    }
 
     #[inline]
     pub fn set_p1_base(&mut self, base: u32) {
         self.p1_base = addr_lw_trim(base);
     }
+    #[inline]
+    pub fn p1_base(&self) -> u32 {
+        self.p1_base
+    }
+
+    /// The system page table is at a physical address.
+    #[inline]
+    pub fn set_sys_base(&mut self, base: u32) {
+        self.sys_base = addr_lw_trim(base) & 0x3FFF_FFFF;
+    }
+    #[inline]
+    pub fn sys_base(&self) -> u32 {
+        self.sys_base
+    }
+
+    #[inline]
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
 }
 
 /// Address translation
+/// See chapter 4 of the VAX Architecture Reference Manual (1987)
 impl VAXMMU {
-    /// Checks an address for validity
-    /// See page 209 of the VAX Architecture Reference Manual (1987)
-    pub fn is_address_valid(&self, addr: u32) -> bool {
-        let region = VAXMMU::address_region(addr); 
-        let maddr = addr & 0x3FFF_F800;
-        
-        match region {
-            0 if maddr < (self.p0_len * 512) => true,
-            1 if maddr > (self.p1_len * 512) => true,
-            2 if maddr > (self.sys_len * 512) => true,
-            _ => false,
-        }
-    }
-
     /// Returns 0, 1, 2, or 3, representing the region of the address.
     #[inline]
     pub fn address_region(addr: u32) -> u8 {
         ((addr & 0xC000_0000) >> 30) as u8
     }
 
-    /// Gets the physical address of the PTE used to translate the input.
-    /// Returns None if address is invalid or MMU is disabled
-    pub fn get_pte_address(&self, translatee: u32) -> Option<u32> {
-        if !self.is_address_valid(translatee) { return None; }
+    /// The virtual page number within the address's region.
+    #[inline]
+    fn page_number(addr: u32) -> u32 {
+        (addr >> 9) & 0x1F_FFFF
+    }
+
+    /// Translates a virtual address to a physical one, through the page tables in `bus` while
+    /// memory management is on. With `check`, the access has to be allowed by the page's
+    /// protection, and a write marks the page modified. Without, as for a debugger, anything
+    /// mapped is reached and nothing is changed. There's no translation buffer, so TBIA and
+    /// TBIS have nothing to do.
+    pub fn translate(
+        &self,
+        bus: &mut VAXBus,
+        addr: u32,
+        check: Option<(PrivilegeMode, MemoryAccessType)>,
+    ) -> Result<u32, MemoryFault> {
+        if !self.enabled {
+            return Ok(addr);
+        }
+        let write = matches!(check, Some((_, MemoryAccessType::Write)));
+        let intent = if write { FAULT_WRITE } else { 0 };
+        let vpn = VAXMMU::page_number(addr);
 
-        let pfn: PFN = translatee.into();
+        let pte_address = match VAXMMU::address_region(addr) {
+            0 if vpn < self.p0_len => self.process_pte(bus, self.p0_base, vpn, intent)?,
+            1 if vpn >= self.p1_len => self.process_pte(bus, self.p1_base, vpn, intent)?,
+            2 if vpn < self.sys_len => self.sys_base.wrapping_add(vpn * 4),
+            _ => return Err(MemoryFault::AccessViolation(intent | FAULT_LENGTH)),
+        };
+        let pte = bus.read_u32(pte_address)?;
+        if let Some((mode, access)) = check {
+            let code = PTEProtectionCode::from_int((pte >> 27) as u8 & 0xF).unwrap();
+            if !code.can_access(mode, access) {
+                return Err(MemoryFault::AccessViolation(intent));
+            }
+        }
+        if pte & PTE_VALID == 0 {
+            return Err(MemoryFault::TranslationNotValid(intent));
+        }
+        if write && pte & PTE_MODIFIED == 0 {
+            bus.write_u32(pte_address, pte | PTE_MODIFIED)?;
+        }
+        Ok((pte & PTE_PFN) << 9 | addr & (PAGE_SIZE - 1))
+    }
 
-        match VAXMMU::address_region(translatee) {
-            0 if (pfn.0 > self.p0_len) =>
-                Some((pfn.0 * 4) + self.p0_base),
-            1 if (pfn.0 > self.p1_len) =>
-                Some((pfn.0 * 4) + self.p1_base),
-            2 if (pfn.0 > self.sys_len) =>
-                Some((pfn.0 * 4) + self.sys_base),
-            _ => None,
+    /// The physical address of the page table entry for page `vpn` of a process region whose
+    /// table starts at `base`, found through the system page table, as process page tables are
+    /// in system space.
+    fn process_pte(&self, bus: &mut VAXBus, base: u32, vpn: u32, intent: u32) -> Result<u32, MemoryFault> {
+        let address = base.wrapping_add(vpn * 4);
+        let svpn = VAXMMU::page_number(address);
+        if VAXMMU::address_region(address) != 2 || svpn >= self.sys_len {
+            return Err(MemoryFault::AccessViolation(intent | FAULT_PTE_REFERENCE | FAULT_LENGTH));
         }
+        let spte = bus.read_u32(self.sys_base.wrapping_add(svpn * 4))?;
+        if spte & PTE_VALID == 0 {
+            return Err(MemoryFault::TranslationNotValid(intent | FAULT_PTE_REFERENCE));
+        }
+        Ok((spte & PTE_PFN) << 9 | address & (PAGE_SIZE - 1))
     }
 }
 
+#[cfg(test)]
 mod tests {
     use crate::ervax::cpu::mmu::*;
     #[test] 
@@ -208,4 +279,52 @@ mod tests {
         assert_eq!(VAXMMU::address_region(0x0000_0000), 0);
         assert_eq!(VAXMMU::address_region(0xC000_0000), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn translation() {
+        use MemoryAccessType::*;
+        use PrivilegeMode::*;
+        let pte = |code: PTEProtectionCode, pfn: u32| PTE_VALID | (code as u32) << 27 | pfn;
+        let mut bus = VAXBus::new(1 << 20);
+        let mut mmu = VAXMMU::new();
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0123, None), Ok(0x8000_0123));
+
+        // The system page table at 0x10000: S0 page 0 is the P0 page table, and pages 1 and 2
+        // are kernel only and invalid.
+        mmu.set_sys_base(0x10000);
+        mmu.set_sys_len(3);
+        bus.write_u32(0x10000, pte(PTEProtectionCode::KernW, 0x90)).unwrap();
+        bus.write_u32(0x10004, pte(PTEProtectionCode::KernR, 0x91)).unwrap();
+        bus.write_u32(0x10008, (PTEProtectionCode::KernW as u32) << 27).unwrap();
+        // P0 pages 0 and 1, the second user readable.
+        mmu.set_p0_base(0x8000_0000);
+        mmu.set_p0_len(2);
+        bus.write_u32(0x90 << 9, pte(PTEProtectionCode::UserW, 0x20)).unwrap();
+        bus.write_u32((0x90 << 9) + 4, pte(PTEProtectionCode::UserR, 0x21)).unwrap();
+        // P1's last page, its table entry at the end of S0 page 0.
+        mmu.set_p1_base(0x8000_0200 - 0x80_0000);
+        mmu.set_p1_len(0x1F_FFFF);
+        bus.write_u32((0x90 << 9) + 0x1FC, pte(PTEProtectionCode::UserW, 0x22)).unwrap();
+        mmu.set_enabled(true);
+
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0204, Some((Kernel, Read))), Ok(0x12204));
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0204, Some((User, Read))), Err(MemoryFault::AccessViolation(0)));
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0204, Some((Kernel, Write))), Err(MemoryFault::AccessViolation(FAULT_WRITE)));
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0400, None), Err(MemoryFault::TranslationNotValid(0)));
+        assert_eq!(mmu.translate(&mut bus, 0x8000_0600, None), Err(MemoryFault::AccessViolation(FAULT_LENGTH)));
+        assert_eq!(mmu.translate(&mut bus, 0x0000_0210, Some((User, Read))), Ok(0x4210));
+        assert_eq!(mmu.translate(&mut bus, 0x0000_0210, Some((User, Write))), Err(MemoryFault::AccessViolation(FAULT_WRITE)));
+        assert_eq!(mmu.translate(&mut bus, 0x0000_0400, None), Err(MemoryFault::AccessViolation(FAULT_LENGTH)));
+        assert_eq!(mmu.translate(&mut bus, 0x7FFF_FFFC, Some((User, Read))), Ok(0x45FC));
+        assert_eq!(mmu.translate(&mut bus, 0x7FFF_FC00, None), Err(MemoryFault::AccessViolation(FAULT_LENGTH)));
+        assert_eq!(mmu.translate(&mut bus, 0xC000_0000, None), Err(MemoryFault::AccessViolation(FAULT_LENGTH)));
+
+        // Writing marks the page modified; a debugger looking doesn't.
+        assert_eq!(mmu.translate(&mut bus, 0x10, Some((User, Write))), Ok(0x4010));
+        assert_eq!(bus.read_u32(0x90 << 9), Ok(pte(PTEProtectionCode::UserW, 0x20) | PTE_MODIFIED));
+
+        // A process page whose table entry is on an invalid system page.
+        mmu.set_p0_base(0x8000_0400);
+        assert_eq!(mmu.translate(&mut bus, 0x10, Some((User, Write))), Err(MemoryFault::TranslationNotValid(FAULT_WRITE | FAULT_PTE_REFERENCE)));
+    }
+}
@@ -10,6 +10,54 @@ pub mod interrupts;
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct RegID(u8);
 
+impl RegID {
+    pub const AP: RegID = RegID(12);
+    pub const FP: RegID = RegID(13);
+    pub const SP: RegID = RegID(14);
+    pub const PC: RegID = RegID(15);
+
+    #[inline]
+    pub fn new(id: u8) -> RegID {
+        assert!(id < 16);
+        RegID(id)
+    }
+
+    #[inline]
+    pub fn id(self) -> u8 {
+        self.0
+    }
+
+    /// The name MACRO-32 uses for this register.
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; 16] = [
+            "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7",
+            "R8", "R9", "R10", "R11", "AP", "FP", "SP", "PC",
+        ];
+        NAMES[self.0 as usize]
+    }
+
+    /// Parses a register name, accepting both the symbolic names (AP, FP, SP, PC) and R12 through R15.
+    pub fn from_name(name: &str) -> Option<RegID> {
+        let name = name.to_ascii_uppercase();
+        match name.as_str() {
+            "AP" => return Some(RegID::AP),
+            "FP" => return Some(RegID::FP),
+            "SP" => return Some(RegID::SP),
+            "PC" => return Some(RegID::PC),
+            _ => {}
+        }
+
+        let num = name.strip_prefix('R')?;
+        if num.is_empty() || (num.len() > 1 && num.starts_with('0')) {
+            return None;
+        }
+        match num.parse::<u8>() {
+            Ok(v) if v < 16 => Some(RegID(v)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, FromPrimitive, ToPrimitive)]
 pub enum PrivilegeMode {
     Kernel = 0,
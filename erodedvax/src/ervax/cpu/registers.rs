@@ -55,6 +55,22 @@ pub enum PrivRegisters {
     TXCS = 34,
     /// Console Transmit Data buffer
     TXDB = 35,
+    /// Console Saved ISP
+    SAVISP = 41,
+    /// Console Saved PC
+    SAVPC = 42,
+    /// Console Saved PSL
+    SAVPSL = 43,
+    /// I/O Bus Reset
+    IORESET = 55,
     /// Memory Management Enable
     MAPEN = 56,
+    /// Translation Buffer Invalidate All
+    TBIA = 57,
+    /// Translation Buffer Invalidate Single
+    TBIS = 58,
+    /// System Identification
+    SID = 62,
+    /// Translation Buffer Check
+    TBCHK = 63,
 }
\ No newline at end of file
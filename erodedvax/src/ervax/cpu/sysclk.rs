@@ -1,18 +1,240 @@
-#[derive(Copy, Clone, Debug)]
-pub struct SystemClock {
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+/// Where the emulated machine's idea of time comes from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimeMode {
+    /// Host time, for interactive use.
+    RealTime,
+    /// Derived from cycles executed, so runs are reproducible.
+    Virtual,
+}
+
+/// Cycles per microsecond of virtual time, roughly a MicroVAX II.
+pub const DEFAULT_CYCLES_PER_US: u32 = 1;
+
+/// Identifies a scheduled event, for cancelling or rescheduling it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventId(u64);
+
+/// Work to do at some cycle, given whatever owns the clock.
+pub type EventCallback<C> = Box<dyn FnOnce(&mut C)>;
+
+/// Counts cycles, and holds events devices have scheduled for future cycles.
+/// `C` is what event callbacks get to work on, the ExecutionContext that owns the clock.
+pub struct SystemClock<C> {
     cycles_per_tick: u32,
     cycles_this_tick: u32,
-    cycles_all_time: usize,
+    cycles_all_time: u64,
+
+    mode: TimeMode,
+    cycles_per_us: u32,
+    started: Instant,
+
+    next_id: u64,
+    /// Ordered by cycle, then by when they were scheduled.
+    events: BTreeMap<(u64, EventId), EventCallback<C>>,
+    /// When each pending event is due.
+    due: HashMap<EventId, u64>,
 }
 
-impl SystemClock {
+impl<C> SystemClock<C> {
+    pub fn new(cycles_per_tick: u32, mode: TimeMode) -> SystemClock<C> {
+        SystemClock {
+            cycles_per_tick,
+            cycles_this_tick: 0,
+            cycles_all_time: 0,
+            mode,
+            cycles_per_us: DEFAULT_CYCLES_PER_US,
+            started: Instant::now(),
+            next_id: 0,
+            events: BTreeMap::new(),
+            due: HashMap::new(),
+        }
+    }
+
     pub fn consume_cycles(&mut self, amnt: u32) -> bool {
         self.cycles_this_tick += amnt;
-        self.cycles_all_time += amnt as usize;
+        self.cycles_all_time += amnt as u64;
         return self.cycles_this_tick >= self.cycles_per_tick;
     }
 
     pub fn new_tick(&mut self) {
         self.cycles_this_tick = 0;
     }
-}
\ No newline at end of file
+
+    #[inline]
+    pub fn cycles_all_time(&self) -> u64 {
+        self.cycles_all_time
+    }
+
+    #[inline]
+    pub fn mode(&self) -> TimeMode {
+        self.mode
+    }
+
+    pub fn set_cycles_per_us(&mut self, cycles: u32) {
+        assert!(cycles > 0);
+        self.cycles_per_us = cycles;
+    }
+
+    /// Microseconds of emulated time since the clock was created.
+    pub fn now_us(&self) -> u64 {
+        match self.mode {
+            TimeMode::RealTime => self.started.elapsed().as_micros() as u64,
+            TimeMode::Virtual => self.cycles_all_time / self.cycles_per_us as u64,
+        }
+    }
+
+    /// Cycles it takes to run for `us` microseconds. Only exact in virtual time, in real time
+    /// the CPU may run faster or slower than that.
+    pub fn us_to_cycles(&self, us: u64) -> u64 {
+        us.saturating_mul(self.cycles_per_us as u64)
+    }
+}
+
+/// Scheduling.
+impl<C> SystemClock<C> {
+    /// Schedules `callback` to run once `cycles_all_time` reaches `cycle`.
+    /// Events due at the same cycle run in the order they were scheduled.
+    pub fn schedule_at(&mut self, cycle: u64, callback: EventCallback<C>) -> EventId {
+        let id = EventId(self.next_id);
+        self.next_id += 1;
+        self.events.insert((cycle, id), callback);
+        self.due.insert(id, cycle);
+        id
+    }
+
+    pub fn schedule_in(&mut self, cycles: u64, callback: EventCallback<C>) -> EventId {
+        self.schedule_at(self.cycles_all_time.saturating_add(cycles), callback)
+    }
+
+    pub fn schedule_in_us(&mut self, us: u64, callback: EventCallback<C>) -> EventId {
+        self.schedule_in(self.us_to_cycles(us), callback)
+    }
+
+    /// Returns false if the event already ran or was cancelled.
+    pub fn cancel(&mut self, id: EventId) -> bool {
+        match self.due.remove(&id) {
+            Some(cycle) => self.events.remove(&(cycle, id)).is_some(),
+            None => false,
+        }
+    }
+
+    /// Moves a pending event to another cycle. Returns false if the event already ran or was cancelled.
+    pub fn reschedule(&mut self, id: EventId, cycle: u64) -> bool {
+        let old = match self.due.get_mut(&id) {
+            Some(c) => std::mem::replace(c, cycle),
+            None => return false,
+        };
+        let callback = self.events.remove(&(old, id)).unwrap();
+        self.events.insert((cycle, id), callback);
+        true
+    }
+
+    pub fn is_scheduled(&self, id: EventId) -> bool {
+        self.due.contains_key(&id)
+    }
+
+    /// When the next event is due.
+    pub fn next_event_at(&self) -> Option<u64> {
+        self.events.keys().next().map(|(cycle, _)| *cycle)
+    }
+
+    /// Removes and returns the next event if it's due.
+    pub fn pop_due(&mut self) -> Option<EventCallback<C>> {
+        let key = *self.events.keys().next()?;
+        if key.0 > self.cycles_all_time {
+            return None;
+        }
+        self.due.remove(&key.1);
+        self.events.remove(&key)
+    }
+
+    /// Counts cycles that passed without anything being executed.
+    pub fn skip_cycles(&mut self, cycles: u64) {
+        self.cycles_all_time += cycles;
+        self.cycles_this_tick = self.cycles_this_tick.saturating_add(cycles.min(u32::MAX as u64) as u32);
+    }
+
+    /// Jumps time forward to the next event, for when there's nothing to do until then.
+    /// Returns false, leaving time alone, if nothing is scheduled.
+    pub fn skip_to_next_event(&mut self) -> bool {
+        match self.next_event_at() {
+            Some(cycle) => {
+                self.skip_cycles(cycle.saturating_sub(self.cycles_all_time));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_time() {
+        let mut clk = SystemClock::<()>::new(1000, TimeMode::Virtual);
+        clk.set_cycles_per_us(4);
+        assert_eq!(clk.now_us(), 0);
+        clk.consume_cycles(10);
+        assert_eq!(clk.now_us(), 2);
+        assert_eq!(clk.cycles_all_time(), 10);
+        assert_eq!(clk.us_to_cycles(3), 12);
+    }
+
+    fn run_due(clk: &mut SystemClock<Vec<u32>>, log: &mut Vec<u32>) {
+        while let Some(cb) = clk.pop_due() {
+            cb(log);
+        }
+    }
+
+    #[test]
+    fn scheduler_order() {
+        let mut clk = SystemClock::new(1000, TimeMode::Virtual);
+        let mut log = vec![];
+
+        clk.schedule_at(20, Box::new(|l: &mut Vec<u32>| l.push(3)));
+        clk.schedule_at(10, Box::new(|l: &mut Vec<u32>| l.push(1)));
+        clk.schedule_in(10, Box::new(|l: &mut Vec<u32>| l.push(2)));
+        assert_eq!(clk.next_event_at(), Some(10));
+
+        run_due(&mut clk, &mut log);
+        assert!(log.is_empty());
+
+        clk.consume_cycles(15);
+        run_due(&mut clk, &mut log);
+        assert_eq!(log, vec![1, 2]);
+
+        assert!(clk.skip_to_next_event());
+        assert_eq!(clk.cycles_all_time(), 20);
+        run_due(&mut clk, &mut log);
+        assert_eq!(log, vec![1, 2, 3]);
+        assert!(!clk.skip_to_next_event());
+    }
+
+    #[test]
+    fn scheduler_cancel_reschedule() {
+        let mut clk = SystemClock::new(1000, TimeMode::Virtual);
+        let mut log = vec![];
+
+        let a = clk.schedule_at(5, Box::new(|l: &mut Vec<u32>| l.push(1)));
+        let b = clk.schedule_at(6, Box::new(|l: &mut Vec<u32>| l.push(2)));
+        assert!(clk.cancel(a));
+        assert!(!clk.cancel(a));
+        assert!(!clk.is_scheduled(a));
+
+        assert!(clk.reschedule(b, 50));
+        assert_eq!(clk.next_event_at(), Some(50));
+        clk.consume_cycles(10);
+        run_due(&mut clk, &mut log);
+        assert!(log.is_empty());
+
+        clk.consume_cycles(40);
+        run_due(&mut clk, &mut log);
+        assert_eq!(log, vec![2]);
+        assert!(!clk.reschedule(b, 60));
+    }
+}
@@ -0,0 +1,276 @@
+//! The interval clock (ICCS/NICR/ICR) and time-of-year clock (TODR) processor registers.
+//! Both work in microseconds taken from the SystemClock, so they follow its time mode.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ervax::cpu::interrupts::{
+    vectors,
+    Interrupt,
+    InterruptSource,
+    IPL_CLOCK,
+};
+
+/// ICCS error bit, an overflow happened while INT was still set. Write 1 to clear.
+pub const ICCS_ERR: u32 = 0x8000_0000;
+/// ICCS interrupt bit, set when ICR overflows. Write 1 to clear.
+pub const ICCS_INT: u32 = 0x80;
+pub const ICCS_IE: u32 = 0x40;
+/// ICCS single step, adds one to ICR when written while the clock isn't running.
+pub const ICCS_SGL: u32 = 0x20;
+/// ICCS transfer, copies NICR into ICR when written.
+pub const ICCS_XFR: u32 = 0x10;
+pub const ICCS_RUN: u32 = 0x01;
+
+/// TODR value at the start of the year, by VMS's convention.
+pub const TODR_BASE: u32 = 0x1000_0000;
+/// Microseconds per TODR count.
+pub const TODR_RESOLUTION_US: u64 = 10_000;
+
+/// The interval clock. ICR counts up once a microsecond while running, and on overflowing is
+/// reloaded from NICR and requests an interrupt.
+pub struct IntervalClock {
+    iccs: u32,
+    nicr: u32,
+    icr: u32,
+    /// When ICR was last brought up to date.
+    updated_us: u64,
+    int_requested: bool,
+}
+
+impl Default for IntervalClock {
+    fn default() -> Self {
+        IntervalClock::new()
+    }
+}
+
+impl IntervalClock {
+    pub fn new() -> IntervalClock {
+        IntervalClock {
+            iccs: 0,
+            nicr: 0,
+            icr: 0,
+            updated_us: 0,
+            int_requested: false,
+        }
+    }
+
+    pub fn reset(&mut self, now_us: u64) {
+        *self = IntervalClock::new();
+        self.updated_us = now_us;
+    }
+
+    fn overflow(&mut self) {
+        if self.iccs & ICCS_INT != 0 {
+            self.iccs |= ICCS_ERR;
+        }
+        self.iccs |= ICCS_INT;
+        self.icr = self.nicr;
+        if self.iccs & ICCS_IE != 0 {
+            self.int_requested = true;
+        }
+    }
+
+    /// Brings ICR up to date.
+    pub fn update(&mut self, now_us: u64) {
+        let elapsed = now_us.saturating_sub(self.updated_us);
+        self.updated_us = now_us;
+        if self.iccs & ICCS_RUN == 0 || elapsed == 0 {
+            return;
+        }
+
+        let to_overflow = (1u64 << 32) - self.icr as u64;
+        if elapsed < to_overflow {
+            self.icr += elapsed as u32;
+            return;
+        }
+        self.overflow();
+
+        // A period runs from NICR up to the next overflow. Any more overflows were missed.
+        let left = elapsed - to_overflow;
+        let period = (1u64 << 32) - self.nicr as u64;
+        if left >= period {
+            self.overflow();
+        }
+        self.icr = self.nicr + (left % period) as u32;
+    }
+
+    /// Microseconds from `now_us` until ICR next overflows, None if it isn't running.
+    pub fn next_overflow_in(&mut self, now_us: u64) -> Option<u64> {
+        self.update(now_us);
+        if self.iccs & ICCS_RUN == 0 {
+            return None;
+        }
+        Some((1u64 << 32) - self.icr as u64)
+    }
+
+    pub fn read_iccs(&mut self, now_us: u64) -> u32 {
+        self.update(now_us);
+        self.iccs
+    }
+
+    pub fn write_iccs(&mut self, now_us: u64, v: u32) {
+        self.update(now_us);
+
+        self.iccs &= !(v & (ICCS_ERR | ICCS_INT));
+        let was_running = self.iccs & ICCS_RUN != 0;
+        self.iccs = (self.iccs & (ICCS_ERR | ICCS_INT)) | (v & (ICCS_IE | ICCS_RUN));
+
+        if v & ICCS_XFR != 0 {
+            self.icr = self.nicr;
+        }
+        if v & ICCS_SGL != 0 && !was_running && v & ICCS_RUN == 0 {
+            match self.icr.checked_add(1) {
+                Some(icr) => self.icr = icr,
+                None => self.overflow(),
+            }
+        }
+        if self.iccs & ICCS_IE == 0 {
+            self.int_requested = false;
+        }
+    }
+
+    pub fn read_icr(&mut self, now_us: u64) -> u32 {
+        self.update(now_us);
+        self.icr
+    }
+
+    pub fn write_nicr(&mut self, v: u32) {
+        self.nicr = v;
+    }
+
+    /// NICR is write only on hardware, this is for debuggers.
+    pub fn nicr(&self) -> u32 {
+        self.nicr
+    }
+}
+
+impl InterruptSource for IntervalClock {
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        if self.int_requested {
+            Some(Interrupt::new(IPL_CLOCK, vectors::INTERVAL_CLOCK))
+        } else {
+            None
+        }
+    }
+
+    fn acknowledge(&mut self, vector: u16) {
+        if vector == vectors::INTERVAL_CLOCK {
+            self.int_requested = false;
+        }
+    }
+}
+
+/// The time-of-year clock, counting in units of 10ms.
+pub struct TimeOfYear {
+    /// TODR at `set_us`.
+    value: u32,
+    set_us: u64,
+}
+
+impl TimeOfYear {
+    /// A clock reading `value` at `now_us`.
+    pub fn new(value: u32, now_us: u64) -> TimeOfYear {
+        TimeOfYear { value, set_us: now_us }
+    }
+
+    /// A clock set from the host's time, counting from TODR_BASE at the start of the year (UTC).
+    pub fn from_host(now_us: u64) -> TimeOfYear {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = now.as_secs() - start_of_year(now.as_secs());
+        let counts = secs * 100 + now.subsec_millis() as u64 / 10;
+        TimeOfYear::new(TODR_BASE.wrapping_add(counts as u32), now_us)
+    }
+
+    pub fn read(&self, now_us: u64) -> u32 {
+        let counts = now_us.saturating_sub(self.set_us) / TODR_RESOLUTION_US;
+        self.value.wrapping_add(counts as u32)
+    }
+
+    pub fn write(&mut self, now_us: u64, v: u32) {
+        self.value = v;
+        self.set_us = now_us;
+    }
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+/// Seconds since the Unix epoch at the start of the year containing `secs`.
+fn start_of_year(secs: u64) -> u64 {
+    let mut start = 0;
+    let mut year = 1970;
+    loop {
+        let len = if is_leap_year(year) { 366 } else { 365 } * 86400;
+        if start + len > secs {
+            return start;
+        }
+        start += len;
+        year += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_clock_counts() {
+        let mut clk = IntervalClock::new();
+        clk.write_nicr(-1000i32 as u32);
+        clk.write_iccs(0, ICCS_XFR);
+        assert_eq!(clk.read_icr(500), -1000i32 as u32, "counting while stopped");
+
+        clk.write_iccs(500, ICCS_RUN | ICCS_IE);
+        assert_eq!(clk.read_icr(900), -600i32 as u32);
+        assert_eq!(clk.next_overflow_in(900), Some(600));
+        assert_eq!(clk.pending_interrupt(), None);
+
+        assert_eq!(clk.read_icr(1600), -900i32 as u32);
+        assert_eq!(clk.read_iccs(1600), ICCS_INT | ICCS_IE | ICCS_RUN);
+        assert_eq!(clk.pending_interrupt(), Some(Interrupt::new(IPL_CLOCK, vectors::INTERVAL_CLOCK)));
+        clk.acknowledge(vectors::INTERVAL_CLOCK);
+        assert_eq!(clk.pending_interrupt(), None);
+
+        // Overflowing again before INT is cleared is an error.
+        assert_eq!(clk.read_iccs(2600), ICCS_ERR | ICCS_INT | ICCS_IE | ICCS_RUN);
+        clk.write_iccs(2600, ICCS_ERR | ICCS_INT | ICCS_IE | ICCS_RUN);
+        assert_eq!(clk.read_iccs(2600), ICCS_IE | ICCS_RUN);
+        assert_eq!(clk.read_icr(2600), -900i32 as u32);
+    }
+
+    #[test]
+    fn interval_clock_missed_overflows() {
+        let mut clk = IntervalClock::new();
+        clk.write_nicr(-100i32 as u32);
+        clk.write_iccs(0, ICCS_XFR | ICCS_RUN);
+        assert_eq!(clk.read_icr(250), -50i32 as u32);
+        assert_eq!(clk.read_iccs(250), ICCS_ERR | ICCS_INT | ICCS_RUN);
+        assert_eq!(clk.pending_interrupt(), None, "interrupts disabled");
+    }
+
+    #[test]
+    fn interval_clock_single_step() {
+        let mut clk = IntervalClock::new();
+        clk.write_nicr(u32::MAX - 1);
+        clk.write_iccs(0, ICCS_XFR);
+        clk.write_iccs(0, ICCS_SGL);
+        assert_eq!(clk.read_icr(0), u32::MAX);
+        assert_eq!(clk.read_iccs(0), 0);
+        clk.write_iccs(0, ICCS_IE | ICCS_SGL);
+        assert_eq!(clk.read_iccs(0), ICCS_INT | ICCS_IE);
+        assert!(clk.pending_interrupt().is_some());
+    }
+
+    #[test]
+    fn time_of_year() {
+        let mut todr = TimeOfYear::new(TODR_BASE, 0);
+        assert_eq!(todr.read(25_000), TODR_BASE + 2);
+        todr.write(30_000, 7);
+        assert_eq!(todr.read(1_030_000), 107);
+
+        // 2001-03-01 is 59 days into a non-leap year.
+        assert_eq!(start_of_year(983_404_800), 978_307_200);
+        assert!(TimeOfYear::from_host(0).read(0) >= TODR_BASE);
+    }
+}
@@ -0,0 +1,374 @@
+//! The console terminal, reached through the RXCS/RXDB/TXCS/TXDB processor registers.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{
+    mpsc::{self, Receiver, TryRecvError},
+    Arc,
+    Mutex,
+};
+use std::thread;
+
+use crate::ervax::cpu::interrupts::{
+    vectors,
+    Interrupt,
+    InterruptSource,
+    IPL_CONSOLE,
+};
+
+/// RXCS/TXCS ready bit, also called done on the receive side.
+pub const CSR_READY: u32 = 0x80;
+/// RXCS/TXCS interrupt enable bit.
+pub const CSR_IE: u32 = 0x40;
+
+/// ^P, which stands in for the BREAK key as the way to halt the CPU from the terminal.
+pub const HALT_CHARACTER: u8 = 0x10;
+
+/// Where characters the guest transmits end up.
+pub trait ConsoleSink {
+    fn write_byte(&mut self, b: u8);
+}
+
+/// Where characters the guest receives come from. Must not block.
+pub trait ConsoleSource {
+    fn poll(&mut self) -> Option<u8>;
+}
+
+pub struct StdoutSink;
+
+impl ConsoleSink for StdoutSink {
+    fn write_byte(&mut self, b: u8) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        // There's nowhere to report a failure to, the guest has no way of knowing.
+        let _ = out.write_all(&[b]).and_then(|_| out.flush());
+    }
+}
+
+pub struct FileSink(File);
+
+impl FileSink {
+    pub fn create(path: &str) -> io::Result<FileSink> {
+        File::create(path).map(FileSink)
+    }
+}
+
+impl ConsoleSink for FileSink {
+    fn write_byte(&mut self, b: u8) {
+        let _ = self.0.write_all(&[b]);
+    }
+}
+
+/// Collects output in memory. Clones share the same buffer, so keep one to read it back.
+#[derive(Clone, Default)]
+pub struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Returns and clears everything written so far.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl ConsoleSink for MemorySink {
+    fn write_byte(&mut self, b: u8) {
+        self.0.lock().unwrap().push(b);
+    }
+}
+
+/// Bytes sent from elsewhere in the process.
+pub struct ChannelSource(Receiver<u8>);
+
+impl ChannelSource {
+    pub fn new(rx: Receiver<u8>) -> ChannelSource {
+        ChannelSource(rx)
+    }
+}
+
+impl ConsoleSource for ChannelSource {
+    fn poll(&mut self) -> Option<u8> {
+        match self.0.try_recv() {
+            Ok(b) => Some(b),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+/// Reads the host's stdin on a background thread, as stdin can't be polled portably.
+pub struct StdinSource(ChannelSource);
+
+impl StdinSource {
+    pub fn spawn() -> StdinSource {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            let mut stdin = io::stdin();
+            while let Ok(n @ 1..=64) = stdin.read(&mut buf) {
+                if buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                    break;
+                }
+            }
+        });
+        StdinSource(ChannelSource(rx))
+    }
+}
+
+impl ConsoleSource for StdinSource {
+    fn poll(&mut self) -> Option<u8> {
+        self.0.poll()
+    }
+}
+
+pub struct Console {
+    sink: Box<dyn ConsoleSink>,
+    source: Option<Box<dyn ConsoleSource>>,
+    /// Received characters not yet moved into RXDB.
+    input: VecDeque<u8>,
+
+    rxcs: u32,
+    rxdb: u32,
+    txcs: u32,
+
+    rx_int: bool,
+    tx_int: bool,
+
+    /// The character that asks for the CPU to halt instead of going to the guest, if any.
+    halt_character: Option<u8>,
+    halt_requested: bool,
+}
+
+impl Console {
+    pub fn new(sink: Box<dyn ConsoleSink>) -> Console {
+        Console {
+            sink,
+            source: None,
+            input: VecDeque::new(),
+            rxcs: 0,
+            rxdb: 0,
+            txcs: CSR_READY,
+            rx_int: false,
+            tx_int: false,
+            halt_character: None,
+            halt_requested: false,
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn ConsoleSink>) {
+        self.sink = sink;
+    }
+
+    pub fn set_source(&mut self, source: Option<Box<dyn ConsoleSource>>) {
+        self.source = source;
+    }
+
+    /// Queues host input for the guest.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        self.poll_input();
+    }
+
+    /// Makes `c` from the source ask for a halt, for whatever's playing the console program.
+    pub fn set_halt_character(&mut self, c: Option<u8>) {
+        self.halt_character = c;
+    }
+
+    /// Whether the halt character has come in since last asked.
+    pub fn take_halt_request(&mut self) -> bool {
+        std::mem::take(&mut self.halt_requested)
+    }
+
+    fn pull_source(&mut self) {
+        if let Some(source) = self.source.as_mut() {
+            while let Some(b) = source.poll() {
+                if Some(b) == self.halt_character {
+                    self.halt_requested = true;
+                } else {
+                    self.input.push_back(b);
+                }
+            }
+        }
+    }
+
+    /// Pulls in any input from the source and, if RXDB is free, moves the next character into it.
+    pub fn poll_input(&mut self) {
+        self.pull_source();
+        if self.rxcs & CSR_READY == 0 {
+            if let Some(b) = self.input.pop_front() {
+                self.rxdb = b as u32;
+                self.rxcs |= CSR_READY;
+                self.rx_int = self.rxcs & CSR_IE != 0;
+            }
+        }
+    }
+
+    /// The next character of input, bypassing RXDB, for the console program while the CPU is
+    /// halted.
+    pub fn take_input(&mut self) -> Option<u8> {
+        self.pull_source();
+        self.input.pop_front()
+    }
+
+    /// Writes to the terminal from the console program.
+    pub fn write_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.sink.write_byte(b);
+        }
+    }
+
+    /// Back to power-up state, throwing away pending input.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.rxcs = 0;
+        self.rxdb = 0;
+        self.txcs = CSR_READY;
+        self.rx_int = false;
+        self.tx_int = false;
+        self.halt_requested = false;
+    }
+
+    pub fn read_rxcs(&self) -> u32 {
+        self.rxcs
+    }
+
+    pub fn write_rxcs(&mut self, v: u32) {
+        let enabling = v & CSR_IE != 0 && self.rxcs & CSR_IE == 0;
+        self.rxcs = (self.rxcs & CSR_READY) | (v & CSR_IE);
+        if self.rxcs & CSR_IE == 0 {
+            self.rx_int = false;
+        } else if enabling && self.rxcs & CSR_READY != 0 {
+            self.rx_int = true;
+        }
+    }
+
+    /// Reading the data buffer frees it for the next character.
+    pub fn read_rxdb(&mut self) -> u32 {
+        let v = self.rxdb;
+        self.rxcs &= !CSR_READY;
+        self.rx_int = false;
+        self.poll_input();
+        v
+    }
+
+    pub fn read_txcs(&self) -> u32 {
+        self.txcs
+    }
+
+    pub fn write_txcs(&mut self, v: u32) {
+        let enabling = v & CSR_IE != 0 && self.txcs & CSR_IE == 0;
+        self.txcs = (self.txcs & CSR_READY) | (v & CSR_IE);
+        if self.txcs & CSR_IE == 0 {
+            self.tx_int = false;
+        } else if enabling && self.txcs & CSR_READY != 0 {
+            self.tx_int = true;
+        }
+    }
+
+    /// Transmits a character. Bits 11:8 select the console's own functions instead of the
+    /// terminal when nonzero, none of which are implemented, so those writes are dropped.
+    /// The character goes out immediately, so the transmitter is ready again right away.
+    pub fn write_txdb(&mut self, v: u32) {
+        if (v >> 8) & 0xF == 0 {
+            self.sink.write_byte(v as u8);
+        }
+        self.tx_int = self.txcs & CSR_IE != 0;
+    }
+}
+
+impl InterruptSource for Console {
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        if self.rx_int {
+            Some(Interrupt::new(IPL_CONSOLE, vectors::CONSOLE_RECEIVE))
+        } else if self.tx_int {
+            Some(Interrupt::new(IPL_CONSOLE, vectors::CONSOLE_TRANSMIT))
+        } else {
+            None
+        }
+    }
+
+    fn acknowledge(&mut self, vector: u16) {
+        match vector {
+            vectors::CONSOLE_RECEIVE => self.rx_int = false,
+            vectors::CONSOLE_TRANSMIT => self.tx_int = false,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn console() -> (Console, MemorySink) {
+        let sink = MemorySink::new();
+        (Console::new(Box::new(sink.clone())), sink)
+    }
+
+    #[test]
+    fn console_transmit() {
+        let (mut con, sink) = console();
+        assert_eq!(con.read_txcs(), CSR_READY);
+
+        for b in b"OK\r\n" {
+            con.write_txdb(*b as u32);
+        }
+        con.write_txdb(0xF02); // Console function, not a character.
+        assert_eq!(sink.take(), b"OK\r\n");
+        assert_eq!(con.pending_interrupt(), None);
+
+        con.write_txcs(CSR_IE);
+        assert_eq!(con.pending_interrupt(), Some(Interrupt::new(IPL_CONSOLE, vectors::CONSOLE_TRANSMIT)));
+        con.acknowledge(vectors::CONSOLE_TRANSMIT);
+        assert_eq!(con.pending_interrupt(), None);
+
+        con.write_txdb(b'A' as u32);
+        assert_eq!(con.pending_interrupt(), Some(Interrupt::new(IPL_CONSOLE, vectors::CONSOLE_TRANSMIT)));
+        con.write_txcs(0);
+        assert_eq!(con.pending_interrupt(), None);
+        assert_eq!(sink.contents(), b"A");
+    }
+
+    #[test]
+    fn console_receive() {
+        let (mut con, _) = console();
+        assert_eq!(con.read_rxcs(), 0);
+
+        con.push_input(b"hi");
+        assert_eq!(con.read_rxcs(), CSR_READY);
+        assert_eq!(con.pending_interrupt(), None);
+
+        // Enabling interrupts with a character waiting interrupts straight away.
+        con.write_rxcs(CSR_IE);
+        assert_eq!(con.pending_interrupt(), Some(Interrupt::new(IPL_CONSOLE, vectors::CONSOLE_RECEIVE)));
+        assert_eq!(con.read_rxdb(), b'h' as u32);
+
+        // The next character is loaded as soon as the buffer is read.
+        assert_eq!(con.read_rxcs(), CSR_READY | CSR_IE);
+        assert!(con.pending_interrupt().is_some());
+        con.acknowledge(vectors::CONSOLE_RECEIVE);
+        assert_eq!(con.pending_interrupt(), None);
+        assert_eq!(con.read_rxdb(), b'i' as u32);
+        assert_eq!(con.read_rxcs(), CSR_IE);
+    }
+
+    #[test]
+    fn console_channel_source() {
+        let (mut con, _) = console();
+        let (tx, rx) = mpsc::channel();
+        con.set_source(Some(Box::new(ChannelSource::new(rx))));
+
+        con.poll_input();
+        assert_eq!(con.read_rxcs(), 0);
+        tx.send(b'x').unwrap();
+        con.poll_input();
+        assert_eq!(con.read_rxdb(), b'x' as u32);
+    }
+}
@@ -0,0 +1,559 @@
+//! The DELQA Q-bus Ethernet controller, in the DEQNA compatible mode every driver knows.
+//!
+//! The host hands the controller lists of buffer descriptors in memory: one list to receive
+//! frames into, one of frames to transmit. Writing the high half of a list's address starts
+//! the controller on it, and it carries on down the list until it comes to a descriptor the
+//! host hasn't made valid, when it flags the list invalid and waits for the host to start it
+//! again. Which frames are received is set with a setup frame, transmitted like any other but
+//! holding the addresses to listen on, and looped back to the receive list instead of sent.
+//!
+//! Frames go out as soon as they're on the transmit list. Arriving frames wait in a queue
+//! until there's somewhere on the receive list to put them.
+
+use std::any::Any;
+use std::collections::VecDeque;
+
+use crate::ervax::devices::{
+    ethernet::{is_multicast, EthernetBackend, MacAddress, MAX_FRAME, MIN_FRAME},
+    iopage::{BusRequest, Dma, DmaError, IoPageDevice, BR4},
+};
+
+/// Where the first controller's registers usually are, 17774440 in the I/O page.
+pub const DEFAULT_BASE: u32 = 0o14440;
+pub const DEFAULT_VECTOR: u16 = 0o120;
+
+/// Register offsets. The first six words read as the station address, a byte each.
+pub const REG_RCLL: u32 = 4;
+pub const REG_RCLH: u32 = 6;
+pub const REG_XMTL: u32 = 8;
+pub const REG_XMTH: u32 = 10;
+pub const REG_VAR: u32 = 12;
+pub const REG_CSR: u32 = 14;
+
+/// CSR, a frame has been received.
+pub const CSR_RI: u16 = 0x8000;
+/// CSR, there's carrier, which with nothing to lose there always is.
+pub const CSR_CA: u16 = 0x2000;
+/// CSR, the fuse supplying the transceiver is good.
+pub const CSR_OK: u16 = 0x1000;
+/// CSR, sanity timer enable. The timer isn't modelled.
+pub const CSR_SE: u16 = 0x0400;
+pub const CSR_EL: u16 = 0x0200;
+/// CSR, internal loopback when clear.
+pub const CSR_IL: u16 = 0x0100;
+/// CSR, a frame has been transmitted, or the controller couldn't get at memory.
+pub const CSR_XI: u16 = 0x0080;
+pub const CSR_IE: u16 = 0x0040;
+/// CSR, the receive list needs starting again.
+pub const CSR_RL: u16 = 0x0020;
+/// CSR, the transmit list needs starting again.
+pub const CSR_XL: u16 = 0x0010;
+pub const CSR_BD: u16 = 0x0008;
+/// CSR, the controller couldn't get at memory.
+pub const CSR_NI: u16 = 0x0004;
+pub const CSR_SR: u16 = 0x0002;
+pub const CSR_RE: u16 = 0x0001;
+const CSR_WRITABLE: u16 = CSR_SE | CSR_EL | CSR_IL | CSR_IE | CSR_BD | CSR_SR | CSR_RE;
+/// Cleared by writing 1.
+const CSR_W1C: u16 = CSR_RI | CSR_XI | CSR_NI;
+
+/// VAR, the interrupt vector.
+pub const VAR_VECTOR: u16 = 0x03FC;
+/// VAR, DELQA rather than DEQNA mode select, and the host's identity bit.
+const VAR_WRITABLE: u16 = 0x8001 | VAR_VECTOR;
+
+/// Descriptor address word: valid.
+pub const DESC_V: u16 = 0x8000;
+/// Descriptor address word: chain, the address is where the list carries on.
+pub const DESC_C: u16 = 0x4000;
+/// Descriptor address word: transmit, the last buffer of the frame.
+pub const DESC_E: u16 = 0x2000;
+/// Descriptor address word: transmit, the frame is a setup frame.
+pub const DESC_S: u16 = 0x1000;
+/// Descriptor address word: transmit, the buffer ends on a low byte.
+pub const DESC_L: u16 = 0x0080;
+/// Descriptor address word: the buffer starts on a high byte.
+pub const DESC_H: u16 = 0x0040;
+const DESC_ADDRESS_HIGH: u16 = 0x003F;
+/// Written to a descriptor's flag word when the controller takes it.
+pub const FLAG_USED: u16 = 0xFFFF;
+pub const DESCRIPTOR_BYTES: u32 = 12;
+
+/// Status word 1: the frame carries on in the next buffer.
+pub const STATUS_LASTNOT: u16 = 0x8000;
+/// Status word 1: an error, or on receive with LASTNOT, just that the buffer was used.
+pub const STATUS_ERROR_USED: u16 = 0x4000;
+/// Status word 1, receive: a setup frame or loopback.
+pub const STATUS_ESETUP: u16 = 0x2000;
+/// Status word 1, transmit: the frame was abandoned.
+pub const STATUS_ABORT: u16 = 0x0200;
+/// Status word 1, receive: bits 10:8 of the received byte length.
+const STATUS_RBL_HIGH: u16 = 0x0700;
+
+/// Frames waiting for room on the receive list. More are dropped.
+pub const RECEIVE_QUEUE: usize = 32;
+/// Addresses a setup frame holds.
+pub const SETUP_ADDRESSES: usize = 14;
+
+struct Frame {
+    data: Vec<u8>,
+    /// A looped back setup frame, which has its own status, and isn't filtered.
+    setup: bool,
+}
+
+/// A buffer on a list.
+struct Descriptor {
+    /// Where the descriptor is.
+    at: u32,
+    bits: u16,
+    addr: u32,
+    len: usize,
+}
+
+pub struct Delqa {
+    base: u32,
+    mac: MacAddress,
+    backend: Box<dyn EthernetBackend>,
+
+    csr: u16,
+    var: u16,
+    rcll: u16,
+    rclh: u16,
+    xmtl: u16,
+    xmth: u16,
+    /// The next descriptors on each list.
+    rcv_list: u32,
+    xmt_list: u32,
+    /// Frames the host should run the transmit list for.
+    xmt_pending: bool,
+    queue: VecDeque<Frame>,
+
+    /// From the last setup frame.
+    filter: Vec<MacAddress>,
+    all_multicast: bool,
+    promiscuous: bool,
+    polled_at: u64,
+}
+
+impl Delqa {
+    /// A controller with its registers `base` bytes into the I/O page and station address
+    /// `mac`, on the Ethernet `backend` reaches.
+    pub fn new(base: u32, mac: MacAddress, backend: Box<dyn EthernetBackend>) -> Delqa {
+        let mut delqa = Delqa {
+            base,
+            mac,
+            backend,
+            csr: 0,
+            var: 0,
+            rcll: 0,
+            rclh: 0,
+            xmtl: 0,
+            xmth: 0,
+            rcv_list: 0,
+            xmt_list: 0,
+            xmt_pending: false,
+            queue: VecDeque::new(),
+            filter: vec![],
+            all_multicast: false,
+            promiscuous: false,
+            polled_at: 0,
+        };
+        delqa.power_up();
+        delqa
+    }
+
+    #[inline]
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn power_up(&mut self) {
+        self.var = 0;
+        self.initialize();
+    }
+
+    /// A software reset: both lists stop, and what was waiting to be received is lost.
+    fn initialize(&mut self) {
+        self.csr = CSR_RL | CSR_XL | CSR_OK | CSR_CA;
+        self.xmt_pending = false;
+        self.queue.clear();
+        self.filter.clear();
+        self.all_multicast = false;
+        self.promiscuous = false;
+    }
+
+    fn vector(&self) -> u16 {
+        self.var & VAR_VECTOR
+    }
+
+    fn write_csr(&mut self, v: u16, mask: u16) {
+        let reset = v & mask & CSR_SR != 0 && self.csr & CSR_SR == 0;
+        self.csr &= !(v & mask & CSR_W1C);
+        let writable = mask & CSR_WRITABLE;
+        self.csr = (self.csr & !writable) | (v & writable);
+        if reset {
+            let kept = self.csr & CSR_WRITABLE;
+            self.initialize();
+            self.csr |= kept;
+        }
+    }
+
+    /// Takes the next valid buffer on the list at `list`, following chains, None if the list
+    /// has run out.
+    fn next_descriptor(list: &mut u32, dma: &mut dyn Dma) -> Result<Option<Descriptor>, DmaError> {
+        // A list of nothing but chains would keep the controller forever, so give up after a
+        // while, as though it had run out.
+        for _ in 0..256 {
+            let at = *list;
+            let bits = dma.read_u16(at + 2)?;
+            let addr = ((bits & DESC_ADDRESS_HIGH) as u32) << 16 | dma.read_u16(at + 4)? as u32;
+            if bits & DESC_V == 0 {
+                return Ok(None);
+            }
+            if bits & DESC_C != 0 {
+                *list = addr & !1;
+                continue;
+            }
+            let words = (dma.read_u16(at + 6)? as i16).wrapping_neg() as u16 as usize;
+            dma.write_u16(at, FLAG_USED)?;
+            *list = at + DESCRIPTOR_BYTES;
+            return Ok(Some(Descriptor { at, bits, addr, len: words * 2 }));
+        }
+        Ok(None)
+    }
+
+    fn write_status(d: &Descriptor, status1: u16, status2: u16, dma: &mut dyn Dma) -> Result<(), DmaError> {
+        dma.write_u16(d.at + 8, status1)?;
+        dma.write_u16(d.at + 10, status2)
+    }
+
+    /// Sends everything on the transmit list. A frame longer than Ethernet allows is dropped,
+    /// with an error in the status of its last buffer.
+    fn transmit(&mut self, dma: &mut dyn Dma) -> Result<(), DmaError> {
+        let mut frame = vec![];
+        let mut buffers = vec![];
+        let mut too_long = false;
+        loop {
+            let Some(d) = Delqa::next_descriptor(&mut self.xmt_list, dma)? else {
+                self.csr |= CSR_XL;
+                return Ok(());
+            };
+            let (mut addr, mut len) = (d.addr, d.len);
+            if d.bits & DESC_H != 0 {
+                addr += 1;
+                len = len.saturating_sub(1);
+            }
+            if d.bits & DESC_L != 0 {
+                len = len.saturating_sub(1);
+            }
+            let start = frame.len();
+            too_long |= start + len > MAX_FRAME;
+            if !too_long {
+                frame.resize(start + len, 0);
+                dma.read(addr, &mut frame[start..])?;
+            }
+
+            if d.bits & DESC_E == 0 {
+                buffers.push(d);
+                continue;
+            }
+            for b in buffers.drain(..) {
+                Delqa::write_status(&b, STATUS_LASTNOT, 0, dma)?;
+            }
+            if too_long {
+                too_long = false;
+                frame.clear();
+                Delqa::write_status(&d, STATUS_ERROR_USED | STATUS_ABORT, 0, dma)?;
+                self.csr |= CSR_XI;
+                continue;
+            }
+            if d.bits & DESC_S != 0 {
+                self.setup(&frame);
+                self.loop_back(Frame { data: std::mem::take(&mut frame), setup: true });
+            } else {
+                frame.resize(frame.len().max(MIN_FRAME), 0);
+                if self.csr & CSR_IL == 0 || self.csr & CSR_EL != 0 {
+                    self.loop_back(Frame { data: std::mem::take(&mut frame), setup: false });
+                } else {
+                    self.backend.send(&frame);
+                    frame.clear();
+                }
+            }
+            Delqa::write_status(&d, 0, 0, dma)?;
+            self.csr |= CSR_XI;
+        }
+    }
+
+    fn loop_back(&mut self, frame: Frame) {
+        if self.queue.len() < RECEIVE_QUEUE {
+            self.queue.push_back(frame);
+        }
+    }
+
+    /// Takes the addresses to listen on, and the modes, from a setup frame. The addresses are
+    /// down the columns of two 8 by 8 blocks of bytes, the first column of each unused.
+    fn setup(&mut self, frame: &[u8]) {
+        let byte = |i: usize| frame.get(i).copied();
+        self.filter = (0..SETUP_ADDRESSES)
+            .filter_map(|n| {
+                let first = if n < 7 { 1 + n } else { 0o101 + n - 7 };
+                let mut mac = [0; 6];
+                for (j, b) in mac.iter_mut().enumerate() {
+                    *b = byte(first + 8 * j)?;
+                }
+                Some(mac)
+            })
+            .collect();
+        // A longer frame sets the modes with the low bits of its length.
+        let long = frame.len() > 128;
+        self.all_multicast = long && frame.len() & 1 != 0;
+        self.promiscuous = long && frame.len() & 2 != 0;
+    }
+
+    fn accepts(&self, frame: &[u8]) -> bool {
+        let dest = &frame[..6];
+        self.promiscuous
+            || (self.all_multicast && is_multicast(dest))
+            || self.filter.iter().any(|a| a == dest)
+    }
+
+    /// Puts a frame in buffers on the receive list, false if there wasn't one to start it in.
+    /// A frame that runs off the end of the list is lost.
+    fn deliver(&mut self, frame: &Frame, dma: &mut dyn Dma) -> Result<bool, DmaError> {
+        let len = frame.data.len();
+        let rbl = if frame.setup { len } else { len - MIN_FRAME } as u16;
+        let mut done = 0;
+        loop {
+            let Some(d) = Delqa::next_descriptor(&mut self.rcv_list, dma)? else {
+                self.csr |= CSR_RL;
+                return Ok(done > 0);
+            };
+            let start = if d.bits & DESC_H != 0 { 1 } else { 0 };
+            let n = d.len.saturating_sub(start).min(len - done);
+            dma.write(d.addr + start as u32, &frame.data[done..done + n])?;
+            done += n;
+
+            let mut status1 = rbl & STATUS_RBL_HIGH;
+            if frame.setup {
+                status1 |= STATUS_ESETUP;
+            }
+            if done < len {
+                status1 |= STATUS_LASTNOT | STATUS_ERROR_USED;
+            }
+            Delqa::write_status(&d, status1, (rbl & 0xFF) * 0x101, dma)?;
+            if done == len {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn receive(&mut self, dma: &mut dyn Dma) -> Result<(), DmaError> {
+        while self.csr & (CSR_RE | CSR_RL) == CSR_RE {
+            let Some(frame) = self.queue.pop_front() else { return Ok(()) };
+            if self.deliver(&frame, dma)? {
+                self.csr |= CSR_RI;
+            } else {
+                self.queue.push_front(frame);
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings in what's arrived from outside, keeping what the setup frame says to.
+    fn poll_backend(&mut self) {
+        while let Some(mut data) = self.backend.poll() {
+            if data.len() < 14 || self.csr & CSR_RE == 0 || !self.accepts(&data) {
+                continue;
+            }
+            data.resize(data.len().max(MIN_FRAME), 0);
+            self.loop_back(Frame { data, setup: false });
+        }
+    }
+}
+
+impl IoPageDevice for Delqa {
+    fn name(&self) -> &str {
+        "DELQA"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        16
+    }
+
+    fn read(&mut self, offset: u32) -> u16 {
+        match offset {
+            REG_VAR => self.var,
+            REG_CSR => self.csr,
+            _ => 0xFF00 | self.mac[offset as usize / 2] as u16,
+        }
+    }
+
+    fn write(&mut self, offset: u32, v: u16, mask: u16) {
+        let merge = |r: u16| (r & !mask) | (v & mask);
+        match offset {
+            REG_RCLL => self.rcll = merge(self.rcll),
+            REG_RCLH => {
+                self.rclh = merge(self.rclh);
+                self.rcv_list = ((self.rclh as u32 & 0x3F) << 16 | self.rcll as u32) & !1;
+                self.csr &= !CSR_RL;
+            }
+            REG_XMTL => self.xmtl = merge(self.xmtl),
+            REG_XMTH => {
+                self.xmth = merge(self.xmth);
+                self.xmt_list = ((self.xmth as u32 & 0x3F) << 16 | self.xmtl as u32) & !1;
+                self.csr &= !CSR_XL;
+                self.xmt_pending = true;
+            }
+            REG_VAR => self.var = (self.var & !(mask & VAR_WRITABLE)) | (v & mask & VAR_WRITABLE),
+            REG_CSR => self.write_csr(v, mask),
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.power_up();
+    }
+
+    fn interrupt_request(&self) -> Option<BusRequest> {
+        if self.csr & CSR_IE != 0 && self.csr & (CSR_RI | CSR_XI) != 0 {
+            Some(BusRequest::new(BR4, self.vector()))
+        } else {
+            None
+        }
+    }
+
+    fn service(&mut self, now_us: u64, dma: &mut dyn Dma) {
+        if now_us != self.polled_at {
+            self.polled_at = now_us;
+            self.poll_backend();
+        }
+        let mut result = Ok(());
+        if std::mem::take(&mut self.xmt_pending) {
+            result = self.transmit(dma);
+        }
+        if let Err(DmaError::NonExistent(_)) = result.and_then(|_| self.receive(dma)) {
+            self.csr |= CSR_NI | CSR_XI;
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::VAXBus, interrupts::{Interrupt, InterruptSource}},
+        devices::{
+            ethernet::{Hub, BROADCAST},
+            uqssp::tests::{machine, DATA, IO_BASE},
+        },
+    };
+
+    const MAC: MacAddress = [0x08, 0x00, 0x2B, 0x01, 0x02, 0x03];
+    const CSR: u32 = IO_BASE + DEFAULT_BASE + REG_CSR;
+    const RCV: u32 = 0x1000;
+    const XMT: u32 = 0x1100;
+
+    /// Puts a descriptor for `len` bytes at `addr` at `at`.
+    fn descriptor(bus: &mut VAXBus, at: u32, bits: u16, addr: u32, len: usize) {
+        bus.write_u16(at, 0x8000).unwrap();
+        bus.write_u16(at + 2, DESC_V | bits | (addr >> 16) as u16).unwrap();
+        bus.write_u16(at + 4, addr as u16).unwrap();
+        bus.write_u16(at + 6, (-((len as i16 + 1) / 2)) as u16).unwrap();
+        bus.write_u16(at + 8, 0).unwrap();
+        bus.write_u16(at + 12 + 2, 0).unwrap();
+    }
+
+    fn start(bus: &mut VAXBus, reg: u32, list: u32) {
+        bus.write_u16(IO_BASE + DEFAULT_BASE + reg, list as u16).unwrap();
+        bus.write_u16(IO_BASE + DEFAULT_BASE + reg + 2, (list >> 16) as u16).unwrap();
+    }
+
+    fn status(bus: &mut VAXBus, at: u32) -> (u16, u16, u16) {
+        (bus.read_u16(at).unwrap(), bus.read_u16(at + 8).unwrap(), bus.read_u16(at + 10).unwrap())
+    }
+
+    #[test]
+    fn delqa_frames() {
+        let hub = Hub::new();
+        let mut far = hub.port();
+        let mut bus = machine(Box::new(Delqa::new(DEFAULT_BASE, MAC, Box::new(hub.port()))));
+        let reg = |r: u32| IO_BASE + DEFAULT_BASE + r;
+
+        assert_eq!(bus.read_u16(reg(0)), Ok(0xFF08));
+        assert_eq!(bus.read_u16(reg(10)), Ok(0xFF03));
+        assert_eq!(bus.read_u16(CSR), Ok(CSR_RL | CSR_XL | CSR_OK | CSR_CA));
+        bus.write_u16(reg(REG_VAR), 0x8000 | 0o154).unwrap();
+        bus.write_u16(CSR, CSR_IL | CSR_IE | CSR_RE).unwrap();
+
+        // A setup frame listening for our own address and broadcast, looped back.
+        let mut setup = [0; 128];
+        for j in 0..6 {
+            setup[1 + 8 * j] = MAC[j];
+            setup[2 + 8 * j] = BROADCAST[j];
+        }
+        bus.write_bytes(DATA, &setup).unwrap();
+        descriptor(&mut bus, XMT, DESC_E | DESC_S, DATA, 128);
+        descriptor(&mut bus, RCV, 0, DATA + 0x400, 256);
+        start(&mut bus, REG_RCLL, RCV);
+        start(&mut bus, REG_XMTL, XMT);
+        assert_eq!(status(&mut bus, XMT), (FLAG_USED, 0, 0));
+        assert_eq!(status(&mut bus, RCV), (FLAG_USED, STATUS_ESETUP, 0x8080));
+        assert_eq!(bus.read_u16(CSR).map(|c| c & (CSR_RI | CSR_XI | CSR_XL | CSR_RL)), Ok(CSR_RI | CSR_XI | CSR_XL));
+        assert_eq!(bus.pending_interrupt(), Some(Interrupt::new(0x14, 0x200 + 0o154)));
+        bus.write_u16(CSR, CSR_RI | CSR_XI | CSR_IL | CSR_IE | CSR_RE).unwrap();
+        assert_eq!(bus.pending_interrupt(), None);
+        assert!(far.poll().is_none());
+
+        // A frame in two buffers goes out padded.
+        let frame: Vec<u8> = [&BROADCAST[..], &MAC[..], &[0x60, 0x03, b'h', b'i']].concat();
+        bus.write_bytes(DATA, &frame[..6]).unwrap();
+        bus.write_bytes(DATA + 0x100, &frame[6..]).unwrap();
+        descriptor(&mut bus, XMT, 0, DATA, 6);
+        descriptor(&mut bus, XMT + 12, DESC_E, DATA + 0x100, 10);
+        start(&mut bus, REG_XMTL, XMT);
+        assert_eq!((status(&mut bus, XMT), status(&mut bus, XMT + 12)), ((FLAG_USED, STATUS_LASTNOT, 0), (FLAG_USED, 0, 0)));
+        let sent = far.poll().unwrap();
+        assert_eq!((sent.len(), &sent[..16]), (MIN_FRAME, &frame[..]));
+
+        // Frames for somebody else are ignored, ours wait for a receive buffer.
+        far.send(&[&[0x08, 0, 0x2B, 9, 9, 9][..], &[0; 60]].concat());
+        let long: Vec<u8> = [&MAC[..], &[0x55; 94][..]].concat();
+        far.send(&long);
+        bus.set_time(10_000);
+        assert_eq!(bus.read_u16(CSR).map(|c| c & (CSR_RI | CSR_RL)), Ok(CSR_RL));
+        descriptor(&mut bus, RCV, 0, DATA + 0x400, 64);
+        descriptor(&mut bus, RCV + 12, 0, DATA + 0x800, 64);
+        start(&mut bus, REG_RCLL, RCV);
+        let rbl = 100 - MIN_FRAME as u16;
+        assert_eq!(status(&mut bus, RCV), (FLAG_USED, STATUS_LASTNOT | STATUS_ERROR_USED, rbl * 0x101));
+        assert_eq!(status(&mut bus, RCV + 12), (FLAG_USED, 0, rbl * 0x101));
+        assert_eq!((bus.read_u8(DATA + 0x400 + 5), bus.read_u8(DATA + 0x800 + 35)), (Ok(3), Ok(0x55)));
+        assert_eq!(bus.read_u16(CSR).map(|c| c & (CSR_RI | CSR_RL)), Ok(CSR_RI));
+
+        // A frame too long for Ethernet, from buffers that all claim the same memory, is dropped.
+        for i in 0..4 {
+            descriptor(&mut bus, XMT + 12 * i, if i == 3 { DESC_E } else { 0 }, DATA, 512);
+        }
+        start(&mut bus, REG_XMTL, XMT);
+        assert_eq!(status(&mut bus, XMT + 24), (FLAG_USED, STATUS_LASTNOT, 0));
+        assert_eq!(status(&mut bus, XMT + 36), (FLAG_USED, STATUS_ERROR_USED | STATUS_ABORT, 0));
+        assert!(far.poll().is_none());
+
+        // Transmitting from memory that isn't there.
+        descriptor(&mut bus, XMT, DESC_E, 0x3F_0000, 64);
+        start(&mut bus, REG_XMTL, XMT);
+        assert_eq!(bus.read_u16(CSR).map(|c| c & (CSR_NI | CSR_XI)), Ok(CSR_NI | CSR_XI));
+
+        // A software reset stops both lists.
+        bus.write_u16(CSR, CSR_SR | CSR_IL).unwrap();
+        assert_eq!(bus.read_u16(CSR), Ok(CSR_SR | CSR_IL | CSR_RL | CSR_XL | CSR_OK | CSR_CA));
+    }
+}
@@ -0,0 +1,211 @@
+//! Disk drives: their geometries, and the host storage behind them.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// What a drive looks like to an MSCP host: its size and layout, and what it calls itself.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub name: &'static str,
+    /// Logical blocks available to the host.
+    pub lbns: u32,
+    /// Sectors per track.
+    pub track: u16,
+    /// Tracks per group.
+    pub group: u16,
+    /// Groups per cylinder.
+    pub cylinder: u16,
+    /// Blocks in one copy of the replacement and caching table.
+    pub rct_size: u16,
+    pub rct_copies: u8,
+    /// Replacement blocks per track.
+    pub rbns: u8,
+    /// MSCP drive model number.
+    pub model: u8,
+    /// MSCP media type identifier.
+    pub media: u32,
+}
+
+/// A media type identifier: two letters for the device name, up to three for the drive
+/// type, and its number.
+pub(crate) const fn media_id(dev: [u8; 2], kind: [u8; 3], n: u32) -> u32 {
+    const fn letter(c: u8) -> u32 {
+        if c == 0 { 0 } else { (c - b'@') as u32 }
+    }
+    letter(dev[0]) << 27 | letter(dev[1]) << 22 | letter(kind[0]) << 17 | letter(kind[1]) << 12
+        | letter(kind[2]) << 7 | n
+}
+
+pub const RD53: Geometry = Geometry {
+    name: "RD53", lbns: 138_672, track: 17, group: 8, cylinder: 1,
+    rct_size: 280, rct_copies: 1, rbns: 0, model: 9, media: media_id(*b"DU", *b"RD\0", 53),
+};
+pub const RD54: Geometry = Geometry {
+    name: "RD54", lbns: 311_200, track: 17, group: 15, cylinder: 1,
+    rct_size: 609, rct_copies: 1, rbns: 0, model: 13, media: media_id(*b"DU", *b"RD\0", 54),
+};
+pub const RA81: Geometry = Geometry {
+    name: "RA81", lbns: 891_072, track: 51, group: 14, cylinder: 1,
+    rct_size: 2856, rct_copies: 1, rbns: 1, model: 5, media: media_id(*b"DU", *b"RA\0", 81),
+};
+pub const RA82: Geometry = Geometry {
+    name: "RA82", lbns: 1_216_665, track: 57, group: 15, cylinder: 1,
+    rct_size: 3420, rct_copies: 1, rbns: 1, model: 11, media: media_id(*b"DU", *b"RA\0", 82),
+};
+pub const RA90: Geometry = Geometry {
+    name: "RA90", lbns: 2_376_153, track: 69, group: 13, cylinder: 1,
+    rct_size: 1794, rct_copies: 1, rbns: 1, model: 19, media: media_id(*b"DU", *b"RA\0", 90),
+};
+
+pub const GEOMETRIES: &[Geometry] = &[RD53, RD54, RA81, RA82, RA90];
+
+impl Geometry {
+    /// Looks a drive type up by name, ignoring case.
+    pub fn by_name(name: &str) -> Option<Geometry> {
+        GEOMETRIES.iter().copied().find(|g| g.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Bytes of storage the host can get at.
+    pub fn bytes(&self) -> u64 {
+        self.lbns as u64 * BLOCK_SIZE as u64
+    }
+}
+
+/// Somewhere to keep a disk's blocks.
+pub trait BlockStore {
+    /// Reads at `offset` bytes in. Anything never written reads as zeros.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+}
+
+/// A raw image file on the host, one block after another.
+pub struct FileStore(File);
+
+impl BlockStore for FileStore {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        // A file shorter than the drive just hasn't had the rest written yet.
+        let mut done = 0;
+        while done < buf.len() {
+            match self.0.read(&mut buf[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buf[done..].fill(0);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        self.0.write_all(data)
+    }
+}
+
+/// Blocks kept in memory, starting out zeroed.
+#[derive(Default)]
+pub struct MemoryStore(Vec<u8>);
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = (offset as usize).min(self.0.len());
+        let n = (self.0.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&self.0[start..start + n]);
+        buf[n..].fill(0);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset as usize + data.len();
+        if self.0.len() < end {
+            self.0.resize(end, 0);
+        }
+        self.0[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A drive with its storage.
+pub struct Disk {
+    geometry: Geometry,
+    store: Box<dyn BlockStore>,
+    read_only: bool,
+}
+
+impl Disk {
+    pub fn new(geometry: Geometry, store: Box<dyn BlockStore>, read_only: bool) -> Disk {
+        Disk { geometry, store, read_only }
+    }
+
+    /// A drive backed by the image file at `path`. A missing image is created, unless the drive
+    /// is read only.
+    pub fn open(path: &str, geometry: Geometry, read_only: bool) -> io::Result<Disk> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(path)?;
+        Ok(Disk::new(geometry, Box::new(FileStore(file)), read_only))
+    }
+
+    #[inline]
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn read_blocks(&mut self, lbn: u32, buf: &mut [u8]) -> io::Result<()> {
+        self.store.read_at(lbn as u64 * BLOCK_SIZE as u64, buf)
+    }
+
+    pub fn write_blocks(&mut self, lbn: u32, data: &[u8]) -> io::Result<()> {
+        self.store.write_at(lbn as u64 * BLOCK_SIZE as u64, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_image_file() {
+        assert_eq!(RD54.media, 0x2564_4036);
+        assert_eq!(Geometry::by_name("ra81"), Some(RA81));
+
+        let path = std::env::temp_dir().join(format!("erodedvax-disk-{}.img", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        assert!(Disk::open(path, RD53, true).is_err());
+
+        // A new image starts out empty, and reads past what's been written as zeros.
+        let mut disk = Disk::open(path, RD53, false).unwrap();
+        let mut block = [0xFF; BLOCK_SIZE * 2];
+        disk.read_blocks(10, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 0));
+        disk.write_blocks(10, &[0xA5; BLOCK_SIZE]).unwrap();
+        drop(disk);
+
+        let mut disk = Disk::open(path, RD53, true).unwrap();
+        disk.read_blocks(10, &mut block).unwrap();
+        assert_eq!((block[0], block[BLOCK_SIZE - 1], block[BLOCK_SIZE]), (0xA5, 0xA5, 0));
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 11 * BLOCK_SIZE as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+}
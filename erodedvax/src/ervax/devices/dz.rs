@@ -0,0 +1,404 @@
+//! The DZ asynchronous serial line multiplexer, and chips compatible with it such as the DC7085.
+//! This is the register interface shared by every variant. Where the registers sit and how the
+//! interrupts reach the CPU is up to whatever the DZ is attached to.
+
+use std::collections::VecDeque;
+
+use crate::ervax::devices::{
+    console::{ConsoleSink, ConsoleSource},
+    serial::{ConsoleLine, SerialEndpoint},
+};
+
+/// Register numbers, in the order the registers sit in address space.
+pub const REG_CSR: usize = 0;
+/// RBUF when read, LPR when written.
+pub const REG_RBUF: usize = 1;
+pub const REG_LPR: usize = 1;
+pub const REG_TCR: usize = 2;
+/// MSR when read, TDR when written.
+pub const REG_MSR: usize = 3;
+pub const REG_TDR: usize = 3;
+pub const REGISTERS: usize = 4;
+
+/// CSR, a line is ready for a character in TDR.
+pub const CSR_TRDY: u16 = 0x8000;
+pub const CSR_TIE: u16 = 0x4000;
+/// CSR, the silo has filled up to the alarm level.
+pub const CSR_SA: u16 = 0x2000;
+pub const CSR_SAE: u16 = 0x1000;
+/// CSR, the line TRDY is for.
+pub const CSR_TLINE: u16 = 0x0700;
+/// CSR, there's a character in the silo.
+pub const CSR_RDONE: u16 = 0x0080;
+pub const CSR_RIE: u16 = 0x0040;
+/// CSR, master scan enable. Nothing is received or transmitted without it.
+pub const CSR_MSE: u16 = 0x0020;
+pub const CSR_CLR: u16 = 0x0010;
+pub const CSR_MAINT: u16 = 0x0008;
+const CSR_WRITABLE: u16 = CSR_TIE | CSR_SAE | CSR_RIE | CSR_MSE | CSR_MAINT;
+
+/// RBUF, the character is valid.
+pub const RBUF_DVAL: u16 = 0x8000;
+/// RBUF, the silo overflowed and characters were lost before this one.
+pub const RBUF_OERR: u16 = 0x4000;
+
+/// TCR, the high byte: each line's data terminal ready. Dropping it hangs the line up.
+pub const TCR_DTR: u16 = 0xFF00;
+
+/// LPR, the line's receiver is on.
+pub const LPR_RXON: u16 = 0x1000;
+pub const LPR_LINE: u16 = 0x0007;
+
+/// Characters the silo holds.
+pub const SILO_SIZE: usize = 64;
+/// Characters in the silo that set SA.
+pub const SILO_ALARM: usize = 16;
+
+/// The host end of one serial line, and its modem signals.
+#[derive(Default)]
+pub struct DzLine {
+    endpoint: Option<Box<dyn SerialEndpoint>>,
+    rx_on: bool,
+    /// Somebody was connected at the last poll.
+    carrier: bool,
+    /// Somebody connected while DTR was down, and hasn't been answered yet.
+    ring: bool,
+}
+
+impl DzLine {
+    pub fn is_connected(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    pub fn has_carrier(&self) -> bool {
+        self.carrier
+    }
+
+    pub fn is_ringing(&self) -> bool {
+        self.ring
+    }
+}
+
+pub struct Dz {
+    lines: Vec<DzLine>,
+    csr: u16,
+    tcr: u16,
+    silo: VecDeque<u16>,
+    /// Characters were lost since the last one put in the silo.
+    overrun: bool,
+    /// Where the transmit scanner looks for a ready line next.
+    scan: usize,
+}
+
+impl Dz {
+    /// A DZ with `lines` lines, 4 or 8, none of them connected.
+    pub fn new(lines: usize) -> Dz {
+        assert!(lines == 4 || lines == 8);
+        Dz {
+            lines: (0..lines).map(|_| DzLine::default()).collect(),
+            csr: 0,
+            tcr: 0,
+            silo: VecDeque::new(),
+            overrun: false,
+            scan: 0,
+        }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn line(&self, n: usize) -> &DzLine {
+        &self.lines[n]
+    }
+
+    /// Connects line `n` to the host. Output on lines without a sink is thrown away.
+    pub fn connect(&mut self, n: usize, sink: Option<Box<dyn ConsoleSink>>, source: Option<Box<dyn ConsoleSource>>) {
+        if sink.is_none() && source.is_none() {
+            let line = &mut self.lines[n];
+            line.endpoint = None;
+            line.carrier = false;
+            line.ring = false;
+        } else {
+            self.attach(n, Box::new(ConsoleLine::new(sink, source)));
+        }
+    }
+
+    /// Puts `endpoint` at the host end of line `n`.
+    pub fn attach(&mut self, n: usize, mut endpoint: Box<dyn SerialEndpoint>) {
+        let line = &mut self.lines[n];
+        line.carrier = endpoint.carrier();
+        line.ring = false;
+        line.endpoint = Some(endpoint);
+    }
+
+    fn dtr(&self, n: usize) -> bool {
+        self.tcr & (0x100 << n) != 0
+    }
+
+    /// Receives `bytes` on line `n`, as though the host had sent them.
+    pub fn push_input(&mut self, n: usize, bytes: &[u8]) {
+        for &b in bytes {
+            self.receive(n, b);
+        }
+    }
+
+    /// Follows connections coming and going, and pulls in whatever input the lines have.
+    pub fn poll_input(&mut self) {
+        for n in 0..self.lines.len() {
+            let dtr = self.dtr(n);
+            let line = &mut self.lines[n];
+            let Some(endpoint) = line.endpoint.as_mut() else { continue };
+            let carrier = endpoint.carrier();
+            line.ring = carrier && !dtr && (line.ring || !line.carrier);
+            line.carrier = carrier;
+            while let Some(b) = self.lines[n].endpoint.as_mut().and_then(|e| e.poll()) {
+                self.receive(n, b);
+            }
+        }
+    }
+
+    /// Sets TCR, hanging up the lines whose DTR went down.
+    fn set_tcr(&mut self, tcr: u16) {
+        let dropped = self.tcr & !tcr & TCR_DTR;
+        self.tcr = tcr;
+        for (n, line) in self.lines.iter_mut().enumerate() {
+            if dropped & (0x100 << n) != 0 {
+                if let Some(endpoint) = line.endpoint.as_mut() {
+                    endpoint.hang_up();
+                    line.carrier = endpoint.carrier();
+                }
+            }
+            if tcr & (0x100 << n) != 0 {
+                line.ring = false;
+            }
+        }
+    }
+
+    fn receive(&mut self, n: usize, b: u8) {
+        if self.csr & CSR_MSE == 0 || !self.lines[n].rx_on {
+            return;
+        }
+        if self.silo.len() == SILO_SIZE {
+            self.overrun = true;
+            return;
+        }
+        let oerr = if self.overrun { RBUF_OERR } else { 0 };
+        self.overrun = false;
+        self.silo.push_back(RBUF_DVAL | oerr | (n as u16) << 8 | b as u16);
+        if self.silo.len() >= SILO_ALARM && self.csr & CSR_SAE != 0 {
+            self.csr |= CSR_SA;
+        }
+    }
+
+    /// Back to power-up state, as IORESET or setting CLR does. DTR goes down, so connected
+    /// lines hang up.
+    pub fn reset(&mut self) {
+        self.csr = 0;
+        self.set_tcr(0);
+        self.silo.clear();
+        self.overrun = false;
+        self.scan = 0;
+        self.lines.iter_mut().for_each(|l| l.rx_on = false);
+    }
+
+    /// The line the transmit scanner stops at, one with its TCR enable set. Transmission is
+    /// instant, so every enabled line is always ready.
+    fn ready_line(&self) -> Option<usize> {
+        if self.csr & CSR_MSE == 0 {
+            return None;
+        }
+        let n = self.lines.len();
+        (0..n).map(|i| (self.scan + i) % n).find(|&l| self.tcr & (1 << l) != 0)
+    }
+
+    fn csr(&self) -> u16 {
+        let mut csr = self.csr;
+        if let Some(line) = self.ready_line() {
+            csr |= CSR_TRDY | (line as u16) << 8;
+        }
+        if !self.silo.is_empty() {
+            csr |= CSR_RDONE;
+        }
+        csr
+    }
+
+    /// The receiver wants attention: a character is waiting, or with the alarm enabled, the
+    /// silo has filled up to the alarm level.
+    pub fn rx_interrupt(&self) -> bool {
+        let alarm = if self.csr & CSR_SAE != 0 { self.csr & CSR_SA != 0 } else { !self.silo.is_empty() };
+        self.csr & CSR_RIE != 0 && alarm
+    }
+
+    pub fn tx_interrupt(&self) -> bool {
+        self.csr & CSR_TIE != 0 && self.ready_line().is_some()
+    }
+
+    pub fn read(&mut self, reg: usize) -> u16 {
+        match reg {
+            REG_CSR => self.csr(),
+            REG_RBUF => {
+                let v = self.silo.pop_front().unwrap_or(0);
+                if self.silo.is_empty() {
+                    self.csr &= !CSR_SA;
+                }
+                v
+            }
+            REG_TCR => self.tcr,
+            // Carrier detect in the high byte, ring in the low.
+            REG_MSR => self.lines.iter().enumerate().fold(0, |msr, (n, l)| {
+                msr | (l.carrier as u16) << (n + 8) | (l.ring as u16) << n
+            }),
+            _ => 0,
+        }
+    }
+
+    /// Writes the bits of `v` under `mask`, for byte writes to a word register.
+    pub fn write(&mut self, reg: usize, v: u16, mask: u16) {
+        match reg {
+            REG_CSR => {
+                if v & mask & CSR_CLR != 0 {
+                    self.reset();
+                    return;
+                }
+                let writable = mask & CSR_WRITABLE;
+                self.csr = (self.csr & !writable) | (v & writable);
+                if self.csr & CSR_SAE == 0 {
+                    self.csr &= !CSR_SA;
+                }
+            }
+            REG_LPR => {
+                let line = (v & LPR_LINE) as usize;
+                if line < self.lines.len() {
+                    self.lines[line].rx_on = v & LPR_RXON != 0;
+                }
+            }
+            REG_TCR => self.set_tcr((self.tcr & !mask) | (v & mask)),
+            REG_TDR => {
+                // Writing only the break bits doesn't send anything.
+                if mask & 0xFF == 0 {
+                    return;
+                }
+                if let Some(line) = self.ready_line() {
+                    if let Some(endpoint) = self.lines[line].endpoint.as_mut() {
+                        endpoint.write_byte(v as u8);
+                    }
+                    self.scan = (line + 1) % self.lines.len();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::devices::{console::MemorySink, serial::ChannelLine};
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn dz_lines() {
+        let mut dz = Dz::new(4);
+        let out = [MemorySink::new(), MemorySink::new()];
+        dz.connect(1, Some(Box::new(out[0].clone())), None);
+        dz.connect(3, Some(Box::new(out[1].clone())), None);
+
+        // Nothing happens until the scanner is on.
+        dz.write(REG_TCR, 0x0A, 0xFFFF);
+        assert_eq!(dz.read(REG_CSR) & CSR_TRDY, 0);
+        dz.write(REG_CSR, CSR_MSE | CSR_TIE, 0xFFFF);
+        assert!(dz.tx_interrupt());
+
+        // The scanner goes round the enabled lines.
+        assert_eq!(dz.read(REG_CSR) & (CSR_TRDY | CSR_TLINE), CSR_TRDY | 0x100);
+        dz.write(REG_TDR, b'a' as u16, 0xFFFF);
+        assert_eq!(dz.read(REG_CSR) & CSR_TLINE, 0x300);
+        dz.write(REG_TDR, b'b' as u16, 0x00FF);
+        dz.write(REG_TDR, b'c' as u16, 0xFFFF);
+        assert_eq!(out[0].take(), b"ac");
+        assert_eq!(out[1].take(), b"b");
+        dz.write(REG_TCR, 0, 0x00FF);
+        assert!(!dz.tx_interrupt());
+
+        // Only lines with their receiver on take input.
+        dz.write(REG_CSR, CSR_MSE | CSR_RIE, 0xFFFF);
+        dz.push_input(2, b"x");
+        assert!(!dz.rx_interrupt());
+        dz.write(REG_LPR, LPR_RXON | 2, 0xFFFF);
+        dz.push_input(2, b"xy");
+        assert!(dz.rx_interrupt());
+        assert_eq!(dz.read(REG_CSR) & CSR_RDONE, CSR_RDONE);
+        assert_eq!(dz.read(REG_RBUF), RBUF_DVAL | 0x200 | b'x' as u16);
+        assert_eq!(dz.read(REG_RBUF), RBUF_DVAL | 0x200 | b'y' as u16);
+        assert_eq!(dz.read(REG_RBUF), 0);
+        assert!(!dz.rx_interrupt());
+
+        // With the alarm on, the receiver waits for the silo to fill, and an overflow is flagged.
+        dz.write(REG_CSR, CSR_SAE, CSR_SAE);
+        dz.push_input(2, &[b'z'; SILO_ALARM - 1]);
+        assert!(!dz.rx_interrupt());
+        dz.push_input(2, &[b'z'; SILO_SIZE + 2 - SILO_ALARM]);
+        assert!(dz.rx_interrupt());
+        for _ in 0..SILO_SIZE {
+            assert_eq!(dz.read(REG_RBUF) & RBUF_OERR, 0);
+        }
+        dz.push_input(2, b"!");
+        assert_eq!(dz.read(REG_RBUF), RBUF_DVAL | RBUF_OERR | 0x200 | b'!' as u16);
+
+        assert_eq!(dz.read(REG_MSR), 0x0A00);
+        dz.write(REG_CSR, CSR_CLR, 0xFFFF);
+        assert_eq!(dz.read(REG_CSR), 0);
+    }
+
+    /// A line whose caller the test controls.
+    struct Phone(Rc<Cell<bool>>);
+
+    impl SerialEndpoint for Phone {
+        fn write_byte(&mut self, _b: u8) {}
+
+        fn poll(&mut self) -> Option<u8> {
+            None
+        }
+
+        fn carrier(&mut self) -> bool {
+            self.0.get()
+        }
+
+        fn hang_up(&mut self) {
+            self.0.set(false);
+        }
+    }
+
+    #[test]
+    fn dz_modem_control() {
+        let mut dz = Dz::new(8);
+        let caller = Rc::new(Cell::new(false));
+        dz.attach(6, Box::new(Phone(caller.clone())));
+        dz.write(REG_CSR, CSR_MSE, 0xFFFF);
+        assert_eq!(dz.read(REG_MSR), 0);
+
+        // A call rings until the guest answers with DTR, and dropping DTR hangs up.
+        caller.set(true);
+        dz.poll_input();
+        assert_eq!(dz.read(REG_MSR), 0x4040);
+        dz.write(REG_TCR, 0x4000, 0xFF00);
+        assert_eq!(dz.read(REG_MSR), 0x4000);
+        dz.write(REG_TCR, 0, 0xFF00);
+        assert!(!caller.get());
+        assert_eq!(dz.read(REG_MSR), 0);
+
+        let (line, mut far) = ChannelLine::pair();
+        dz.attach(5, Box::new(line));
+        dz.write(REG_LPR, LPR_RXON | 5, 0xFFFF);
+        dz.write(REG_TCR, 0x2020, 0xFFFF);
+        far.write_byte(b'k');
+        dz.poll_input();
+        assert_eq!(dz.read(REG_RBUF), RBUF_DVAL | 0x500 | b'k' as u16);
+        dz.write(REG_TDR, b'o' as u16, 0xFFFF);
+        assert_eq!(far.drain(), b"o");
+        drop(far);
+        dz.poll_input();
+        assert_eq!(dz.read(REG_MSR), 0);
+    }
+}
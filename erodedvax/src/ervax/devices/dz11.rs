@@ -0,0 +1,111 @@
+//! The DZ11 and DZQ11 serial line multiplexers: the DZ on the UNIBUS, eight lines, and on the
+//! Q-bus, four. Receive interrupts are at the vector the DZ is set up for, transmit interrupts
+//! at the one after it.
+
+use std::any::Any;
+
+use crate::ervax::devices::{
+    dz::{self, Dz},
+    iopage::{BusRequest, Dma, IoPageDevice, BR4, BR5},
+};
+
+/// Where the first DZ's registers usually are, 17760100 in the I/O page.
+pub const DEFAULT_BASE: u32 = 0o10100;
+pub const DEFAULT_VECTOR: u16 = 0o300;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Dz11,
+    Dzq11,
+}
+
+impl Variant {
+    pub fn lines(self) -> usize {
+        match self {
+            Variant::Dz11 => 8,
+            Variant::Dzq11 => 4,
+        }
+    }
+
+    pub fn level(self) -> u8 {
+        match self {
+            Variant::Dz11 => BR5,
+            Variant::Dzq11 => BR4,
+        }
+    }
+}
+
+pub struct Dz11 {
+    variant: Variant,
+    base: u32,
+    vector: u16,
+    dz: Dz,
+    /// When the lines were last polled for input.
+    polled_at: u64,
+}
+
+impl Dz11 {
+    /// A multiplexer with its registers `base` bytes into the I/O page, and none of its lines
+    /// connected.
+    pub fn new(variant: Variant, base: u32, vector: u16) -> Dz11 {
+        Dz11 { variant, base, vector, dz: Dz::new(variant.lines()), polled_at: 0 }
+    }
+
+    pub fn dz(&mut self) -> &mut Dz {
+        &mut self.dz
+    }
+}
+
+impl IoPageDevice for Dz11 {
+    fn name(&self) -> &str {
+        match self.variant {
+            Variant::Dz11 => "DZ11",
+            Variant::Dzq11 => "DZQ11",
+        }
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        dz::REGISTERS as u32 * 2
+    }
+
+    fn read(&mut self, offset: u32) -> u16 {
+        self.dz.read(offset as usize / 2)
+    }
+
+    fn write(&mut self, offset: u32, v: u16, mask: u16) {
+        self.dz.write(offset as usize / 2, v, mask);
+    }
+
+    fn reset(&mut self) {
+        self.dz.reset();
+    }
+
+    /// The conditions themselves are the requests, so there's nothing for taking an interrupt
+    /// to clear.
+    fn interrupt_request(&self) -> Option<BusRequest> {
+        if self.dz.rx_interrupt() {
+            Some(BusRequest::new(self.variant.level(), self.vector))
+        } else if self.dz.tx_interrupt() {
+            Some(BusRequest::new(self.variant.level(), self.vector + 4))
+        } else {
+            None
+        }
+    }
+
+    fn service(&mut self, now_us: u64, _dma: &mut dyn Dma) {
+        // Every register access comes through here too, which is far more often than the host
+        // needs asking.
+        if now_us != self.polled_at {
+            self.polled_at = now_us;
+            self.dz.poll_input();
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,166 @@
+//! Where an Ethernet controller's frames go: a backend sends the frames the guest transmits,
+//! and hands over the ones that arrive for it. Frames are whole, from the destination address
+//! up to the end of the data, without the CRC.
+
+use std::collections::VecDeque;
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc,
+    Mutex,
+};
+
+/// Bytes in a station address.
+pub const ADDRESS_BYTES: usize = 6;
+/// The shortest frame on the wire, without the CRC. Shorter ones are padded out.
+pub const MIN_FRAME: usize = 60;
+pub const MAX_FRAME: usize = 1514;
+
+pub type MacAddress = [u8; ADDRESS_BYTES];
+
+pub const BROADCAST: MacAddress = [0xFF; ADDRESS_BYTES];
+
+/// Multicast addresses, broadcast among them, have the low bit of the first byte set.
+pub fn is_multicast(address: &[u8]) -> bool {
+    address[0] & 1 != 0
+}
+
+/// Frames to and from the outside. Nothing here may block.
+pub trait EthernetBackend {
+    fn send(&mut self, frame: &[u8]);
+    fn poll(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Sends every frame straight back.
+#[derive(Default)]
+pub struct Loopback(VecDeque<Vec<u8>>);
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback::default()
+    }
+}
+
+impl EthernetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.0.push_back(frame.to_vec());
+    }
+
+    fn poll(&mut self) -> Option<Vec<u8>> {
+        self.0.pop_front()
+    }
+}
+
+/// A segment of Ethernet inside the process. Whatever one port sends, every other port
+/// receives, and it's up to the controllers to filter out what isn't theirs. Ports can be on
+/// different threads.
+#[derive(Clone, Default)]
+pub struct Hub(Arc<Mutex<Vec<HubLink>>>);
+
+/// A port's number, and how to get frames to it.
+type HubLink = (usize, Sender<Vec<u8>>);
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub::default()
+    }
+
+    /// A new port on the hub.
+    pub fn port(&self) -> HubPort {
+        let (tx, rx) = mpsc::channel();
+        let mut ports = self.0.lock().unwrap();
+        let id = ports.iter().map(|(id, _)| id + 1).max().unwrap_or(0);
+        ports.push((id, tx));
+        HubPort { id, hub: self.clone(), rx }
+    }
+}
+
+pub struct HubPort {
+    id: usize,
+    hub: Hub,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl EthernetBackend for HubPort {
+    fn send(&mut self, frame: &[u8]) {
+        // Ports that have gone away drop off the hub.
+        let mut ports = self.hub.0.lock().unwrap();
+        ports.retain(|(id, tx)| *id == self.id || tx.send(frame.to_vec()).is_ok());
+    }
+
+    fn poll(&mut self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+#[cfg(unix)]
+pub use self::socket::SocketBackend;
+
+#[cfg(unix)]
+mod socket {
+    use std::io;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::{Path, PathBuf};
+
+    use super::{EthernetBackend, MAX_FRAME};
+
+    /// A link to one other emulator over a Unix datagram socket, a frame a datagram. Each end
+    /// binds its own path and sends to the other's.
+    pub struct SocketBackend {
+        socket: UnixDatagram,
+        peer: PathBuf,
+    }
+
+    impl SocketBackend {
+        /// Binds `local`, replacing whatever was there, to talk to `peer`.
+        pub fn new(local: &Path, peer: &Path) -> io::Result<SocketBackend> {
+            match std::fs::remove_file(local) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            let socket = UnixDatagram::bind(local)?;
+            socket.set_nonblocking(true)?;
+            Ok(SocketBackend { socket, peer: peer.to_path_buf() })
+        }
+    }
+
+    impl EthernetBackend for SocketBackend {
+        fn send(&mut self, frame: &[u8]) {
+            // Nobody at the other end yet is just a quiet wire.
+            let _ = self.socket.send_to(frame, &self.peer);
+        }
+
+        fn poll(&mut self) -> Option<Vec<u8>> {
+            let mut buf = vec![0; MAX_FRAME];
+            let n = self.socket.recv(&mut buf).ok()?;
+            buf.truncate(n);
+            Some(buf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ethernet_backends() {
+        let hub = Hub::new();
+        let (mut a, mut b, c) = (hub.port(), hub.port(), hub.port());
+        a.send(b"one");
+        drop(c);
+        b.send(b"two");
+        assert_eq!((a.poll(), a.poll()), (Some(b"two".to_vec()), None));
+        assert_eq!((b.poll(), b.poll()), (Some(b"one".to_vec()), None));
+        assert_eq!(hub.0.lock().unwrap().len(), 2);
+
+        let dir = std::env::temp_dir();
+        let (pa, pb) = (dir.join(format!("erodedvax-{}-a", std::process::id())), dir.join(format!("erodedvax-{}-b", std::process::id())));
+        let mut a = SocketBackend::new(&pa, &pb).unwrap();
+        let mut b = SocketBackend::new(&pb, &pa).unwrap();
+        a.send(b"over the socket");
+        assert_eq!((b.poll(), b.poll()), (Some(b"over the socket".to_vec()), None));
+        assert!(is_multicast(&BROADCAST));
+        std::fs::remove_file(pa).unwrap();
+        std::fs::remove_file(pb).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! What devices on the PDP-11 style buses, Q-bus and UNIBUS, look like to their bus adapter.
+//! Their registers are 16-bit words in an 8 KiB I/O page, they get at memory by DMA through the
+//! adapter's map, and they interrupt at one of BR4 through BR7 with a vector of their own.
+
+use std::any::Any;
+use std::fmt;
+
+/// Bytes in the I/O page.
+pub const IO_PAGE_SIZE: u32 = 8192;
+
+/// Bus request levels.
+pub const BR4: u8 = 4;
+pub const BR5: u8 = 5;
+pub const BR6: u8 = 6;
+pub const BR7: u8 = 7;
+
+/// An interrupt request on the bus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusRequest {
+    /// BR4 through BR7.
+    pub level: u8,
+    /// The device's vector, an offset into the adapter's part of the SCB.
+    pub vector: u16,
+}
+
+impl BusRequest {
+    pub fn new(level: u8, vector: u16) -> BusRequest {
+        debug_assert!((BR4..=BR7).contains(&level));
+        BusRequest { level, vector }
+    }
+
+    /// The IPL the CPU sees the request at, BR4 being 0x14.
+    pub fn ipl(self) -> u8 {
+        0x10 + self.level
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmaError {
+    /// Nothing responded at the bus address, the map register wasn't valid or there's no memory
+    /// behind it.
+    NonExistent(u32),
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DmaError::NonExistent(addr) => write!(f, "non-existent memory at bus address {:#o}", addr),
+        }
+    }
+}
+
+/// Memory as a device sees it, addressed with bus addresses.
+pub trait Dma {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), DmaError>;
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), DmaError>;
+
+    fn read_u16(&mut self, addr: u32) -> Result<u16, DmaError> {
+        let mut b = [0; 2];
+        self.read(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn write_u16(&mut self, addr: u32, v: u16) -> Result<(), DmaError> {
+        self.write(addr, &v.to_le_bytes())
+    }
+
+    fn read_u32(&mut self, addr: u32) -> Result<u32, DmaError> {
+        let mut b = [0; 4];
+        self.read(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn write_u32(&mut self, addr: u32, v: u32) -> Result<(), DmaError> {
+        self.write(addr, &v.to_le_bytes())
+    }
+}
+
+pub trait IoPageDevice {
+    /// Short name, for diagnostics.
+    fn name(&self) -> &str;
+
+    /// Offset of the first register in the I/O page.
+    fn base(&self) -> u32;
+
+    /// Bytes of registers.
+    fn size(&self) -> u32;
+
+    /// Reads the register at `offset` bytes from the first one, always even.
+    fn read(&mut self, offset: u32) -> u16;
+
+    /// Writes the bits of `v` under `mask` to the register at `offset`, the mask being 0x00FF or
+    /// 0xFF00 for byte writes.
+    fn write(&mut self, offset: u32, v: u16, mask: u16);
+
+    /// Power-up, or a bus reset such as IORESET.
+    fn reset(&mut self) {}
+
+    fn interrupt_request(&self) -> Option<BusRequest> {
+        None
+    }
+
+    /// The CPU is taking an interrupt at `vector`, which may or may not be this device's.
+    fn acknowledge(&mut self, _vector: u16) {}
+
+    /// Called after each access to the adapter and each time update, with the time in
+    /// microseconds, to do whatever work the device has: DMA, completing commands, polling
+    /// the host.
+    fn service(&mut self, _now_us: u64, _dma: &mut dyn Dma) {}
+
+    /// For getting at a particular device's own interface again.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// The devices in an I/O page, and the CPU's view of it: byte, word, and longword accesses
+/// broken up into accesses to the word registers.
+#[derive(Default)]
+pub struct IoPage {
+    devices: Vec<Box<dyn IoPageDevice>>,
+}
+
+impl IoPage {
+    pub fn new() -> IoPage {
+        IoPage::default()
+    }
+
+    /// Adds a device. Panics if its registers overlap another device's or run off the end of
+    /// the I/O page, which is a mistake in the configuration.
+    pub fn add_device(&mut self, device: Box<dyn IoPageDevice>) {
+        let (base, end) = (device.base(), device.base() + device.size());
+        assert!(base.is_multiple_of(2) && end <= IO_PAGE_SIZE, "{} registers outside the I/O page", device.name());
+        let overlapping = self.devices.iter().find(|d| base < d.base() + d.size() && d.base() < end);
+        if let Some(d) = overlapping {
+            panic!("{} registers overlap {}", device.name(), d.name());
+        }
+        self.devices.push(device);
+    }
+
+    /// The first device of type `T`.
+    pub fn find_device<T: 'static>(&mut self) -> Option<&mut T> {
+        self.devices.iter_mut().find_map(|d| d.as_any_mut().downcast_mut::<T>())
+    }
+
+    fn device_at(&mut self, offset: u32) -> Option<&mut Box<dyn IoPageDevice>> {
+        self.devices.iter_mut().find(|d| (d.base()..d.base() + d.size()).contains(&offset))
+    }
+
+    /// Reads `size` bytes at `offset`, None if some word of it has no device.
+    pub fn read(&mut self, offset: u32, size: u32) -> Option<u32> {
+        let first = offset & !1;
+        let mut v: u64 = 0;
+        for (i, word) in (first..offset + size).step_by(2).enumerate() {
+            let d = self.device_at(word)?;
+            let base = d.base();
+            v |= (d.read(word - base) as u64) << (i * 16);
+        }
+        Some((v >> ((offset - first) * 8)) as u32 & (u32::MAX >> (32 - size * 8)))
+    }
+
+    /// Writes the low `size` bytes of `v` at `offset`, false if some word of it has no device.
+    pub fn write(&mut self, offset: u32, size: u32, v: u32) -> bool {
+        let first = offset & !1;
+        let shift = (offset - first) * 8;
+        let (v, mask) = ((v as u64) << shift, ((u32::MAX >> (32 - size * 8)) as u64) << shift);
+        for (i, word) in (first..offset + size).step_by(2).enumerate() {
+            let Some(d) = self.device_at(word) else { return false };
+            let base = d.base();
+            d.write(word - base, (v >> (i * 16)) as u16, (mask >> (i * 16)) as u16);
+        }
+        true
+    }
+
+    pub fn reset(&mut self) {
+        self.devices.iter_mut().for_each(|d| d.reset());
+    }
+
+    /// The request the adapter passes on: the highest level, the device added first winning
+    /// ties as though it were nearest the CPU.
+    pub fn interrupt_request(&self) -> Option<BusRequest> {
+        self.devices.iter()
+            .filter_map(|d| d.interrupt_request())
+            .fold(None, |best: Option<BusRequest>, r| match best {
+                Some(b) if b.level >= r.level => Some(b),
+                _ => Some(r),
+            })
+    }
+
+    /// The request of the device nearest the CPU requesting at `level`.
+    pub fn request_at(&self, level: u8) -> Option<BusRequest> {
+        self.devices.iter()
+            .filter_map(|d| d.interrupt_request())
+            .find(|r| r.level == level)
+    }
+
+    pub fn acknowledge(&mut self, vector: u16) {
+        self.devices.iter_mut().for_each(|d| d.acknowledge(vector));
+    }
+
+    pub fn service(&mut self, now_us: u64, dma: &mut dyn Dma) {
+        self.devices.iter_mut().for_each(|d| d.service(now_us, dma));
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Copies a longword from the bus address in its second register to the one after it,
+    /// interrupting when done.
+    #[derive(Default)]
+    pub struct Copier {
+        csr: u16,
+        addr: u16,
+        go: bool,
+    }
+
+    impl IoPageDevice for Copier {
+        fn name(&self) -> &str {
+            "copier"
+        }
+
+        fn base(&self) -> u32 {
+            0o12150
+        }
+
+        fn size(&self) -> u32 {
+            4
+        }
+
+        fn read(&mut self, offset: u32) -> u16 {
+            if offset == 0 { self.csr } else { self.addr }
+        }
+
+        fn write(&mut self, offset: u32, v: u16, mask: u16) {
+            if offset == 0 {
+                self.csr = (self.csr & !mask) | (v & mask & 0x40);
+                self.go = v & mask & 1 != 0;
+            } else {
+                self.addr = (self.addr & !mask) | (v & mask);
+            }
+        }
+
+        fn interrupt_request(&self) -> Option<BusRequest> {
+            if self.csr & 0xC0 == 0xC0 { Some(BusRequest::new(BR5, 0o120)) } else { None }
+        }
+
+        fn acknowledge(&mut self, vector: u16) {
+            if vector == 0o120 {
+                self.csr &= !0x80;
+            }
+        }
+
+        fn service(&mut self, _now_us: u64, dma: &mut dyn Dma) {
+            if std::mem::take(&mut self.go) {
+                let addr = self.addr as u32;
+                let result = dma.read_u32(addr).and_then(|v| dma.write_u32(addr + 4, v));
+                self.csr |= if result.is_ok() { 0x80 } else { 0x8080 };
+            }
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+}
@@ -0,0 +1,19 @@
+pub mod clock;
+pub mod console;
+pub mod delqa;
+pub mod disk;
+pub mod dz;
+pub mod dz11;
+pub mod ethernet;
+pub mod iopage;
+pub mod mscp;
+pub mod ncr5380;
+pub mod qbus;
+pub mod rom;
+pub mod scsi;
+pub mod serial;
+pub mod tape;
+pub mod tmscp;
+pub mod uba;
+pub mod uqssp;
+pub mod watch;
@@ -0,0 +1,287 @@
+//! An MSCP disk server, behind the port of a disk controller such as the RQDX3 or UDA50.
+//!
+//! Transfers are done a block at a time, straight from the drive's storage.
+
+use crate::ervax::devices::{
+    disk::{Disk, BLOCK_SIZE},
+    iopage::{Dma, DmaError},
+    uqssp::{get_u16, get_u32, put_u16, put_u32, Controller, Port, Server},
+};
+
+/// Where the first disk controller's registers usually are, 17772150 in the I/O page.
+pub const DEFAULT_BASE: u32 = 0o12150;
+
+/// Opcodes.
+pub const OP_ABORT: u8 = 1;
+pub const OP_GET_COMMAND_STATUS: u8 = 2;
+pub const OP_GET_UNIT_STATUS: u8 = 3;
+pub const OP_SET_CONTROLLER_CHARACTERISTICS: u8 = 4;
+pub const OP_AVAILABLE: u8 = 8;
+pub const OP_ONLINE: u8 = 9;
+pub const OP_ERASE: u8 = 18;
+pub const OP_READ: u8 = 33;
+pub const OP_WRITE: u8 = 34;
+/// Added to the opcode in a response's endcode.
+pub const OP_END: u8 = 0x80;
+
+/// Status codes, with subcodes from bit 5 up.
+pub const ST_SUCCESS: u16 = 0;
+pub const ST_INVALID_COMMAND: u16 = 1;
+pub const ST_UNIT_OFFLINE: u16 = 3;
+pub const ST_UNIT_AVAILABLE: u16 = 4;
+pub const ST_WRITE_PROTECTED: u16 = 6 | 256 << 5;
+pub const ST_HOST_BUFFER_ACCESS: u16 = 9;
+pub const ST_DRIVE_ERROR: u16 = 11;
+
+/// Unit flags, the drive is hardware write protected.
+pub const UF_WPH: u16 = 0x2000;
+
+struct Unit {
+    disk: Disk,
+    online: bool,
+}
+
+/// The disk drives on a controller.
+#[derive(Default)]
+pub struct MscpServer {
+    units: Vec<Option<Unit>>,
+}
+
+/// A disk controller.
+pub type Mscp = Port<MscpServer>;
+
+impl Mscp {
+    /// A controller with its registers `base` bytes into the I/O page, and no drives.
+    pub fn new(controller: Controller, base: u32) -> Mscp {
+        Port::with_server(controller, base, MscpServer::default())
+    }
+
+    /// Puts a drive on the controller as unit `n`.
+    pub fn attach(&mut self, n: usize, disk: Disk) {
+        let units = &mut self.server().units;
+        if units.len() <= n {
+            units.resize_with(n + 1, || None);
+        }
+        units[n] = Some(Unit { disk, online: false });
+    }
+
+    pub fn disk(&mut self, n: usize) -> Option<&mut Disk> {
+        self.server().units.get_mut(n)?.as_mut().map(|u| &mut u.disk)
+    }
+}
+
+impl Server for MscpServer {
+    fn response_len(&self, opcode: u8) -> usize {
+        match opcode {
+            OP_GET_UNIT_STATUS => 48,
+            OP_ONLINE => 44,
+            OP_READ | OP_WRITE | OP_ERASE => 32,
+            _ => 12,
+        }
+    }
+
+    fn execute(&mut self, cmd: &[u8], rsp: &mut [u8], dma: &mut dyn Dma) -> u16 {
+        match cmd[8] {
+            OP_GET_UNIT_STATUS => self.unit_status(cmd, rsp),
+            OP_ONLINE => self.online(cmd, rsp),
+            OP_AVAILABLE => match self.unit(cmd) {
+                Ok(unit) => {
+                    unit.online = false;
+                    ST_SUCCESS
+                }
+                Err(status) => status,
+            },
+            OP_READ | OP_WRITE | OP_ERASE => self.transfer(cmd, rsp, dma),
+            _ => ST_INVALID_COMMAND | 8 << 8,
+        }
+    }
+
+    fn initialize(&mut self) {
+        self.units.iter_mut().flatten().for_each(|u| u.online = false);
+    }
+}
+
+impl MscpServer {
+    fn unit(&mut self, cmd: &[u8]) -> Result<&mut Unit, u16> {
+        let n = get_u16(cmd, 4) as usize;
+        self.units.get_mut(n).and_then(|u| u.as_mut()).ok_or(ST_UNIT_OFFLINE)
+    }
+
+    /// The unit flags and identifier, which ONLINE and GET UNIT STATUS both give.
+    fn describe(n: u16, unit: &Unit, rsp: &mut [u8]) {
+        let g = unit.disk.geometry();
+        put_u16(rsp, 14, if unit.disk.is_read_only() { UF_WPH } else { 0 });
+        put_u32(rsp, 20, n as u32 + 1);
+        rsp[26] = g.model;
+        rsp[27] = 2;
+        put_u32(rsp, 28, g.media);
+    }
+
+    fn unit_status(&mut self, cmd: &[u8], rsp: &mut [u8]) -> u16 {
+        let n = get_u16(cmd, 4);
+        let unit = match self.unit(cmd) {
+            Ok(unit) => unit,
+            Err(status) => return status,
+        };
+        MscpServer::describe(n, unit, rsp);
+        let g = unit.disk.geometry();
+        put_u16(rsp, 36, g.track);
+        put_u16(rsp, 38, g.group);
+        put_u16(rsp, 40, g.cylinder);
+        put_u16(rsp, 44, g.rct_size);
+        rsp[46] = g.rbns;
+        rsp[47] = g.rct_copies;
+        if unit.online { ST_SUCCESS } else { ST_UNIT_AVAILABLE }
+    }
+
+    fn online(&mut self, cmd: &[u8], rsp: &mut [u8]) -> u16 {
+        let n = get_u16(cmd, 4);
+        let unit = match self.unit(cmd) {
+            Ok(unit) => unit,
+            Err(status) => return status,
+        };
+        unit.online = true;
+        MscpServer::describe(n, unit, rsp);
+        put_u32(rsp, 36, unit.disk.geometry().lbns);
+        ST_SUCCESS
+    }
+
+    /// READ, WRITE, and ERASE, a block at a time.
+    fn transfer(&mut self, cmd: &[u8], rsp: &mut [u8], dma: &mut dyn Dma) -> u16 {
+        let opcode = cmd[8];
+        let count = get_u32(cmd, 12) as usize;
+        let buffer = get_u32(cmd, 16) & 0x3F_FFFF;
+        let lbn = get_u32(cmd, 28);
+
+        let unit = match self.unit(cmd) {
+            Ok(unit) => unit,
+            Err(status) => return status,
+        };
+        if !unit.online {
+            return ST_UNIT_AVAILABLE;
+        }
+        if opcode != OP_READ && unit.disk.is_read_only() {
+            return ST_WRITE_PROTECTED;
+        }
+        let blocks = count.div_ceil(BLOCK_SIZE) as u64;
+        if lbn as u64 + blocks > unit.disk.geometry().lbns as u64 {
+            return ST_INVALID_COMMAND | 28 << 8;
+        }
+
+        let mut block = [0; BLOCK_SIZE];
+        let mut done = 0;
+        let status = loop {
+            if done == count {
+                break ST_SUCCESS;
+            }
+            let n = (count - done).min(BLOCK_SIZE);
+            let b = lbn + (done / BLOCK_SIZE) as u32;
+            let addr = buffer + done as u32;
+            let result = match opcode {
+                OP_READ => match unit.disk.read_blocks(b, &mut block) {
+                    Ok(()) => dma.write(addr, &block[..n]).map_err(Some),
+                    Err(_) => Err(None),
+                },
+                _ => {
+                    let mut fetched = Ok(());
+                    if n < BLOCK_SIZE {
+                        fetched = unit.disk.read_blocks(b, &mut block).map_err(|_| None);
+                    }
+                    if opcode == OP_WRITE {
+                        fetched = fetched.and_then(|_| dma.read(addr, &mut block[..n]).map_err(Some));
+                    } else {
+                        block[..n].fill(0);
+                    }
+                    fetched.and_then(|_| unit.disk.write_blocks(b, &block).map_err(|_| None))
+                }
+            };
+            match result {
+                Ok(()) => done += n,
+                Err(Some(DmaError::NonExistent(_))) => break ST_HOST_BUFFER_ACCESS,
+                Err(None) => break ST_DRIVE_ERROR,
+            }
+        };
+        put_u32(rsp, 12, done as u32);
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::devices::{
+        disk::{MemoryStore, RD53},
+        uqssp::{tests::{self, init, IO_BASE, DATA}, REG_IP, REG_SA, SA_S1, SA_S1_DI, SA_S1_QB},
+    };
+    use crate::ervax::cpu::bus::VAXBus;
+
+    fn machine() -> VAXBus {
+        let mut mscp = Mscp::new(Controller::Rqdx3, DEFAULT_BASE);
+        mscp.attach(0, Disk::new(RD53, Box::new(MemoryStore::new()), false));
+        mscp.attach(2, Disk::new(RD53, Box::new(MemoryStore::new()), true));
+        tests::machine(Box::new(mscp))
+    }
+
+    fn command(bus: &mut VAXBus, n: usize, packet: &[u8]) -> Vec<u8> {
+        tests::command(bus, DEFAULT_BASE, n, packet)
+    }
+
+    fn packet(unit: u16, opcode: u8, count: u32, buffer: u32, lbn: u32) -> Vec<u8> {
+        tests::packet(unit, opcode, 0, count, buffer, lbn)
+    }
+
+    #[test]
+    fn mscp_disk() {
+        let mut bus = machine();
+        init(&mut bus, DEFAULT_BASE, Controller::Rqdx3);
+
+        let r = command(&mut bus, 0, &packet(0, OP_SET_CONTROLLER_CHARACTERISTICS, 0, 0, 0));
+        assert_eq!((r.len(), get_u32(&r, 0), r[8], get_u16(&r, 10)), (32, 0x1234, OP_SET_CONTROLLER_CHARACTERISTICS | OP_END, ST_SUCCESS));
+        assert_eq!(r[26], 19);
+
+        let r = command(&mut bus, 1, &packet(1, OP_GET_UNIT_STATUS, 0, 0, 0));
+        assert_eq!(get_u16(&r, 10), ST_UNIT_OFFLINE);
+        let r = command(&mut bus, 2, &packet(0, OP_GET_UNIT_STATUS, 0, 0, 0));
+        assert_eq!((r.len(), get_u16(&r, 10), get_u32(&r, 28)), (48, ST_UNIT_AVAILABLE, RD53.media));
+        assert_eq!((get_u16(&r, 36), get_u16(&r, 38), get_u16(&r, 44)), (17, 8, 280));
+
+        // Not online yet.
+        let r = command(&mut bus, 3, &packet(0, OP_READ, 512, DATA, 0));
+        assert_eq!(get_u16(&r, 10), ST_UNIT_AVAILABLE);
+        let r = command(&mut bus, 4, &packet(0, OP_ONLINE, 0, 0, 0));
+        assert_eq!((r.len(), get_u16(&r, 10), get_u32(&r, 36)), (44, ST_SUCCESS, RD53.lbns));
+
+        // Write a block and a half, then read them back.
+        for i in 0..768 {
+            bus.write_u8(DATA + i, i as u8 ^ 0x5A).unwrap();
+        }
+        let r = command(&mut bus, 5, &packet(0, OP_WRITE, 768, DATA, 100));
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 12)), (ST_SUCCESS, 768));
+        let r = command(&mut bus, 6, &packet(0, OP_READ, 1024, DATA + 0x1000, 100));
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 12)), (ST_SUCCESS, 1024));
+        for i in 0..1024 {
+            let expected = if i < 768 { i as u8 ^ 0x5A } else { 0 };
+            assert_eq!(bus.read_u8(DATA + 0x1000 + i), Ok(expected));
+        }
+        let r = command(&mut bus, 7, &packet(0, OP_ERASE, 512, 0, 100));
+        assert_eq!(get_u16(&r, 10), ST_SUCCESS);
+        command(&mut bus, 8, &packet(0, OP_READ, 512, DATA + 0x1000, 100));
+        assert_eq!(bus.read_u32(DATA + 0x1000), Ok(0));
+        assert_eq!(bus.read_u8(DATA + 0x1200), Ok(0x5A));
+
+        // Past the end, into memory that isn't there, and onto a protected drive.
+        let r = command(&mut bus, 9, &packet(0, OP_READ, 1024, DATA, RD53.lbns - 1));
+        assert_eq!(get_u16(&r, 10), ST_INVALID_COMMAND | 28 << 8);
+        let r = command(&mut bus, 10, &packet(0, OP_READ, 1024, 0x3FFE00, 0));
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 12)), (ST_HOST_BUFFER_ACCESS, 0));
+        command(&mut bus, 11, &packet(2, OP_ONLINE, 0, 0, 0));
+        let r = command(&mut bus, 12, &packet(2, OP_WRITE, 512, DATA, 0));
+        assert_eq!(get_u16(&r, 10), ST_WRITE_PROTECTED);
+        let r = command(&mut bus, 13, &packet(0, 0x7F, 0, 0, 0));
+        assert_eq!(get_u16(&r, 10), ST_INVALID_COMMAND | 8 << 8);
+
+        // Writing IP starts over.
+        bus.write_u16(IO_BASE + DEFAULT_BASE + REG_IP, 0).unwrap();
+        assert_eq!(bus.read_u16(IO_BASE + DEFAULT_BASE + REG_SA), Ok(SA_S1 | SA_S1_QB | SA_S1_DI));
+    }
+}
@@ -0,0 +1,418 @@
+//! The NCR 5380 SCSI bus controller, as used on workstation boards with on-board SCSI.
+//!
+//! Disks can be attached at SCSI IDs 0 to 6, the controller being ID 7. The host selects one
+//! and moves every byte by programmed I/O: it waits for REQ, and for phases into the host reads
+//! the data bus, for phases out of it drives ODR, then raises and drops ACK. A target answers
+//! selection at once, then goes through message out if the host raised ATN, command, data in
+//! or out, status, and a COMMAND COMPLETE message, and lets go of the bus. Arbitration is always
+//! won. DMA, and interrupts on anything but a bus reset, aren't modelled.
+
+use crate::ervax::devices::{
+    disk::Disk,
+    scsi::{self, ScsiDisk},
+};
+
+/// Register numbers. Several registers are different when read and when written.
+pub const REG_CSD: usize = 0;
+pub const REG_ODR: usize = 0;
+pub const REG_ICR: usize = 1;
+pub const REG_MR: usize = 2;
+pub const REG_TCR: usize = 3;
+pub const REG_CSBS: usize = 4;
+pub const REG_SER: usize = 4;
+pub const REG_BSR: usize = 5;
+pub const REG_SDS: usize = 5;
+pub const REG_IDR: usize = 6;
+pub const REG_SDTR: usize = 6;
+pub const REG_RPI: usize = 7;
+pub const REG_SDIR: usize = 7;
+pub const REGISTERS: usize = 8;
+
+/// The controller's own SCSI ID, and so how many targets there's room for.
+pub const HOST_ID: usize = 7;
+
+/// ICR, assert RST.
+pub const ICR_RST: u8 = 0x80;
+/// ICR, arbitration in progress.
+pub const ICR_AIP: u8 = 0x40;
+/// ICR, arbitration was lost.
+pub const ICR_LA: u8 = 0x20;
+pub const ICR_ACK: u8 = 0x10;
+pub const ICR_BSY: u8 = 0x08;
+pub const ICR_SEL: u8 = 0x04;
+pub const ICR_ATN: u8 = 0x02;
+/// ICR, drive the data bus from ODR.
+pub const ICR_DBUS: u8 = 0x01;
+const ICR_WRITABLE: u8 = ICR_RST | ICR_ACK | ICR_BSY | ICR_SEL | ICR_ATN | ICR_DBUS;
+
+/// MR, start arbitration.
+pub const MR_ARB: u8 = 0x01;
+
+/// CSBS, the bus signals.
+pub const CSBS_RST: u8 = 0x80;
+pub const CSBS_BSY: u8 = 0x40;
+pub const CSBS_REQ: u8 = 0x20;
+pub const CSBS_MSG: u8 = 0x10;
+pub const CSBS_CD: u8 = 0x08;
+pub const CSBS_IO: u8 = 0x04;
+pub const CSBS_SEL: u8 = 0x02;
+
+/// BSR, the interrupt request.
+pub const BSR_INT: u8 = 0x10;
+/// BSR, the bus phase matches TCR.
+pub const BSR_PHSM: u8 = 0x08;
+pub const BSR_ATN: u8 = 0x02;
+pub const BSR_ACK: u8 = 0x01;
+
+/// SCSI messages.
+pub const MSG_COMMAND_COMPLETE: u8 = 0x00;
+
+/// Information transfer phases, by their MSG, C/D and I/O signals as TCR has them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    DataOut = 0,
+    DataIn = 1,
+    Command = 2,
+    Status = 3,
+    MessageOut = 6,
+    MessageIn = 7,
+}
+
+impl Phase {
+    /// Whether the target drives the data bus.
+    fn is_input(self) -> bool {
+        self as u8 & 1 != 0
+    }
+}
+
+/// A target that's been selected and holds the bus.
+struct Connection {
+    id: usize,
+    /// None until SEL drops after selection.
+    phase: Option<Phase>,
+    req: bool,
+    /// What the target drives the data bus with, in phases into the host.
+    data: u8,
+    cdb: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Ncr5380 {
+    odr: u8,
+    icr: u8,
+    mr: u8,
+    tcr: u8,
+    /// The interrupt request, dropped by reading RPI.
+    int: bool,
+    targets: [Option<ScsiDisk>; HOST_ID],
+    connection: Option<Connection>,
+}
+
+impl Ncr5380 {
+    pub fn new() -> Ncr5380 {
+        Ncr5380::default()
+    }
+
+    /// Attaches a disk at SCSI ID `id`, which must be below HOST_ID.
+    pub fn attach(&mut self, id: usize, disk: Disk) {
+        self.targets[id] = Some(ScsiDisk::new(disk));
+    }
+
+    pub fn disk(&mut self, id: usize) -> Option<&mut Disk> {
+        self.targets.get_mut(id)?.as_mut().map(|t| t.disk())
+    }
+
+    /// Resets the controller and the bus. Attached disks stay attached.
+    pub fn reset(&mut self) {
+        let targets = std::mem::take(&mut self.targets);
+        *self = Ncr5380 { targets, ..Ncr5380::default() };
+        self.targets.iter_mut().flatten().for_each(|t| t.reset());
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.int
+    }
+
+    /// The data bus, driven by a target in phases into the host, otherwise by the controller.
+    fn data_bus(&self) -> u8 {
+        match &self.connection {
+            Some(Connection { phase: Some(phase), data, .. }) if phase.is_input() => *data,
+            _ if self.icr & ICR_DBUS != 0 => self.odr,
+            _ => 0,
+        }
+    }
+
+    /// MSG, C/D and I/O, as the target drives them.
+    fn bus_phase(&self) -> u8 {
+        match &self.connection {
+            Some(Connection { phase: Some(phase), .. }) => *phase as u8,
+            _ => 0,
+        }
+    }
+
+    fn target(&mut self) -> Option<&mut ScsiDisk> {
+        let id = self.connection.as_ref()?.id;
+        self.targets[id].as_mut()
+    }
+
+    /// The target raises REQ for the next byte of its phase, putting it on the bus if it's
+    /// going to the host. Data in runs out into status.
+    fn request(&mut self) {
+        let atn = self.icr & ICR_ATN != 0;
+        let phase = match self.connection.as_ref().and_then(|c| c.phase) {
+            Some(phase) => phase,
+            None => return,
+        };
+        let (phase, data) = match phase {
+            Phase::MessageOut if !atn => (Phase::Command, 0),
+            Phase::DataIn => match self.target().and_then(|t| t.read_byte()) {
+                Some(b) => (Phase::DataIn, b),
+                None => (Phase::Status, self.target().map_or(0, |t| t.status())),
+            },
+            Phase::Status => (Phase::Status, self.target().map_or(0, |t| t.status())),
+            Phase::MessageIn => (Phase::MessageIn, MSG_COMMAND_COMPLETE),
+            phase => (phase, 0),
+        };
+        let c = self.connection.as_mut().unwrap();
+        c.phase = Some(phase);
+        c.data = data;
+        c.req = true;
+    }
+
+    /// The host acknowledged the byte on the bus. The target takes it if it's going the
+    /// target's way, drops REQ, and moves on to whatever phase comes next.
+    fn acknowledge(&mut self) {
+        let byte = self.data_bus();
+        let c = match self.connection.as_mut() {
+            Some(c) if c.req => c,
+            _ => return,
+        };
+        c.req = false;
+        let phase = c.phase;
+        let next = match phase {
+            Some(Phase::Command) => {
+                c.cdb.push(byte);
+                if c.cdb.len() < scsi::command_length(c.cdb[0]) {
+                    Phase::Command
+                } else {
+                    let cdb = std::mem::take(&mut c.cdb);
+                    match self.target().map(|t| t.command(&cdb)) {
+                        Some(scsi::Phase::DataIn) => Phase::DataIn,
+                        Some(scsi::Phase::DataOut) => Phase::DataOut,
+                        _ => Phase::Status,
+                    }
+                }
+            }
+            Some(Phase::DataOut) => {
+                if self.target().is_some_and(|t| t.write_byte(byte)) { Phase::DataOut } else { Phase::Status }
+            }
+            Some(Phase::Status) => Phase::MessageIn,
+            Some(Phase::MessageIn) => {
+                self.connection = None;
+                return;
+            }
+            // Messages from the host, IDENTIFY and the like, change nothing.
+            Some(phase) => phase,
+            None => return,
+        };
+        self.connection.as_mut().unwrap().phase = Some(next);
+    }
+
+    /// Selection: with the host's BSY released and SEL raised, the target whose ID bit is
+    /// on the data bus answers with BSY.
+    fn select(&mut self) {
+        if self.connection.is_some() || self.icr & (ICR_SEL | ICR_BSY) != ICR_SEL || self.icr & ICR_DBUS == 0 {
+            return;
+        }
+        let id = (0..HOST_ID).find(|&id| self.odr & 1 << id != 0 && self.targets[id].is_some());
+        if let Some(id) = id {
+            self.connection = Some(Connection { id, phase: None, req: false, data: 0, cdb: vec![] });
+        }
+    }
+
+    fn write_icr(&mut self, v: u8) {
+        let old = self.icr;
+        self.icr = v & ICR_WRITABLE;
+        // Asserting RST resets everything on the bus, and interrupts.
+        if v & ICR_RST != 0 && old & ICR_RST == 0 {
+            self.int = true;
+            self.connection = None;
+            self.targets.iter_mut().flatten().for_each(|t| t.reset());
+        }
+        let rose = |bit| self.icr & bit != 0 && old & bit == 0;
+        let fell = |bit| self.icr & bit == 0 && old & bit != 0;
+        let (ack_rose, ack_fell, sel_fell) = (rose(ICR_ACK), fell(ICR_ACK), fell(ICR_SEL));
+
+        if ack_rose {
+            self.acknowledge();
+        } else if ack_fell {
+            self.request();
+        }
+        if sel_fell {
+            // Selected, the target starts with message out if the host wants to send one.
+            if let Some(c) = self.connection.as_mut().filter(|c| c.phase.is_none()) {
+                c.phase = Some(if self.icr & ICR_ATN != 0 { Phase::MessageOut } else { Phase::Command });
+                self.request();
+            }
+        }
+        self.select();
+    }
+
+    pub fn read(&mut self, reg: usize) -> u8 {
+        match reg {
+            REG_CSD | REG_IDR => self.data_bus(),
+            REG_ICR => {
+                let aip = if self.mr & MR_ARB != 0 { ICR_AIP } else { 0 };
+                self.icr | aip
+            }
+            REG_MR => self.mr,
+            REG_TCR => self.tcr,
+            REG_CSBS => {
+                let mut csbs = self.bus_phase() << 2;
+                if self.icr & ICR_RST != 0 {
+                    csbs |= CSBS_RST;
+                }
+                if self.icr & ICR_BSY != 0 || self.connection.is_some() {
+                    csbs |= CSBS_BSY;
+                }
+                if self.connection.as_ref().is_some_and(|c| c.req) {
+                    csbs |= CSBS_REQ;
+                }
+                if self.icr & ICR_SEL != 0 {
+                    csbs |= CSBS_SEL;
+                }
+                csbs
+            }
+            REG_BSR => {
+                let mut bsr = 0;
+                if self.int {
+                    bsr |= BSR_INT;
+                }
+                // With no target driving MSG, C/D and I/O, the bus is in data out phase.
+                if self.tcr & 0x7 == self.bus_phase() {
+                    bsr |= BSR_PHSM;
+                }
+                if self.icr & ICR_ATN != 0 {
+                    bsr |= BSR_ATN;
+                }
+                if self.icr & ICR_ACK != 0 {
+                    bsr |= BSR_ACK;
+                }
+                bsr
+            }
+            REG_RPI => {
+                self.int = false;
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: usize, v: u8) {
+        match reg {
+            REG_ODR => {
+                self.odr = v;
+                self.select();
+            }
+            REG_ICR => self.write_icr(v),
+            REG_MR => self.mr = v,
+            REG_TCR => self.tcr = v & 0xF,
+            // Selection by a target and starting DMA never happen.
+            REG_SER | REG_SDS | REG_SDTR | REG_SDIR => {}
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::devices::disk::{MemoryStore, BLOCK_SIZE, RD54};
+
+    /// Moves one byte of the current phase by programmed I/O, the way a host driver does.
+    fn transfer(scsi: &mut Ncr5380, out: u8) -> u8 {
+        assert_eq!(scsi.read(REG_CSBS) & CSBS_REQ, CSBS_REQ);
+        let phase = (scsi.read(REG_CSBS) >> 2) & 7;
+        scsi.write(REG_TCR, phase);
+        assert_eq!(scsi.read(REG_BSR) & BSR_PHSM, BSR_PHSM);
+        let input = phase & 1 != 0;
+        let b = if input {
+            scsi.read(REG_CSD)
+        } else {
+            scsi.write(REG_ODR, out);
+            out
+        };
+        let dbus = if input { 0 } else { ICR_DBUS };
+        scsi.write(REG_ICR, ICR_ACK | dbus);
+        assert_eq!(scsi.read(REG_CSBS) & CSBS_REQ, 0);
+        scsi.write(REG_ICR, 0);
+        b
+    }
+
+    fn phase(scsi: &mut Ncr5380) -> u8 {
+        (scsi.read(REG_CSBS) >> 2) & 7
+    }
+
+    #[test]
+    fn ncr5380_empty_bus() {
+        let mut scsi = Ncr5380::new();
+
+        // Arbitration, then selection of target 2, which never answers.
+        scsi.write(REG_ODR, 0x80);
+        scsi.write(REG_MR, MR_ARB);
+        assert_eq!(scsi.read(REG_ICR) & (ICR_AIP | ICR_LA), ICR_AIP);
+        scsi.write(REG_ODR, 0x84);
+        scsi.write(REG_ICR, ICR_SEL | ICR_DBUS);
+        scsi.write(REG_MR, 0);
+        assert_eq!(scsi.read(REG_CSD), 0x84);
+        assert_eq!(scsi.read(REG_CSBS) & (CSBS_BSY | CSBS_SEL), CSBS_SEL);
+        scsi.write(REG_ICR, 0);
+        assert_eq!(scsi.read(REG_CSBS), 0);
+
+        // A bus reset interrupts until RPI is read.
+        scsi.write(REG_ICR, ICR_RST);
+        scsi.write(REG_ICR, 0);
+        assert!(scsi.interrupt());
+        assert_eq!(scsi.read(REG_BSR) & BSR_INT, BSR_INT);
+        scsi.read(REG_RPI);
+        assert!(!scsi.interrupt());
+    }
+
+    #[test]
+    fn ncr5380_read_block() {
+        let mut disk = Disk::new(RD54, Box::new(MemoryStore::new()), false);
+        disk.write_blocks(5, &[0x5A; BLOCK_SIZE]).unwrap();
+        let mut scsi = Ncr5380::new();
+        scsi.attach(3, disk);
+
+        // Select target 3 with ATN, for an IDENTIFY message.
+        scsi.write(REG_ODR, 0x80 | 1 << 3);
+        scsi.write(REG_ICR, ICR_SEL | ICR_BSY | ICR_ATN | ICR_DBUS);
+        scsi.write(REG_ICR, ICR_SEL | ICR_ATN | ICR_DBUS);
+        assert_eq!(scsi.read(REG_CSBS) & CSBS_BSY, CSBS_BSY);
+        scsi.write(REG_ICR, ICR_ATN);
+        assert_eq!(phase(&mut scsi), Phase::MessageOut as u8);
+        scsi.write(REG_ODR, 0x80);
+        scsi.write(REG_ICR, ICR_ACK | ICR_DBUS);
+        scsi.write(REG_ICR, 0);
+
+        // READ(6) of block 5.
+        assert_eq!(phase(&mut scsi), Phase::Command as u8);
+        for b in [scsi::READ_6, 0, 0, 5, 1, 0].iter() {
+            transfer(&mut scsi, *b);
+        }
+        assert_eq!(phase(&mut scsi), Phase::DataIn as u8);
+        let block: Vec<u8> = (0..BLOCK_SIZE).map(|_| transfer(&mut scsi, 0)).collect();
+        assert!(block.iter().all(|&b| b == 0x5A));
+        assert_eq!(phase(&mut scsi), Phase::Status as u8);
+        assert_eq!(transfer(&mut scsi, 0), scsi::STATUS_GOOD);
+        assert_eq!(phase(&mut scsi), Phase::MessageIn as u8);
+        assert_eq!(transfer(&mut scsi, 0), MSG_COMMAND_COMPLETE);
+        assert_eq!(scsi.read(REG_CSBS), 0);
+
+        // A reset leaves the disk attached.
+        scsi.reset();
+        assert!(scsi.disk(3).is_some());
+        assert!(scsi.disk(2).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+//! The Q22-bus adapter: the Q-bus I/O page as the CPU sees it, and the map that translates
+//! Q-bus DMA addresses to local memory.
+
+use std::any::Any;
+
+use crate::ervax::{
+    cpu::{
+        bus::{BusError, VAXBusDevice},
+        interrupts::Interrupt,
+    },
+    devices::iopage::{Dma, DmaError, IoPage, IoPageDevice},
+};
+
+/// One register for each 512 byte page of the 4 MiB Q22 address space.
+pub const MAP_ENTRIES: usize = 8192;
+/// Bytes of physical address space the map registers take up.
+pub const MAP_BYTES: u32 = MAP_ENTRIES as u32 * 4;
+
+/// The map register is valid, DMA to its page goes to local memory.
+pub const MAP_VALID: u32 = 0x8000_0000;
+/// Local memory page frame number.
+pub const MAP_PFN: u32 = 0x0000_7FFF;
+
+/// Q-bus addresses are 22 bits.
+pub const QBUS_ADDRESS_SPACE: u32 = 1 << 22;
+
+/// The map registers.
+pub struct QbusMap {
+    regs: Vec<u32>,
+}
+
+impl QbusMap {
+    pub fn new() -> QbusMap {
+        QbusMap { regs: vec![0; MAP_ENTRIES] }
+    }
+
+    pub fn register(&self, n: usize) -> u32 {
+        self.regs[n]
+    }
+
+    pub fn set_register(&mut self, n: usize, v: u32) {
+        self.regs[n] = v & (MAP_VALID | MAP_PFN);
+    }
+
+    /// The local memory address a 22-bit Q-bus address maps to, None if its map register isn't valid.
+    pub fn translate(&self, qaddr: u32) -> Option<u32> {
+        let reg = self.regs[(qaddr as usize >> 9) & (MAP_ENTRIES - 1)];
+        if reg & MAP_VALID == 0 {
+            return None;
+        }
+        Some(((reg & MAP_PFN) << 9) | (qaddr & 0x1FF))
+    }
+}
+
+impl Default for QbusMap {
+    fn default() -> Self {
+        QbusMap::new()
+    }
+}
+
+/// Local memory through the map, as Q-bus devices see it.
+pub struct QbusDma<'a> {
+    map: &'a QbusMap,
+    ram: &'a mut [u8],
+}
+
+impl QbusDma<'_> {
+    /// Splits a transfer at page boundaries, as each page has its own map register.
+    fn pages(&self, addr: u32, len: usize) -> Result<Vec<(std::ops::Range<usize>, usize)>, DmaError> {
+        let mut pages = vec![];
+        let mut done = 0;
+        while done < len {
+            let qaddr = addr + done as u32;
+            let n = (0x200 - (qaddr as usize & 0x1FF)).min(len - done);
+            let local = match self.map.translate(qaddr) {
+                Some(local) if qaddr < QBUS_ADDRESS_SPACE && local as usize + n <= self.ram.len() => local as usize,
+                _ => return Err(DmaError::NonExistent(qaddr)),
+            };
+            pages.push((local..local + n, done));
+            done += n;
+        }
+        Ok(pages)
+    }
+}
+
+impl Dma for QbusDma<'_> {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), DmaError> {
+        for (local, at) in self.pages(addr, buf.len())? {
+            buf[at..at + local.len()].copy_from_slice(&self.ram[local]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), DmaError> {
+        for (local, at) in self.pages(addr, data.len())? {
+            let n = local.len();
+            self.ram[local].copy_from_slice(&data[at..at + n]);
+        }
+        Ok(())
+    }
+}
+
+/// The adapter. The I/O page appears at `io_base` in physical address space and the map
+/// registers, longwords, at `map_base`. Device vectors are offsets from `vector_base` in the SCB.
+pub struct Qbus {
+    io_base: u32,
+    map_base: u32,
+    vector_base: u16,
+    map: QbusMap,
+    io_page: IoPage,
+    now_us: u64,
+}
+
+impl Qbus {
+    pub fn new(io_base: u32, map_base: u32, vector_base: u16) -> Qbus {
+        Qbus { io_base, map_base, vector_base, map: QbusMap::new(), io_page: IoPage::new(), now_us: 0 }
+    }
+
+    pub fn map(&self) -> &QbusMap {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut QbusMap {
+        &mut self.map
+    }
+
+    /// Puts a device on the bus. Panics if its registers overlap another's.
+    pub fn add_device(&mut self, device: Box<dyn IoPageDevice>) {
+        self.io_page.add_device(device);
+    }
+
+    /// The first device of type `T` on the bus.
+    pub fn find_device<T: 'static>(&mut self) -> Option<&mut T> {
+        self.io_page.find_device()
+    }
+
+    /// Memory as the devices see it.
+    pub fn dma<'a>(&'a self, ram: &'a mut [u8]) -> QbusDma<'a> {
+        QbusDma { map: &self.map, ram }
+    }
+
+    fn is_map(&self, addr: u32) -> bool {
+        (self.map_base..self.map_base + MAP_BYTES).contains(&addr)
+    }
+}
+
+impl VAXBusDevice for Qbus {
+    fn name(&self) -> &str {
+        "Q22-bus"
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        if self.is_map(addr) {
+            let offset = addr - self.map_base;
+            let reg = self.map.register(offset as usize / 4);
+            return Ok(reg >> ((offset & 3) * 8) & (u32::MAX >> (32 - size * 8)));
+        }
+        self.io_page.read(addr - self.io_base, size).ok_or(BusError::NonExistent(addr))
+    }
+
+    fn write(&mut self, addr: u32, size: u32, v: u32) -> Result<(), BusError> {
+        if self.is_map(addr) {
+            let offset = addr - self.map_base;
+            let n = offset as usize / 4;
+            let shift = (offset & 3) * 8;
+            let mask = (u32::MAX >> (32 - size * 8)) << shift;
+            let reg = (self.map.register(n) & !mask) | ((v << shift) & mask);
+            self.map.set_register(n, reg);
+            return Ok(());
+        }
+        if self.io_page.write(addr - self.io_base, size, v) {
+            Ok(())
+        } else {
+            Err(BusError::NonExistent(addr))
+        }
+    }
+
+    fn reset(&mut self) {
+        self.io_page.reset();
+    }
+
+    fn interrupt_request(&self) -> Option<Interrupt> {
+        self.io_page.interrupt_request().map(|r| Interrupt::new(r.ipl(), self.vector_base + r.vector))
+    }
+
+    fn acknowledge_interrupt(&mut self, vector: u16) {
+        if let Some(vector) = vector.checked_sub(self.vector_base) {
+            self.io_page.acknowledge(vector);
+        }
+    }
+
+    fn set_time(&mut self, now_us: u64) {
+        self.now_us = now_us;
+    }
+
+    fn service(&mut self, ram: &mut [u8]) {
+        let mut dma = QbusDma { map: &self.map, ram };
+        self.io_page.service(self.now_us, &mut dma);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::VAXBus, interrupts::InterruptSource},
+        devices::iopage::{tests::Copier, IO_PAGE_SIZE},
+    };
+
+    const IO_BASE: u32 = 0x2000_0000;
+    const MAP_BASE: u32 = 0x2008_8000;
+
+    #[test]
+    fn qbus_adapter() {
+        let mut bus = VAXBus::new(1 << 20);
+        let mut qbus = Qbus::new(IO_BASE, MAP_BASE, 0x200);
+        qbus.add_device(Box::new(Copier::default()));
+        let id = bus.add_device(Box::new(qbus));
+        bus.map(id, IO_BASE, IO_PAGE_SIZE);
+        bus.map(id, MAP_BASE, MAP_BYTES);
+
+        // Q-bus page 1 is local page 0x20, straddling into page 2 at local page 0x30.
+        bus.write_u32(MAP_BASE + 4, MAP_VALID | 0x20).unwrap();
+        bus.write_u16(MAP_BASE + 8, 0x30).unwrap();
+        bus.write_u8(MAP_BASE + 11, (MAP_VALID >> 24) as u8).unwrap();
+        assert_eq!(bus.read_u32(MAP_BASE + 8), Ok(MAP_VALID | 0x30));
+        bus.write_u32(0x20 << 9 | 0x1FA, 0x1234_5678).unwrap();
+
+        // Byte and longword accesses split into word accesses.
+        let csr = IO_BASE + 0o12150;
+        bus.write_u32(csr, 0x03FA << 16 | 0x40).unwrap();
+        assert_eq!(bus.read_u16(csr + 2), Ok(0x03FA));
+        bus.write_u8(csr, 0x41).unwrap();
+        assert_eq!(bus.read_u8(csr), Ok(0xC0));
+        assert_eq!(bus.read_u16(0x30 << 9), Ok(0x1234));
+        assert_eq!(bus.read_u16(0x20 << 9 | 0x1FE), Ok(0x5678));
+
+        assert_eq!(bus.pending_interrupt(), Some(Interrupt::new(0x15, 0x200 + 0o120)));
+        bus.acknowledge(0x200 + 0o120);
+        assert_eq!(bus.pending_interrupt(), None);
+
+        // DMA through an invalid map register fails.
+        bus.write_u16(csr + 2, 0x0600).unwrap();
+        bus.write_u8(csr, 0x01).unwrap();
+        assert_eq!(bus.read_u16(csr), Ok(0x8080));
+
+        assert_eq!(bus.read_u16(IO_BASE), Err(BusError::NonExistent(IO_BASE)));
+        assert!(bus.write_u8(csr + 4, 0).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Read-only memory, such as a board's boot ROM.
+
+use std::any::Any;
+
+use crate::ervax::cpu::bus::{BusError, VAXBusDevice};
+
+/// What an unprogrammed EPROM reads as.
+pub const ERASED: u8 = 0xFF;
+
+/// A ROM of a power of two size, repeating through however much address space it's mapped at.
+/// Writes are refused.
+pub struct Rom {
+    bytes: Vec<u8>,
+}
+
+impl Rom {
+    /// A ROM of `size` bytes holding `image`, the rest erased. `size` must be a power of two at
+    /// least as big as the image.
+    pub fn new(image: &[u8], size: usize) -> Rom {
+        assert!(size.is_power_of_two() && image.len() <= size);
+        let mut bytes = vec![ERASED; size];
+        bytes[..image.len()].copy_from_slice(image);
+        Rom { bytes }
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl VAXBusDevice for Rom {
+    fn name(&self) -> &str {
+        "ROM"
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        let mask = self.bytes.len() - 1;
+        let v = (0..size as usize).rev().fold(0, |v, i| {
+            (v << 8) | self.bytes[(addr as usize + i) & mask] as u32
+        });
+        Ok(v)
+    }
+
+    fn write(&mut self, addr: u32, _size: u32, _v: u32) -> Result<(), BusError> {
+        Err(BusError::NonExistent(addr))
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,302 @@
+//! SCSI disk targets: the commands a host needs to find a disk and move blocks to and from it,
+//! run against a Disk. A target takes a command descriptor block, then hands data to the
+//! initiator or takes it a byte at a time, reading and writing a block at a time as it goes.
+//! Anything it can't do ends in CHECK CONDITION, with sense data saying why.
+
+use crate::ervax::devices::disk::{Disk, BLOCK_SIZE};
+
+pub const STATUS_GOOD: u8 = 0x00;
+pub const STATUS_CHECK_CONDITION: u8 = 0x02;
+
+/// Sense keys.
+pub const SENSE_NONE: u8 = 0x0;
+pub const SENSE_MEDIUM_ERROR: u8 = 0x3;
+pub const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+pub const SENSE_DATA_PROTECT: u8 = 0x7;
+
+/// Additional sense codes.
+pub const ASC_WRITE_ERROR: u8 = 0x0C;
+pub const ASC_READ_ERROR: u8 = 0x11;
+pub const ASC_INVALID_COMMAND: u8 = 0x20;
+pub const ASC_BLOCK_OUT_OF_RANGE: u8 = 0x21;
+pub const ASC_WRITE_PROTECTED: u8 = 0x27;
+
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const READ_6: u8 = 0x08;
+pub const WRITE_6: u8 = 0x0A;
+pub const INQUIRY: u8 = 0x12;
+pub const MODE_SELECT_6: u8 = 0x15;
+pub const MODE_SENSE_6: u8 = 0x1A;
+pub const START_STOP_UNIT: u8 = 0x1B;
+pub const PREVENT_ALLOW_REMOVAL: u8 = 0x1E;
+pub const READ_CAPACITY: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2A;
+pub const VERIFY_10: u8 = 0x2F;
+pub const SYNCHRONIZE_CACHE: u8 = 0x35;
+
+/// How long a command descriptor block starting with `opcode` is, from its group.
+pub fn command_length(opcode: u8) -> usize {
+    match opcode >> 5 {
+        1 | 2 => 10,
+        4 => 16,
+        5 => 12,
+        _ => 6,
+    }
+}
+
+/// What comes after the command phase.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    DataIn,
+    DataOut,
+    Status,
+}
+
+/// Blocks still to move for a READ or WRITE.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Transfer {
+    lbn: u32,
+    blocks: u32,
+}
+
+pub struct ScsiDisk {
+    disk: Disk,
+    /// Sense key and additional sense code left by the last command, for REQUEST SENSE.
+    sense: (u8, u8),
+    status: u8,
+    /// What's ready for the initiator in data in, or taken from it so far in data out.
+    data: Vec<u8>,
+    pos: usize,
+    /// Bytes a data out phase wants before there's something to do with them.
+    expect: usize,
+    transfer: Option<Transfer>,
+}
+
+impl ScsiDisk {
+    pub fn new(disk: Disk) -> ScsiDisk {
+        ScsiDisk {
+            disk,
+            sense: (SENSE_NONE, 0),
+            status: STATUS_GOOD,
+            data: vec![],
+            pos: 0,
+            expect: 0,
+            transfer: None,
+        }
+    }
+
+    pub fn disk(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+
+    /// Drops any command in progress, as a bus reset does.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.pos = 0;
+        self.expect = 0;
+        self.transfer = None;
+    }
+
+    /// The status byte of the last command.
+    #[inline]
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    fn check(&mut self, key: u8, asc: u8) -> Phase {
+        self.sense = (key, asc);
+        self.status = STATUS_CHECK_CONDITION;
+        self.reset();
+        Phase::Status
+    }
+
+    fn data_in(&mut self, mut data: Vec<u8>, allocation: usize) -> Phase {
+        data.truncate(allocation);
+        self.data = data;
+        Phase::DataIn
+    }
+
+    /// Starts the command in `cdb`, and says which phase it goes to next.
+    pub fn command(&mut self, cdb: &[u8]) -> Phase {
+        self.reset();
+        self.status = STATUS_GOOD;
+        let lbns = self.disk.geometry().lbns;
+        let be = |bytes: &[u8]| bytes.iter().fold(0u32, |v, &b| v << 8 | b as u32);
+
+        let (lbn, blocks) = match cdb[0] {
+            READ_6 | WRITE_6 => {
+                let blocks = if cdb[4] == 0 { 256 } else { cdb[4] as u32 };
+                (be(&cdb[1..4]) & 0x1F_FFFF, blocks)
+            }
+            READ_10 | WRITE_10 => (be(&cdb[2..6]), be(&cdb[7..9])),
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_REMOVAL | VERIFY_10 | SYNCHRONIZE_CACHE => {
+                return Phase::Status;
+            }
+            REQUEST_SENSE => {
+                let (key, asc) = std::mem::replace(&mut self.sense, (SENSE_NONE, 0));
+                let mut sense = vec![0; 18];
+                sense[0] = 0x70;
+                sense[2] = key;
+                sense[7] = 10;
+                sense[12] = asc;
+                // SCSI-1 initiators ask for 0 and mean 4.
+                let allocation = if cdb[4] == 0 { 4 } else { cdb[4] as usize };
+                return self.data_in(sense, allocation);
+            }
+            INQUIRY => {
+                let mut inquiry = vec![0x00, 0x00, 0x02, 0x02, 31, 0, 0, 0];
+                inquiry.extend_from_slice(b"DEC     ");
+                inquiry.extend_from_slice(format!("{:<16}", self.disk.geometry().name).as_bytes());
+                inquiry.extend_from_slice(b"0001");
+                return self.data_in(inquiry, cdb[4] as usize);
+            }
+            READ_CAPACITY => {
+                let mut capacity = (lbns - 1).to_be_bytes().to_vec();
+                capacity.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                return self.data_in(capacity, 8);
+            }
+            MODE_SENSE_6 => {
+                // The header and one block descriptor, no pages.
+                let protect = if self.disk.is_read_only() { 0x80 } else { 0 };
+                let mut mode = vec![11, 0, protect, 8, 0];
+                mode.extend_from_slice(&lbns.to_be_bytes()[1..]);
+                mode.push(0);
+                mode.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                return self.data_in(mode, cdb[4] as usize);
+            }
+            MODE_SELECT_6 => {
+                // Nothing here can be changed, so the parameters are taken and dropped.
+                self.expect = cdb[4] as usize;
+                return if self.expect == 0 { Phase::Status } else { Phase::DataOut };
+            }
+            _ => return self.check(SENSE_ILLEGAL_REQUEST, ASC_INVALID_COMMAND),
+        };
+
+        if lbn as u64 + blocks as u64 > lbns as u64 {
+            return self.check(SENSE_ILLEGAL_REQUEST, ASC_BLOCK_OUT_OF_RANGE);
+        }
+        let writing = matches!(cdb[0], WRITE_6 | WRITE_10);
+        if writing && self.disk.is_read_only() {
+            return self.check(SENSE_DATA_PROTECT, ASC_WRITE_PROTECTED);
+        }
+        if blocks == 0 {
+            return Phase::Status;
+        }
+        self.transfer = Some(Transfer { lbn, blocks });
+        if writing {
+            self.expect = BLOCK_SIZE;
+            Phase::DataOut
+        } else {
+            Phase::DataIn
+        }
+    }
+
+    /// The next byte for the initiator in data in, reading the next block when it's needed.
+    /// None once there's nothing more, or a read failed; the status says which.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.pos == self.data.len() {
+            let t = self.transfer.take()?;
+            self.data.resize(BLOCK_SIZE, 0);
+            self.pos = 0;
+            if self.disk.read_blocks(t.lbn, &mut self.data).is_err() {
+                self.check(SENSE_MEDIUM_ERROR, ASC_READ_ERROR);
+                return None;
+            }
+            if t.blocks > 1 {
+                self.transfer = Some(Transfer { lbn: t.lbn + 1, blocks: t.blocks - 1 });
+            }
+        }
+        self.pos += 1;
+        Some(self.data[self.pos - 1])
+    }
+
+    /// Takes a byte from the initiator in data out, writing each block as it's filled.
+    /// Returns whether it wants more.
+    pub fn write_byte(&mut self, b: u8) -> bool {
+        if self.expect == 0 {
+            return false;
+        }
+        self.data.push(b);
+        if self.data.len() < self.expect {
+            return true;
+        }
+        let result = match self.transfer.take() {
+            Some(t) => {
+                let result = self.disk.write_blocks(t.lbn, &self.data);
+                if t.blocks > 1 {
+                    self.transfer = Some(Transfer { lbn: t.lbn + 1, blocks: t.blocks - 1 });
+                }
+                result
+            }
+            None => Ok(()),
+        };
+        self.data.clear();
+        if result.is_err() {
+            self.check(SENSE_MEDIUM_ERROR, ASC_WRITE_ERROR);
+            return false;
+        }
+        if self.transfer.is_none() {
+            self.expect = 0;
+        }
+        self.expect != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::devices::disk::{MemoryStore, RD53};
+
+    fn run(disk: &mut ScsiDisk, cdb: &[u8], out: &[u8]) -> (Vec<u8>, u8) {
+        let mut data = vec![];
+        match disk.command(cdb) {
+            Phase::DataIn => {
+                while let Some(b) = disk.read_byte() {
+                    data.push(b);
+                }
+            }
+            Phase::DataOut => {
+                for &b in out {
+                    if !disk.write_byte(b) {
+                        break;
+                    }
+                }
+            }
+            Phase::Status => {}
+        }
+        (data, disk.status())
+    }
+
+    #[test]
+    fn scsi_disk_commands() {
+        assert_eq!((command_length(READ_6), command_length(READ_10)), (6, 10));
+        let mut disk = ScsiDisk::new(Disk::new(RD53, Box::new(MemoryStore::new()), false));
+
+        let (inquiry, status) = run(&mut disk, &[INQUIRY, 0, 0, 0, 36, 0], &[]);
+        assert_eq!((inquiry.len(), status), (36, STATUS_GOOD));
+        assert_eq!(&inquiry[8..20], b"DEC     RD53");
+        let (capacity, _) = run(&mut disk, &[READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(capacity, [(RD53.lbns - 1).to_be_bytes(), 512u32.to_be_bytes()].concat());
+
+        // Two blocks out with WRITE(10), back with READ(6).
+        let blocks: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i / BLOCK_SIZE) as u8 + 1).collect();
+        assert_eq!(run(&mut disk, &[WRITE_10, 0, 0, 0, 0, 9, 0, 0, 2, 0], &blocks), (vec![], STATUS_GOOD));
+        assert_eq!(run(&mut disk, &[READ_6, 0, 0, 9, 2, 0], &[]), (blocks.clone(), STATUS_GOOD));
+
+        // Past the end, and something it doesn't know, leave sense data.
+        let end = RD53.lbns.to_be_bytes();
+        assert_eq!(run(&mut disk, &[READ_10, 0, end[0], end[1], end[2], end[3], 0, 0, 1, 0], &[]).1, STATUS_CHECK_CONDITION);
+        let (sense, _) = run(&mut disk, &[REQUEST_SENSE, 0, 0, 0, 18, 0], &[]);
+        assert_eq!((sense[2], sense[12]), (SENSE_ILLEGAL_REQUEST, ASC_BLOCK_OUT_OF_RANGE));
+        assert_eq!(run(&mut disk, &[0xC0, 0, 0, 0, 0, 0], &[]).1, STATUS_CHECK_CONDITION);
+        let (sense, _) = run(&mut disk, &[REQUEST_SENSE, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(sense, [0x70, 0, SENSE_ILLEGAL_REQUEST, 0]);
+
+        let mut disk = ScsiDisk::new(Disk::new(RD53, Box::new(MemoryStore::new()), true));
+        assert_eq!(run(&mut disk, &[WRITE_6, 0, 0, 0, 1, 0], &blocks).1, STATUS_CHECK_CONDITION);
+        let (mode, _) = run(&mut disk, &[MODE_SENSE_6, 0, 0, 0, 12, 0], &[]);
+        assert_eq!((mode.len(), mode[2]), (12, 0x80));
+    }
+}
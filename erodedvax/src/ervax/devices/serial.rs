@@ -0,0 +1,455 @@
+//! The host ends of serial lines: where a line's output goes, where its input comes from, and
+//! whether anybody's there. A line can be a TCP port telnet clients connect to, a pseudo
+//! terminal, or a channel to elsewhere in the process.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, Sender, TryRecvError},
+    Arc,
+};
+
+use crate::ervax::devices::console::{ConsoleSink, ConsoleSource};
+
+/// The host end of one serial line. Nothing here may block.
+pub trait SerialEndpoint {
+    fn write_byte(&mut self, b: u8);
+
+    fn poll(&mut self) -> Option<u8>;
+
+    /// Somebody is connected. Endpoints that are always there always have carrier.
+    fn carrier(&mut self) -> bool {
+        true
+    }
+
+    /// The guest dropped DTR: disconnect whoever is there.
+    fn hang_up(&mut self) {}
+}
+
+/// A console sink and source as a line, always connected.
+pub struct ConsoleLine {
+    sink: Option<Box<dyn ConsoleSink>>,
+    source: Option<Box<dyn ConsoleSource>>,
+}
+
+impl ConsoleLine {
+    /// Output with no sink is thrown away.
+    pub fn new(sink: Option<Box<dyn ConsoleSink>>, source: Option<Box<dyn ConsoleSource>>) -> ConsoleLine {
+        ConsoleLine { sink, source }
+    }
+}
+
+impl SerialEndpoint for ConsoleLine {
+    fn write_byte(&mut self, b: u8) {
+        if let Some(sink) = self.sink.as_mut() {
+            sink.write_byte(b);
+        }
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        self.source.as_mut().and_then(|s| s.poll())
+    }
+}
+
+/// One end of a line to elsewhere in the process. The line goes down when either end hangs up
+/// or is dropped.
+pub struct ChannelLine {
+    tx: Sender<u8>,
+    rx: Receiver<u8>,
+    up: Arc<AtomicBool>,
+}
+
+impl ChannelLine {
+    /// Both ends of a line: one for the device, one for whatever's at the far end.
+    pub fn pair() -> (ChannelLine, ChannelLine) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let up = Arc::new(AtomicBool::new(true));
+        (
+            ChannelLine { tx: a_tx, rx: a_rx, up: up.clone() },
+            ChannelLine { tx: b_tx, rx: b_rx, up },
+        )
+    }
+
+    /// Everything received so far.
+    pub fn drain(&mut self) -> Vec<u8> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl SerialEndpoint for ChannelLine {
+    fn write_byte(&mut self, b: u8) {
+        // The far end may be gone, and the guest has no way of knowing.
+        let _ = self.tx.send(b);
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        match self.rx.try_recv() {
+            Ok(b) => Some(b),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    fn carrier(&mut self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    fn hang_up(&mut self) {
+        self.up.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ChannelLine {
+    fn drop(&mut self) {
+        self.hang_up();
+    }
+}
+
+/// Telnet protocol bytes.
+pub mod telnet {
+    pub const IAC: u8 = 255;
+    pub const DONT: u8 = 254;
+    pub const DO: u8 = 253;
+    pub const WONT: u8 = 252;
+    pub const WILL: u8 = 251;
+    pub const SB: u8 = 250;
+    pub const SE: u8 = 240;
+
+    pub const BINARY: u8 = 0;
+    pub const ECHO: u8 = 1;
+    pub const SGA: u8 = 3;
+    pub const LINEMODE: u8 = 34;
+
+    /// Sent when a client connects: the guest echoes, characters go one at a time, and both
+    /// directions are 8-bit clean.
+    pub const GREETING: [u8; 15] = [
+        IAC, WILL, SGA, IAC, WILL, ECHO, IAC, WILL, BINARY, IAC, DO, BINARY, IAC, DONT, LINEMODE,
+    ];
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    /// Just after a carriage return, where a client may add a NUL.
+    Return,
+    Iac,
+    /// Waiting for the option of a WILL, WONT, DO, or DONT.
+    Option,
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// A line reached by connecting to a TCP port, with enough of the telnet protocol for telnet
+/// clients to work as terminals. One client at a time; others are told the line is busy.
+pub struct TcpLine {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    state: TelnetState,
+    input: VecDeque<u8>,
+}
+
+impl TcpLine {
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLine> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpLine { listener, client: None, state: TelnetState::Data, input: VecDeque::new() })
+    }
+
+    /// A line on `port` of the loopback interface, only reachable from this host.
+    pub fn localhost(port: u16) -> io::Result<TcpLine> {
+        TcpLine::listen(("127.0.0.1", port))
+    }
+
+    pub fn port(&self) -> io::Result<u16> {
+        self.listener.local_addr().map(|a| a.port())
+    }
+
+    /// Takes new connections, and reads whatever the client has sent.
+    fn service(&mut self) {
+        while let Ok((mut stream, _)) = self.listener.accept() {
+            if self.client.is_some() {
+                let _ = stream.write_all(b"\r\nLine busy\r\n");
+                continue;
+            }
+            let greeted = stream.set_nonblocking(true)
+                .and_then(|_| stream.set_nodelay(true))
+                .and_then(|_| stream.write_all(&telnet::GREETING));
+            if greeted.is_ok() {
+                self.client = Some(stream);
+                self.state = TelnetState::Data;
+            }
+        }
+
+        let mut buf = [0; 256];
+        while let Some(client) = self.client.as_mut() {
+            match client.read(&mut buf) {
+                Ok(0) => self.client = None,
+                Ok(n) => buf[..n].iter().for_each(|&b| self.receive(b)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.client = None,
+            }
+        }
+    }
+
+    /// Takes a byte from the client, keeping the data and dropping the telnet commands.
+    fn receive(&mut self, b: u8) {
+        use self::telnet::*;
+        self.state = match (self.state, b) {
+            (TelnetState::Data, IAC) | (TelnetState::Return, IAC) => TelnetState::Iac,
+            (TelnetState::Return, 0) => TelnetState::Data,
+            (TelnetState::Data, _) | (TelnetState::Return, _) => {
+                self.input.push_back(b);
+                if b == b'\r' { TelnetState::Return } else { TelnetState::Data }
+            }
+            (TelnetState::Iac, IAC) => {
+                self.input.push_back(IAC);
+                TelnetState::Data
+            }
+            (TelnetState::Iac, WILL..=DONT) => TelnetState::Option,
+            (TelnetState::Iac, SB) => TelnetState::Subnegotiation,
+            (TelnetState::Iac, _) | (TelnetState::Option, _) => TelnetState::Data,
+            (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationIac,
+            (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+            (TelnetState::SubnegotiationIac, SE) => TelnetState::Data,
+            (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+        };
+    }
+}
+
+impl SerialEndpoint for TcpLine {
+    fn write_byte(&mut self, b: u8) {
+        let Some(client) = self.client.as_mut() else { return };
+        let sent = if b == telnet::IAC { client.write_all(&[b, b]) } else { client.write_all(&[b]) };
+        // A client that can't keep up loses output, as it would on a real line.
+        if let Err(e) = sent {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                self.client = None;
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        if self.input.is_empty() {
+            self.service();
+        }
+        self.input.pop_front()
+    }
+
+    fn carrier(&mut self) -> bool {
+        self.service();
+        self.client.is_some()
+    }
+
+    fn hang_up(&mut self) {
+        self.client = None;
+        self.input.clear();
+    }
+}
+
+#[cfg(unix)]
+pub use self::pty::PtyLine;
+
+#[cfg(unix)]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::mem::MaybeUninit;
+    use std::os::raw::c_int;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::mpsc::{self, Sender};
+    use std::thread;
+
+    use super::SerialEndpoint;
+    use crate::ervax::devices::console::{ChannelSource, ConsoleSource};
+
+    fn check(r: c_int) -> io::Result<()> {
+        if r < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    /// The path of the terminal side of the pseudo terminal `fd`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn terminal_name(fd: RawFd) -> io::Result<String> {
+        let mut buf = [0 as libc::c_char; 128];
+        // SAFETY: buf is as long as ptsname_r is told, and it NUL terminates what it writes.
+        unsafe {
+            match libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) {
+                0 => Ok(CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()),
+                e => Err(io::Error::from_raw_os_error(e)),
+            }
+        }
+    }
+
+    /// The path of the terminal side of the pseudo terminal `fd`. ptsname answers in a buffer
+    /// of its own, so lines opening at once take turns.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn terminal_name(fd: RawFd) -> io::Result<String> {
+        static TURN: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        // SAFETY: ptsname's buffer is only used while this holds the turn, copied out before
+        // giving it up.
+        unsafe {
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(CStr::from_ptr(name).to_string_lossy().into_owned())
+        }
+    }
+
+    /// A line on a new pseudo terminal, for a terminal program to open by name.
+    ///
+    /// The terminal side is held open and put in raw mode, so the line's there whether or not
+    /// anything has it open, and nothing the guest sends is echoed back to it. Reads and
+    /// writes happen on threads of their own, as the controlling side can't be polled portably.
+    pub struct PtyLine {
+        name: String,
+        output: Sender<u8>,
+        input: ChannelSource,
+        /// Held open, see above.
+        _terminal: File,
+    }
+
+    impl PtyLine {
+        pub fn open() -> io::Result<PtyLine> {
+            let master = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open("/dev/ptmx")?;
+            let fd = master.as_raw_fd();
+            // SAFETY: fd is an open pseudo terminal master.
+            unsafe {
+                check(libc::grantpt(fd))?;
+                check(libc::unlockpt(fd))?;
+            }
+            let name = terminal_name(fd)?;
+
+            // Not as a controlling terminal, should the emulator be a session leader without one.
+            let terminal = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&name)?;
+            let tfd = terminal.as_raw_fd();
+            // SAFETY: tfd is an open terminal, and tcgetattr fills in the termios before
+            // anything reads it.
+            unsafe {
+                let mut termios = MaybeUninit::<libc::termios>::uninit();
+                check(libc::tcgetattr(tfd, termios.as_mut_ptr()))?;
+                let mut termios = termios.assume_init();
+                libc::cfmakeraw(&mut termios);
+                check(libc::tcsetattr(tfd, libc::TCSANOW, &termios))?;
+            }
+
+            let mut reader = master.try_clone()?;
+            let (in_tx, in_rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut buf = [0; 64];
+                while let Ok(n @ 1..=64) = reader.read(&mut buf) {
+                    if buf[..n].iter().any(|b| in_tx.send(*b).is_err()) {
+                        break;
+                    }
+                }
+            });
+            let mut writer = master;
+            let (out_tx, out_rx) = mpsc::channel::<u8>();
+            thread::spawn(move || {
+                while let Ok(b) = out_rx.recv() {
+                    let mut bytes = vec![b];
+                    bytes.extend(out_rx.try_iter());
+                    if writer.write_all(&bytes).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Ok(PtyLine { name, output: out_tx, input: ChannelSource::new(in_rx), _terminal: terminal })
+        }
+
+        /// The terminal side's device name, such as /dev/pts/3.
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl SerialEndpoint for PtyLine {
+        fn write_byte(&mut self, b: u8) {
+            let _ = self.output.send(b);
+        }
+
+        fn poll(&mut self) -> Option<u8> {
+            self.input.poll()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Polls `line` until `n` bytes have come in, or a second has gone by.
+    fn receive(line: &mut TcpLine, n: usize) -> Vec<u8> {
+        let start = Instant::now();
+        let mut got = vec![];
+        while got.len() < n && start.elapsed() < Duration::from_secs(1) {
+            match line.poll() {
+                Some(b) => got.push(b),
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        got
+    }
+
+    #[test]
+    fn serial_endpoints() {
+        let (mut dev, mut far) = ChannelLine::pair();
+        dev.write_byte(b'a');
+        far.write_byte(b'b');
+        assert_eq!((far.drain(), dev.poll(), dev.poll()), (vec![b'a'], Some(b'b'), None));
+        assert!(dev.carrier());
+        drop(far);
+        assert!(!dev.carrier());
+
+        let mut line = TcpLine::localhost(0).unwrap();
+        assert!(!line.carrier());
+        let mut client = TcpStream::connect(("127.0.0.1", line.port().unwrap())).unwrap();
+        let start = Instant::now();
+        while !line.carrier() && start.elapsed() < Duration::from_secs(1) {}
+        assert!(line.carrier());
+
+        let mut greeting = [0; 15];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, telnet::GREETING);
+
+        // Telnet commands are dropped, doubled IACs and CR NUL come through as one byte.
+        use self::telnet::*;
+        client.write_all(&[b'h', IAC, DO, ECHO, IAC, SB, 24, 0, IAC, SE, b'\r', 0, IAC, IAC, b'i']).unwrap();
+        assert_eq!(receive(&mut line, 4), [b'h', b'\r', IAC, b'i']);
+        line.write_byte(IAC);
+        line.write_byte(b'!');
+        let mut echoed = [0; 3];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, [IAC, IAC, b'!']);
+
+        drop(client);
+        let start = Instant::now();
+        while line.carrier() && start.elapsed() < Duration::from_secs(1) {}
+        assert!(!line.carrier());
+
+        // A pseudo terminal's raw, so a line ending goes straight through with no echo.
+        #[cfg(unix)]
+        {
+            let mut pty = PtyLine::open().unwrap();
+            let mut terminal = std::fs::OpenOptions::new().read(true).write(true).open(pty.name()).unwrap();
+            terminal.write_all(b"x\r").unwrap();
+            let start = Instant::now();
+            let mut got = vec![];
+            while got.len() < 2 && start.elapsed() < Duration::from_secs(1) {
+                got.extend(pty.poll());
+            }
+            assert_eq!(got, b"x\r");
+            pty.write_byte(b'\n');
+            let mut out = [0; 1];
+            terminal.read_exact(&mut out).unwrap();
+            assert_eq!(out, [b'\n']);
+        }
+    }
+}
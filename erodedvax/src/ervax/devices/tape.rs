@@ -0,0 +1,254 @@
+//! Tapes, kept in SIMH style `.tap` image files.
+//!
+//! An image is the tape's objects one after another. A record is its length as a little endian
+//! longword, the data padded to an even length, then the length again, so the tape can be read
+//! in either direction. A zero longword is a tape mark, and the end of the image, or an
+//! 0xFFFFFFFF longword, is the end of what's been written. Writing anywhere on the tape loses
+//! everything after it, as it would on a real one.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+pub const TAPE_MARK: u32 = 0;
+pub const END_OF_MEDIUM: u32 = 0xFFFF_FFFF;
+/// Erased tape, skipped over in either direction.
+pub const ERASE_GAP: u32 = 0xFFFF_FFFE;
+/// Record length, the record had an error when it was written.
+pub const BAD_RECORD: u32 = 0x8000_0000;
+/// Record length, the longest an image can hold.
+pub const RECORD_LENGTH: u32 = 0x00FF_FFFF;
+
+/// Somewhere to keep a tape image.
+pub trait Medium: Read + Write + Seek {
+    /// Cuts the image off after `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Medium for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// An image kept in memory.
+impl Medium for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// What the tape moved over.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Object {
+    /// A record of this many bytes.
+    Record(u32),
+    /// A record of this many bytes that was written with an error.
+    BadRecord(u32),
+    TapeMark,
+    /// Going forward, there's nothing more on the tape. The tape doesn't move.
+    EndOfMedium,
+    /// Going back, the tape's already at the beginning.
+    BeginningOfTape,
+}
+
+/// A tape in a drive.
+pub struct Tape {
+    medium: Box<dyn Medium>,
+    read_only: bool,
+    /// Bytes into the image.
+    offset: u64,
+    /// Objects between the beginning of the tape and here.
+    position: u32,
+}
+
+impl Tape {
+    pub fn new(medium: Box<dyn Medium>, read_only: bool) -> Tape {
+        Tape { medium, read_only, offset: 0, position: 0 }
+    }
+
+    /// A tape kept in the image file at `path`. A missing image is created, as a blank tape,
+    /// unless the tape is read only.
+    pub fn open(path: &str, read_only: bool) -> io::Result<Tape> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(path)?;
+        Ok(Tape::new(Box::new(file), read_only))
+    }
+
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Objects, records and tape marks, between the beginning of the tape and here.
+    #[inline]
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    #[inline]
+    pub fn at_beginning(&self) -> bool {
+        self.offset == 0
+    }
+
+    pub fn rewind(&mut self) {
+        self.offset = 0;
+        self.position = 0;
+    }
+
+    /// The longword at `offset`, None past the end of the image.
+    fn longword(&mut self, offset: u64) -> io::Result<Option<u32>> {
+        let mut b = [0; 4];
+        self.medium.seek(SeekFrom::Start(offset))?;
+        match self.medium.read_exact(&mut b) {
+            Ok(()) => Ok(Some(u32::from_le_bytes(b))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves forward over the next object, putting what's in it in `data` if it's a record.
+    fn forward(&mut self, data: Option<&mut Vec<u8>>) -> io::Result<Object> {
+        loop {
+            let len = match self.longword(self.offset)? {
+                None | Some(END_OF_MEDIUM) => return Ok(Object::EndOfMedium),
+                Some(len) => len,
+            };
+            match len {
+                TAPE_MARK => {
+                    self.offset += 4;
+                    self.position += 1;
+                    return Ok(Object::TapeMark);
+                }
+                ERASE_GAP => self.offset += 4,
+                _ => {
+                    let n = len & RECORD_LENGTH;
+                    if let Some(data) = data {
+                        data.resize(n as usize, 0);
+                        self.medium.read_exact(data)?;
+                    }
+                    self.offset += 8 + ((n as u64 + 1) & !1);
+                    self.position += 1;
+                    return Ok(if len & BAD_RECORD != 0 { Object::BadRecord(n) } else { Object::Record(n) });
+                }
+            }
+        }
+    }
+
+    /// Reads the next record into `data`. Anything else leaves `data` alone.
+    pub fn read(&mut self, data: &mut Vec<u8>) -> io::Result<Object> {
+        self.forward(Some(data))
+    }
+
+    /// Moves forward over the next object.
+    pub fn skip(&mut self) -> io::Result<Object> {
+        self.forward(None)
+    }
+
+    /// Moves back over the object before this one.
+    pub fn skip_back(&mut self) -> io::Result<Object> {
+        loop {
+            if self.offset < 4 {
+                return Ok(Object::BeginningOfTape);
+            }
+            let len = self.longword(self.offset - 4)?.unwrap_or(ERASE_GAP);
+            match len {
+                TAPE_MARK => {
+                    self.offset -= 4;
+                    self.position = self.position.saturating_sub(1);
+                    return Ok(Object::TapeMark);
+                }
+                ERASE_GAP | END_OF_MEDIUM => self.offset -= 4,
+                _ => {
+                    let n = len & RECORD_LENGTH;
+                    self.offset = self.offset.saturating_sub(8 + ((n as u64 + 1) & !1));
+                    self.position = self.position.saturating_sub(1);
+                    return Ok(if len & BAD_RECORD != 0 { Object::BadRecord(n) } else { Object::Record(n) });
+                }
+            }
+        }
+    }
+
+    /// Writes `bytes` here, then cuts the tape off after them.
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "tape is write protected"));
+        }
+        self.medium.seek(SeekFrom::Start(self.offset))?;
+        self.medium.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        self.medium.set_len(self.offset)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = (data.len() as u32).to_le_bytes();
+        let mut record = Vec::with_capacity(data.len() + 9);
+        record.extend_from_slice(&len);
+        record.extend_from_slice(data);
+        if !data.len().is_multiple_of(2) {
+            record.push(0);
+        }
+        record.extend_from_slice(&len);
+        self.put(&record)?;
+        self.position += 1;
+        Ok(())
+    }
+
+    pub fn write_tape_mark(&mut self) -> io::Result<()> {
+        self.put(&TAPE_MARK.to_le_bytes())?;
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Erases everything from here on.
+    pub fn erase(&mut self) -> io::Result<()> {
+        self.put(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tape_image() {
+        // A record of 3 bytes, a tape mark, a gap, a bad record of 2 bytes, then the end.
+        let mut image = vec![3, 0, 0, 0, b'a', b'b', b'c', 0, 3, 0, 0, 0, 0, 0, 0, 0];
+        image.extend_from_slice(&ERASE_GAP.to_le_bytes());
+        image.extend_from_slice(&[2, 0, 0, 0x80, b'x', b'y', 2, 0, 0, 0x80]);
+        image.extend_from_slice(&END_OF_MEDIUM.to_le_bytes());
+        let mut tape = Tape::new(Box::new(Cursor::new(image)), false);
+
+        let mut data = vec![];
+        assert_eq!(tape.read(&mut data).unwrap(), Object::Record(3));
+        assert_eq!(data, b"abc");
+        assert_eq!(tape.skip().unwrap(), Object::TapeMark);
+        assert_eq!(tape.read(&mut data).unwrap(), Object::BadRecord(2));
+        assert_eq!(data, b"xy");
+        assert_eq!((tape.skip().unwrap(), tape.position()), (Object::EndOfMedium, 3));
+
+        assert_eq!(tape.skip_back().unwrap(), Object::BadRecord(2));
+        assert_eq!(tape.skip_back().unwrap(), Object::TapeMark);
+        assert_eq!(tape.skip_back().unwrap(), Object::Record(3));
+        assert_eq!((tape.skip_back().unwrap(), tape.position()), (Object::BeginningOfTape, 0));
+
+        // Writing after the first record loses the rest.
+        tape.skip().unwrap();
+        tape.write(b"hello").unwrap();
+        tape.write_tape_mark().unwrap();
+        tape.rewind();
+        assert_eq!(tape.skip().unwrap(), Object::Record(3));
+        assert_eq!(tape.read(&mut data).unwrap(), Object::Record(5));
+        assert_eq!(data, b"hello");
+        assert_eq!(tape.skip().unwrap(), Object::TapeMark);
+        assert_eq!(tape.skip().unwrap(), Object::EndOfMedium);
+
+        let mut tape = Tape::new(Box::new(Cursor::new(vec![])), true);
+        assert_eq!(tape.skip().unwrap(), Object::EndOfMedium);
+        assert!(tape.write_tape_mark().is_err());
+    }
+}
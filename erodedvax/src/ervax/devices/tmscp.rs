@@ -0,0 +1,325 @@
+//! A TMSCP tape server, behind the port of a tape controller such as the TQK50, with TK50
+//! drives holding tape images.
+//!
+//! Records go straight between the image and host memory. The serious exception condition
+//! isn't modelled, so hosts never have to clear it before carrying on after a tape mark or an
+//! error.
+
+use crate::ervax::devices::{
+    disk::media_id,
+    iopage::{Dma, DmaError},
+    mscp::{
+        OP_AVAILABLE, OP_ERASE, OP_GET_UNIT_STATUS, OP_ONLINE, OP_READ, OP_WRITE,
+        ST_DRIVE_ERROR, ST_HOST_BUFFER_ACCESS, ST_INVALID_COMMAND, ST_SUCCESS,
+        ST_UNIT_AVAILABLE, ST_UNIT_OFFLINE, ST_WRITE_PROTECTED, UF_WPH,
+    },
+    tape::{Object, Tape, RECORD_LENGTH},
+    uqssp::{get_u16, get_u32, put_u16, put_u32, Controller, Port, Server},
+};
+
+/// Where the first tape controller's registers usually are, 17774500 in the I/O page.
+pub const DEFAULT_BASE: u32 = 0o14500;
+
+/// Opcodes TMSCP adds to MSCP's.
+pub const OP_SET_UNIT_CHARACTERISTICS: u8 = 10;
+pub const OP_ERASE_GAP: u8 = 22;
+pub const OP_WRITE_TAPE_MARK: u8 = 36;
+pub const OP_REPOSITION: u8 = 37;
+
+/// Modifiers, in the word after the opcode.
+pub const MD_REWIND: u16 = 0x0002;
+/// REPOSITION, the record count counts tape marks too, and the tape mark count is ignored.
+pub const MD_OBJECT_COUNT: u16 = 0x0004;
+pub const MD_REVERSE: u16 = 0x0008;
+pub const MD_UNLOAD: u16 = 0x0010;
+
+/// Status codes TMSCP adds to MSCP's.
+pub const ST_DATA_ERROR: u16 = 8;
+pub const ST_BOT: u16 = 13;
+pub const ST_TAPE_MARK: u16 = 14;
+pub const ST_RECORD_TRUNCATED: u16 = 16;
+
+/// The TK50 drive's MSCP model number and media type.
+pub const TK50_MODEL: u8 = 3;
+pub const TK50_MEDIA: u32 = media_id(*b"MU", *b"TK\0", 50);
+/// Tape format, TK50 cartridge.
+pub const TF_TK50: u16 = 0x0201;
+
+struct Unit {
+    tape: Tape,
+    online: bool,
+}
+
+/// The tape drives on a controller.
+#[derive(Default)]
+pub struct TmscpServer {
+    units: Vec<Option<Unit>>,
+}
+
+/// A tape controller.
+pub type Tmscp = Port<TmscpServer>;
+
+impl Tmscp {
+    /// A controller with its registers `base` bytes into the I/O page, and no drives.
+    pub fn new(controller: Controller, base: u32) -> Tmscp {
+        Port::with_server(controller, base, TmscpServer::default())
+    }
+
+    /// Puts a drive with a tape in it on the controller as unit `n`.
+    pub fn attach(&mut self, n: usize, tape: Tape) {
+        let units = &mut self.server().units;
+        if units.len() <= n {
+            units.resize_with(n + 1, || None);
+        }
+        units[n] = Some(Unit { tape, online: false });
+    }
+
+    pub fn tape(&mut self, n: usize) -> Option<&mut Tape> {
+        self.server().units.get_mut(n)?.as_mut().map(|u| &mut u.tape)
+    }
+}
+
+impl Server for TmscpServer {
+    fn response_len(&self, opcode: u8) -> usize {
+        match opcode {
+            OP_READ | OP_WRITE => 40,
+            OP_GET_UNIT_STATUS | OP_ONLINE | OP_SET_UNIT_CHARACTERISTICS | OP_REPOSITION
+            | OP_WRITE_TAPE_MARK | OP_ERASE | OP_ERASE_GAP => 36,
+            _ => 12,
+        }
+    }
+
+    fn execute(&mut self, cmd: &[u8], rsp: &mut [u8], dma: &mut dyn Dma) -> u16 {
+        let opcode = cmd[8];
+        let n = get_u16(cmd, 4);
+        let modifiers = get_u16(cmd, 10);
+        let unit = match self.units.get_mut(n as usize).and_then(|u| u.as_mut()) {
+            Some(unit) => unit,
+            None => return ST_UNIT_OFFLINE,
+        };
+
+        let status = match opcode {
+            OP_GET_UNIT_STATUS => {
+                TmscpServer::describe(n, unit, rsp);
+                if unit.online { ST_SUCCESS } else { ST_UNIT_AVAILABLE }
+            }
+            OP_ONLINE | OP_SET_UNIT_CHARACTERISTICS => {
+                // There's only the one format, so there's nothing to set.
+                unit.online = true;
+                TmscpServer::describe(n, unit, rsp);
+                ST_SUCCESS
+            }
+            OP_AVAILABLE => {
+                if modifiers & MD_UNLOAD != 0 {
+                    unit.tape.rewind();
+                }
+                unit.online = false;
+                ST_SUCCESS
+            }
+            _ if !unit.online => ST_UNIT_AVAILABLE,
+            OP_READ | OP_WRITE => TmscpServer::transfer(cmd, unit, rsp, dma),
+            OP_REPOSITION => TmscpServer::reposition(cmd, unit, rsp),
+            OP_WRITE_TAPE_MARK | OP_ERASE if unit.tape.is_read_only() => ST_WRITE_PROTECTED,
+            OP_WRITE_TAPE_MARK => status(unit.tape.write_tape_mark().map(|_| ST_SUCCESS)),
+            OP_ERASE => status(unit.tape.erase().map(|_| ST_SUCCESS)),
+            OP_ERASE_GAP => ST_SUCCESS,
+            _ => ST_INVALID_COMMAND | 8 << 8,
+        };
+        if matches!(opcode, OP_READ | OP_WRITE | OP_REPOSITION | OP_WRITE_TAPE_MARK | OP_ERASE | OP_ERASE_GAP) {
+            put_u32(rsp, 32, unit.tape.position());
+        }
+        status
+    }
+
+    fn initialize(&mut self) {
+        self.units.iter_mut().flatten().for_each(|u| u.online = false);
+    }
+}
+
+/// The status for what the drive did, a drive error if it couldn't get at the image.
+fn status(result: std::io::Result<u16>) -> u16 {
+    result.unwrap_or(ST_DRIVE_ERROR)
+}
+
+impl TmscpServer {
+    /// The unit flags, identifier, and format, which ONLINE, SET UNIT CHARACTERISTICS, and GET
+    /// UNIT STATUS all give.
+    fn describe(n: u16, unit: &Unit, rsp: &mut [u8]) {
+        put_u16(rsp, 14, if unit.tape.is_read_only() { UF_WPH } else { 0 });
+        put_u32(rsp, 20, n as u32 + 1);
+        rsp[26] = TK50_MODEL;
+        rsp[27] = 3;
+        put_u32(rsp, 28, TK50_MEDIA);
+        put_u16(rsp, 32, TF_TK50);
+    }
+
+    /// READ and WRITE, a record at a time.
+    fn transfer(cmd: &[u8], unit: &mut Unit, rsp: &mut [u8], dma: &mut dyn Dma) -> u16 {
+        let count = get_u32(cmd, 12);
+        if count > RECORD_LENGTH {
+            return ST_INVALID_COMMAND | 12 << 8;
+        }
+        let count = count as usize;
+        let buffer = get_u32(cmd, 16) & 0x3F_FFFF;
+        let mut data = vec![];
+
+        if cmd[8] == OP_WRITE {
+            if unit.tape.is_read_only() {
+                return ST_WRITE_PROTECTED;
+            }
+            data.resize(count, 0);
+            if let Err(DmaError::NonExistent(_)) = dma.read(buffer, &mut data) {
+                return ST_HOST_BUFFER_ACCESS;
+            }
+            if unit.tape.write(&data).is_err() {
+                return ST_DRIVE_ERROR;
+            }
+            put_u32(rsp, 12, count as u32);
+            return ST_SUCCESS;
+        }
+
+        let (len, status) = match unit.tape.read(&mut data) {
+            Ok(Object::Record(len)) if len as usize > count => (len, ST_RECORD_TRUNCATED),
+            Ok(Object::Record(len)) => (len, ST_SUCCESS),
+            Ok(Object::BadRecord(len)) => (len, ST_DATA_ERROR),
+            Ok(Object::TapeMark) => return ST_TAPE_MARK,
+            Ok(Object::EndOfMedium | Object::BeginningOfTape) => return ST_DATA_ERROR,
+            Err(_) => return ST_DRIVE_ERROR,
+        };
+        let n = (len as usize).min(count);
+        if dma.write(buffer, &data[..n]).is_err() {
+            return ST_HOST_BUFFER_ACCESS;
+        }
+        put_u32(rsp, 12, n as u32);
+        put_u32(rsp, 36, len);
+        status
+    }
+
+    /// REPOSITION: rewind, then skip tape marks, then records, either way.
+    fn reposition(cmd: &[u8], unit: &mut Unit, rsp: &mut [u8]) -> u16 {
+        let modifiers = get_u16(cmd, 10);
+        let (want_records, want_marks) = (get_u32(cmd, 12), get_u32(cmd, 16));
+        let objects = modifiers & MD_OBJECT_COUNT != 0;
+        let tape = &mut unit.tape;
+        let step = |tape: &mut Tape| {
+            if modifiers & MD_REVERSE != 0 { tape.skip_back() } else { tape.skip() }
+        };
+
+        if modifiers & (MD_REWIND | MD_UNLOAD) != 0 {
+            tape.rewind();
+        }
+        let (mut records, mut marks) = (0, 0);
+        let result = (|| {
+            while !objects && marks < want_marks {
+                match step(tape)? {
+                    Object::TapeMark => marks += 1,
+                    Object::Record(_) | Object::BadRecord(_) => {}
+                    Object::EndOfMedium => return Ok(ST_DATA_ERROR),
+                    Object::BeginningOfTape => return Ok(ST_BOT),
+                }
+            }
+            while records < want_records {
+                match step(tape)? {
+                    Object::Record(_) | Object::BadRecord(_) => records += 1,
+                    Object::TapeMark if objects => {
+                        records += 1;
+                        marks += 1;
+                    }
+                    Object::TapeMark => {
+                        marks += 1;
+                        return Ok(ST_TAPE_MARK);
+                    }
+                    Object::EndOfMedium => return Ok(ST_DATA_ERROR),
+                    Object::BeginningOfTape => return Ok(ST_BOT),
+                }
+            }
+            Ok(ST_SUCCESS)
+        })();
+        put_u32(rsp, 12, records);
+        put_u32(rsp, 16, marks);
+        status(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::bus::VAXBus,
+        devices::uqssp::tests::{self, init, DATA},
+    };
+    use std::io::Cursor;
+
+    fn command(bus: &mut VAXBus, n: usize, opcode: u8, modifiers: u16, count: u32, buffer: u32) -> Vec<u8> {
+        tests::command(bus, DEFAULT_BASE, n, &tests::packet(0, opcode, modifiers, count, buffer, 0))
+    }
+
+    /// Status, then records and tape marks skipped, then position.
+    fn reposition(bus: &mut VAXBus, n: usize, modifiers: u16, records: u32, marks: u32) -> (u16, u32, u32, u32) {
+        let r = command(bus, n, OP_REPOSITION, modifiers, records, marks);
+        (get_u16(&r, 10), get_u32(&r, 12), get_u32(&r, 16), get_u32(&r, 32))
+    }
+
+    #[test]
+    fn tmscp_tape() {
+        let mut tqk50 = Tmscp::new(Controller::Tqk50, DEFAULT_BASE);
+        tqk50.attach(0, Tape::new(Box::new(Cursor::new(vec![])), false));
+        let mut bus = tests::machine(Box::new(tqk50));
+        init(&mut bus, DEFAULT_BASE, Controller::Tqk50);
+
+        let r = command(&mut bus, 0, OP_READ, 0, 512, DATA);
+        assert_eq!(get_u16(&r, 10), ST_UNIT_AVAILABLE);
+        let r = command(&mut bus, 1, OP_ONLINE, 0, 0, 0);
+        assert_eq!((r.len(), get_u16(&r, 10), get_u32(&r, 28)), (36, ST_SUCCESS, TK50_MEDIA));
+
+        // Two files: two records and one, each followed by a tape mark.
+        for i in 0..300 {
+            bus.write_u8(DATA + i, i as u8).unwrap();
+        }
+        for (i, &len) in [300, 100].iter().enumerate() {
+            let r = command(&mut bus, 2 + i, OP_WRITE, 0, len, DATA);
+            assert_eq!((get_u16(&r, 10), get_u32(&r, 12), get_u32(&r, 32)), (ST_SUCCESS, len, i as u32 + 1));
+        }
+        command(&mut bus, 4, OP_WRITE_TAPE_MARK, 0, 0, 0);
+        command(&mut bus, 5, OP_WRITE, 0, 7, DATA);
+        let r = command(&mut bus, 6, OP_WRITE_TAPE_MARK, 0, 0, 0);
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 32)), (ST_SUCCESS, 5));
+
+        // Rewind, and read the first record back into a buffer too small for it.
+        assert_eq!(reposition(&mut bus, 7, MD_REWIND, 0, 0), (ST_SUCCESS, 0, 0, 0));
+        let r = command(&mut bus, 8, OP_READ, 0, 200, DATA + 0x1000);
+        assert_eq!((r.len(), get_u16(&r, 10), get_u32(&r, 12), get_u32(&r, 36)), (40, ST_RECORD_TRUNCATED, 200, 300));
+        assert_eq!((bus.read_u8(DATA + 0x1000 + 199), bus.read_u8(DATA + 0x1000 + 200)), (Ok(199), Ok(0)));
+        let r = command(&mut bus, 9, OP_READ, 0, 512, DATA + 0x1000);
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 12), get_u32(&r, 32)), (ST_SUCCESS, 100, 2));
+        let r = command(&mut bus, 10, OP_READ, 0, 512, DATA + 0x1000);
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 32)), (ST_TAPE_MARK, 3));
+
+        // Skipping records stops at a tape mark; skipping files and objects doesn't.
+        assert_eq!(reposition(&mut bus, 11, MD_REWIND, 5, 0), (ST_TAPE_MARK, 2, 1, 3));
+        assert_eq!(reposition(&mut bus, 12, MD_REWIND, 0, 2), (ST_SUCCESS, 0, 2, 5));
+        assert_eq!(reposition(&mut bus, 13, MD_REVERSE, 1, 1), (ST_SUCCESS, 1, 1, 3));
+        assert_eq!(reposition(&mut bus, 14, MD_REWIND | MD_OBJECT_COUNT, 3, 0), (ST_SUCCESS, 3, 1, 3));
+        let r = command(&mut bus, 15, OP_READ, 0, 512, DATA + 0x1000);
+        assert_eq!((get_u16(&r, 10), get_u32(&r, 12)), (ST_SUCCESS, 7));
+        assert_eq!(reposition(&mut bus, 16, 0, 2, 0), (ST_TAPE_MARK, 0, 1, 5));
+        assert_eq!(reposition(&mut bus, 17, 0, 1, 0), (ST_DATA_ERROR, 0, 0, 5));
+        assert_eq!(reposition(&mut bus, 18, MD_REVERSE | MD_OBJECT_COUNT, 10, 0), (ST_BOT, 5, 2, 0));
+
+        // Erasing the second file loses it.
+        reposition(&mut bus, 19, 0, 0, 1);
+        command(&mut bus, 20, OP_ERASE, 0, 0, 0);
+        assert_eq!(reposition(&mut bus, 21, MD_REWIND, 0, 2), (ST_DATA_ERROR, 0, 1, 3));
+
+        // No record can be longer than an image can say.
+        let r = command(&mut bus, 22, OP_WRITE, 0, RECORD_LENGTH + 1, DATA);
+        assert_eq!(get_u16(&r, 10), ST_INVALID_COMMAND | 12 << 8);
+
+        let r = command(&mut bus, 23, OP_AVAILABLE, MD_UNLOAD, 0, 0);
+        assert_eq!(get_u16(&r, 10), ST_SUCCESS);
+        let r = command(&mut bus, 24, OP_GET_UNIT_STATUS, 0, 0, 0);
+        assert_eq!((get_u16(&r, 10), get_u16(&r, 32)), (ST_UNIT_AVAILABLE, TF_TK50));
+        let r = command(&mut bus, 25, OP_READ, 0, 512, DATA);
+        assert_eq!(get_u16(&r, 10), ST_UNIT_AVAILABLE);
+    }
+}
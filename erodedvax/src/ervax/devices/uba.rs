@@ -0,0 +1,449 @@
+//! The UNIBUS adapter of the VAX-11/780, the DW780. It sits on the SBI as a nexus, with its own
+//! registers and the map registers in the nexus's register space, and the 256 KiB UNIBUS
+//! address space, I/O page at the top, in a window of its own.
+//!
+//! Device interrupts don't reach the CPU directly. The adapter interrupts through its nexus's
+//! vector at the IPL of the request, and software reads the BRRVR for that level to get the
+//! device's vector, which is what acknowledges the device.
+//!
+//! Writes through a buffered data path collect in the path's quadword buffer, which goes to
+//! memory when it fills, when the transfer moves on to another quadword, or when software
+//! purges the path. Until then the DPR shows BNE. Reads through a buffered path come straight
+//! from memory.
+
+use std::any::Any;
+
+use crate::ervax::{
+    cpu::{
+        bus::{BusError, VAXBusDevice},
+        interrupts::Interrupt,
+    },
+    devices::iopage::{Dma, DmaError, IoPage, IoPageDevice, BR4, BR7, IO_PAGE_SIZE},
+};
+
+/// UNIBUS addresses are 18 bits.
+pub const UNIBUS_ADDRESS_SPACE: u32 = 1 << 18;
+/// Where the I/O page starts in UNIBUS address space, and so in the window.
+pub const IO_PAGE_START: u32 = UNIBUS_ADDRESS_SPACE - IO_PAGE_SIZE;
+
+/// Bytes of nexus register space.
+pub const NEXUS_BYTES: u32 = 0x2000;
+/// The first nexus on a VAX-11/780, at TR 0; each TR's registers follow the last's.
+pub const NEXUS_BASE_780: u32 = 0x2000_0000;
+/// UNIBUS address space windows on a VAX-11/780, one for each of the four adapters.
+pub const UNIBUS_SPACE_780: u32 = 0x2010_0000;
+
+/// Register offsets in nexus space.
+pub const CNFGR: u32 = 0x00;
+pub const UACR: u32 = 0x04;
+pub const UASR: u32 = 0x08;
+pub const DCR: u32 = 0x0C;
+pub const FMER: u32 = 0x10;
+pub const FUBAR: u32 = 0x18;
+/// Four registers, one for each of BR4 through BR7.
+pub const BRSVR: u32 = 0x20;
+pub const BRRVR: u32 = 0x30;
+/// Sixteen registers, data path 0 being the direct one.
+pub const DPR: u32 = 0x40;
+pub const MAPR: u32 = 0x800;
+
+/// Map registers, the UNIBUS address space below the I/O page.
+pub const MAP_ENTRIES: usize = (IO_PAGE_START / 512) as usize;
+pub const DATA_PATHS: usize = 16;
+
+/// CNFGR, the adapter's nexus type.
+pub const CNFGR_ADAPTER_CODE: u32 = 0x28;
+/// CNFGR, UNIBUS initialization complete.
+pub const CNFGR_UBIC: u32 = 0x0001_0000;
+
+/// UACR, initialize the adapter and the UNIBUS.
+pub const UACR_ADINIT: u32 = 0x01;
+/// UACR, interrupts are passed on through BRRVR.
+pub const UACR_BRIE: u32 = 0x20;
+/// UACR, UNIBUS interrupts are passed on at all.
+pub const UACR_IFS: u32 = 0x40;
+const UACR_WRITABLE: u32 = 0x7E;
+
+/// UASR, a transfer used an invalid map register. Written as 1 to clear.
+pub const UASR_IVMR: u32 = 0x08;
+
+/// Map register, valid.
+pub const MAP_MRV: u32 = 0x8000_0000;
+/// Map register, a buffered data path transfer starts a byte into memory.
+pub const MAP_BO: u32 = 0x0200_0000;
+/// Map register, which data path transfers go through.
+pub const MAP_DPDB: u32 = 0x01E0_0000;
+pub const MAP_PFN: u32 = 0x001F_FFFF;
+const MAP_WRITABLE: u32 = MAP_MRV | MAP_BO | MAP_DPDB | MAP_PFN;
+
+/// DPR, the buffer holds data. Written as 1 to purge it.
+pub const DPR_BNE: u32 = 0x8000_0000;
+
+/// What a buffered data path holds on its way to memory: bytes of the quadword at
+/// `quadword`, those in `mask`.
+#[derive(Copy, Clone, Default)]
+struct Buffer {
+    quadword: usize,
+    data: [u8; 8],
+    mask: u8,
+}
+
+impl Buffer {
+    /// Writes the bytes held to memory, emptying the buffer.
+    fn purge(&mut self, ram: &mut [u8]) {
+        for (i, &b) in self.data.iter().enumerate() {
+            if self.mask & 1 << i != 0 {
+                ram[self.quadword + i] = b;
+            }
+        }
+        self.mask = 0;
+    }
+}
+
+/// Memory through the map, as UNIBUS devices see it.
+pub struct UbaDma<'a> {
+    map: &'a [u32],
+    dprs: &'a mut [u32; DATA_PATHS],
+    buffers: &'a mut [Buffer; DATA_PATHS],
+    ram: &'a mut [u8],
+    /// The first UNIBUS address that failed.
+    failed: Option<u32>,
+}
+
+impl UbaDma<'_> {
+    /// Splits a transfer at page boundaries, as each page has its own map register, giving the
+    /// local addresses, where they start in the transfer, and the data path.
+    fn pages(&mut self, addr: u32, len: usize) -> Result<Vec<(std::ops::Range<usize>, usize, usize)>, DmaError> {
+        let mut pages = vec![];
+        let mut done = 0;
+        while done < len {
+            let uaddr = addr + done as u32;
+            let n = (0x200 - (uaddr as usize & 0x1FF)).min(len - done);
+            let reg = self.map.get(uaddr as usize >> 9).copied().unwrap_or(0);
+            let path = ((reg & MAP_DPDB) >> 21) as usize;
+            // Byte offset only applies to buffered transfers.
+            let offset = if path != 0 && reg & MAP_BO != 0 { 1 } else { 0 };
+            let local = ((reg & MAP_PFN) << 9 | (uaddr & 0x1FF)) as usize + offset;
+            if reg & MAP_MRV == 0 || local + n > self.ram.len() {
+                self.failed.get_or_insert(uaddr);
+                return Err(DmaError::NonExistent(uaddr));
+            }
+            if path != 0 {
+                self.dprs[path] = (self.dprs[path] & !0xFFFF) | (uaddr >> 2 & 0xFFFF);
+            }
+            pages.push((local..local + n, done, path));
+            done += n;
+        }
+        Ok(pages)
+    }
+}
+
+impl Dma for UbaDma<'_> {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), DmaError> {
+        for (local, at, _) in self.pages(addr, buf.len())? {
+            buf[at..at + local.len()].copy_from_slice(&self.ram[local]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), DmaError> {
+        for (local, at, path) in self.pages(addr, data.len())? {
+            let n = local.len();
+            if path == 0 {
+                self.ram[local].copy_from_slice(&data[at..at + n]);
+                continue;
+            }
+            let buffer = &mut self.buffers[path];
+            for (l, &b) in local.zip(&data[at..at + n]) {
+                if buffer.mask != 0 && buffer.quadword != l & !7 {
+                    buffer.purge(self.ram);
+                }
+                buffer.quadword = l & !7;
+                buffer.data[l & 7] = b;
+                buffer.mask |= 1 << (l & 7);
+                if buffer.mask == 0xFF {
+                    buffer.purge(self.ram);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The adapter, its registers at `nexus_base` and its UNIBUS window at `space_base`. It
+/// interrupts as nexus `tr`.
+pub struct Uba {
+    nexus_base: u32,
+    space_base: u32,
+    tr: u16,
+
+    cnfgr: u32,
+    uacr: u32,
+    uasr: u32,
+    fubar: u32,
+    brsvr: [u32; 4],
+    dprs: [u32; DATA_PATHS],
+    buffers: [Buffer; DATA_PATHS],
+    /// Data paths software has asked to purge, done when the adapter next gets at memory.
+    purges: u16,
+    map: Vec<u32>,
+
+    io_page: IoPage,
+    now_us: u64,
+}
+
+impl Uba {
+    pub fn new(nexus_base: u32, space_base: u32, tr: u16) -> Uba {
+        assert!(tr < 16);
+        Uba {
+            nexus_base,
+            space_base,
+            tr,
+            cnfgr: CNFGR_ADAPTER_CODE | CNFGR_UBIC,
+            uacr: 0,
+            uasr: 0,
+            fubar: 0,
+            brsvr: [0; 4],
+            dprs: [0; DATA_PATHS],
+            buffers: [Buffer::default(); DATA_PATHS],
+            purges: 0,
+            map: vec![0; MAP_ENTRIES],
+            io_page: IoPage::new(),
+            now_us: 0,
+        }
+    }
+
+    /// The adapter on a VAX-11/780 at nexus `tr`, the `n`th UNIBUS adapter.
+    pub fn vax780(tr: u16, n: u32) -> Uba {
+        Uba::new(NEXUS_BASE_780 + tr as u32 * NEXUS_BYTES, UNIBUS_SPACE_780 + n * UNIBUS_ADDRESS_SPACE, tr)
+    }
+
+    pub fn nexus_base(&self) -> u32 {
+        self.nexus_base
+    }
+
+    pub fn space_base(&self) -> u32 {
+        self.space_base
+    }
+
+    /// Puts a device on the bus. Panics if its registers overlap another's.
+    pub fn add_device(&mut self, device: Box<dyn IoPageDevice>) {
+        self.io_page.add_device(device);
+    }
+
+    /// The first device of type `T` on the bus.
+    pub fn find_device<T: 'static>(&mut self) -> Option<&mut T> {
+        self.io_page.find_device()
+    }
+
+    pub fn map_register(&self, n: usize) -> u32 {
+        self.map[n]
+    }
+
+    /// The SCB vector the adapter interrupts through at BR `level`: nexus vectors are a
+    /// longword for each TR, in a block of 64 bytes for each IPL from 0x14.
+    pub fn vector(&self, level: u8) -> u16 {
+        0x100 + (level - BR4) as u16 * 0x40 + self.tr * 4
+    }
+
+    /// Adapter initialization, also what power-up and a bus reset do.
+    fn init(&mut self) {
+        self.cnfgr = CNFGR_ADAPTER_CODE | CNFGR_UBIC;
+        self.uacr = 0;
+        self.uasr = 0;
+        self.fubar = 0;
+        self.dprs = [0; DATA_PATHS];
+        self.buffers = [Buffer::default(); DATA_PATHS];
+        self.purges = 0;
+        self.map.iter_mut().for_each(|r| *r = 0);
+        self.io_page.reset();
+    }
+
+    fn read_register(&mut self, offset: u32) -> u32 {
+        match offset {
+            CNFGR => self.cnfgr,
+            UACR => self.uacr,
+            UASR => self.uasr,
+            FUBAR => self.fubar,
+            o if (BRSVR..BRSVR + 16).contains(&o) => self.brsvr[(o - BRSVR) as usize / 4],
+            o if (BRRVR..BRRVR + 16).contains(&o) => {
+                // Reading the vector is the device's acknowledgement. Nobody there reads as 0.
+                let level = BR4 + ((o - BRRVR) / 4) as u8;
+                match self.io_page.request_at(level) {
+                    Some(r) => {
+                        self.io_page.acknowledge(r.vector);
+                        r.vector as u32
+                    }
+                    None => 0,
+                }
+            }
+            o if (DPR..DPR + DATA_PATHS as u32 * 4).contains(&o) => {
+                let n = (o - DPR) as usize / 4;
+                self.dprs[n] | if self.buffers[n].mask != 0 { DPR_BNE } else { 0 }
+            }
+            o if (MAPR..MAPR + MAP_ENTRIES as u32 * 4).contains(&o) => self.map[(o - MAPR) as usize / 4],
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, v: u32) {
+        match offset {
+            CNFGR => self.cnfgr &= !(v & CNFGR_UBIC),
+            UACR => {
+                if v & UACR_ADINIT != 0 {
+                    self.init();
+                } else {
+                    self.uacr = v & UACR_WRITABLE;
+                }
+            }
+            UASR => self.uasr &= !v,
+            o if (BRSVR..BRSVR + 16).contains(&o) => self.brsvr[(o - BRSVR) as usize / 4] = v & 0xFFFF,
+            o if (DPR..DPR + DATA_PATHS as u32 * 4).contains(&o) && v & DPR_BNE != 0 => {
+                self.purges |= 1 << ((o - DPR) / 4);
+            }
+            o if (MAPR..MAPR + MAP_ENTRIES as u32 * 4).contains(&o) => {
+                self.map[(o - MAPR) as usize / 4] = v & MAP_WRITABLE;
+            }
+            _ => {}
+        }
+    }
+
+    fn in_nexus(&self, addr: u32) -> bool {
+        (self.nexus_base..self.nexus_base + NEXUS_BYTES).contains(&addr)
+    }
+}
+
+impl VAXBusDevice for Uba {
+    fn name(&self) -> &str {
+        "UNIBUS adapter"
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        if self.in_nexus(addr) {
+            let offset = addr - self.nexus_base;
+            let reg = self.read_register(offset & !3);
+            return Ok(reg >> ((offset & 3) * 8) & (u32::MAX >> (32 - size * 8)));
+        }
+        // Only the I/O page has anything in it, there's no UNIBUS memory.
+        let uaddr = addr - self.space_base;
+        match uaddr.checked_sub(IO_PAGE_START) {
+            Some(offset) => self.io_page.read(offset, size).ok_or(BusError::NonExistent(addr)),
+            None => Err(BusError::NonExistent(addr)),
+        }
+    }
+
+    fn write(&mut self, addr: u32, size: u32, v: u32) -> Result<(), BusError> {
+        if self.in_nexus(addr) {
+            // Adapter registers only take longword writes.
+            if size == 4 && addr.is_multiple_of(4) {
+                self.write_register(addr - self.nexus_base, v);
+            }
+            return Ok(());
+        }
+        let uaddr = addr - self.space_base;
+        match uaddr.checked_sub(IO_PAGE_START) {
+            Some(offset) if self.io_page.write(offset, size, v) => Ok(()),
+            _ => Err(BusError::NonExistent(addr)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.init();
+    }
+
+    fn interrupt_request(&self) -> Option<Interrupt> {
+        if self.uacr & (UACR_IFS | UACR_BRIE) != UACR_IFS | UACR_BRIE {
+            return None;
+        }
+        let level = (BR4..=BR7).rev().find(|&l| self.io_page.request_at(l).is_some())?;
+        Some(Interrupt::new(0x10 + level, self.vector(level)))
+    }
+
+    fn set_time(&mut self, now_us: u64) {
+        self.now_us = now_us;
+    }
+
+    fn service(&mut self, ram: &mut [u8]) {
+        let purges = std::mem::take(&mut self.purges);
+        for (n, buffer) in self.buffers.iter_mut().enumerate() {
+            if purges & 1 << n != 0 {
+                buffer.purge(ram);
+            }
+        }
+        let mut dma = UbaDma { map: &self.map, dprs: &mut self.dprs, buffers: &mut self.buffers, ram, failed: None };
+        self.io_page.service(self.now_us, &mut dma);
+        if let Some(uaddr) = dma.failed {
+            self.uasr |= UASR_IVMR;
+            self.fubar = uaddr >> 2 & 0xFFFF;
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::VAXBus, interrupts::InterruptSource},
+        devices::iopage::tests::Copier,
+    };
+
+    #[test]
+    fn unibus_adapter() {
+        let mut bus = VAXBus::new(1 << 20);
+        let mut uba = Uba::vax780(3, 0);
+        uba.add_device(Box::new(Copier::default()));
+        let (nexus, space) = (uba.nexus_base(), uba.space_base());
+        let id = bus.add_device(Box::new(uba));
+        bus.map(id, nexus, NEXUS_BYTES);
+        bus.map(id, space, UNIBUS_ADDRESS_SPACE);
+        assert_eq!(nexus, 0x2000_6000);
+        assert_eq!(bus.read_u32(nexus + CNFGR), Ok(CNFGR_ADAPTER_CODE | CNFGR_UBIC));
+
+        // UNIBUS page 1 is local page 0x20 directly, page 2 local page 0x30 through data
+        // path 1, a byte in.
+        bus.write_u32(nexus + MAPR + 4, MAP_MRV | 0x20).unwrap();
+        bus.write_u32(nexus + MAPR + 8, MAP_MRV | MAP_BO | 1 << 21 | 0x30).unwrap();
+        bus.write_u32(0x20 << 9 | 0x1FA, 0x1234_5678).unwrap();
+
+        let csr = space + IO_PAGE_START + 0o12150;
+        bus.write_u16(csr + 2, 0x03FA).unwrap();
+        bus.write_u16(csr, 0x41).unwrap();
+        assert_eq!(bus.read_u16(0x20 << 9 | 0x1FE), Ok(0x5678));
+
+        // The bytes through the data path wait in its buffer until it's purged.
+        assert_eq!(bus.read_u16(0x30 << 9 | 1), Ok(0));
+        assert_eq!(bus.read_u32(nexus + DPR + 4), Ok(DPR_BNE | 0x400 >> 2));
+        bus.write_u32(nexus + DPR + 4, DPR_BNE).unwrap();
+        assert_eq!(bus.read_u16(0x30 << 9 | 1), Ok(0x1234));
+        assert_eq!(bus.read_u32(nexus + DPR + 4), Ok(0x400 >> 2));
+
+        // The device's request only gets through once the adapter passes interrupts on, and
+        // reading BRRVR is what acknowledges it.
+        assert_eq!(bus.pending_interrupt(), None);
+        bus.write_u32(nexus + UACR, UACR_IFS | UACR_BRIE).unwrap();
+        assert_eq!(bus.pending_interrupt(), Some(Interrupt::new(0x15, 0x140 + 3 * 4)));
+        bus.acknowledge(0x140 + 3 * 4);
+        assert_eq!(bus.read_u32(nexus + BRRVR), Ok(0));
+        assert_eq!(bus.read_u32(nexus + BRRVR + 4), Ok(0o120));
+        assert_eq!(bus.pending_interrupt(), None);
+
+        // An invalid map register is reported by the adapter.
+        bus.write_u16(csr + 2, 0x0600).unwrap();
+        bus.write_u16(csr, 0x01).unwrap();
+        assert_eq!(bus.read_u32(nexus + UASR), Ok(UASR_IVMR));
+        assert_eq!(bus.read_u32(nexus + FUBAR), Ok(0x600 >> 2));
+        bus.write_u32(nexus + UASR, UASR_IVMR).unwrap();
+        assert_eq!(bus.read_u32(nexus + UASR), Ok(0));
+
+        assert_eq!(bus.read_u16(space), Err(BusError::NonExistent(space)));
+
+        // Adapter initialization clears the map.
+        bus.write_u32(nexus + UACR, UACR_ADINIT).unwrap();
+        assert_eq!(bus.read_u32(nexus + MAPR + 4), Ok(0));
+        assert_eq!(bus.read_u32(nexus + UACR), Ok(0));
+    }
+}
@@ -0,0 +1,560 @@
+//! The port of the Q-bus and UNIBUS storage controllers: MSCP disk controllers such as the
+//! RQDX3 and UDA50, and TMSCP tape controllers such as the TQK50.
+//!
+//! The host talks to the port through two registers: IP and SA. SA carries the four step
+//! initialization handshake that tells the port where the communications area is, after which
+//! commands and responses travel through rings of descriptors in host memory. Reading IP has
+//! the port poll the command ring, writing it starts initialization again.
+//!
+//! The port deals with the controller's own commands, and hands the rest to the server behind
+//! it. Commands complete as soon as the port sees them, so nothing is ever outstanding.
+
+use std::any::Any;
+
+use crate::ervax::devices::{
+    iopage::{BusRequest, Dma, IoPageDevice, BR4, BR5},
+    mscp::{
+        OP_ABORT, OP_END, OP_GET_COMMAND_STATUS, OP_SET_CONTROLLER_CHARACTERISTICS,
+        ST_INVALID_COMMAND, ST_SUCCESS,
+    },
+};
+
+pub const REG_IP: u32 = 0;
+pub const REG_SA: u32 = 2;
+
+/// SA, a fatal error, the low bits saying what.
+pub const SA_ER: u16 = 0x8000;
+/// SA, which initialization step the port is at.
+pub const SA_S1: u16 = 0x0800;
+pub const SA_S2: u16 = 0x1000;
+pub const SA_S3: u16 = 0x2000;
+pub const SA_S4: u16 = 0x4000;
+/// SA step 1, the port does 22-bit addressing.
+pub const SA_S1_QB: u16 = 0x0200;
+/// SA step 1, the port has diagnostics the host can run. None are implemented, but the bit is
+/// what every port reports.
+pub const SA_S1_DI: u16 = 0x0100;
+
+/// Host's step 1 word: always set.
+pub const S1_VALID: u16 = 0x8000;
+/// Host's step 1 word: interrupts are enabled.
+pub const S1_IE: u16 = 0x0080;
+/// Host's step 1 word: the interrupt vector divided by 4.
+pub const S1_VECTOR: u16 = 0x007F;
+/// Host's step 3 word: do the purge and poll test.
+pub const S3_PP: u16 = 0x8000;
+/// Host's step 4 word: go.
+pub const S4_GO: u16 = 0x0001;
+
+/// Fatal error codes in SA.
+pub const ERR_PACKET_READ: u16 = 1;
+pub const ERR_PACKET_WRITE: u16 = 2;
+pub const ERR_RING_READ: u16 = 6;
+pub const ERR_RING_WRITE: u16 = 7;
+
+/// Ring descriptor, the port owns the entry.
+pub const DESC_OWN: u32 = 0x8000_0000;
+/// Ring descriptor, interrupt when the entry changes hands.
+pub const DESC_FLAG: u32 = 0x4000_0000;
+const DESC_ADDRESS: u32 = 0x3FFF_FFFF;
+
+/// Bytes of a command packet looked at. The longest, READ and WRITE, are 32.
+const COMMAND_BYTES: usize = 48;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Controller {
+    /// The Q-bus disk controller of the MicroVAX II.
+    Rqdx3,
+    /// The UNIBUS disk controller of the larger machines.
+    Uda50,
+    /// The Q-bus TK50 tape controller.
+    Tqk50,
+    /// The UNIBUS TK50 tape controller.
+    Tuk50,
+}
+
+impl Controller {
+    pub fn name(self) -> &'static str {
+        match self {
+            Controller::Rqdx3 => "RQDX3",
+            Controller::Uda50 => "UDA50",
+            Controller::Tqk50 => "TQK50",
+            Controller::Tuk50 => "TUK50",
+        }
+    }
+
+    /// MSCP controller model number.
+    pub fn model(self) -> u8 {
+        match self {
+            Controller::Rqdx3 => 19,
+            Controller::Uda50 => 6,
+            Controller::Tqk50 => 3,
+            Controller::Tuk50 => 9,
+        }
+    }
+
+    pub fn level(self) -> u8 {
+        if self.is_qbus() { BR4 } else { BR5 }
+    }
+
+    fn is_qbus(self) -> bool {
+        matches!(self, Controller::Rqdx3 | Controller::Tqk50)
+    }
+
+    fn step1(self) -> u16 {
+        if self.is_qbus() { SA_S1 | SA_S1_QB | SA_S1_DI } else { SA_S1 | SA_S1_DI }
+    }
+}
+
+/// What's behind a port: the units, and what the commands for them mean.
+pub trait Server {
+    /// Bytes of the response to a command with `opcode`.
+    fn response_len(&self, opcode: u8) -> usize;
+
+    /// Carries out a command, filling in the rest of the response the port made for it, which
+    /// has the command reference, unit, and endcode already. Returns the status.
+    fn execute(&mut self, cmd: &[u8], rsp: &mut [u8], dma: &mut dyn Dma) -> u16;
+
+    /// The port's starting over. Units stay attached, but go back to being available.
+    fn initialize(&mut self);
+}
+
+pub fn get_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+pub fn get_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+pub fn put_u16(b: &mut [u8], at: usize, v: u16) {
+    b[at..at + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u32(b: &mut [u8], at: usize, v: u32) {
+    b[at..at + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Step1,
+    Step2,
+    Step3,
+    /// Purge and poll test: waiting for the host to write SA.
+    PurgeWrite,
+    /// Purge and poll test: waiting for the host to read IP.
+    PollRead,
+    Step4,
+    Up,
+    Failed,
+}
+
+pub struct Port<S> {
+    controller: Controller,
+    base: u32,
+    server: S,
+
+    state: State,
+    sa: u16,
+    /// The host's step 1 word: ring sizes, interrupt enable, and vector.
+    s1: u16,
+    /// Bus address of the response ring. The command ring follows it, and the interrupt
+    /// indicators come just before.
+    ring_base: u32,
+    cmd_ring: u32,
+    rsp_ring: u32,
+    cmd_next: u32,
+    rsp_next: u32,
+    /// Credits not yet handed back to the host.
+    credits: u8,
+    /// A response waiting for a free slot in the response ring.
+    held: Option<Vec<u8>>,
+    /// The communications area needs clearing before the port's up.
+    clear_area: bool,
+    int_pending: bool,
+}
+
+impl<S: Server> Port<S> {
+    /// A port with its registers `base` bytes into the I/O page, in front of `server`.
+    pub fn with_server(controller: Controller, base: u32, server: S) -> Port<S> {
+        let mut port = Port {
+            controller,
+            base,
+            server,
+            state: State::Step1,
+            sa: 0,
+            s1: 0,
+            ring_base: 0,
+            cmd_ring: 0,
+            rsp_ring: 0,
+            cmd_next: 0,
+            rsp_next: 0,
+            credits: 0,
+            held: None,
+            clear_area: false,
+            int_pending: false,
+        };
+        port.initialize();
+        port
+    }
+
+    #[inline]
+    pub fn controller(&self) -> Controller {
+        self.controller
+    }
+
+    #[inline]
+    pub fn server(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    /// Back to step 1.
+    fn initialize(&mut self) {
+        self.state = State::Step1;
+        self.sa = self.controller.step1();
+        self.s1 = 0;
+        self.held = None;
+        self.clear_area = false;
+        self.int_pending = false;
+        self.server.initialize();
+    }
+
+    fn vector(&self) -> u16 {
+        (self.s1 & S1_VECTOR) * 4
+    }
+
+    /// Interrupts the host, if it asked for interrupts.
+    fn interrupt(&mut self) {
+        if self.s1 & S1_IE != 0 && self.vector() != 0 {
+            self.int_pending = true;
+        }
+    }
+
+    fn fail(&mut self, code: u16) {
+        self.state = State::Failed;
+        self.sa = SA_ER | code;
+        self.interrupt();
+    }
+
+    fn step4(&mut self) {
+        self.state = State::Step4;
+        self.sa = SA_S4 | (self.controller.model() as u16) << 4 | 1;
+        self.interrupt();
+    }
+
+    fn write_sa(&mut self, v: u16) {
+        match self.state {
+            State::Step1 if v & S1_VALID != 0 => {
+                self.s1 = v;
+                self.rsp_ring = 1 << ((v >> 8) & 7);
+                self.cmd_ring = 1 << ((v >> 11) & 7);
+                self.state = State::Step2;
+                self.sa = SA_S2 | (v >> 8) & 0xFF;
+                self.interrupt();
+            }
+            State::Step2 => {
+                self.ring_base = (v & 0xFFFE) as u32;
+                self.state = State::Step3;
+                self.sa = SA_S3 | self.s1 & 0xFF;
+                self.interrupt();
+            }
+            State::Step3 => {
+                self.ring_base |= ((v & 0x7FFF) as u32) << 16;
+                if v & S3_PP != 0 {
+                    self.state = State::PurgeWrite;
+                    self.sa = 0;
+                } else {
+                    self.step4();
+                }
+            }
+            State::PurgeWrite => self.state = State::PollRead,
+            State::Step4 if v & S4_GO != 0 => {
+                self.state = State::Up;
+                self.sa = 0;
+                self.cmd_next = 0;
+                self.rsp_next = 0;
+                self.credits = self.cmd_ring.min(15) as u8;
+                self.clear_area = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn cmd_desc(&self, n: u32) -> u32 {
+        self.ring_base + 4 * (self.rsp_ring + n)
+    }
+
+    fn rsp_desc(&self, n: u32) -> u32 {
+        self.ring_base + 4 * n
+    }
+
+    /// Takes the commands the host has given the port, as long as there's somewhere to put the
+    /// responses.
+    fn poll(&mut self, dma: &mut dyn Dma) -> Result<(), u16> {
+        loop {
+            if let Some(response) = self.held.take() {
+                if !self.respond(&response, dma)? {
+                    self.held = Some(response);
+                    return Ok(());
+                }
+            }
+
+            let at = self.cmd_desc(self.cmd_next);
+            let desc = dma.read_u32(at).map_err(|_| ERR_RING_READ)?;
+            if desc & DESC_OWN == 0 {
+                return Ok(());
+            }
+            let addr = desc & DESC_ADDRESS;
+            let len = dma.read_u16(addr.wrapping_sub(4)).map_err(|_| ERR_PACKET_READ)? as usize;
+            let mut packet = [0; COMMAND_BYTES];
+            let n = len.min(COMMAND_BYTES);
+            dma.read(addr, &mut packet[..n]).map_err(|_| ERR_PACKET_READ)?;
+
+            dma.write_u32(at, desc & !DESC_OWN).map_err(|_| ERR_RING_WRITE)?;
+            if desc & DESC_FLAG != 0 {
+                dma.write_u16(self.ring_base.wrapping_sub(4), 1).map_err(|_| ERR_RING_WRITE)?;
+                self.interrupt();
+            }
+            self.cmd_next = (self.cmd_next + 1) % self.cmd_ring;
+            self.credits = (self.credits + 1).min(15);
+
+            self.held = Some(self.execute(&packet, dma));
+        }
+    }
+
+    /// Puts a response in the next response slot, false if the host hasn't given the port one.
+    fn respond(&mut self, response: &[u8], dma: &mut dyn Dma) -> Result<bool, u16> {
+        let at = self.rsp_desc(self.rsp_next);
+        let desc = dma.read_u32(at).map_err(|_| ERR_RING_READ)?;
+        if desc & DESC_OWN == 0 {
+            return Ok(false);
+        }
+        let addr = desc & DESC_ADDRESS;
+        let room = dma.read_u16(addr.wrapping_sub(4)).map_err(|_| ERR_PACKET_WRITE)? as usize;
+        let n = response.len().min(room);
+
+        // The envelope: length, then message type 0 with the credits, then connection 0.
+        let mut envelope = [0; 4];
+        put_u16(&mut envelope, 0, n as u16);
+        envelope[2] = self.credits;
+        dma.write(addr - 4, &envelope).map_err(|_| ERR_PACKET_WRITE)?;
+        dma.write(addr, &response[..n]).map_err(|_| ERR_PACKET_WRITE)?;
+        self.credits = 0;
+
+        dma.write_u32(at, desc & !DESC_OWN).map_err(|_| ERR_RING_WRITE)?;
+        if desc & DESC_FLAG != 0 {
+            dma.write_u16(self.ring_base.wrapping_sub(2), 1).map_err(|_| ERR_RING_WRITE)?;
+            self.interrupt();
+        }
+        self.rsp_next = (self.rsp_next + 1) % self.rsp_ring;
+        Ok(true)
+    }
+
+    fn execute(&mut self, cmd: &[u8], dma: &mut dyn Dma) -> Vec<u8> {
+        let opcode = cmd[8];
+        let len = match opcode {
+            OP_ABORT => 12,
+            OP_GET_COMMAND_STATUS => 20,
+            OP_SET_CONTROLLER_CHARACTERISTICS => 32,
+            _ => self.server.response_len(opcode).max(12),
+        };
+        let mut rsp = vec![0; len];
+        rsp[..6].copy_from_slice(&cmd[..6]);
+        rsp[8] = opcode | OP_END;
+
+        let status = match opcode {
+            OP_ABORT => ST_SUCCESS,
+            OP_GET_COMMAND_STATUS => {
+                // Nothing's ever outstanding, so there's no status to give.
+                rsp[12..16].copy_from_slice(&cmd[12..16]);
+                ST_SUCCESS
+            }
+            OP_SET_CONTROLLER_CHARACTERISTICS => {
+                if get_u16(cmd, 12) != 0 {
+                    ST_INVALID_COMMAND | 12 << 8
+                } else {
+                    put_u16(&mut rsp, 16, 60);
+                    put_u32(&mut rsp, 20, self.base);
+                    rsp[26] = self.controller.model();
+                    rsp[27] = 1;
+                    ST_SUCCESS
+                }
+            }
+            _ => self.server.execute(cmd, &mut rsp, dma),
+        };
+        put_u16(&mut rsp, 10, status);
+        rsp
+    }
+}
+
+impl<S: Server + 'static> IoPageDevice for Port<S> {
+    fn name(&self) -> &str {
+        self.controller.name()
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&mut self, offset: u32) -> u16 {
+        match offset {
+            REG_IP => {
+                if self.state == State::PollRead {
+                    self.step4();
+                }
+                // Polling happens whenever the port's up anyway.
+                0
+            }
+            _ => self.sa,
+        }
+    }
+
+    fn write(&mut self, offset: u32, v: u16, mask: u16) {
+        // Both registers only make sense written whole.
+        if mask != 0xFFFF {
+            return;
+        }
+        match offset {
+            REG_IP => self.initialize(),
+            _ => self.write_sa(v),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.initialize();
+    }
+
+    fn interrupt_request(&self) -> Option<BusRequest> {
+        if self.int_pending { Some(BusRequest::new(self.controller.level(), self.vector())) } else { None }
+    }
+
+    fn acknowledge(&mut self, vector: u16) {
+        if self.int_pending && vector == self.vector() {
+            self.int_pending = false;
+        }
+    }
+
+    fn service(&mut self, _now_us: u64, dma: &mut dyn Dma) {
+        if self.clear_area {
+            self.clear_area = false;
+            let len = 8 + 4 * (self.rsp_ring + self.cmd_ring) as usize;
+            if dma.write(self.ring_base.wrapping_sub(8), &vec![0; len]).is_err() {
+                self.fail(ERR_RING_WRITE);
+            }
+        }
+        if self.state == State::Up {
+            if let Err(code) = self.poll(dma) {
+                self.fail(code);
+            }
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    //! A Q-bus machine to try ports out in, and a host side of the port for it.
+
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::VAXBus, interrupts::{Interrupt, InterruptSource}},
+        devices::{
+            iopage::IO_PAGE_SIZE,
+            qbus::{Qbus, MAP_VALID},
+        },
+    };
+
+    pub const IO_BASE: u32 = 0x2000_0000;
+    const MAP_BASE: u32 = 0x2008_8000;
+
+    /// Interrupt indicators at 0x1000, then a response ring of 2 and a command ring of 2.
+    const RINGS: u32 = 0x1008;
+    const RSP: [u32; 2] = [0x2004, 0x2104];
+    const CMD: [u32; 2] = [0x2204, 0x2304];
+    /// Somewhere for data, clear of the rings.
+    pub const DATA: u32 = 0x3000;
+
+    /// A Q-bus with the first 256 KiB of memory mapped, and `device` on it.
+    pub fn machine(device: Box<dyn IoPageDevice>) -> VAXBus {
+        let mut bus = VAXBus::new(1 << 20);
+        let mut qbus = Qbus::new(IO_BASE, MAP_BASE, 0x200);
+        for n in 0..512 {
+            qbus.map_mut().set_register(n, MAP_VALID | n as u32);
+        }
+        qbus.add_device(device);
+        let id = bus.add_device(Box::new(qbus));
+        bus.map(id, IO_BASE, IO_PAGE_SIZE);
+        bus.map(id, MAP_BASE, 0x8000);
+        bus
+    }
+
+    /// Takes the port at `base` through initialization, with interrupts at vector 0o154.
+    pub fn init(bus: &mut VAXBus, base: u32, controller: Controller) {
+        let sa = IO_BASE + base + REG_SA;
+        assert_eq!(bus.read_u16(sa), Ok(controller.step1()));
+        // Rings of 2, interrupts at vector 0o154.
+        bus.write_u16(sa, S1_VALID | 1 << 11 | 1 << 8 | S1_IE | (0o154 / 4)).unwrap();
+        assert_eq!(bus.read_u16(sa), Ok(SA_S2 | 0x89));
+        assert_eq!(bus.pending_interrupt(), Some(Interrupt::new(0x14, 0x200 + 0o154)));
+        bus.acknowledge(0x200 + 0o154);
+        bus.write_u16(sa, RINGS as u16).unwrap();
+        assert_eq!(bus.read_u16(sa), Ok(SA_S3 | S1_IE | (0o154 / 4)));
+        bus.write_u16(sa, (RINGS >> 16) as u16).unwrap();
+        assert_eq!(bus.read_u16(sa), Ok(SA_S4 | (controller.model() as u16) << 4 | 1));
+        bus.write_u32(0x1000, 0xFFFF_FFFF).unwrap();
+        bus.write_u16(sa, S4_GO).unwrap();
+        assert_eq!(bus.read_u16(sa), Ok(0));
+        assert_eq!(bus.read_u32(0x1000), Ok(0));
+        bus.acknowledge(0x200 + 0o154);
+
+        for (i, &addr) in RSP.iter().enumerate() {
+            bus.write_u16(addr - 4, 0x100).unwrap();
+            bus.write_u32(RINGS + 4 * i as u32, DESC_OWN | DESC_FLAG | addr).unwrap();
+        }
+    }
+
+    /// Sends a command through the next slot in the command ring of the port at `base`, and
+    /// returns the response.
+    pub fn command(bus: &mut VAXBus, base: u32, n: usize, packet: &[u8]) -> Vec<u8> {
+        let (cmd, rsp) = (CMD[n % 2], RSP[n % 2]);
+        bus.write_u16(cmd - 4, packet.len() as u16).unwrap();
+        bus.write_bytes(cmd, packet).unwrap();
+        bus.write_u32(RINGS + 8 + 4 * (n % 2) as u32, DESC_OWN | cmd).unwrap();
+        bus.read_u16(IO_BASE + base + REG_IP).unwrap();
+
+        assert_eq!(bus.read_u32(RINGS + 8 + 4 * (n % 2) as u32), Ok(cmd));
+        assert_eq!(bus.read_u32(RINGS + 4 * (n % 2) as u32), Ok(DESC_FLAG | rsp));
+        assert_eq!(bus.read_u16(0x1006), Ok(1));
+        assert_eq!(bus.pending_interrupt(), Some(Interrupt::new(0x14, 0x200 + 0o154)));
+        bus.acknowledge(0x200 + 0o154);
+        bus.write_u16(0x1006, 0).unwrap();
+
+        let len = bus.read_u16(rsp - 4).unwrap() as usize;
+        let response = (0..len).map(|i| bus.read_u8(rsp + i as u32).unwrap()).collect();
+        // Give the slot back for the next time round.
+        bus.write_u16(rsp - 4, 0x100).unwrap();
+        bus.write_u32(RINGS + 4 * (n % 2) as u32, DESC_OWN | DESC_FLAG | rsp).unwrap();
+        response
+    }
+
+    /// A command packet with reference 0x1234, the byte count and buffer where READ and WRITE
+    /// have them, and `param` at 28, where disks want their LBN.
+    pub fn packet(unit: u16, opcode: u8, modifiers: u16, count: u32, buffer: u32, param: u32) -> Vec<u8> {
+        let mut p = vec![0; 32];
+        put_u32(&mut p, 0, 0x1234);
+        put_u16(&mut p, 4, unit);
+        p[8] = opcode;
+        put_u16(&mut p, 10, modifiers);
+        put_u32(&mut p, 12, count);
+        put_u32(&mut p, 16, buffer);
+        put_u32(&mut p, 28, param);
+        p
+    }
+}
@@ -0,0 +1,266 @@
+//! The MC146818 "watch chip", a battery backed time-of-year clock with 50 bytes of NVRAM.
+//! Boards without a TODR register keep the date in it, and the console keeps settings in the NVRAM.
+
+use std::any::Any;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ervax::cpu::bus::{BusError, VAXBusDevice};
+
+pub const REG_SECONDS: usize = 0;
+pub const REG_MINUTES: usize = 2;
+pub const REG_HOURS: usize = 4;
+pub const REG_DAY_OF_WEEK: usize = 6;
+pub const REG_DAY: usize = 7;
+pub const REG_MONTH: usize = 8;
+pub const REG_YEAR: usize = 9;
+pub const REG_A: usize = 10;
+pub const REG_B: usize = 11;
+pub const REG_C: usize = 12;
+pub const REG_D: usize = 13;
+/// First byte of NVRAM.
+pub const REG_NVRAM: usize = 14;
+pub const NVRAM_BYTES: usize = 64 - REG_NVRAM;
+
+/// Register A, update in progress. Never set, updates are instant here.
+pub const A_UIP: u8 = 0x80;
+/// Register B, time frozen while it's set.
+pub const B_SET: u8 = 0x80;
+/// Register B, time registers are binary rather than BCD.
+pub const B_DM: u8 = 0x04;
+/// Register B, 24 hour rather than 12 hour.
+pub const B_24H: u8 = 0x02;
+/// Register D, the battery is good, so time and NVRAM are valid.
+pub const D_VRT: u8 = 0x80;
+
+/// Time the chip starts at in virtual time: 2000-01-01 00:00:00 UTC.
+pub const VIRTUAL_EPOCH: i64 = 946_684_800;
+
+pub struct WatchChip {
+    base: u32,
+    /// Bytes of address space per register.
+    stride: u32,
+    regs: [u8; 64],
+
+    /// Seconds since the Unix epoch at `set_us`.
+    secs: i64,
+    set_us: u64,
+    now_us: u64,
+}
+
+impl WatchChip {
+    /// A chip at `base` reading `secs` (seconds since the Unix epoch) at time 0. Registers are
+    /// `stride` bytes apart, each in the low byte.
+    pub fn new(base: u32, stride: u32, secs: i64) -> WatchChip {
+        let mut regs = [0; 64];
+        regs[REG_A] = 0x20;
+        regs[REG_B] = B_24H;
+        regs[REG_D] = D_VRT;
+        WatchChip { base, stride, regs, secs, set_us: 0, now_us: 0 }
+    }
+
+    /// A chip set from the host's clock.
+    pub fn from_host(base: u32, stride: u32) -> WatchChip {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        WatchChip::new(base, stride, now.as_secs() as i64)
+    }
+
+    /// Bytes of address space the chip takes up.
+    pub fn size(&self) -> u32 {
+        64 * self.stride
+    }
+
+    pub fn nvram(&self) -> &[u8] {
+        &self.regs[REG_NVRAM..]
+    }
+
+    /// Loads saved NVRAM contents, as many bytes as there are.
+    pub fn load_nvram(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(NVRAM_BYTES);
+        self.regs[REG_NVRAM..REG_NVRAM + n].copy_from_slice(&bytes[..n]);
+    }
+
+    /// The time the chip reads, in seconds since the Unix epoch.
+    pub fn time(&self) -> i64 {
+        if self.regs[REG_B] & B_SET != 0 {
+            return self.secs;
+        }
+        self.secs + (self.now_us.saturating_sub(self.set_us) / 1_000_000) as i64
+    }
+
+    fn set_secs(&mut self, secs: i64) {
+        self.secs = secs;
+        self.set_us = self.now_us;
+    }
+
+    fn encode(&self, v: u8) -> u8 {
+        if self.regs[REG_B] & B_DM != 0 { v } else { ((v / 10) << 4) | (v % 10) }
+    }
+
+    fn decode(&self, v: u8) -> u8 {
+        if self.regs[REG_B] & B_DM != 0 { v } else { (v >> 4) * 10 + (v & 0xF) }
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        let secs = self.time();
+        let days = secs.div_euclid(86400);
+        let of_day = secs.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+
+        match reg {
+            REG_SECONDS => self.encode((of_day % 60) as u8),
+            REG_MINUTES => self.encode((of_day / 60 % 60) as u8),
+            REG_HOURS => {
+                let hour = (of_day / 3600) as u8;
+                if self.regs[REG_B] & B_24H != 0 {
+                    self.encode(hour)
+                } else {
+                    let pm = if hour >= 12 { 0x80 } else { 0 };
+                    self.encode((hour + 11) % 12 + 1) | pm
+                }
+            }
+            // 1 is Sunday, 1970-01-01 was a Thursday.
+            REG_DAY_OF_WEEK => self.encode(((days + 4).rem_euclid(7) + 1) as u8),
+            REG_DAY => self.encode(day as u8),
+            REG_MONTH => self.encode(month as u8),
+            REG_YEAR => self.encode(year.rem_euclid(100) as u8),
+            REG_A => self.regs[REG_A] & !A_UIP,
+            REG_C => 0,
+            REG_D => D_VRT,
+            r => self.regs[r],
+        }
+    }
+
+    fn write_reg(&mut self, reg: usize, v: u8) {
+        let secs = self.time();
+        let days = secs.div_euclid(86400);
+        let of_day = secs.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        let (hour, minute, second) = (of_day / 3600, of_day / 60 % 60, of_day % 60);
+        let at = |y: i64, mo: i64, d: i64, h: i64, mi: i64, s: i64| days_from_civil(y, mo, d) * 86400 + h * 3600 + mi * 60 + s;
+
+        match reg {
+            REG_SECONDS => self.set_secs(at(year, month, day, hour, minute, self.decode(v) as i64)),
+            REG_MINUTES => self.set_secs(at(year, month, day, hour, self.decode(v) as i64, second)),
+            REG_HOURS => {
+                let h = if self.regs[REG_B] & B_24H != 0 {
+                    self.decode(v) as i64
+                } else {
+                    self.decode(v & 0x7F) as i64 % 12 + if v & 0x80 != 0 { 12 } else { 0 }
+                };
+                self.set_secs(at(year, month, day, h, minute, second));
+            }
+            // Derived from the date.
+            REG_DAY_OF_WEEK => {}
+            REG_DAY => self.set_secs(at(year, month, self.decode(v) as i64, hour, minute, second)),
+            REG_MONTH => self.set_secs(at(year, self.decode(v) as i64, day, hour, minute, second)),
+            REG_YEAR => {
+                let yy = self.decode(v) as i64;
+                let year = if yy < 70 { 2000 + yy } else { 1900 + yy };
+                self.set_secs(at(year, month, day, hour, minute, second));
+            }
+            REG_B => {
+                // Freezing keeps the time as of now, unfreezing starts it running from there.
+                self.secs = secs;
+                self.set_us = self.now_us;
+                self.regs[REG_B] = v;
+            }
+            REG_C | REG_D => {}
+            r => self.regs[r] = v,
+        }
+    }
+}
+
+impl VAXBusDevice for WatchChip {
+    fn name(&self) -> &str {
+        "watch chip"
+    }
+
+    fn read(&mut self, addr: u32, _size: u32) -> Result<u32, BusError> {
+        let offset = addr - self.base;
+        if !offset.is_multiple_of(self.stride) {
+            return Ok(0);
+        }
+        Ok(self.read_reg((offset / self.stride) as usize) as u32)
+    }
+
+    fn write(&mut self, addr: u32, _size: u32, v: u32) -> Result<(), BusError> {
+        let offset = addr - self.base;
+        if offset.is_multiple_of(self.stride) {
+            self.write_reg((offset / self.stride) as usize, v as u8);
+        }
+        Ok(())
+    }
+
+    fn set_time(&mut self, now_us: u64) {
+        self.now_us = now_us;
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The date `days` after 1970-01-01, as year, month, and day.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg(chip: &mut WatchChip, r: u32) -> u32 {
+        chip.read(r * 2, 1).unwrap()
+    }
+
+    #[test]
+    fn watch_chip_time() {
+        // 2001-03-01 23:59:58, a Thursday.
+        let mut chip = WatchChip::new(0, 2, 983_491_198);
+        chip.set_time(3_000_000);
+        assert_eq!(reg(&mut chip, REG_SECONDS as u32), 0x01);
+        assert_eq!(reg(&mut chip, REG_MINUTES as u32), 0x00);
+        assert_eq!(reg(&mut chip, REG_HOURS as u32), 0x00);
+        assert_eq!(reg(&mut chip, REG_DAY as u32), 0x02);
+        assert_eq!(reg(&mut chip, REG_MONTH as u32), 0x03);
+        assert_eq!(reg(&mut chip, REG_YEAR as u32), 0x01);
+        assert_eq!(reg(&mut chip, REG_DAY_OF_WEEK as u32), 6);
+        assert_eq!(reg(&mut chip, REG_D as u32), D_VRT as u32);
+
+        // Setting the time in binary, frozen while SET is on.
+        chip.write(REG_B as u32 * 2, 1, (B_SET | B_DM | B_24H) as u32).unwrap();
+        chip.write(REG_YEAR as u32 * 2, 1, 99).unwrap();
+        chip.write(REG_MONTH as u32 * 2, 1, 12).unwrap();
+        chip.write(REG_DAY as u32 * 2, 1, 31).unwrap();
+        chip.write(REG_HOURS as u32 * 2, 1, 23).unwrap();
+        chip.set_time(10_000_000);
+        assert_eq!(reg(&mut chip, REG_SECONDS as u32), 1);
+        chip.write(REG_B as u32 * 2, 1, (B_DM | B_24H) as u32).unwrap();
+        chip.set_time(12_000_000);
+        assert_eq!(chip.time(), days_from_civil(1999, 12, 31) * 86400 + 23 * 3600 + 3);
+
+        chip.write((REG_NVRAM as u32 + 1) * 2, 1, 0x5A).unwrap();
+        assert_eq!(chip.nvram()[1], 0x5A);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+    }
+}
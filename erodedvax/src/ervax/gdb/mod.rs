@@ -0,0 +1,504 @@
+//! A stub for GDB's remote serial protocol, so `target remote` can debug what a machine runs.
+//!
+//! The stub serves one connection, on a localhost TCP port or a Unix socket, and the CPU only
+//! runs when the debugger says to. Registers are GDB's VAX numbering: R0 through PC, then the
+//! PSL as register 16. Memory is read and written a byte at a time at virtual addresses,
+//! through the page tables while memory management is on, whatever the pages' protection and
+//! without tripping watchpoints. Breakpoints are kept by the stub and matched against PC before each instruction,
+//! so nothing in memory changes; watchpoints are the CPU's own.
+
+pub mod packet;
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+use crate::ervax::cpu::{
+    execution::{ExecutionContext, WatchKind, Watchpoint},
+    instrs::OperandWidth,
+    RegID,
+};
+use self::packet::{encode, hex, unhex, Decoder, Received};
+
+/// Registers in a `g` packet: R0 through PC, and the PSL.
+pub const REGISTERS: usize = 17;
+const PSL: usize = 16;
+
+/// Instructions run between looks for an interrupt from the debugger.
+const POLL_INTERVAL: u32 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// A connection to a debugger.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Where the stub waits for a debugger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A port on the loopback interface.
+    Tcp(u16),
+    /// A Unix socket at this path.
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// A port number, or else a socket path.
+    pub fn parse(s: &str) -> Endpoint {
+        match s.parse() {
+            Ok(port) => Endpoint::Tcp(port),
+            Err(_) => Endpoint::Unix(PathBuf::from(s)),
+        }
+    }
+
+    /// Waits for one debugger to connect.
+    pub fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Endpoint::Tcp(port) => {
+                let (stream, _) = TcpListener::bind(("127.0.0.1", *port))?.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+                // A socket left over from an earlier run would stop the bind; anything else stays.
+                if std::fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+                    std::fs::remove_file(path)?;
+                }
+                let (stream, _) = UnixListener::bind(path)?.accept()?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets need a Unix host")),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(port) => write!(f, "localhost port {}", port),
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// How a debugging session ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The debugger let go, and the machine may carry on.
+    Detached,
+    /// The debugger asked for the machine to be stopped for good.
+    Killed,
+    /// The connection closed.
+    Disconnected,
+}
+
+pub struct GdbStub<C> {
+    conn: C,
+    decoder: Decoder,
+    input: VecDeque<u8>,
+    /// Whether packets are acknowledged, until the debugger turns that off.
+    acks: bool,
+    last_sent: Vec<u8>,
+    breakpoints: BTreeSet<u32>,
+    /// The reply to `?`, from the last time the CPU stopped.
+    stop: String,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(conn: C) -> GdbStub<C> {
+        GdbStub {
+            conn,
+            decoder: Decoder::new(),
+            input: VecDeque::new(),
+            acks: true,
+            last_sent: vec![],
+            breakpoints: BTreeSet::new(),
+            stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// Serves the debugger until it detaches or kills the machine, or goes away.
+    pub fn serve(&mut self, exec: &mut ExecutionContext) -> io::Result<Outcome> {
+        loop {
+            let packet = match self.receive()? {
+                Some(Received::Packet(p)) => p,
+                Some(Received::BadChecksum) => {
+                    self.conn.write_all(b"-")?;
+                    continue;
+                }
+                Some(Received::Nak) => {
+                    self.conn.write_all(&self.last_sent)?;
+                    continue;
+                }
+                Some(_) => continue,
+                None => return Ok(Outcome::Disconnected),
+            };
+            if self.acks {
+                self.conn.write_all(b"+")?;
+            }
+            if let Some(outcome) = self.command(exec, &String::from_utf8_lossy(&packet))? {
+                self.conn.flush()?;
+                return Ok(outcome);
+            }
+        }
+    }
+
+    /// The next message from the debugger, waiting for one. None once the connection closes.
+    fn receive(&mut self) -> io::Result<Option<Received>> {
+        loop {
+            while let Some(b) = self.input.pop_front() {
+                if let Some(r) = self.decoder.push(b) {
+                    return Ok(Some(r));
+                }
+            }
+            let mut buf = [0; 1024];
+            match self.conn.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.input.extend(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether the debugger has sent an interrupt while the CPU was running.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut buf = [0; 256];
+        let read = loop {
+            match self.conn.read(&mut buf) {
+                Ok(n) => break Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(0),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.conn.set_nonblocking(false)?;
+        self.input.extend(&buf[..read?]);
+        while let Some(b) = self.input.pop_front() {
+            match self.decoder.push(b) {
+                Some(Received::Interrupt) => return Ok(true),
+                Some(Received::Nak) => self.conn.write_all(&self.last_sent)?,
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last_sent = encode(data.as_bytes());
+        self.conn.write_all(&self.last_sent)?;
+        self.conn.flush()
+    }
+
+    /// Carries out one packet. Returns how the session ended, if it did.
+    fn command(&mut self, exec: &mut ExecutionContext, packet: &str) -> io::Result<Option<Outcome>> {
+        let (kind, args) = packet.split_at(packet.char_indices().nth(1).map_or(packet.len(), |(i, _)| i));
+        let reply = match kind {
+            "?" => self.stop.clone(),
+            "g" => hex(&(0..REGISTERS).flat_map(|n| register(exec, n).to_le_bytes()).collect::<Vec<_>>()),
+            "G" => match unhex(args).filter(|b| b.len() == REGISTERS * 4) {
+                Some(bytes) => {
+                    let values: Vec<u32> = bytes.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+                    // The PSL first, so SP goes to the stack of the mode it picks.
+                    set_register(exec, PSL, values[PSL]);
+                    values[..PSL].iter().enumerate().for_each(|(n, &v)| set_register(exec, n, v));
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTERS => hex(&register(exec, n).to_le_bytes()),
+                _ => "E01".to_string(),
+            },
+            "P" => match split(args, '=').and_then(|(n, v)| Some((usize::from_str_radix(n, 16).ok()?, unhex(v)?))) {
+                Some((n, v)) if n < REGISTERS && v.len() == 4 => {
+                    set_register(exec, n, u32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "m" => match split(args, ',').and_then(|(a, l)| Some((number(a)?, number(l)?))) {
+                Some((address, len)) => read_memory(exec, address, len.min(0x800)),
+                None => "E01".to_string(),
+            },
+            "M" => match split(args, ':').and_then(|(range, data)| Some((split(range, ',')?, unhex(data)?))) {
+                Some(((address, _), data)) => match number(address) {
+                    Some(address) => write_memory(exec, address, &data),
+                    None => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            "c" | "s" => {
+                if let Some(pc) = number(args) {
+                    exec.set_pc(pc);
+                }
+                self.stop = self.resume(exec, kind == "s")?;
+                self.stop.clone()
+            }
+            "Z" | "z" => self.breakpoint(exec, kind == "Z", args),
+            "D" => {
+                self.send("OK")?;
+                return Ok(Some(Outcome::Detached));
+            }
+            "k" => return Ok(Some(Outcome::Killed)),
+            "H" => "OK".to_string(),
+            "q" | "Q" | "v" => match packet {
+                "QStartNoAckMode" => {
+                    self.send("OK")?;
+                    self.acks = false;
+                    return Ok(None);
+                }
+                p if p.starts_with("qSupported") => "PacketSize=1000;QStartNoAckMode+".to_string(),
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                p if p.starts_with("qSymbol") => "OK".to_string(),
+                p if p.starts_with("vKill") => {
+                    self.send("OK")?;
+                    return Ok(Some(Outcome::Killed));
+                }
+                _ => String::new(),
+            },
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    /// Runs the CPU for one instruction, or until it reaches a breakpoint, hits a watchpoint,
+    /// halts or is interrupted. Returns the stop reply.
+    fn resume(&mut self, exec: &mut ExecutionContext, step: bool) -> io::Result<String> {
+        // Whatever the debugger itself looked at doesn't count.
+        exec.take_watch_hit();
+        if exec.is_halted() {
+            let pc = exec.pc();
+            exec.start(pc);
+        }
+        let mut count = 0;
+        loop {
+            // The instruction at a breakpoint runs when continuing from it.
+            if count > 0 && self.breakpoints.contains(&exec.pc()) {
+                break;
+            }
+            let running = exec.execute_step();
+            if let Some((w, address)) = exec.take_watch_hit() {
+                let kind = match w.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return Ok(format!("T{:02x}{}:{:x};", SIGTRAP, kind, address));
+            }
+            if !running || step {
+                break;
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+        Ok(format!("S{:02x}", SIGTRAP))
+    }
+
+    /// Sets or clears a breakpoint or watchpoint, from a `type,address,kind` packet.
+    fn breakpoint(&mut self, exec: &mut ExecutionContext, set: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (kind, address, len) = match (fields.next(), fields.next().and_then(number), fields.next().and_then(number)) {
+            (Some(kind), Some(address), Some(len)) => (kind, address, len),
+            _ => return "E01".to_string(),
+        };
+        let watch = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return String::new(),
+        };
+        match (watch, set) {
+            (None, true) => {
+                self.breakpoints.insert(address);
+            }
+            (None, false) => {
+                self.breakpoints.remove(&address);
+            }
+            (Some(kind), true) => exec.add_watchpoint(Watchpoint { address, len, kind }),
+            (Some(kind), false) => {
+                exec.remove_watchpoint(Watchpoint { address, len, kind });
+            }
+        }
+        "OK".to_string()
+    }
+}
+
+fn register(exec: &ExecutionContext, n: usize) -> u32 {
+    match n {
+        PSL => exec.psl(),
+        n => exec.reg(RegID::new(n as u8)),
+    }
+}
+
+fn set_register(exec: &mut ExecutionContext, n: usize, value: u32) {
+    match n {
+        PSL => exec.set_psl(value),
+        n => exec.set_reg(RegID::new(n as u8), value),
+    }
+}
+
+fn split(s: &str, at: char) -> Option<(&str, &str)> {
+    let i = s.find(at)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+fn number(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// As many of the bytes as can be read, or an error if none can.
+fn read_memory(exec: &mut ExecutionContext, address: u32, len: u32) -> String {
+    let mut bytes = vec![];
+    for i in 0..len {
+        match exec.debug_read(address.wrapping_add(i), OperandWidth::Byte) {
+            Ok(b) => bytes.push(b as u8),
+            Err(_) => break,
+        }
+    }
+    if bytes.is_empty() && len > 0 {
+        return "E14".to_string();
+    }
+    hex(&bytes)
+}
+
+fn write_memory(exec: &mut ExecutionContext, address: u32, data: &[u8]) -> String {
+    for (i, &b) in data.iter().enumerate() {
+        if exec.debug_write(address.wrapping_add(i as u32), OperandWidth::Byte, b as u128).is_err() {
+            return "E14".to_string();
+        }
+    }
+    "OK".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::{instrs::assemble, sysclk::TimeMode};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A debugger's end of the connection, acknowledging what it gets.
+    struct Client {
+        stream: TcpStream,
+        decoder: Decoder,
+    }
+
+    impl Client {
+        fn ask(&mut self, data: &str) -> String {
+            self.stream.write_all(&encode(data.as_bytes())).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut b = [0];
+            loop {
+                self.stream.read_exact(&mut b).unwrap();
+                if let Some(Received::Packet(p)) = self.decoder.push(b[0]) {
+                    self.stream.write_all(b"+").unwrap();
+                    return String::from_utf8(p).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scripted_session() {
+        let code = assemble("
+                MOVL #^X2000, R1
+                CLRL R0
+        LOOP:   INCL R0
+                CMPL R0, #3
+                BLSS LOOP
+                MOVL R0, (R1)
+                HALT
+        SPIN:   BRB SPIN
+        ", 0x1000).unwrap();
+        let (lp, spin) = (code.symbols["LOOP"], code.symbols["SPIN"]);
+        let mut exec = ExecutionContext::with_time_mode(TimeMode::Virtual);
+        exec.bus().write_bytes(0x1000, &code.bytes).unwrap();
+        exec.start(0x1000);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut gdb = Client { stream, decoder: Decoder::new() };
+            assert_eq!(gdb.ask("qSupported:swbreak+"), "PacketSize=1000;QStartNoAckMode+");
+            assert_eq!(gdb.ask("?"), "S05");
+            assert_eq!(gdb.ask("vCont?"), "");
+            assert_eq!(gdb.ask(&format!("Z0,{:x},1", lp)), "OK");
+            assert_eq!(gdb.ask("c"), "S05");
+            assert_eq!(gdb.ask("pf"), hex(&lp.to_le_bytes()));
+            assert_eq!(gdb.ask("p0"), "00000000");
+            assert_eq!(gdb.ask("c"), "S05");
+            assert_eq!(gdb.ask("p0"), "01000000");
+            assert_eq!(gdb.ask(&format!("z0,{:x},1", lp)), "OK");
+
+            assert_eq!(gdb.ask("Z2,2000,4"), "OK");
+            assert_eq!(gdb.ask("c"), "T05watch:2000;");
+            assert_eq!(gdb.ask("m2000,4"), "03000000");
+            assert_eq!(gdb.ask("M2000,2:beef"), "OK");
+            assert_eq!(gdb.ask("m2000,3"), "beef00");
+            assert_eq!(gdb.ask("s"), "S05");
+            assert_eq!(gdb.ask("?"), "S05");
+
+            assert_eq!(gdb.ask("g").len(), REGISTERS * 8);
+            assert_eq!(gdb.ask("P1=78563412"), "OK");
+            assert_eq!(gdb.ask("p1"), "78563412");
+            assert_eq!(gdb.ask("p11"), "E01");
+
+            // Running forever, until interrupted.
+            assert_eq!(gdb.ask(&format!("Pf={}", hex(&spin.to_le_bytes()))), "OK");
+            gdb.stream.write_all(&encode(b"c")).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            gdb.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(gdb.reply(), "S02");
+            assert_eq!(gdb.ask("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut stub = GdbStub::new(stream);
+        assert_eq!(stub.serve(&mut exec).unwrap(), Outcome::Detached);
+        client.join().unwrap();
+        assert_eq!(exec.reg(RegID::new(1)), 0x1234_5678);
+        assert_eq!(exec.bus().read_u32(0x2000).unwrap(), 0xEFBE);
+        assert_eq!(exec.pc(), spin);
+    }
+}
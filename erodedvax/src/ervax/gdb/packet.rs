@@ -0,0 +1,142 @@
+//! Remote serial protocol framing: `$data#cs`, where cs is the sum of the data bytes modulo 256
+//! in two hex digits. `}` escapes the next byte, XORed with 0x20. Outside a packet, `+` and `-`
+//! acknowledge or reject the last one sent, and a lone 0x03 asks the stub to stop the target.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Received {
+    /// A packet whose checksum was right, unescaped.
+    Packet(Vec<u8>),
+    BadChecksum,
+    Interrupt,
+    Ack,
+    Nak,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Data,
+    Escape,
+    Checksum(Option<u8>),
+}
+
+/// Picks packets and other messages out of a stream of bytes.
+pub struct Decoder {
+    state: State,
+    data: Vec<u8>,
+    sum: u8,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { state: State::Idle, data: vec![], sum: 0 }
+    }
+
+    pub fn push(&mut self, b: u8) -> Option<Received> {
+        match self.state {
+            State::Idle => match b {
+                b'$' => {
+                    self.state = State::Data;
+                    self.data.clear();
+                    self.sum = 0;
+                    None
+                }
+                b'+' => Some(Received::Ack),
+                b'-' => Some(Received::Nak),
+                0x03 => Some(Received::Interrupt),
+                _ => None,
+            },
+            State::Data if b == b'#' => {
+                self.state = State::Checksum(None);
+                None
+            }
+            State::Data | State::Escape => {
+                self.sum = self.sum.wrapping_add(b);
+                if self.state == State::Escape {
+                    self.data.push(b ^ 0x20);
+                    self.state = State::Data;
+                } else if b == b'}' {
+                    self.state = State::Escape;
+                } else {
+                    self.data.push(b);
+                }
+                None
+            }
+            State::Checksum(None) => {
+                self.state = State::Checksum(Some(b));
+                None
+            }
+            State::Checksum(Some(high)) => {
+                self.state = State::Idle;
+                let sum = std::str::from_utf8(&[high, b]).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+                if sum == Some(self.sum) {
+                    Some(Received::Packet(std::mem::take(&mut self.data)))
+                } else {
+                    Some(Received::BadChecksum)
+                }
+            }
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+/// Frames `data` as a packet, escaping what needs it.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![b'$'];
+    for &b in data {
+        if let b'$' | b'#' | b'}' | b'*' = b {
+            packet.extend_from_slice(&[b'}', b ^ 0x20]);
+        } else {
+            packet.push(b);
+        }
+    }
+    let sum = packet[1..].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+    packet.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+    packet
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        assert_eq!(encode(b"OK"), b"$OK#9a");
+        assert_eq!(encode(b"a#b"), b"$a}\x03b#43");
+
+        let mut decoder = Decoder::new();
+        let received: Vec<_> = b"+$m1000,4#8e\x03-$a}\x03b#43$OK#00"
+            .iter()
+            .filter_map(|&b| decoder.push(b))
+            .collect();
+        assert_eq!(received, vec![
+            Received::Ack,
+            Received::Packet(b"m1000,4".to_vec()),
+            Received::Interrupt,
+            Received::Nak,
+            Received::Packet(b"a#b".to_vec()),
+            Received::BadChecksum,
+        ]);
+
+        assert_eq!(hex(&[0xDE, 0xAD, 0x01]), "dead01");
+        assert_eq!(unhex("dead01"), Some(vec![0xDE, 0xAD, 0x01]));
+        assert_eq!(unhex("dea"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
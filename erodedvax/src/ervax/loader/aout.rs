@@ -0,0 +1,192 @@
+//! BSD VAX a.out executables.
+//!
+//! The header is eight longwords: the magic number, the sizes of the text, data, bss and symbol
+//! table, the entry point, and the sizes of the text and data relocations. 4.3BSD's magic number is
+//! just the number. NetBSD's is big endian, with a machine ID and flags above it.
+//!
+//! Text is loaded at 0. OMAGIC data follows the text directly, and NMAGIC and ZMAGIC data starts on
+//! the next page. A ZMAGIC header has the first page of the file to itself, so the text starts on
+//! the page after it. The symbol table follows the relocations, and the string table follows the
+//! symbol table, starting with its own size.
+
+use crate::ervax::loader::{
+    slice_at, string_at, u32_at, Executable, Format, LoadError, Segment, Symbol, SymbolKind,
+};
+
+pub const OMAGIC: u16 = 0o407;
+pub const NMAGIC: u16 = 0o410;
+pub const ZMAGIC: u16 = 0o413;
+
+/// NetBSD machine IDs, for 1K and 4K page executables. 4.3BSD's have none.
+pub const MID_VAX1K: u16 = 140;
+pub const MID_VAX: u16 = 150;
+
+const HEADER_SIZE: usize = 32;
+const NLIST_SIZE: usize = 12;
+
+/// Symbol types, in the low bits of n_type.
+const N_EXT: u8 = 0x01;
+const N_TYPE: u8 = 0x1E;
+const N_ABS: u8 = 0x02;
+const N_TEXT: u8 = 0x04;
+const N_DATA: u8 = 0x06;
+const N_BSS: u8 = 0x08;
+/// Debugger symbols have one of these bits set.
+const N_STAB: u8 = 0xE0;
+
+/// The magic number and machine ID.
+fn magic(bytes: &[u8]) -> Option<(u16, u16)> {
+    let midmag = u32_at(bytes, 0, "header").ok()?;
+    if midmag >> 16 == 0 {
+        return Some((midmag as u16, 0));
+    }
+    let midmag = midmag.swap_bytes();
+    Some((midmag as u16, (midmag >> 16) as u16 & 0x3FF))
+}
+
+pub fn is_aout(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_SIZE && matches!(magic(bytes), Some((OMAGIC, _)) | Some((NMAGIC, _)) | Some((ZMAGIC, _)))
+}
+
+#[inline]
+fn round_up(v: u32, to: u32) -> Option<u32> {
+    v.checked_add(to - 1).map(|v| v & !(to - 1))
+}
+
+fn symbols(bytes: &[u8], offset: usize, size: usize) -> Result<Vec<Symbol>, LoadError> {
+    let table = slice_at(bytes, offset, size, "symbol table")?;
+    let strings_size = u32_at(bytes, offset + size, "string table")? as usize;
+    let strings = slice_at(bytes, offset + size, strings_size, "string table")?;
+
+    let mut symbols = vec![];
+    for entry in table.chunks_exact(NLIST_SIZE) {
+        let n_type = entry[4];
+        let kind = match n_type & N_TYPE {
+            _ if n_type & N_STAB != 0 => continue,
+            N_ABS => SymbolKind::Absolute,
+            N_TEXT => SymbolKind::Code,
+            N_DATA | N_BSS => SymbolKind::Data,
+            _ => continue,
+        };
+        let name = match string_at(strings, u32_at(entry, 0, "symbol table")? as usize) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        symbols.push(Symbol {
+            name,
+            address: u32_at(entry, 8, "symbol table")?,
+            size: 0,
+            kind,
+            global: n_type & N_EXT != 0,
+        });
+    }
+    Ok(symbols)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Executable, LoadError> {
+    let (magic, mid) = magic(bytes).ok_or(LoadError::Truncated("header"))?;
+    let page = match mid {
+        0 | MID_VAX1K => 1024,
+        MID_VAX => 4096,
+        _ => return Err(LoadError::Unsupported(format!("a.out for machine {}", mid))),
+    };
+    let field = |n: usize| u32_at(bytes, n * 4, "header");
+    let (text, data, bss, syms, entry) = (field(1)?, field(2)?, field(3)?, field(4)?, field(5)?);
+    let relocations = field(6)?.wrapping_add(field(7)?);
+
+    let (format, text_offset) = match magic {
+        OMAGIC => (Format::OMagic, HEADER_SIZE),
+        NMAGIC => (Format::NMagic, HEADER_SIZE),
+        _ => (Format::ZMagic, page as usize),
+    };
+    let data_address = match format {
+        Format::OMagic => text,
+        _ => round_up(text, page).ok_or_else(|| LoadError::Unsupported(format!("text of {} bytes", text)))?,
+    };
+    let data_offset = text_offset + text as usize;
+
+    let mut program = Executable {
+        format,
+        entry,
+        segments: vec![
+            Segment {
+                address: 0,
+                data: slice_at(bytes, text_offset, text as usize, "text")?.to_vec(),
+                size: text,
+                executable: true,
+            },
+            Segment {
+                address: data_address,
+                data: slice_at(bytes, data_offset, data as usize, "data")?.to_vec(),
+                size: data.wrapping_add(bss),
+                executable: false,
+            },
+        ],
+        symbols: vec![],
+    };
+    if syms != 0 {
+        let offset = data_offset + data as usize + relocations as usize;
+        program.symbols = symbols(bytes, offset, syms as usize)?;
+        program.sort_symbols();
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Makes a 4.3BSD style a.out, with symbols given as name, n_type and value.
+    pub(crate) fn image(magic: u16, text: &[u8], data: &[u8], bss: u32, syms: &[(&str, u8, u32)]) -> Vec<u8> {
+        let mut strings = vec![0; 4];
+        let mut table = vec![];
+        for (name, n_type, value) in syms {
+            table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            table.extend_from_slice(&[*n_type, 0, 0, 0]);
+            table.extend_from_slice(&value.to_le_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        let size = strings.len() as u32;
+        strings[..4].copy_from_slice(&size.to_le_bytes());
+
+        let header = [magic as u32, text.len() as u32, data.len() as u32, bss, table.len() as u32, 0, 0, 0];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|v| v.to_le_bytes()).collect();
+        if magic == ZMAGIC {
+            bytes.resize(1024, 0);
+        }
+        for part in &[text, data, &table, &strings] {
+            bytes.extend_from_slice(part);
+        }
+        bytes
+    }
+
+    #[test]
+    fn aout_layouts() {
+        let syms = [("_main", N_TEXT | N_EXT, 0x10), ("_buf", N_BSS | N_EXT, 0x404), ("x.o", 0x1F, 0), ("lsym", 0x24, 8)];
+        let zmagic = parse(&image(ZMAGIC, &[1; 0x300], &[2; 4], 12, &syms)).unwrap();
+        assert_eq!(zmagic.format, Format::ZMagic);
+        assert_eq!(zmagic.segments[0].data, vec![1; 0x300]);
+        assert_eq!((zmagic.segments[1].address, zmagic.segments[1].size), (0x400, 16));
+        let names: Vec<_> = zmagic.symbols.iter().map(|s| (s.name.as_str(), s.kind)).collect();
+        assert_eq!(names, vec![("_main", SymbolKind::Code), ("_buf", SymbolKind::Data)]);
+
+        let omagic = parse(&image(OMAGIC, &[1; 0x302], &[2; 4], 0, &[])).unwrap();
+        assert_eq!((omagic.segments[1].address, omagic.end()), (0x302, 0x306));
+        assert!(omagic.symbols.is_empty());
+
+        // NetBSD's header, for 4K pages.
+        let mut netbsd = image(NMAGIC, &[1; 0x10], &[2; 4], 0, &[]);
+        netbsd[..4].copy_from_slice(&(((MID_VAX as u32) << 16) | NMAGIC as u32).to_be_bytes());
+        assert!(is_aout(&netbsd));
+        assert_eq!(parse(&netbsd).unwrap().segments[1].address, 0x1000);
+        netbsd[1] = 0x86;
+        assert_eq!(parse(&netbsd), Err(LoadError::Unsupported("a.out for machine 134".to_string())));
+
+        let short = image(OMAGIC, &[1; 0x10], &[], 0, &[]);
+        assert_eq!(parse(&short[..40]), Err(LoadError::Truncated("text")));
+        let mut huge = image(NMAGIC, &[1; 0x10], &[], 0, &[]);
+        huge[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse(&huge), Err(LoadError::Unsupported(format!("text of {} bytes", u32::MAX))));
+    }
+}
@@ -0,0 +1,210 @@
+//! ELF32 executables for the VAX, as NetBSD makes them: little endian, machine EM_VAX.
+//!
+//! The PT_LOAD program headers are the segments, at their virtual addresses. Symbols come from
+//! the SHT_SYMTAB section, and its string table is the section it links to.
+
+use crate::ervax::loader::{
+    slice_at, string_at, u16_at, u32_at, Executable, Format, LoadError, Segment, Symbol, SymbolKind,
+};
+
+pub const EM_VAX: u16 = 75;
+
+const HEADER_SIZE: usize = 52;
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SYM_SIZE: usize = 16;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const STB_LOCAL: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x7FELF")
+}
+
+/// A section header's fields, by longword.
+struct Section<'a>(&'a [u8]);
+
+impl Section<'_> {
+    fn field(&self, n: usize) -> Result<u32, LoadError> {
+        u32_at(self.0, n * 4, "section headers")
+    }
+
+    fn contents<'b>(&self, bytes: &'b [u8]) -> Result<&'b [u8], LoadError> {
+        slice_at(bytes, self.field(4)? as usize, self.field(5)? as usize, "sections")
+    }
+}
+
+fn sections(bytes: &[u8]) -> Result<Vec<Section<'_>>, LoadError> {
+    let offset = u32_at(bytes, 32, "header")? as usize;
+    let size = u16_at(bytes, 46, "header")? as usize;
+    let count = u16_at(bytes, 48, "header")? as usize;
+    (0..count)
+        .map(|i| slice_at(bytes, offset + i * size, size.max(40), "section headers").map(Section))
+        .collect()
+}
+
+fn symbols(bytes: &[u8]) -> Result<Vec<Symbol>, LoadError> {
+    let sections = sections(bytes)?;
+    let mut symbols = vec![];
+    for table in sections.iter().filter(|s| s.field(1).ok() == Some(SHT_SYMTAB)) {
+        let strings = match sections.get(table.field(6)? as usize) {
+            Some(s) => s.contents(bytes)?,
+            None => return Err(LoadError::Truncated("section headers")),
+        };
+        for entry in table.contents(bytes)?.chunks_exact(SYM_SIZE) {
+            let (info, section) = (entry[12], u16_at(entry, 14, "symbol table")?);
+            let kind = match (info & 0xF, section) {
+                (_, SHN_UNDEF) | (STT_SECTION, _) | (STT_FILE, _) => continue,
+                (_, SHN_ABS) => SymbolKind::Absolute,
+                (STT_FUNC, _) => SymbolKind::Procedure,
+                (STT_OBJECT, _) => SymbolKind::Data,
+                // Labels without a type are code if their section is.
+                _ => match sections.get(section as usize).map(|s| s.field(2)).transpose()? {
+                    Some(flags) if flags & SHF_EXECINSTR != 0 => SymbolKind::Code,
+                    _ => SymbolKind::Data,
+                },
+            };
+            let name = match string_at(strings, u32_at(entry, 0, "symbol table")? as usize) {
+                Some(name) if !name.is_empty() => name,
+                _ => continue,
+            };
+            symbols.push(Symbol {
+                name,
+                address: u32_at(entry, 4, "symbol table")?,
+                size: u32_at(entry, 8, "symbol table")?,
+                kind,
+                global: info >> 4 != STB_LOCAL,
+            });
+        }
+    }
+    Ok(symbols)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Executable, LoadError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(LoadError::Truncated("header"));
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(LoadError::Unsupported("64-bit ELF".to_string()));
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(LoadError::Unsupported("big endian ELF".to_string()));
+    }
+    let machine = u16_at(bytes, 18, "header")?;
+    if machine != EM_VAX {
+        return Err(LoadError::Unsupported(format!("ELF for machine {}", machine)));
+    }
+    match u16_at(bytes, 16, "header")? {
+        ET_EXEC => {}
+        1 => return Err(LoadError::Unsupported("an ELF relocatable object".to_string())),
+        3 => return Err(LoadError::Unsupported("an ELF shared object".to_string())),
+        t => return Err(LoadError::Unsupported(format!("ELF file type {}", t))),
+    }
+
+    let entry = u32_at(bytes, 24, "header")?;
+    let offset = u32_at(bytes, 28, "header")? as usize;
+    let size = u16_at(bytes, 42, "header")? as usize;
+    let count = u16_at(bytes, 44, "header")? as usize;
+    let mut segments = vec![];
+    for i in 0..count {
+        let header = slice_at(bytes, offset + i * size, size.max(32), "program headers")?;
+        let field = |n: usize| u32_at(header, n * 4, "program headers");
+        if field(0)? != PT_LOAD {
+            continue;
+        }
+        let (file_size, mem_size) = (field(4)?, field(5)?);
+        segments.push(Segment {
+            address: field(2)?,
+            data: slice_at(bytes, field(1)? as usize, file_size as usize, "program segments")?.to_vec(),
+            size: mem_size.max(file_size),
+            executable: field(6)? & PF_X != 0,
+        });
+    }
+
+    let mut program = Executable { format: Format::Elf, entry, segments, symbols: symbols(bytes)? };
+    program.sort_symbols();
+    Ok(program)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Makes an executable with one text segment at `address`, and symbols given as name, st_info
+    /// and value. Symbols are in the text section, section 1.
+    pub(crate) fn image(address: u32, text: &[u8], bss: u32, syms: &[(&str, u8, u32)]) -> Vec<u8> {
+        let mut strings = vec![0];
+        let mut table = vec![0; SYM_SIZE];
+        for (name, info, value) in syms {
+            let fields = [strings.len() as u32, *value, 0];
+            table.extend(fields.iter().flat_map(|v| v.to_le_bytes()));
+            table.extend_from_slice(&[*info, 0, 1, 0]);
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+
+        // Header, one program header, text, symbols, strings, then four section headers.
+        let text_at = HEADER_SIZE + 32;
+        let table_at = text_at + text.len();
+        let strings_at = table_at + table.len();
+        let sections_at = strings_at + strings.len();
+        let mut bytes = b"\x7FELF\x01\x01\x01".to_vec();
+        bytes.resize(16, 0);
+        let half = |b: &mut Vec<u8>, v: u16| b.extend_from_slice(&v.to_le_bytes());
+        let long = |b: &mut Vec<u8>, v: u32| b.extend_from_slice(&v.to_le_bytes());
+        half(&mut bytes, ET_EXEC);
+        half(&mut bytes, EM_VAX);
+        for v in &[1, address, HEADER_SIZE as u32, sections_at as u32, 0] {
+            long(&mut bytes, *v);
+        }
+        for v in &[HEADER_SIZE as u16, 32, 1, 40, 4, 0] {
+            half(&mut bytes, *v);
+        }
+        let text_size = text.len() as u32;
+        for v in &[PT_LOAD, text_at as u32, address, address, text_size, text_size + bss, 5, 0x1000] {
+            long(&mut bytes, *v);
+        }
+        bytes.extend_from_slice(text);
+        bytes.extend_from_slice(&table);
+        bytes.extend_from_slice(&strings);
+        let section_headers = [
+            [0; 10],
+            [0, 1, 6, address, text_at as u32, text_size, 0, 0, 4, 0],
+            [0, SHT_SYMTAB, 0, 0, table_at as u32, table.len() as u32, 3, 1, 4, SYM_SIZE as u32],
+            [0, 3, 0, 0, strings_at as u32, strings.len() as u32, 0, 0, 1, 0],
+        ];
+        for v in section_headers.iter().flatten() {
+            long(&mut bytes, *v);
+        }
+        bytes
+    }
+
+    #[test]
+    fn elf_executables() {
+        let syms = [("main", 0x12, 0x10_0010), ("loop", 0x00, 0x10_0014), ("crt0.c", 0x04, 0)];
+        let program = parse(&image(0x10_0000, &[1; 0x20], 8, &syms)).unwrap();
+        assert_eq!(program.entry, 0x10_0000);
+        assert_eq!(program.segments, vec![Segment { address: 0x10_0000, data: vec![1; 0x20], size: 0x28, executable: true }]);
+        let kinds: Vec<_> = program.symbols.iter().map(|s| (s.name.as_str(), s.kind, s.global)).collect();
+        assert_eq!(kinds, vec![("main", SymbolKind::Procedure, true), ("loop", SymbolKind::Code, false)]);
+        assert_eq!(program.procedures(), vec![0x10_0010]);
+
+        let mut other = image(0x1000, &[], 0, &[]);
+        other[18] = 62;
+        assert_eq!(parse(&other), Err(LoadError::Unsupported("ELF for machine 62".to_string())));
+        other[4] = 2;
+        assert_eq!(parse(&other), Err(LoadError::Unsupported("64-bit ELF".to_string())));
+        assert_eq!(parse(&other[..20]), Err(LoadError::Truncated("header")));
+    }
+}
@@ -0,0 +1,316 @@
+//! Loaders for standalone programs: BSD VAX a.out (OMAGIC, NMAGIC and ZMAGIC) and ELF32 EM_VAX
+//! executables. An Executable is what's in the file, its segments and symbols, and loading it
+//! puts the segments into RAM and starts the CPU at its entry point.
+//!
+//! As the BSD kernels do, the entry point is taken to be a procedure entry mask, and execution
+//! starts at the instruction after it. Memory management is off, so the addresses the program
+//! was linked at are physical ones.
+
+use std::fmt;
+
+use crate::ervax::{
+    cpu::{execution::ExecutionContext, instrs::Labels},
+    utils::addr_lw_trim,
+};
+
+pub mod aout;
+pub mod elf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// There's no a.out or ELF header.
+    UnknownFormat,
+    /// The file ends in the middle of this part of it.
+    Truncated(&'static str),
+    /// It's an executable, but not one that runs here.
+    Unsupported(String),
+    /// This many bytes at this address don't fit in memory.
+    OutsideMemory(u32, u32),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::UnknownFormat => write!(f, "it isn't an a.out or ELF executable"),
+            LoadError::Truncated(part) => write!(f, "the file ends in the middle of its {}", part),
+            LoadError::Unsupported(what) => write!(f, "{} isn't supported", what),
+            LoadError::OutsideMemory(address, size) => {
+                write!(f, "{} bytes at ^X{:X} don't fit in memory", size, address)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// a.out with text and data together, impure.
+    OMagic,
+    /// a.out with read-only text, data on the next page.
+    NMagic,
+    /// a.out laid out for demand paging, the header a page to itself.
+    ZMagic,
+    Elf,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::OMagic => "a.out OMAGIC",
+            Format::NMagic => "a.out NMAGIC",
+            Format::ZMagic => "a.out ZMAGIC",
+            Format::Elf => "ELF",
+        })
+    }
+}
+
+/// A piece of the program that's loaded into memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    /// What's in the file. The rest of the segment, up to its size, is zeroed.
+    pub data: Vec<u8>,
+    pub size: u32,
+    pub executable: bool,
+}
+
+impl Segment {
+    #[inline]
+    pub fn end(&self) -> u32 {
+        self.address.wrapping_add(self.size)
+    }
+
+    #[inline]
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.address) < self.size
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// A procedure, whose address is its entry mask. Only ELF says which symbols these are.
+    Procedure,
+    /// Anything else in the text.
+    Code,
+    Data,
+    /// A value rather than an address.
+    Absolute,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// As the file has it, with the leading underscore of a.out C names.
+    pub name: String,
+    pub address: u32,
+    /// Zero when the file doesn't say.
+    pub size: u32,
+    pub kind: SymbolKind,
+    /// Visible to other objects, rather than local to one.
+    pub global: bool,
+}
+
+/// A program read from an executable file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Executable {
+    pub format: Format,
+    /// The procedure entry mask execution starts after.
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Ordered by address, globals first among symbols at the same address.
+    pub symbols: Vec<Symbol>,
+}
+
+/// Reads a little endian word at `offset`, or says which `part` of the file is cut short.
+pub(crate) fn u16_at(bytes: &[u8], offset: usize, part: &'static str) -> Result<u16, LoadError> {
+    match bytes.get(offset..offset.wrapping_add(2)) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(LoadError::Truncated(part)),
+    }
+}
+
+pub(crate) fn u32_at(bytes: &[u8], offset: usize, part: &'static str) -> Result<u32, LoadError> {
+    match bytes.get(offset..offset.wrapping_add(4)) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(LoadError::Truncated(part)),
+    }
+}
+
+/// The `len` bytes at `offset`.
+pub(crate) fn slice_at<'a>(bytes: &'a [u8], offset: usize, len: usize, part: &'static str) -> Result<&'a [u8], LoadError> {
+    offset.checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(LoadError::Truncated(part))
+}
+
+/// The NUL terminated string at `offset` in a string table.
+pub(crate) fn string_at(table: &[u8], offset: usize) -> Option<String> {
+    let rest = table.get(offset..)?;
+    let len = rest.iter().position(|b| *b == 0)?;
+    Some(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+impl Executable {
+    /// Reads an executable, whichever kind it is.
+    pub fn parse(bytes: &[u8]) -> Result<Executable, LoadError> {
+        if elf::is_elf(bytes) {
+            elf::parse(bytes)
+        } else if aout::is_aout(bytes) {
+            aout::parse(bytes)
+        } else {
+            Err(LoadError::UnknownFormat)
+        }
+    }
+
+    /// Where execution starts, after the entry mask.
+    #[inline]
+    pub fn start_pc(&self) -> u32 {
+        self.entry.wrapping_add(2)
+    }
+
+    /// The first address past every segment, where a heap could begin.
+    pub fn end(&self) -> u32 {
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+
+    /// Puts the symbols in order, for the lookups below.
+    pub(crate) fn sort_symbols(&mut self) {
+        self.symbols.sort_by_key(|s| (s.address, !s.global));
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// The symbol `address` is in or after, and how far past it the address is. Absolute
+    /// symbols aren't addresses, so they're left out.
+    pub fn symbolize(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let at = self.symbols.partition_point(|s| s.address <= address);
+        self.symbols[..at].iter().rev()
+            .filter(|s| s.kind != SymbolKind::Absolute)
+            .find(|s| s.size == 0 || address - s.address < s.size)
+            .map(|s| (s, address - s.address))
+    }
+
+    /// Names for the disassembler, one per address.
+    pub fn labels(&self) -> Labels {
+        let mut labels = Labels::new();
+        for s in self.symbols.iter().filter(|s| s.kind != SymbolKind::Absolute) {
+            labels.entry(s.address).or_insert_with(|| s.name.clone());
+        }
+        labels
+    }
+
+    /// The addresses of procedure entry masks, for following calls when disassembling.
+    pub fn procedures(&self) -> Vec<u32> {
+        self.symbols.iter()
+            .filter(|s| s.kind == SymbolKind::Procedure)
+            .map(|s| s.address)
+            .collect()
+    }
+
+    /// Puts the segments into RAM and starts the CPU at the entry point, in the power-up state.
+    pub fn load(&self, exec: &mut ExecutionContext) -> Result<(), LoadError> {
+        for segment in &self.segments {
+            let outside = || LoadError::OutsideMemory(segment.address, segment.size);
+            if segment.address as u64 + segment.size as u64 > exec.bus().ram_size() as u64 {
+                return Err(outside());
+            }
+            let size = segment.size as usize;
+            let data = &segment.data[..segment.data.len().min(size)];
+            exec.bus().write_bytes(segment.address, data).map_err(|_| outside())?;
+            // Then the rest is cleared, a page at a time.
+            let zeros = [0; 4096];
+            let mut done = data.len();
+            while done < size {
+                let n = (size - done).min(zeros.len());
+                exec.bus().write_bytes(segment.address + done as u32, &zeros[..n]).map_err(|_| outside())?;
+                done += n;
+            }
+        }
+        exec.power_up(self.start_pc());
+        Ok(())
+    }
+}
+
+/// Sets up a stack below `top` the way a BSD kernel leaves one for a new program: SP pointing at
+/// argc, followed by the argv pointers and a zero, then the environment pointers and a zero, with
+/// the strings they point to above them. Returns the new SP.
+pub fn push_args(exec: &mut ExecutionContext, top: u32, args: &[&str], env: &[&str]) -> Result<u32, LoadError> {
+    let strings: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let pointers = (1 + args.len() + 1 + env.len() + 1) * 4;
+    let size = strings + pointers;
+    let outside = LoadError::OutsideMemory(top.wrapping_sub(size as u32), size as u32);
+    if size > top as usize {
+        return Err(outside);
+    }
+
+    // The strings go at the top, and the vectors below them on a longword boundary.
+    let mut at = top - strings as u32;
+    let sp = addr_lw_trim(at) - pointers as u32;
+    let mut vector = vec![args.len() as u32];
+    for (i, s) in args.iter().chain(env).enumerate() {
+        exec.bus().write_bytes(at, s.as_bytes()).map_err(|_| outside.clone())?;
+        exec.bus().write_bytes(at + s.len() as u32, &[0]).map_err(|_| outside.clone())?;
+        vector.push(at);
+        at += s.len() as u32 + 1;
+        if i + 1 == args.len() {
+            vector.push(0);
+        }
+    }
+    if args.is_empty() {
+        vector.push(0);
+    }
+    vector.push(0);
+
+    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+    exec.bus().write_bytes(sp, &bytes).map_err(|_| outside)?;
+    exec.set_sp(sp);
+    Ok(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::cpu::{bus::VAXBus, execution::HaltReason, instrs::assemble, sysclk::TimeMode, RegID};
+
+    #[test]
+    fn load_and_start() {
+        // Adds up the lengths of the arguments, then halts with the total in R6.
+        let source = "
+        START:  .WORD 0
+                CLRL R6
+                MOVL (SP), R7
+                MOVAL 4(SP), R8
+        NEXT:   MOVL (R8)+, R9
+        CHAR:   TSTB (R9)+
+                BEQL DONE
+                INCL R6
+                BRB CHAR
+        DONE:   SOBGTR R7, NEXT
+                HALT
+        ";
+        let code = assemble(source, 0).unwrap();
+        let image = aout::tests::image(0o407, &code.bytes, &[], 16, &[("_start", 0x05, 0)]);
+        let program = Executable::parse(&image).unwrap();
+        assert_eq!(program.format, Format::OMagic);
+        assert_eq!(program.labels().get(&0).map(String::as_str), Some("_start"));
+        assert_eq!(program.symbolize(4).map(|(s, off)| (s.name.as_str(), off)), Some(("_start", 4)));
+
+        let mut exec = ExecutionContext::with_bus(VAXBus::new(64 << 10), TimeMode::Virtual);
+        program.load(&mut exec).unwrap();
+        let sp = push_args(&mut exec, 0x8000, &["prog", "ab"], &["HOME=/"]).unwrap();
+        assert_eq!((exec.pc(), exec.sp()), (2, sp));
+        assert_eq!(exec.bus().read_u32(sp), Ok(2));
+        assert_eq!(exec.bus().read_u32(sp + 12), Ok(0));
+        assert_eq!(exec.bus().read_u32(sp + 20), Ok(0));
+
+        assert_eq!(exec.run(), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(6)), 6);
+
+        let mut small = ExecutionContext::with_bus(VAXBus::new(32), TimeMode::Virtual);
+        let data_at = code.bytes.len() as u32;
+        assert_eq!(program.load(&mut small), Err(LoadError::OutsideMemory(data_at, 16)));
+        let huge = Executable::parse(&aout::tests::image(0o407, &code.bytes, &[], 0xFFFF_FF00, &[])).unwrap();
+        assert_eq!(huge.load(&mut exec), Err(LoadError::OutsideMemory(data_at, 0xFFFF_FF00)));
+        assert_eq!(Executable::parse(b"#!/bin/sh\n"), Err(LoadError::UnknownFormat));
+    }
+}
@@ -0,0 +1,749 @@
+//! A debugger on the console terminal, for the host to use in place of the console monitor:
+//! the DBG> prompt comes up whenever the CPU halts or stops at a breakpoint, and ^P stops it.
+//! Commands can be shortened as far as they stay unambiguous, DE for DELETE and DU for DUMP.
+//! Numbers are in hex, and addresses can also be a register name, for what's in it, or a
+//! symbol from the program being debugged.
+//!
+//! Breakpoints are matched against PC before each instruction, as a virtual address or with /P
+//! the physical address it maps to, and stop only if their condition on a register holds.
+//! NEXT steps over CALLS, CALLG, JSB and BSBx, and FINISH runs until RET returns from the frame
+//! FP points at.
+
+use std::fmt;
+
+use crate::ervax::{
+    cpu::{
+        execution::{DebugAccessError, ExecutionContext, HaltReason, RingSink},
+        instrs::{decode_at, DecodedInstr, DisasmOptions, Disassembler, InstructionType, Labels, OperandWidth},
+        RegID,
+    },
+    devices::console::HALT_CHARACTER,
+    machine::monitor::{halt_message, parse_hex, split_command, CommandWords, LineEditor},
+};
+
+pub const PROMPT: &str = "DBG> ";
+
+/// Instructions run between looks at the terminal, while breakpoints are being checked.
+const SLICE: u32 = 10_000;
+
+/// The most an instruction can take, to read before decoding.
+const MAX_INSTRUCTION: u32 = 64;
+
+/// The most DUMP shows at once, as much as the GDB stub reads for one `m`.
+const MAX_DUMP: u32 = 0x800;
+
+#[derive(Debug)]
+pub enum DebugError {
+    UnknownCommand(String),
+    BadAddress(String),
+    BadValue(String),
+    BadCondition(String),
+    NoBreakpoint(u32),
+    /// Nothing answered at a memory address.
+    NoMemory(u32),
+    /// A virtual address the page tables don't map, with memory management on.
+    Unmapped(u32),
+    /// FINISH with no call frame to return from.
+    NoFrame,
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::UnknownCommand(c) => write!(f, "?Unknown command {}", c),
+            DebugError::BadAddress(a) => write!(f, "?Bad address {}", a),
+            DebugError::BadValue(v) => write!(f, "?Bad value {}", v),
+            DebugError::BadCondition(c) => write!(f, "?Bad condition {}", c),
+            DebugError::NoBreakpoint(n) => write!(f, "?No breakpoint {:X}", n),
+            DebugError::NoMemory(a) => write!(f, "?No memory at {:08X}", a),
+            DebugError::Unmapped(a) => write!(f, "?No translation for {:08X}", a),
+            DebugError::NoFrame => write!(f, "?No call frame at FP"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    General(RegID),
+    Psl,
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Register> {
+        if name.eq_ignore_ascii_case("PSL") {
+            return Some(Register::Psl);
+        }
+        RegID::from_name(name).map(Register::General)
+    }
+
+    fn read(self, exec: &ExecutionContext) -> u32 {
+        match self {
+            Register::General(r) => exec.reg(r),
+            Register::Psl => exec.psl(),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::General(r) => write!(f, "{}", r.name()),
+            Register::Psl => write!(f, "PSL"),
+        }
+    }
+}
+
+/// A test of a register against a value, unsigned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub compare: &'static str,
+    pub value: u32,
+}
+
+const COMPARISONS: &[&str] = &["==", "!=", "<", "<=", ">", ">="];
+
+impl Condition {
+    /// Parses `register op value`.
+    pub fn parse(words: &[&str]) -> Result<Condition, DebugError> {
+        let bad = || DebugError::BadCondition(words.join(" "));
+        match words {
+            [register, compare, value] => Ok(Condition {
+                register: Register::parse(register).ok_or_else(bad)?,
+                compare: COMPARISONS.iter().find(|c| *c == compare).ok_or_else(bad)?,
+                value: parse_hex(value).ok_or_else(bad)?,
+            }),
+            _ => Err(bad()),
+        }
+    }
+
+    pub fn holds(&self, exec: &ExecutionContext) -> bool {
+        let v = self.register.read(exec);
+        match self.compare {
+            "==" => v == self.value,
+            "!=" => v != self.value,
+            "<" => v < self.value,
+            "<=" => v <= self.value,
+            ">" => v > self.value,
+            _ => v >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {:X}", self.register, self.compare, self.value)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub number: u32,
+    pub address: u32,
+    pub physical: bool,
+    pub condition: Option<Condition>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {} {:08X}", self.number, if self.physical { 'P' } else { 'V' }, self.address)?;
+        match &self.condition {
+            Some(c) => write!(f, " if {}", c),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where NEXT or FINISH stop, if nothing else stops the CPU first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Until {
+    /// Back after a call, with the stack no deeper than before it.
+    Over { pc: u32, sp: u32 },
+    /// Returned from a frame, to its saved PC and FP.
+    Return { pc: u32, fp: u32 },
+}
+
+const COMMANDS: &[(&str, usize)] = &[
+    ("BREAK", 1),
+    ("CONTINUE", 1),
+    ("DELETE", 2),
+    ("DUMP", 2),
+    ("FINISH", 1),
+    ("HELP", 1),
+    ("HISTORY", 2),
+    ("LIST", 1),
+    ("NEXT", 1),
+    ("QUIT", 1),
+    ("REGISTERS", 1),
+    ("STEP", 1),
+];
+
+const HELP: &str = "\
+BREAK [/P] [address [IF register op value]]
+DELETE [n]
+STEP [n]
+NEXT
+FINISH
+CONTINUE
+REGISTERS
+LIST [address] [n]
+DUMP [/P] [address] [length]
+HISTORY [n]
+QUIT";
+
+/// Reads bytes at a virtual address, or physical with `physical`, up to the first that can't be.
+fn read_bytes(exec: &mut ExecutionContext, address: u32, len: u32, physical: bool) -> Result<Vec<u8>, DebugError> {
+    let mut bytes = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        let a = address.wrapping_add(i);
+        let b = if physical {
+            exec.bus().read_u8(a).ok()
+        } else {
+            match exec.debug_read(a, OperandWidth::Byte) {
+                Ok(b) => Some(b as u8),
+                Err(DebugAccessError::Unmapped) => return Err(DebugError::Unmapped(a)),
+                Err(DebugAccessError::Bus(_)) => None,
+            }
+        };
+        match b {
+            Some(b) => bytes.push(b),
+            None => break,
+        }
+    }
+    Ok(bytes)
+}
+
+fn decode(exec: &mut ExecutionContext, address: u32) -> Option<DecodedInstr> {
+    decode_at(&read_bytes(exec, address, MAX_INSTRUCTION, false).ok()?, address).ok()
+}
+
+/// The registers, four to a line, then the PSL with its fields decoded.
+pub fn format_registers(exec: &ExecutionContext) -> String {
+    let mut lines: Vec<String> = (0..16u8)
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|row| {
+            let regs: Vec<String> = row.iter().map(|&n| {
+                let r = RegID::new(n);
+                format!("{:<3} {:08X}", r.name(), exec.reg(r))
+            }).collect();
+            regs.join("   ")
+        })
+        .collect();
+
+    let flags = [
+        (exec.get_trace_pending(), "TP"),
+        (exec.get_first_part_done(), "FPD"),
+        (exec.get_interrupt_stack(), "IS"),
+        (exec.get_decimal_overflow_enable(), "DV"),
+        (exec.get_floating_underflow_enable(), "FU"),
+        (exec.get_integer_overflow_enable(), "IV"),
+        (exec.get_trace_enable(), "T"),
+        (exec.get_negative(), "N"),
+        (exec.get_zero(), "Z"),
+        (exec.get_overflow(), "V"),
+        (exec.get_carry(), "C"),
+    ];
+    let set: Vec<&str> = flags.iter().filter(|(on, _)| *on).map(|(_, name)| *name).collect();
+    lines.push(format!(
+        "PSL {:08X}   CUR={:?} PRV={:?} IPL={:02X} {}",
+        exec.psl(),
+        exec.get_cur_priv_mode(),
+        exec.get_prev_priv_mode(),
+        exec.get_ipl(),
+        set.join(" "),
+    ).trim_end().to_string());
+    lines.join("\n")
+}
+
+/// Memory as hex and text, 16 bytes to a line.
+pub fn format_dump(address: u32, bytes: &[u8]) -> String {
+    let lines: Vec<String> = bytes.chunks(16).enumerate().map(|(i, row)| {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = row.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+        format!("{:08X}  {:<48} {}", address.wrapping_add(i as u32 * 16), hex.join(" "), text)
+    }).collect();
+    lines.join("\n")
+}
+
+pub struct Debugger {
+    editor: LineEditor,
+    /// Whether the prompt is up.
+    prompting: bool,
+    driving: bool,
+    breakpoints: Vec<Breakpoint>,
+    next_number: u32,
+    until: Option<Until>,
+    /// Whether the instruction at PC runs without looking at breakpoints, as it does when
+    /// carrying on from one.
+    resuming: bool,
+    /// Why the debugger stopped the CPU, when it did.
+    stopped: Option<String>,
+    labels: Labels,
+    /// Where DUMP carries on from.
+    next_dump: u32,
+    /// The instructions traced last, for HISTORY.
+    history: Option<RingSink>,
+    quit: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger {
+            editor: LineEditor::new(PROMPT),
+            prompting: false,
+            driving: false,
+            breakpoints: vec![],
+            next_number: 1,
+            until: None,
+            resuming: false,
+            stopped: None,
+            labels: Labels::new(),
+            next_dump: 0,
+            history: None,
+            quit: false,
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Names addresses in listings, and lets commands use the names.
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    /// Where HISTORY finds the instructions run last, a ring the CPU's tracer is filling.
+    pub fn set_history(&mut self, ring: RingSink) {
+        self.history = Some(ring);
+    }
+
+    #[inline]
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Whether QUIT has been typed.
+    #[inline]
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Runs one command line, returning what it prints, lines separated by newlines.
+    pub fn command(&mut self, exec: &mut ExecutionContext, line: &str) -> Result<String, DebugError> {
+        let split = split_command(line, COMMANDS).map_err(|w| DebugError::UnknownCommand(w.to_string()))?;
+        let CommandWords { name, qualifiers, args } = match split {
+            Some(words) => words,
+            None => return Ok(String::new()),
+        };
+        let physical = qualifiers.iter().any(|q| q.eq_ignore_ascii_case("/P"));
+        if let Some(q) = qualifiers.iter().find(|q| !q.eq_ignore_ascii_case("/P")) {
+            return Err(DebugError::BadValue(q.to_string()));
+        }
+
+        match name {
+            "BREAK" => {
+                let address = match args.first() {
+                    Some(a) => self.address(exec, a)?,
+                    None if self.breakpoints.is_empty() => return Ok("No breakpoints".to_string()),
+                    None => return Ok(self.breakpoints.iter().map(|b| b.to_string()).collect::<Vec<_>>().join("\n")),
+                };
+                let condition = match args.get(1) {
+                    Some(word) if word.eq_ignore_ascii_case("IF") => Some(Condition::parse(&args[2..])?),
+                    Some(word) => return Err(DebugError::BadCondition(word.to_string())),
+                    None => None,
+                };
+                let breakpoint = Breakpoint { number: self.next_number, address, physical, condition };
+                self.next_number += 1;
+                self.breakpoints.push(breakpoint);
+                Ok(format!("Breakpoint {}", breakpoint))
+            }
+            "DELETE" => {
+                match args.first() {
+                    Some(n) => {
+                        let n = parse_hex(n).ok_or_else(|| DebugError::BadValue(n.to_string()))?;
+                        let i = self.breakpoints.iter().position(|b| b.number == n).ok_or(DebugError::NoBreakpoint(n))?;
+                        self.breakpoints.remove(i);
+                    }
+                    None => self.breakpoints.clear(),
+                }
+                Ok(String::new())
+            }
+            "STEP" => {
+                let count = match args.first() {
+                    Some(n) => parse_hex(n).ok_or_else(|| DebugError::BadValue(n.to_string()))?,
+                    None => 1,
+                };
+                Ok(self.step(exec, count))
+            }
+            "NEXT" => {
+                use InstructionType::*;
+                let pc = exec.pc();
+                match decode(exec, pc) {
+                    Some(d) if matches!(d.instr, CALLS | CALLG | JSB | BSBB | BSBW) => {
+                        self.until = Some(Until::Over { pc: d.end(), sp: exec.sp() });
+                        self.resume(exec);
+                        Ok(String::new())
+                    }
+                    _ => Ok(self.step(exec, 1)),
+                }
+            }
+            "FINISH" => {
+                let fp = exec.reg(RegID::FP);
+                let mut saved = |offset: u32| exec.debug_read(fp.wrapping_add(offset), OperandWidth::Longword);
+                match (fp, saved(12), saved(16)) {
+                    (0, _, _) => Err(DebugError::NoFrame),
+                    (_, Ok(fp), Ok(pc)) => {
+                        self.until = Some(Until::Return { pc: pc as u32, fp: fp as u32 });
+                        self.resume(exec);
+                        Ok(String::new())
+                    }
+                    _ => Err(DebugError::NoFrame),
+                }
+            }
+            "CONTINUE" => {
+                self.resume(exec);
+                Ok(String::new())
+            }
+            "REGISTERS" => Ok(format_registers(exec)),
+            "LIST" => {
+                let address = match args.first() {
+                    Some(a) => Some(self.address(exec, a)?),
+                    None => None,
+                };
+                let count = match args.get(1) {
+                    Some(n) => parse_hex(n).ok_or_else(|| DebugError::BadValue(n.to_string()))?,
+                    None => 8,
+                };
+                Ok(self.list(exec, address, count))
+            }
+            "DUMP" => {
+                let address = match args.first() {
+                    Some(a) => self.address(exec, a)?,
+                    None => self.next_dump,
+                };
+                let len = match args.get(1) {
+                    Some(n) => parse_hex(n).ok_or_else(|| DebugError::BadValue(n.to_string()))?.min(MAX_DUMP),
+                    None => 0x40,
+                };
+                let bytes = read_bytes(exec, address, len, physical)?;
+                if bytes.is_empty() && len > 0 {
+                    return Err(DebugError::NoMemory(address));
+                }
+                self.next_dump = address.wrapping_add(bytes.len() as u32);
+                Ok(format_dump(address, &bytes))
+            }
+            "HISTORY" => {
+                let count = match args.first() {
+                    Some(n) => parse_hex(n).ok_or_else(|| DebugError::BadValue(n.to_string()))? as usize,
+                    None => 0x10,
+                };
+                let records = match &self.history {
+                    Some(ring) => ring.records(),
+                    None => return Ok("No history is being kept".to_string()),
+                };
+                let lines: Vec<String> = records[records.len().saturating_sub(count)..].iter().map(|r| r.to_string()).collect();
+                Ok(lines.join("\n"))
+            }
+            "QUIT" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Ok(HELP.to_string()),
+        }
+    }
+
+    /// An address: a symbol, a register's contents, or a number.
+    fn address(&self, exec: &ExecutionContext, word: &str) -> Result<u32, DebugError> {
+        if let Some((&address, _)) = self.labels.iter().find(|(_, name)| name.eq_ignore_ascii_case(word)) {
+            return Ok(address);
+        }
+        if let Some(r) = RegID::from_name(word) {
+            return Ok(exec.reg(r));
+        }
+        parse_hex(word).ok_or_else(|| DebugError::BadAddress(word.to_string()))
+    }
+
+    /// Lets the CPU go, from a halt or where it's been stopped.
+    fn resume(&mut self, exec: &mut ExecutionContext) {
+        self.resuming = true;
+        self.stopped = None;
+        let pc = exec.pc();
+        exec.start(pc);
+    }
+
+    /// Runs `count` instructions, or up to a breakpoint, and shows where that left PC.
+    fn step(&mut self, exec: &mut ExecutionContext, count: u32) -> String {
+        self.until = None;
+        self.resume(exec);
+        for i in 0..count {
+            if i > 0 {
+                if let Some(why) = self.stop_here(exec) {
+                    exec.halt(HaltReason::External);
+                    return format!("{}\n{}", why, self.current(exec));
+                }
+            }
+            if !exec.execute_step() {
+                let reason = exec.halt_reason().unwrap();
+                return format!("{}\n{}", halt_message(reason).unwrap_or("?Halted"), self.current(exec));
+            }
+        }
+        exec.halt(HaltReason::External);
+        self.current(exec)
+    }
+
+    /// Why the CPU should stop before the instruction at PC, if it should.
+    fn stop_here(&mut self, exec: &mut ExecutionContext) -> Option<String> {
+        let pc = exec.pc();
+        let returned = match self.until {
+            Some(Until::Over { pc: at, sp }) => pc == at && exec.sp() >= sp,
+            Some(Until::Return { pc: at, fp }) => pc == at && exec.reg(RegID::FP) == fp,
+            None => false,
+        };
+        if returned {
+            let why = match self.until.take() {
+                Some(Until::Return { .. }) => format!("Returned, R0 = {:08X}", exec.reg(RegID::new(0))),
+                _ => String::new(),
+            };
+            return Some(why);
+        }
+        let physical = if self.breakpoints.iter().any(|b| b.physical) { exec.physical_address(pc) } else { None };
+        let hit = self.breakpoints.iter().find(|b| {
+            let at = if b.physical { physical == Some(b.address) } else { pc == b.address };
+            at && b.condition.is_none_or(|c| c.holds(exec))
+        })?;
+        self.until = None;
+        Some(format!("Breakpoint {}", hit))
+    }
+
+    /// Runs the CPU until the next event, or for a while checking breakpoints before each
+    /// instruction when there's anything to check.
+    fn run(&mut self, exec: &mut ExecutionContext) {
+        if self.breakpoints.is_empty() && self.until.is_none() {
+            self.resuming = false;
+            exec.run_until_next_event();
+            return;
+        }
+        for _ in 0..SLICE {
+            if !std::mem::take(&mut self.resuming) {
+                if let Some(why) = self.stop_here(exec) {
+                    self.stopped = Some(why);
+                    exec.halt(HaltReason::External);
+                    return;
+                }
+            }
+            if !exec.execute_step() {
+                return;
+            }
+        }
+    }
+
+    /// The instruction at PC, marked as the next to run.
+    fn current(&self, exec: &mut ExecutionContext) -> String {
+        let pc = exec.pc();
+        self.list(exec, Some(pc), 1)
+    }
+
+    /// Disassembles `count` instructions from `address`, or around PC, the few before it found
+    /// by looking for a start that decodes up to PC exactly.
+    fn list(&self, exec: &mut ExecutionContext, address: Option<u32>, count: u32) -> String {
+        let pc = exec.pc();
+        let mut at = address.unwrap_or(pc);
+        if address.is_none() {
+            for back in (1..=24).rev() {
+                let mut from = pc.wrapping_sub(back);
+                let mut starts = vec![];
+                while from < pc {
+                    starts.push(from);
+                    from = match decode(exec, from) {
+                        Some(d) => d.end(),
+                        None => break,
+                    };
+                }
+                if from == pc && !starts.is_empty() {
+                    at = starts[starts.len().saturating_sub(3)];
+                    break;
+                }
+            }
+        }
+
+        let disassembler = Disassembler::new(DisasmOptions { show_address: true, show_bytes: false }).with_labels(&self.labels);
+        let mut lines = vec![];
+        let mut listed = 0;
+        // Around PC, only what's from PC on counts.
+        while listed < count {
+            if let Some(name) = self.labels.get(&at) {
+                lines.push(format!("{}:", name));
+            }
+            let mark = if at == pc { "=> " } else { "   " };
+            let d = match decode(exec, at) {
+                Some(d) => d,
+                None => {
+                    lines.push(format!("{}{:08X}:  ?", mark, at));
+                    break;
+                }
+            };
+            lines.extend(disassembler.format_lines(&d).into_iter().map(|l| format!("{}{}", mark, l)));
+            if address.is_some() || at >= pc {
+                listed += 1;
+            }
+            at = d.end();
+        }
+        lines.join("\n")
+    }
+
+    /// Drives the console terminal: runs the CPU while it's running, and when it's stopped, takes
+    /// commands from the terminal. Returns whether the CPU is running, so the host knows whether
+    /// to wait for input.
+    pub fn service(&mut self, exec: &mut ExecutionContext) -> bool {
+        if !self.driving {
+            exec.console().set_halt_character(Some(HALT_CHARACTER));
+            self.driving = true;
+        }
+        if !exec.is_halted() {
+            self.prompting = false;
+            self.run(exec);
+        }
+        if !exec.is_halted() {
+            return true;
+        }
+
+        if !self.prompting {
+            self.until = None;
+            let why = match self.stopped.take() {
+                Some(why) => why,
+                None => halt_message(exec.halt_reason().unwrap()).unwrap_or("").to_string(),
+            };
+            let mut out = String::from("\r\n");
+            if !why.is_empty() {
+                out += &why;
+                out += "\r\n";
+            }
+            out += &self.current(exec).replace('\n', "\r\n");
+            out += "\r\n";
+            out += PROMPT;
+            exec.console().write_str(&out);
+            self.prompting = true;
+        }
+        while let Some(b) = exec.console().take_input() {
+            let line = match self.editor.key(exec, b) {
+                Some(line) => line,
+                None => continue,
+            };
+            let out = match self.command(exec, &line) {
+                Ok(out) => out,
+                Err(e) => e.to_string(),
+            };
+            for l in out.lines() {
+                exec.console().write_str(l);
+                exec.console().write_str("\r\n");
+            }
+            if self.quit {
+                return false;
+            }
+            if exec.is_halted() {
+                exec.console().write_str(PROMPT);
+            } else {
+                self.prompting = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{execution::{TraceFilter, Tracer}, instrs::assemble, sysclk::TimeMode},
+        devices::console::{ChannelSource, MemorySink},
+        machine::{MachineBuilder, Model},
+    };
+
+    #[test]
+    fn debugger_commands() {
+        let sink = MemorySink::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut exec = MachineBuilder::new(Model::Ka630)
+            .time_mode(TimeMode::Virtual)
+            .console_sink(Box::new(sink.clone()))
+            .console_source(Box::new(ChannelSource::new(rx)))
+            .build()
+            .unwrap();
+        let code = assemble("
+                CLRL R0
+                PUSHL #2
+                CALLS #1, SUB
+                INCL R0
+                HALT
+        SUB:    .WORD 0
+        BODY:   ADDL3 4(AP), #1, R0
+                RET
+        ", 0x1000).unwrap();
+        exec.bus().write_bytes(0x1000, &code.bytes).unwrap();
+        exec.set_sp(0x8000);
+        exec.start(0x1000);
+        exec.halt(HaltReason::External);
+
+        let mut debugger = Debugger::new();
+        debugger.set_labels(code.labels.clone());
+        assert_eq!(debugger.command(&mut exec, "hi").unwrap(), "No history is being kept");
+        let ring = RingSink::new(4);
+        debugger.set_history(ring.clone());
+        exec.set_tracer(Some(Tracer::new(Box::new(ring), TraceFilter::default())));
+        let body = code.symbols["BODY"];
+        assert!(!debugger.service(&mut exec));
+        assert_eq!(sink.take(), b"\r\n?02 EXT HLT\r\n=> 00001000:  CLRL R0\r\nDBG> ");
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            let out = debugger.command(&mut exec, line).unwrap();
+            while debugger.service(&mut exec) {}
+            out + &String::from_utf8(sink.take()).unwrap()
+        };
+
+        assert_eq!(run(&mut debugger, "b body"), format!("Breakpoint 1 at V {:08X}", body));
+        assert_eq!(run(&mut debugger, "b/p 1000 if r0 == 7"), "Breakpoint 2 at P 00001000 if R0 == 7");
+        assert_eq!(run(&mut debugger, "b"), "1 at V 0000100D\n2 at P 00001000 if R0 == 7");
+        assert_eq!(run(&mut debugger, "s"), "=> 00001002:  PUSHL S^#2");
+        assert_eq!(run(&mut debugger, "n"), "=> 00001004:  CALLS S^#1, B^SUB");
+        // Over the call, but it stops at the breakpoint in it.
+        assert_eq!(run(&mut debugger, "n"), "\r\nBreakpoint 1 at V 0000100D\r\nBODY:\r\n=> 0000100D:  ADDL3 B^4(AP), S^#1, R0\r\nDBG> ");
+        let registers = run(&mut debugger, "reg");
+        assert!(registers.starts_with("R0  00000000   R1  00000000"));
+        assert!(registers.contains("\nAP  00007FF8   FP  00007FE4   SP  00007FE4   PC  0000100D\nPSL "));
+        assert_eq!(run(&mut debugger, "f"), "\r\nReturned, R0 = 00000003\r\n=> 00001008:  INCL R0\r\nDBG> ");
+        assert!(run(&mut debugger, "l").starts_with("   00001000:  CLRL R0\n   00001002:  PUSHL S^#2\n   00001004:  CALLS S^#1, B^SUB\n=> 00001008:  INCL R0\n"));
+        assert_eq!(run(&mut debugger, "l body 2"), "BODY:\n   0000100D:  ADDL3 B^4(AP), S^#1, R0\n   00001012:  RET");
+        assert_eq!(run(&mut debugger, "du 1000 6"), format_dump(0x1000, &code.bytes[..6]));
+        assert_eq!(run(&mut debugger, "du/p fp 4"), format_dump(0, &[0; 4]));
+        assert_eq!(run(&mut debugger, "du/p 0 ffffffff"), format_dump(0, &[0; MAX_DUMP as usize]));
+        assert_eq!(run(&mut debugger, "hi 2"), "\
+0000100D  ADDL3 (00007FFC)=00000002, #00000001, R0=00000000  PSL 00000000
+00001012  RET  PSL 00000000");
+        assert_eq!(run(&mut debugger, "de 1"), "");
+        assert_eq!(run(&mut debugger, "c"), "\r\n?06 HLT INST\r\nSUB:\r\n=> 0000100B:  HALT\r\nDBG> ");
+
+        // Typed at the prompt.
+        for b in b"b\r" {
+            tx.send(*b).unwrap();
+        }
+        assert!(!debugger.service(&mut exec));
+        assert_eq!(sink.take(), b"b\r\n2 at P 00001000 if R0 == 7\r\nDBG> ");
+
+        assert!(matches!(debugger.command(&mut exec, "f"), Err(DebugError::NoFrame)));
+        assert!(matches!(debugger.command(&mut exec, "de 1"), Err(DebugError::NoBreakpoint(1))));
+        assert!(matches!(debugger.command(&mut exec, "d"), Err(DebugError::UnknownCommand(_))));
+        assert!(matches!(debugger.command(&mut exec, "b 1000 if r0 ~ 1"), Err(DebugError::BadCondition(_))));
+        assert!(debugger.command(&mut exec, "h").unwrap().starts_with("BREAK"));
+        let condition = debugger.breakpoints()[0].condition.unwrap();
+        exec.set_reg(RegID::new(0), 7);
+        assert!(condition.holds(&exec));
+        exec.set_reg(RegID::new(0), 6);
+        assert!(!condition.holds(&exec));
+        debugger.command(&mut exec, "quit").unwrap();
+        assert!(debugger.quit_requested());
+    }
+}
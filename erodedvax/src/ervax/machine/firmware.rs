@@ -0,0 +1,187 @@
+//! The built-in console, which stands in for the console ROM when there's no image of one. All it
+//! does is what the ROM does at the end of a boot: reads the boot block off a disk or tape into
+//! memory and starts it, in the power-up state.
+//!
+//! Disks and tapes are MSCP and TMSCP units on the Q-bus, or SCSI disks on a board's own
+//! controller. The boot block is logical block 0 of a disk, or the first record on a tape. It's loaded at
+//! BOOT_BASE and started at its first byte, on a stack just below it, with the unit number in
+//! R3 as VMB leaves it.
+
+use std::fmt;
+
+use crate::ervax::{
+    cpu::{execution::ExecutionContext, RegID},
+    devices::{
+        disk::{Disk, BLOCK_SIZE},
+        mscp::Mscp,
+        ncr5380::HOST_ID,
+        qbus::Qbus,
+        tape::Object,
+        tmscp::Tmscp,
+    },
+    machine::{ka41::Ka41Io, MachineError},
+};
+
+/// Where the boot block goes, the second page of memory.
+pub const BOOT_BASE: u32 = 0x200;
+/// The longest tape record taken as a boot block.
+pub const MAX_BOOT_RECORD: usize = 64 << 10;
+
+/// Something to boot from, named as the console names it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootDevice {
+    /// An MSCP disk unit, DUAn.
+    Disk(usize),
+    /// A TMSCP tape unit, MUAn.
+    Tape(usize),
+    /// The SCSI disk with this ID, DKAn00.
+    ScsiDisk(usize),
+}
+
+impl BootDevice {
+    /// Looks up a device name like DUA0, ignoring case. A missing unit number means unit 0.
+    pub fn from_name(name: &str) -> Option<BootDevice> {
+        let name = name.to_ascii_lowercase();
+        let (kind, unit): (fn(usize) -> Option<BootDevice>, &str) = if let Some(unit) = name.strip_prefix("dua") {
+            (|n| Some(BootDevice::Disk(n)), unit)
+        } else if let Some(unit) = name.strip_prefix("mua") {
+            (|n| Some(BootDevice::Tape(n)), unit)
+        } else if let Some(unit) = name.strip_prefix("dka") {
+            (|n| Some(BootDevice::ScsiDisk(n / 100)).filter(|_| n % 100 == 0 && n / 100 < HOST_ID), unit)
+        } else {
+            return None;
+        };
+        let unit = unit.trim_end_matches(':');
+        if unit.is_empty() {
+            return kind(0);
+        }
+        unit.parse().ok().and_then(kind)
+    }
+
+    /// The unit number in the device's name.
+    pub fn unit(self) -> usize {
+        match self {
+            BootDevice::Disk(unit) | BootDevice::Tape(unit) => unit,
+            BootDevice::ScsiDisk(id) => id * 100,
+        }
+    }
+}
+
+impl fmt::Display for BootDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootDevice::Disk(unit) => write!(f, "DUA{}", unit),
+            BootDevice::Tape(unit) => write!(f, "MUA{}", unit),
+            BootDevice::ScsiDisk(_) => write!(f, "DKA{}", self.unit()),
+        }
+    }
+}
+
+/// Reads the boot block off `device`.
+fn read_boot_block(exec: &mut ExecutionContext, device: BootDevice) -> Result<Vec<u8>, MachineError> {
+    let missing = || MachineError::NoBootDevice(device);
+    let io = |e| MachineError::Io(device.to_string(), e);
+    let first_block = |disk: &mut Disk| {
+        let mut block = vec![0; BLOCK_SIZE];
+        disk.read_blocks(0, &mut block).map_err(io)?;
+        Ok(block)
+    };
+    match device {
+        BootDevice::Disk(unit) => {
+            let qbus = exec.bus().find_device::<Qbus>().ok_or_else(missing)?;
+            first_block(qbus.find_device::<Mscp>().and_then(|c| c.disk(unit)).ok_or_else(missing)?)
+        }
+        BootDevice::ScsiDisk(id) => {
+            let board = exec.bus().find_device::<Ka41Io>().ok_or_else(missing)?;
+            first_block(board.scsi().disk(id).ok_or_else(missing)?)
+        }
+        BootDevice::Tape(unit) => {
+            let qbus = exec.bus().find_device::<Qbus>().ok_or_else(missing)?;
+            let tape = qbus.find_device::<Tmscp>().and_then(|c| c.tape(unit)).ok_or_else(missing)?;
+            tape.rewind();
+            let mut record = vec![];
+            match tape.read(&mut record).map_err(io)? {
+                Object::Record(n) if n as usize <= MAX_BOOT_RECORD => Ok(record),
+                _ => Err(MachineError::NoBootBlock(device)),
+            }
+        }
+    }
+}
+
+/// Loads the boot block from `device` and starts it.
+pub fn boot(exec: &mut ExecutionContext, device: BootDevice) -> Result<(), MachineError> {
+    let block = read_boot_block(exec, device)?;
+    if exec.bus().write_bytes(BOOT_BASE, &block).is_err() {
+        return Err(MachineError::RamSize(exec.bus().ram_size()));
+    }
+    exec.power_up(BOOT_BASE);
+    exec.set_sp(BOOT_BASE);
+    exec.set_reg(RegID::new(3), device.unit() as u32);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::execution::HaltReason,
+        devices::{
+            disk::{Disk, MemoryStore, RD54},
+            tape::Tape,
+        },
+        machine::{MachineBuilder, Model},
+    };
+
+    #[test]
+    fn boot_blocks() {
+        assert_eq!(BootDevice::from_name("DUA1"), Some(BootDevice::Disk(1)));
+        assert_eq!(BootDevice::from_name("mua:"), Some(BootDevice::Tape(0)));
+        assert_eq!(BootDevice::from_name("xqa0"), None);
+        assert_eq!(BootDevice::from_name("dka300"), Some(BootDevice::ScsiDisk(3)));
+        assert_eq!(BootDevice::from_name("dka150"), None);
+        assert_eq!(BootDevice::ScsiDisk(2).to_string(), "DKA200");
+
+        // A boot block that puts its unit number in R5 and halts.
+        let code = [0xD0, 0x53, 0x55, 0x00];
+        let mut disk = Disk::new(RD54, Box::new(MemoryStore::new()), false);
+        disk.write_blocks(0, &[&code[..], &[0; BLOCK_SIZE - 4]].concat()).unwrap();
+        let mut exec = MachineBuilder::new(Model::Ka630)
+            .disk(Disk::new(RD54, Box::new(MemoryStore::new()), false))
+            .disk(disk)
+            .boot(BootDevice::Disk(1))
+            .build()
+            .unwrap();
+        assert_eq!((exec.pc(), exec.sp(), exec.get_ipl()), (BOOT_BASE, BOOT_BASE, 0x1F));
+        assert_eq!(exec.run(), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(5)), 1);
+
+        // A KA41's disks are on its SCSI bus.
+        let mut disk = Disk::new(RD54, Box::new(MemoryStore::new()), false);
+        disk.write_blocks(0, &[&code[..], &[0; BLOCK_SIZE - 4]].concat()).unwrap();
+        let mut exec = MachineBuilder::new(Model::Ka41)
+            .disk(Disk::new(RD54, Box::new(MemoryStore::new()), false))
+            .disk(disk)
+            .boot(BootDevice::ScsiDisk(1))
+            .build()
+            .unwrap();
+        assert_eq!(exec.run(), HaltReason::HaltInstruction);
+        assert_eq!(exec.reg(RegID::new(5)), 100);
+        let built = MachineBuilder::new(Model::Ka41).boot(BootDevice::Disk(0)).build();
+        assert!(matches!(built, Err(MachineError::NoBootDevice(BootDevice::Disk(0)))));
+
+        let mut tape = Tape::new(Box::new(std::io::Cursor::new(vec![])), false);
+        tape.write(&code).unwrap();
+        let mut exec = MachineBuilder::new(Model::Ka630).tape(tape).boot(BootDevice::Tape(0)).build().unwrap();
+        assert_eq!(exec.bus().read_u32(BOOT_BASE), Ok(0x0055_53D0));
+
+        // Without a tape, or an empty one.
+        assert!(matches!(MachineBuilder::new(Model::Ka630).boot(BootDevice::Tape(0)).build(), Err(MachineError::NoBootDevice(_))));
+        let empty = Tape::new(Box::new(std::io::Cursor::new(vec![])), false);
+        let built = MachineBuilder::new(Model::Ka630).tape(empty).boot(BootDevice::Tape(0)).build();
+        assert!(matches!(built, Err(MachineError::NoBootBlock(BootDevice::Tape(0)))));
+
+        // A ROM takes precedence, and is where the CPU starts.
+        let exec = MachineBuilder::new(Model::Ka630).rom(vec![0; 4]).boot(BootDevice::Disk(0)).build().unwrap();
+        assert_eq!((exec.pc(), exec.psl()), (crate::ervax::machine::ka630::ROM_BASE, 0x041F_0000));
+    }
+}
@@ -0,0 +1,352 @@
+//! The KA41 CPU board, as in the VAXstation 3100. A CVAX with no Q-bus: the serial lines, SCSI
+//! controller and watch chip all sit at fixed addresses in I/O space, and interrupt through the
+//! board's own interrupt controller. The console terminal is serial line 3, and disks are SCSI
+//! targets from ID 0 up.
+//!
+//! Which INTREQ bit and vector each device gets is this model's own assignment, the devices
+//! modelled so far don't cover everything the real board has.
+
+use std::any::Any;
+
+use crate::ervax::{
+    cpu::{
+        bus::{BusError, VAXBus, VAXBusDevice},
+        execution::ExecutionContext,
+        interrupts::Interrupt,
+        sysclk::TimeMode,
+    },
+    devices::{
+        dz::{self, Dz},
+        ncr5380::{self, Ncr5380},
+        rom::Rom,
+        watch::{WatchChip, VIRTUAL_EPOCH},
+    },
+    machine::{BoardConfig, MachineError},
+};
+
+/// System type 10, CVAX.
+pub const SID: u32 = 0x0A00_0000;
+
+pub const MAX_RAM: usize = 32 << 20;
+pub const DEFAULT_RAM: usize = 16 << 20;
+/// Memory comes in whole megabytes.
+pub const RAM_GRANULE: usize = 1 << 20;
+
+pub const ROM_BASE: u32 = 0x2004_0000;
+pub const ROM_SIZE: usize = 256 << 10;
+
+/// Configuration and test register, which options are fitted. Reads as none.
+pub const CFGTST: u32 = 0x2002_0000;
+/// Why the processor last halted.
+pub const HLTCOD: u32 = 0x2008_0000;
+/// Interrupt mask, a byte.
+pub const INTMSK: u32 = 0x2008_000C;
+/// Interrupt requests when read, a byte.
+pub const INTREQ: u32 = 0x2008_000F;
+/// Interrupt clear when written, the same byte as INTREQ.
+pub const INTCLR: u32 = 0x2008_000F;
+
+/// The DZ compatible serial controller, its registers a longword apart.
+pub const SERIAL_BASE: u32 = 0x200A_0000;
+pub const SERIAL_LINES: usize = 4;
+pub const CONSOLE_LINE: usize = 3;
+/// The NCR 5380, its registers a longword apart.
+pub const SCSI_BASE: u32 = 0x200C_0080;
+pub const WATCH_BASE: u32 = 0x200B_0000;
+/// Bytes of address space per register for the serial controller, SCSI controller and watch chip.
+pub const REG_STRIDE: u32 = 4;
+
+/// Every device on the board interrupts at this IPL.
+pub const IPL_DEVICES: u8 = 0x14;
+
+/// INTREQ and INTMSK bits, the highest set bit being served first.
+pub const INT_SERIAL_RX: u8 = 0x80;
+pub const INT_SERIAL_TX: u8 = 0x40;
+pub const INT_SCSI: u8 = 0x01;
+
+/// SCB offsets of the devices' vectors.
+pub mod vectors {
+    pub const SERIAL_RX: u16 = 0x2C0;
+    pub const SERIAL_TX: u16 = 0x2C4;
+    pub const SCSI: u16 = 0x3F8;
+}
+
+const INTERRUPTS: [(u8, u16); 3] = [
+    (INT_SERIAL_RX, vectors::SERIAL_RX),
+    (INT_SERIAL_TX, vectors::SERIAL_TX),
+    (INT_SCSI, vectors::SCSI),
+];
+
+/// Everything in the board's I/O space apart from the ROM and watch chip: the board registers
+/// and interrupt controller, and the serial and SCSI controllers behind it.
+///
+/// Requests are level triggered. A bit is set in INTREQ for as long as its device wants
+/// attention, so there's never anything for INTCLR or taking the interrupt to clear.
+pub struct Ka41Io {
+    intmsk: u8,
+    serial: Dz,
+    scsi: Ncr5380,
+}
+
+impl Ka41Io {
+    pub fn new() -> Ka41Io {
+        Ka41Io { intmsk: 0, serial: Dz::new(SERIAL_LINES), scsi: Ncr5380::new() }
+    }
+
+    pub fn serial(&mut self) -> &mut Dz {
+        &mut self.serial
+    }
+
+    pub fn scsi(&mut self) -> &mut Ncr5380 {
+        &mut self.scsi
+    }
+
+    pub fn intreq(&self) -> u8 {
+        let mut req = 0;
+        if self.serial.rx_interrupt() {
+            req |= INT_SERIAL_RX;
+        }
+        if self.serial.tx_interrupt() {
+            req |= INT_SERIAL_TX;
+        }
+        if self.scsi.interrupt() {
+            req |= INT_SCSI;
+        }
+        req
+    }
+
+    /// The board register longword at `addr`.
+    fn board_register(&self, addr: u32) -> u32 {
+        match addr {
+            HLTCOD => 0,
+            INTMSK => self.intmsk as u32 | (self.intreq() as u32) << 24,
+            _ => 0,
+        }
+    }
+}
+
+impl Default for Ka41Io {
+    fn default() -> Self {
+        Ka41Io::new()
+    }
+}
+
+fn size_mask(size: u32) -> u32 {
+    u32::MAX >> (32 - size * 8)
+}
+
+impl VAXBusDevice for Ka41Io {
+    fn name(&self) -> &str {
+        "KA41 I/O"
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        let shift = (addr & 3) * 8;
+        let lw = match addr & !3 {
+            CFGTST => 0,
+            a if (HLTCOD..INTMSK + 4).contains(&a) => self.board_register(a),
+            a if (SERIAL_BASE..SERIAL_BASE + dz::REGISTERS as u32 * REG_STRIDE).contains(&a) => {
+                // The registers are words, in the low half of each longword.
+                if shift >= 16 {
+                    0
+                } else {
+                    self.serial.read(((a - SERIAL_BASE) / REG_STRIDE) as usize) as u32
+                }
+            }
+            a if (SCSI_BASE..SCSI_BASE + ncr5380::REGISTERS as u32 * REG_STRIDE).contains(&a) => {
+                if shift != 0 {
+                    0
+                } else {
+                    self.scsi.read(((a - SCSI_BASE) / REG_STRIDE) as usize) as u32
+                }
+            }
+            _ => return Err(BusError::NonExistent(addr)),
+        };
+        Ok(lw >> shift & size_mask(size))
+    }
+
+    fn write(&mut self, addr: u32, size: u32, v: u32) -> Result<(), BusError> {
+        let shift = (addr & 3) * 8;
+        let (v, mask) = (v << shift, size_mask(size) << shift);
+        match addr & !3 {
+            CFGTST => {}
+            INTMSK => {
+                if mask & 0xFF != 0 {
+                    self.intmsk = v as u8;
+                }
+            }
+            a if (HLTCOD..INTMSK).contains(&a) => {}
+            a if (SERIAL_BASE..SERIAL_BASE + dz::REGISTERS as u32 * REG_STRIDE).contains(&a) => {
+                if mask & 0xFFFF != 0 {
+                    self.serial.write(((a - SERIAL_BASE) / REG_STRIDE) as usize, v as u16, mask as u16);
+                }
+            }
+            a if (SCSI_BASE..SCSI_BASE + ncr5380::REGISTERS as u32 * REG_STRIDE).contains(&a) => {
+                if mask & 0xFF != 0 {
+                    self.scsi.write(((a - SCSI_BASE) / REG_STRIDE) as usize, v as u8);
+                }
+            }
+            _ => return Err(BusError::NonExistent(addr)),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.intmsk = 0;
+        self.serial.reset();
+        self.scsi.reset();
+    }
+
+    fn interrupt_request(&self) -> Option<Interrupt> {
+        let pending = self.intreq() & self.intmsk;
+        INTERRUPTS.iter()
+            .find(|(bit, _)| pending & bit != 0)
+            .map(|&(_, vector)| Interrupt::new(IPL_DEVICES, vector))
+    }
+
+    fn set_time(&mut self, _now_us: u64) {
+        self.serial.poll_input();
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Builds a KA41 with the console terminal on serial line 3.
+pub fn build(config: BoardConfig) -> Result<ExecutionContext, MachineError> {
+    let BoardConfig { ram_size, rom, nvram, time_mode: mode, console_sink, console_source, disks, tapes, serial_lines, ethernet } = config;
+    if disks.len() > ncr5380::HOST_ID {
+        return Err(MachineError::TooManyDisks(ncr5380::HOST_ID));
+    }
+    if !tapes.is_empty() {
+        return Err(MachineError::Unsupported("TMSCP controller"));
+    }
+    if ethernet.is_some() {
+        return Err(MachineError::Unsupported("Ethernet controller"));
+    }
+    if ram_size == 0 || ram_size > MAX_RAM || !ram_size.is_multiple_of(RAM_GRANULE) {
+        return Err(MachineError::RamSize(ram_size));
+    }
+    let rom = rom.unwrap_or_default();
+    if rom.len() > ROM_SIZE {
+        return Err(MachineError::RomSize(rom.len()));
+    }
+
+    let mut bus = VAXBus::new(ram_size);
+
+    bus.attach(Box::new(Rom::new(&rom, ROM_SIZE)), ROM_BASE, ROM_SIZE as u32);
+
+    let mut io = Ka41Io::new();
+    io.serial().connect(CONSOLE_LINE, Some(console_sink), console_source);
+    for (n, endpoint) in serial_lines {
+        if n >= SERIAL_LINES || n == CONSOLE_LINE {
+            return Err(MachineError::SerialLine(n));
+        }
+        io.serial().attach(n, endpoint);
+    }
+    for (id, disk) in disks.into_iter().enumerate() {
+        io.scsi().attach(id, disk);
+    }
+    let io = bus.add_device(Box::new(io));
+    bus.map(io, CFGTST, 4);
+    bus.map(io, HLTCOD, 16);
+    bus.map(io, SERIAL_BASE, dz::REGISTERS as u32 * REG_STRIDE);
+    bus.map(io, SCSI_BASE, ncr5380::REGISTERS as u32 * REG_STRIDE);
+
+    let mut watch = match mode {
+        TimeMode::RealTime => WatchChip::from_host(WATCH_BASE, REG_STRIDE),
+        TimeMode::Virtual => WatchChip::new(WATCH_BASE, REG_STRIDE, VIRTUAL_EPOCH),
+    };
+    if let Some(nvram) = nvram {
+        watch.load_nvram(&nvram);
+    }
+    let size = watch.size();
+    bus.attach(Box::new(watch), WATCH_BASE, size);
+
+    let mut exec = ExecutionContext::with_bus(bus, mode);
+    exec.set_sid(SID);
+    Ok(exec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{interrupts::InterruptSource, registers::PrivRegisters},
+        devices::{
+            console::{ChannelSource, MemorySink},
+            disk::{Disk, MemoryStore, RD54},
+            dz::{CSR_MSE, CSR_RIE, CSR_TIE, LPR_RXON, RBUF_DVAL},
+            ncr5380::{BSR_INT, ICR_RST},
+            serial::ChannelLine,
+            watch::REG_YEAR,
+        },
+        machine::{MachineBuilder, Model},
+    };
+    use std::sync::mpsc;
+
+    fn serial(reg: usize) -> u32 {
+        SERIAL_BASE + reg as u32 * REG_STRIDE
+    }
+
+    #[test]
+    fn ka41_board() {
+        assert_eq!(Model::from_name("VS3100"), Some(Model::Ka41));
+        let out = MemorySink::new();
+        let (tx, rx) = mpsc::channel();
+        let mut exec = MachineBuilder::new(Model::Ka41)
+            .ram_mib(8)
+            .unwrap()
+            .rom(vec![0x01, 0x00])
+            .time_mode(TimeMode::Virtual)
+            .console_sink(Box::new(out.clone()))
+            .console_source(Box::new(ChannelSource::new(rx)))
+            .build()
+            .unwrap();
+
+        assert_eq!(exec.read_ipr(PrivRegisters::SID), SID);
+        assert_eq!(exec.bus().ram_size(), 8 << 20);
+        assert_eq!(exec.bus().read_u32(ROM_BASE + ROM_SIZE as u32 - 4), Ok(0xFFFF_FFFF));
+        assert_eq!(exec.bus().read_u16(ROM_BASE), Ok(0x0001));
+        assert_eq!(exec.bus().read_u32(CFGTST), Ok(0));
+        assert_eq!(exec.bus().read_u8(WATCH_BASE + REG_STRIDE * REG_YEAR as u32), Ok(0x00));
+
+        // The console is serial line 3, which interrupts through INTREQ once unmasked.
+        exec.bus().write_u16(serial(dz::REG_CSR), CSR_MSE | CSR_TIE | CSR_RIE).unwrap();
+        exec.bus().write_u16(serial(dz::REG_TCR), 1 << CONSOLE_LINE).unwrap();
+        assert_eq!(exec.bus().read_u8(INTREQ), Ok(INT_SERIAL_TX));
+        assert_eq!(exec.bus().pending_interrupt(), None);
+        exec.bus().write_u8(INTMSK, INT_SERIAL_TX | INT_SERIAL_RX).unwrap();
+        assert_eq!(exec.bus().pending_interrupt(), Some(Interrupt::new(IPL_DEVICES, vectors::SERIAL_TX)));
+        exec.bus().write_u8(serial(dz::REG_TDR), b'>').unwrap();
+        exec.bus().write_u16(serial(dz::REG_TCR), 0).unwrap();
+        assert_eq!(out.take(), b">");
+        assert_eq!(exec.bus().pending_interrupt(), None);
+
+        // Input turns up when the bus is told the time.
+        exec.bus().write_u16(serial(dz::REG_LPR), LPR_RXON | CONSOLE_LINE as u16).unwrap();
+        tx.send(b'b').unwrap();
+        exec.bus().set_time(10_000);
+        assert_eq!(exec.bus().pending_interrupt(), Some(Interrupt::new(IPL_DEVICES, vectors::SERIAL_RX)));
+        assert_eq!(exec.bus().read_u16(serial(dz::REG_RBUF)), Ok(RBUF_DVAL | 0x300 | b'b' as u16));
+        assert_eq!(exec.bus().pending_interrupt(), None);
+
+        // Resetting the SCSI bus interrupts, until IORESET clears everything.
+        exec.bus().write_u8(INTMSK, INT_SCSI).unwrap();
+        exec.bus().write_u8(SCSI_BASE + REG_STRIDE * ncr5380::REG_ICR as u32, ICR_RST).unwrap();
+        assert_eq!(exec.bus().read_u8(SCSI_BASE + REG_STRIDE * ncr5380::REG_BSR as u32).map(|b| b & BSR_INT), Ok(BSR_INT));
+        assert_eq!(exec.bus().pending_interrupt(), Some(Interrupt::new(IPL_DEVICES, vectors::SCSI)));
+        exec.write_ipr(PrivRegisters::IORESET, 0);
+        assert_eq!(exec.bus().pending_interrupt(), None);
+        assert_eq!(exec.bus().read_u8(INTMSK), Ok(0));
+
+        let (line, _far) = ChannelLine::pair();
+        let console = MachineBuilder::new(Model::Ka41).serial_line(CONSOLE_LINE, Box::new(line)).build();
+        assert!(matches!(console, Err(MachineError::SerialLine(CONSOLE_LINE))));
+        assert!(MachineBuilder::new(Model::Ka41).ram_mib(64).unwrap().build().is_err());
+        let disks = (0..8).fold(MachineBuilder::new(Model::Ka41), |b, _| b.disk(Disk::new(RD54, Box::new(MemoryStore::new()), false)));
+        assert!(matches!(disks.build(), Err(MachineError::TooManyDisks(7))));
+        assert!(MachineBuilder::new(Model::Ka41).rom(vec![0; ROM_SIZE + 1]).build().is_err());
+    }
+}
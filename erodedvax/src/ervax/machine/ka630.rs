@@ -0,0 +1,272 @@
+//! The KA630 CPU board, as in the MicroVAX II. A Q22-bus machine with up to 16 MiB of memory.
+//! The console terminal is on the standard console processor registers, other terminals on a
+//! DZQ11 on the Q-bus, disks on an RQDX3, tapes on a TQK50, and the Ethernet on a DELQA.
+
+use std::any::Any;
+
+use crate::ervax::{
+    cpu::{
+        bus::{BusError, VAXBus, VAXBusDevice},
+        execution::ExecutionContext,
+        sysclk::TimeMode,
+    },
+    devices::{
+        delqa::{self, Delqa},
+        dz11::{self, Dz11, Variant},
+        iopage::IO_PAGE_SIZE,
+        mscp::{self, Mscp},
+        qbus::{Qbus, MAP_BYTES},
+        rom::Rom,
+        tmscp::{self, Tmscp},
+        uqssp::Controller,
+        watch::{WatchChip, VIRTUAL_EPOCH},
+    },
+    machine::{BoardConfig, MachineError},
+};
+
+/// System type 8, MicroVAX II.
+pub const SID: u32 = 0x0800_0000;
+
+pub const MAX_RAM: usize = 16 << 20;
+/// Memory comes in whole megabytes.
+pub const RAM_GRANULE: usize = 1 << 20;
+
+pub const ROM_BASE: u32 = 0x2004_0000;
+pub const ROM_SIZE: usize = 64 << 10;
+/// The ROM repeats through this much address space.
+pub const ROM_WINDOW: u32 = 128 << 10;
+
+/// Memory system error register.
+pub const MSER: u32 = 0x2008_0004;
+/// CPU error address register.
+pub const CEAR: u32 = 0x2008_0008;
+/// DMA error address register.
+pub const DEAR: u32 = 0x2008_000C;
+/// Boot and diagnostic register.
+pub const BDR: u32 = 0x2008_4000;
+
+/// The Q-bus I/O page.
+pub const IO_PAGE_BASE: u32 = 0x2000_0000;
+pub const QBUS_MAP_BASE: u32 = 0x2008_8000;
+/// Q-bus device vectors are in the second page of the SCB.
+pub const QBUS_VECTOR_BASE: u16 = 0x200;
+pub const WATCH_BASE: u32 = 0x200B_8000;
+
+/// BDR, the front panel halt switch allows halting.
+pub const BDR_HALT_ENABLE: u32 = 0x80;
+/// MSER, parity checking enabled. Memory never has parity errors here, so the error bits stay clear.
+pub const MSER_PARITY_ENABLE: u32 = 0x01;
+
+/// The board's own registers: memory error reporting, and the boot and diagnostic register.
+pub struct Ka630Registers {
+    mser: u32,
+    bdr: u32,
+    /// Diagnostic LEDs, the low four bits written to the BDR.
+    leds: u8,
+}
+
+impl Ka630Registers {
+    pub fn new() -> Ka630Registers {
+        Ka630Registers { mser: 0, bdr: BDR_HALT_ENABLE, leds: 0 }
+    }
+
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+}
+
+impl Default for Ka630Registers {
+    fn default() -> Self {
+        Ka630Registers::new()
+    }
+}
+
+impl VAXBusDevice for Ka630Registers {
+    fn name(&self) -> &str {
+        "KA630 registers"
+    }
+
+    fn read(&mut self, addr: u32, _size: u32) -> Result<u32, BusError> {
+        match addr & !3 {
+            MSER => Ok(self.mser),
+            CEAR | DEAR => Ok(0),
+            BDR => Ok(self.bdr),
+            _ => Err(BusError::NonExistent(addr)),
+        }
+    }
+
+    fn write(&mut self, addr: u32, _size: u32, v: u32) -> Result<(), BusError> {
+        match addr & !3 {
+            MSER => self.mser = v & MSER_PARITY_ENABLE,
+            CEAR | DEAR => {}
+            BDR => self.leds = v as u8 & 0xF,
+            _ => return Err(BusError::NonExistent(addr)),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.mser = 0;
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Builds a KA630 with the console terminal on the console registers.
+pub fn build(config: BoardConfig) -> Result<ExecutionContext, MachineError> {
+    let BoardConfig { ram_size, rom, nvram, time_mode: mode, console_sink, console_source, disks, tapes, serial_lines, ethernet } = config;
+    if ram_size == 0 || ram_size > MAX_RAM || !ram_size.is_multiple_of(RAM_GRANULE) {
+        return Err(MachineError::RamSize(ram_size));
+    }
+    let rom = rom.unwrap_or_default();
+    if rom.len() > ROM_SIZE {
+        return Err(MachineError::RomSize(rom.len()));
+    }
+
+    let mut bus = VAXBus::new(ram_size);
+
+    let rom = bus.add_device(Box::new(Rom::new(&rom, ROM_SIZE)));
+    bus.map(rom, ROM_BASE, ROM_WINDOW);
+
+    let regs = bus.add_device(Box::new(Ka630Registers::new()));
+    bus.map(regs, MSER & !0xF, 16);
+    bus.map(regs, BDR, 4);
+
+    let mut qbus = Qbus::new(IO_PAGE_BASE, QBUS_MAP_BASE, QBUS_VECTOR_BASE);
+    if !serial_lines.is_empty() {
+        let mut dzq11 = Dz11::new(Variant::Dzq11, dz11::DEFAULT_BASE, dz11::DEFAULT_VECTOR);
+        for (n, endpoint) in serial_lines {
+            if n >= Variant::Dzq11.lines() {
+                return Err(MachineError::SerialLine(n));
+            }
+            dzq11.dz().attach(n, endpoint);
+        }
+        qbus.add_device(Box::new(dzq11));
+    }
+    if !disks.is_empty() {
+        let mut rqdx3 = Mscp::new(Controller::Rqdx3, mscp::DEFAULT_BASE);
+        for (n, disk) in disks.into_iter().enumerate() {
+            rqdx3.attach(n, disk);
+        }
+        qbus.add_device(Box::new(rqdx3));
+    }
+    if !tapes.is_empty() {
+        let mut tqk50 = Tmscp::new(Controller::Tqk50, tmscp::DEFAULT_BASE);
+        for (n, tape) in tapes.into_iter().enumerate() {
+            tqk50.attach(n, tape);
+        }
+        qbus.add_device(Box::new(tqk50));
+    }
+    if let Some((mac, backend)) = ethernet {
+        qbus.add_device(Box::new(Delqa::new(delqa::DEFAULT_BASE, mac, backend)));
+    }
+    let qbus = bus.add_device(Box::new(qbus));
+    bus.map(qbus, IO_PAGE_BASE, IO_PAGE_SIZE);
+    bus.map(qbus, QBUS_MAP_BASE, MAP_BYTES);
+
+    let mut watch = match mode {
+        TimeMode::RealTime => WatchChip::from_host(WATCH_BASE, 2),
+        TimeMode::Virtual => WatchChip::new(WATCH_BASE, 2, VIRTUAL_EPOCH),
+    };
+    if let Some(nvram) = nvram {
+        watch.load_nvram(&nvram);
+    }
+    let size = watch.size();
+    bus.attach(Box::new(watch), WATCH_BASE, size);
+
+    let mut exec = ExecutionContext::with_bus(bus, mode);
+    exec.set_sid(SID);
+    exec.console().set_sink(console_sink);
+    exec.console().set_source(console_source);
+    Ok(exec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::BusError, registers::PrivRegisters},
+        devices::{
+            disk::{Disk, MemoryStore, RD54},
+            dz::{CSR_MSE, REG_MSR, REG_TCR, REG_TDR},
+            ethernet::Loopback,
+            qbus::MAP_VALID,
+            serial::ChannelLine,
+            tape::Tape,
+            watch::REG_YEAR,
+        },
+        machine::{MachineBuilder, Model},
+    };
+
+    #[test]
+    fn ka630_board() {
+        assert_eq!(Model::from_name("MicroVAX2"), Some(Model::Ka630));
+        let mut exec = MachineBuilder::by_name("ka630").unwrap()
+            .ram_mib(4)
+            .unwrap()
+            .rom(vec![0x01, 0x00])
+            .time_mode(TimeMode::Virtual)
+            .build()
+            .unwrap();
+
+        assert_eq!(exec.read_ipr(PrivRegisters::SID), SID);
+        assert_eq!(exec.bus().ram_size(), 4 << 20);
+        assert_eq!(exec.bus().read_u32(4 << 20), Err(BusError::NonExistent(4 << 20)));
+
+        // The ROM repeats, and can't be written.
+        assert_eq!(exec.bus().read_u32(ROM_BASE), Ok(0xFFFF_0001));
+        assert_eq!(exec.bus().read_u16(ROM_BASE + ROM_SIZE as u32), Ok(0x0001));
+        assert!(exec.bus().write_u8(ROM_BASE, 0).is_err());
+
+        exec.bus().write_u32(QBUS_MAP_BASE + 4, MAP_VALID | 0x123).unwrap();
+        assert_eq!(exec.bus().read_u32(QBUS_MAP_BASE + 4), Ok(MAP_VALID | 0x123));
+        let map = exec.bus().find_device::<Qbus>().unwrap().map();
+        assert_eq!(map.translate(0x200 + 0x10), Some(0x123 << 9 | 0x10));
+        assert_eq!(map.translate(0), None);
+
+        assert_eq!(exec.bus().read_u32(BDR), Ok(BDR_HALT_ENABLE));
+        exec.bus().write_u32(MSER, MSER_PARITY_ENABLE).unwrap();
+        exec.write_ipr(PrivRegisters::IORESET, 0);
+        assert_eq!(exec.bus().read_u32(MSER), Ok(0));
+
+        // Virtual time starts the watch chip in 2000, in BCD.
+        assert_eq!(exec.bus().read_u8(WATCH_BASE + 2 * REG_YEAR as u32), Ok(0x00));
+
+        // Disks go on an RQDX3 on the Q-bus, tapes on a TQK50.
+        assert!(exec.bus().find_device::<Qbus>().unwrap().find_device::<Mscp>().is_none());
+        assert!(exec.bus().find_device::<Qbus>().unwrap().find_device::<Tmscp>().is_none());
+        let mut exec = MachineBuilder::new(Model::Ka630)
+            .disk(Disk::new(RD54, Box::new(MemoryStore::new()), false))
+            .tape(Tape::new(Box::new(std::io::Cursor::new(vec![])), true))
+            .build()
+            .unwrap();
+        let qbus = exec.bus().find_device::<Qbus>().unwrap();
+        assert!(qbus.find_device::<Mscp>().unwrap().disk(0).is_some());
+        assert!(qbus.find_device::<Tmscp>().unwrap().tape(0).unwrap().is_read_only());
+        assert_eq!(exec.bus().read_u16(IO_PAGE_BASE + mscp::DEFAULT_BASE + 2), Ok(0x0B00));
+        assert_eq!(exec.bus().read_u16(IO_PAGE_BASE + tmscp::DEFAULT_BASE + 2), Ok(0x0B00));
+
+        // Other terminals go on a DZQ11.
+        let (line, mut far) = ChannelLine::pair();
+        let mut exec = MachineBuilder::new(Model::Ka630).serial_line(1, Box::new(line)).build().unwrap();
+        let dz = IO_PAGE_BASE + dz11::DEFAULT_BASE;
+        exec.bus().write_u16(dz, CSR_MSE).unwrap();
+        exec.bus().write_u16(dz + 2 * REG_TCR as u32, 0x0002).unwrap();
+        exec.bus().write_u16(dz + 2 * REG_TDR as u32, b'x' as u16).unwrap();
+        assert_eq!(far.drain(), b"x");
+        assert_eq!(exec.bus().read_u16(dz + 2 * REG_MSR as u32), Ok(0x0200));
+        let (line, _far) = ChannelLine::pair();
+        assert!(MachineBuilder::new(Model::Ka630).serial_line(4, Box::new(line)).build().is_err());
+
+        // The Ethernet goes on a DELQA, its station address in the first registers.
+        let mac = [0x08, 0x00, 0x2B, 0x0A, 0x0B, 0x0C];
+        let mut exec = MachineBuilder::new(Model::Ka630).ethernet(mac, Box::new(Loopback::new())).build().unwrap();
+        assert_eq!(exec.bus().read_u16(IO_PAGE_BASE + delqa::DEFAULT_BASE + 10), Ok(0xFF0C));
+
+        assert!(MachineBuilder::by_name("ka630").unwrap().ram_mib(32).unwrap().build().is_err());
+        assert!(matches!(MachineBuilder::new(Model::Ka630).ram_mib(usize::MAX), Err(MachineError::RamMib(usize::MAX))));
+        assert!(MachineBuilder::by_name("pdp11").is_err());
+    }
+}
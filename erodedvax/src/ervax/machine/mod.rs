@@ -0,0 +1,307 @@
+//! Models of particular VAX systems: which CPU board, how much memory, what's on the bus.
+//! Machines are put together with a MachineBuilder, picking the model by name.
+
+use std::{fmt, fs, io};
+
+use crate::ervax::{
+    cpu::{
+        execution::{ExecutionContext, IdleConfig},
+        sysclk::TimeMode,
+    },
+    devices::{
+        console::{ConsoleSink, ConsoleSource, StdoutSink},
+        disk::{Disk, Geometry},
+        ethernet::{EthernetBackend, MacAddress},
+        serial::{SerialEndpoint, TcpLine},
+        tape::Tape,
+    },
+};
+
+pub mod debugger;
+pub mod firmware;
+pub mod ka41;
+pub mod ka630;
+pub mod monitor;
+
+use firmware::BootDevice;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// MicroVAX II.
+    Ka630,
+    /// VAXstation 3100.
+    Ka41,
+}
+
+impl Model {
+    pub const ALL: &'static [Model] = &[Model::Ka630, Model::Ka41];
+
+    /// The name models are selected by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Ka630 => "ka630",
+            Model::Ka41 => "ka41",
+        }
+    }
+
+    /// Other names the model answers to.
+    pub fn aliases(self) -> &'static [&'static str] {
+        match self {
+            Model::Ka630 => &["microvax2", "uvax2"],
+            Model::Ka41 => &["vs3100", "vaxstation3100"],
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Model::Ka630 => "MicroVAX II (KA630)",
+            Model::Ka41 => "VAXstation 3100 (KA41)",
+        }
+    }
+
+    /// Looks a model up by its name or an alias, ignoring case.
+    pub fn from_name(name: &str) -> Option<Model> {
+        let name = name.to_ascii_lowercase();
+        Model::ALL.iter().copied().find(|m| m.name() == name || m.aliases().contains(&name.as_str()))
+    }
+
+    /// Where the console ROM begins, and the CPU starts at power up.
+    pub fn rom_base(self) -> u32 {
+        match self {
+            Model::Ka630 => ka630::ROM_BASE,
+            Model::Ka41 => ka41::ROM_BASE,
+        }
+    }
+
+    /// What the console boots from when it isn't told.
+    pub fn default_boot_device(self) -> BootDevice {
+        match self {
+            Model::Ka630 => BootDevice::Disk(0),
+            Model::Ka41 => BootDevice::ScsiDisk(0),
+        }
+    }
+
+    pub fn default_ram_size(self) -> usize {
+        match self {
+            Model::Ka630 => ka630::MAX_RAM,
+            Model::Ka41 => ka41::DEFAULT_RAM,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MachineError {
+    UnknownModel(String),
+    /// The model can't have this much memory.
+    RamSize(usize),
+    /// A memory size in MiB too big to count in bytes.
+    RamMib(usize),
+    /// The ROM image is bigger than the model's ROM.
+    RomSize(usize),
+    UnknownDrive(String),
+    /// The model has nowhere to put something it was given.
+    Unsupported(&'static str),
+    /// The model can't have more than this many disks.
+    TooManyDisks(usize),
+    /// There's no such serial line for terminals, or it's the console.
+    SerialLine(usize),
+    /// The built-in console was asked to boot from a device that isn't there.
+    NoBootDevice(BootDevice),
+    NoBootBlock(BootDevice),
+    Io(String, io::Error),
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineError::UnknownModel(name) => write!(f, "unknown machine model `{}`", name),
+            MachineError::RamSize(size) => write!(f, "unsupported memory size of {} bytes", size),
+            MachineError::RamMib(mib) => write!(f, "memory size of {} MiB is too big", mib),
+            MachineError::RomSize(size) => write!(f, "ROM image of {} bytes is too big", size),
+            MachineError::UnknownDrive(name) => write!(f, "unknown drive type `{}`", name),
+            MachineError::Unsupported(what) => write!(f, "this model has no {}", what),
+            MachineError::TooManyDisks(n) => write!(f, "this model takes at most {} disks", n),
+            MachineError::SerialLine(n) => write!(f, "this model has no serial line {} for terminals", n),
+            MachineError::NoBootDevice(device) => write!(f, "there's no {} to boot from", device),
+            MachineError::NoBootBlock(device) => write!(f, "there's no boot block on {}", device),
+            MachineError::Io(path, e) => write!(f, "{}: {}", path, e),
+        }
+    }
+}
+
+/// What a board model is built from, collected by a MachineBuilder.
+pub struct BoardConfig {
+    pub ram_size: usize,
+    /// The boot ROM image. Without one the ROM reads as erased.
+    pub rom: Option<Vec<u8>>,
+    /// Saved contents of the watch chip's NVRAM.
+    pub nvram: Option<Vec<u8>>,
+    pub time_mode: TimeMode,
+    /// The console terminal, wherever the board has it.
+    pub console_sink: Box<dyn ConsoleSink>,
+    pub console_source: Option<Box<dyn ConsoleSource>>,
+    /// Disk drives, unit 0 first: MSCP units, or SCSI IDs on a board with SCSI.
+    pub disks: Vec<Disk>,
+    /// TMSCP tape drives, unit 0 first.
+    pub tapes: Vec<Tape>,
+    /// Terminal lines and their line numbers, on the board's serial controller or a DZ.
+    pub serial_lines: Vec<(usize, Box<dyn SerialEndpoint>)>,
+    /// The Ethernet controller's station address, and where its frames go.
+    pub ethernet: Option<(MacAddress, Box<dyn EthernetBackend>)>,
+}
+
+/// Collects the settings for a machine, then builds it.
+pub struct MachineBuilder {
+    model: Model,
+    board: BoardConfig,
+    idle: IdleConfig,
+    boot: Option<BootDevice>,
+}
+
+impl MachineBuilder {
+    pub fn new(model: Model) -> MachineBuilder {
+        MachineBuilder {
+            model,
+            board: BoardConfig {
+                ram_size: model.default_ram_size(),
+                rom: None,
+                nvram: None,
+                time_mode: TimeMode::RealTime,
+                console_sink: Box::new(StdoutSink),
+                console_source: None,
+                disks: vec![],
+                tapes: vec![],
+                serial_lines: vec![],
+                ethernet: None,
+            },
+            idle: IdleConfig::default(),
+            boot: None,
+        }
+    }
+
+    pub fn by_name(name: &str) -> Result<MachineBuilder, MachineError> {
+        Model::from_name(name)
+            .map(MachineBuilder::new)
+            .ok_or_else(|| MachineError::UnknownModel(name.to_string()))
+    }
+
+    #[inline]
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Memory size in bytes, checked against the model when the machine is built.
+    pub fn ram_size(mut self, bytes: usize) -> Self {
+        self.board.ram_size = bytes;
+        self
+    }
+
+    pub fn ram_mib(self, mib: usize) -> Result<Self, MachineError> {
+        let bytes = mib.checked_mul(1 << 20).ok_or(MachineError::RamMib(mib))?;
+        Ok(self.ram_size(bytes))
+    }
+
+    /// The boot ROM image. Without one the ROM reads as erased.
+    pub fn rom(mut self, image: Vec<u8>) -> Self {
+        self.board.rom = Some(image);
+        self
+    }
+
+    pub fn rom_file(self, path: &str) -> Result<Self, MachineError> {
+        let image = fs::read(path).map_err(|e| MachineError::Io(path.to_string(), e))?;
+        Ok(self.rom(image))
+    }
+
+    /// Saved contents of the watch chip's NVRAM.
+    pub fn nvram(mut self, bytes: Vec<u8>) -> Self {
+        self.board.nvram = Some(bytes);
+        self
+    }
+
+    /// Adds a disk drive, as the next MSCP unit number or SCSI ID.
+    pub fn disk(mut self, disk: Disk) -> Self {
+        self.board.disks.push(disk);
+        self
+    }
+
+    /// Adds a drive of type `drive`, such as RD54, backed by the image file at `path`.
+    pub fn disk_file(self, path: &str, drive: &str, read_only: bool) -> Result<Self, MachineError> {
+        let geometry = Geometry::by_name(drive).ok_or_else(|| MachineError::UnknownDrive(drive.to_string()))?;
+        let disk = Disk::open(path, geometry, read_only).map_err(|e| MachineError::Io(path.to_string(), e))?;
+        Ok(self.disk(disk))
+    }
+
+    /// Adds a TMSCP tape drive, as the next unit number.
+    pub fn tape(mut self, tape: Tape) -> Self {
+        self.board.tapes.push(tape);
+        self
+    }
+
+    /// Adds a tape drive with the SIMH style tape image at `path` in it.
+    pub fn tape_file(self, path: &str, read_only: bool) -> Result<Self, MachineError> {
+        let tape = Tape::open(path, read_only).map_err(|e| MachineError::Io(path.to_string(), e))?;
+        Ok(self.tape(tape))
+    }
+
+    /// Puts `endpoint` on terminal line `n`.
+    pub fn serial_line(mut self, n: usize, endpoint: Box<dyn SerialEndpoint>) -> Self {
+        self.board.serial_lines.push((n, endpoint));
+        self
+    }
+
+    /// Puts terminal line `n` on a localhost TCP port, for telnet clients to connect to.
+    pub fn serial_telnet(self, n: usize, port: u16) -> Result<Self, MachineError> {
+        let line = TcpLine::localhost(port).map_err(|e| MachineError::Io(format!("port {}", port), e))?;
+        Ok(self.serial_line(n, Box::new(line)))
+    }
+
+    /// Adds an Ethernet controller with station address `mac`, its frames going to `backend`.
+    pub fn ethernet(mut self, mac: MacAddress, backend: Box<dyn EthernetBackend>) -> Self {
+        self.board.ethernet = Some((mac, backend));
+        self
+    }
+
+    pub fn time_mode(mut self, mode: TimeMode) -> Self {
+        self.board.time_mode = mode;
+        self
+    }
+
+    pub fn idle(mut self, config: IdleConfig) -> Self {
+        self.idle = config;
+        self
+    }
+
+    pub fn console_sink(mut self, sink: Box<dyn ConsoleSink>) -> Self {
+        self.board.console_sink = sink;
+        self
+    }
+
+    pub fn console_source(mut self, source: Box<dyn ConsoleSource>) -> Self {
+        self.board.console_source = Some(source);
+        self
+    }
+
+    /// What the built-in console boots from when there's no ROM. Without either, the machine
+    /// is built halted.
+    pub fn boot(mut self, device: BootDevice) -> Self {
+        self.boot = Some(device);
+        self
+    }
+
+    /// Builds the machine and powers it up, running the console ROM if it has one.
+    pub fn build(self) -> Result<ExecutionContext, MachineError> {
+        let MachineBuilder { model, board, idle, boot } = self;
+        let has_rom = board.rom.is_some();
+        let mut exec = match model {
+            Model::Ka630 => ka630::build(board)?,
+            Model::Ka41 => ka41::build(board)?,
+        };
+        exec.set_idle_config(idle);
+        if has_rom {
+            exec.power_up(model.rom_base());
+        } else if let Some(device) = boot {
+            firmware::boot(&mut exec, device)?;
+        }
+        Ok(exec)
+    }
+}
@@ -0,0 +1,574 @@
+//! The console monitor, what the console program runs while the CPU is halted: the >>> prompt
+//! and its commands, EXAMINE, DEPOSIT, START, CONTINUE, INITIALIZE, BOOT and HALT. Commands can
+//! be shortened to their first letter, HE for HELP.
+//!
+//! The host can hand commands straight to a Monitor, or let it drive the console terminal,
+//! where it takes over whenever the CPU halts, and ^P halts the CPU.
+//!
+//! EXAMINE and DEPOSIT take qualifiers for the address space, /P physical memory, /V virtual,
+//! /G a general register, /I a processor register, for the size, /B, /W, /L or /Q, and /N:n
+//! to go on to the next n locations too. What isn't given is carried over from last time.
+//! Addresses are in hex, or a register name or PSL, or + or - for the location after or
+//! before the last, or * for the last again.
+
+use std::fmt;
+
+use num_traits::FromPrimitive;
+
+use crate::ervax::{
+    cpu::{
+        execution::{DebugAccessError, ExecutionContext, HaltReason, POWER_UP_PSL},
+        instrs::OperandWidth,
+        registers::PrivRegisters,
+        RegID,
+    },
+    devices::console::HALT_CHARACTER,
+    machine::{
+        firmware::{self, BootDevice},
+        MachineError,
+    },
+};
+
+pub const PROMPT: &str = ">>> ";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Space {
+    Physical,
+    Virtual,
+    /// R0 through PC, by number.
+    Register,
+    /// Processor registers, by number.
+    Ipr,
+    Psl,
+}
+
+impl Space {
+    /// The letter the console shows locations in this space with.
+    fn code(self) -> char {
+        match self {
+            Space::Physical => 'P',
+            Space::Virtual => 'V',
+            Space::Register => 'G',
+            Space::Ipr => 'I',
+            Space::Psl => 'M',
+        }
+    }
+
+    fn is_memory(self) -> bool {
+        matches!(self, Space::Physical | Space::Virtual)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Location {
+    space: Space,
+    address: u32,
+    /// Bytes, for memory. Registers are always longwords.
+    size: u32,
+}
+
+impl Location {
+    fn step(self) -> u32 {
+        if self.space.is_memory() { self.size } else { 1 }
+    }
+
+    fn digits(self) -> usize {
+        if self.space.is_memory() { self.size as usize * 2 } else { 8 }
+    }
+}
+
+#[derive(Debug)]
+pub enum MonitorError {
+    UnknownCommand(String),
+    BadQualifier(String),
+    BadAddress(String),
+    BadValue(String),
+    /// A command needs more than it was given.
+    Missing(&'static str),
+    /// Nothing answered at a memory address.
+    NoMemory(Space, u32),
+    /// A virtual address the page tables don't map, with memory management on.
+    Unmapped(u32),
+    Boot(MachineError),
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitorError::UnknownCommand(c) => write!(f, "?Unknown command {}", c),
+            MonitorError::BadQualifier(q) => write!(f, "?Bad qualifier {}", q),
+            MonitorError::BadAddress(a) => write!(f, "?Bad address {}", a),
+            MonitorError::BadValue(v) => write!(f, "?Bad value {}", v),
+            MonitorError::Missing(what) => write!(f, "?Missing {}", what),
+            MonitorError::NoMemory(space, a) => write!(f, "?No memory at {} {:08X}", space.code(), a),
+            MonitorError::Unmapped(a) => write!(f, "?No translation for V {:08X}", a),
+            MonitorError::Boot(e) => write!(f, "?Boot failed, {}", e),
+        }
+    }
+}
+
+/// What the console says the CPU stopped for, numbered as the KA630 numbers them.
+pub fn halt_message(reason: HaltReason) -> Option<&'static str> {
+    Some(match reason {
+        HaltReason::PowerUp => return None,
+        // Only the host stops the CPU for change mode instructions, as the operator does.
+        HaltReason::External | HaltReason::ChangeMode(..) => "?02 EXT HLT",
+        HaltReason::DoubleError => "?05 DBL ERR",
+        HaltReason::HaltInstruction => "?06 HLT INST",
+        HaltReason::BadVector(_) => "?07 SCB ERR",
+        HaltReason::ChangeModeOnInterruptStack => "?0A CHM FR ISTK",
+    })
+}
+
+const COMMANDS: &[(&str, usize)] = &[
+    ("EXAMINE", 1),
+    ("DEPOSIT", 1),
+    ("START", 1),
+    ("CONTINUE", 1),
+    ("INITIALIZE", 1),
+    ("BOOT", 1),
+    ("HALT", 1),
+    ("HELP", 2),
+];
+
+const HELP: &str = "\
+EXAMINE [/P|/V|/G|/I] [/B|/W|/L|/Q] [/N:n] [address]
+DEPOSIT [/P|/V|/G|/I] [/B|/W|/L|/Q] [/N:n] address value
+START address
+CONTINUE
+INITIALIZE
+BOOT [device]
+HALT";
+
+/// A command line taken apart: the command, then its qualifiers and arguments in the order given.
+pub(crate) struct CommandWords<'a> {
+    pub(crate) name: &'static str,
+    pub(crate) qualifiers: Vec<&'a str>,
+    pub(crate) args: Vec<&'a str>,
+}
+
+/// Takes apart a command line for one of `commands`, each given with the fewest letters it can
+/// be abbreviated to. None for a blank line, or the first word if it isn't a command.
+pub(crate) fn split_command<'a>(line: &'a str, commands: &[(&'static str, usize)]) -> Result<Option<CommandWords<'a>>, &'a str> {
+    // Qualifiers can be run up against what they qualify.
+    let mut words = line.split_whitespace().flat_map(|mut word| {
+        std::iter::from_fn(move || {
+            let end = word.char_indices().skip(1).find(|&(_, c)| c == '/').map_or(word.len(), |(i, _)| i);
+            let (w, rest) = word.split_at(end);
+            word = rest;
+            Some(w).filter(|w| !w.is_empty())
+        })
+    });
+    let word = match words.next() {
+        Some(w) => w,
+        None => return Ok(None),
+    };
+    let upper = word.to_ascii_uppercase();
+    let name = commands
+        .iter()
+        .find(|(name, min)| upper.len() >= *min && name.starts_with(upper.as_str()))
+        .map(|(name, _)| *name)
+        .ok_or(word)?;
+    let (qualifiers, args) = words.partition(|w| w.starts_with('/'));
+    Ok(Some(CommandWords { name, qualifiers, args }))
+}
+
+pub(crate) fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// A line being typed at a prompt on the console terminal, echoed as it's typed, with rubout
+/// and ^U to start again.
+pub(crate) struct LineEditor {
+    prompt: &'static str,
+    line: String,
+    /// The last character typed, so CR LF ends one line, not two.
+    last_key: u8,
+}
+
+impl LineEditor {
+    pub(crate) fn new(prompt: &'static str) -> LineEditor {
+        LineEditor { prompt, line: String::new(), last_key: 0 }
+    }
+
+    /// Takes a character typed. Returns the line once it's ended.
+    pub(crate) fn key(&mut self, exec: &mut ExecutionContext, b: u8) -> Option<String> {
+        let last = std::mem::replace(&mut self.last_key, b);
+        match b {
+            b'\n' if last == b'\r' => {}
+            // A host terminal's cooked mode ends lines with LF.
+            b'\r' | b'\n' => {
+                exec.console().write_str("\r\n");
+                return Some(std::mem::take(&mut self.line));
+            }
+            0x08 | 0x7F if self.line.pop().is_some() => exec.console().write_str("\x08 \x08"),
+            // ^U, throw the line away.
+            0x15 => {
+                self.line.clear();
+                exec.console().write_str("^U\r\n");
+                exec.console().write_str(self.prompt);
+            }
+            0x20..=0x7E => {
+                self.line.push(b as char);
+                exec.console().write_str(&(b as char).to_string());
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+pub struct Monitor {
+    /// What's being typed, when driving the terminal.
+    editor: LineEditor,
+    /// Whether the prompt is up.
+    prompting: bool,
+    driving: bool,
+    /// Where EXAMINE and DEPOSIT left off.
+    last: Location,
+    /// What BOOT boots from without being told.
+    boot_device: BootDevice,
+}
+
+impl Default for Monitor {
+    fn default() -> Monitor {
+        Monitor {
+            editor: LineEditor::new(PROMPT),
+            prompting: false,
+            driving: false,
+            last: Location { space: Space::Physical, address: 0, size: 4 },
+            boot_device: BootDevice::Disk(0),
+        }
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    pub fn set_boot_device(&mut self, device: BootDevice) {
+        self.boot_device = device;
+    }
+
+    /// Runs one command line, returning what it prints, lines separated by newlines.
+    pub fn command(&mut self, exec: &mut ExecutionContext, line: &str) -> Result<String, MonitorError> {
+        let split = split_command(line, COMMANDS).map_err(|w| MonitorError::UnknownCommand(w.to_string()))?;
+        let CommandWords { name, qualifiers, args } = match split {
+            Some(words) => words,
+            None => return Ok(String::new()),
+        };
+
+        match name {
+            "EXAMINE" | "DEPOSIT" => {
+                let (mut at, count) = self.location(&qualifiers, args.first().copied())?;
+                let value = match (name, args.get(1)) {
+                    ("EXAMINE", _) => None,
+                    (_, Some(v)) => Some(u64::from_str_radix(v, 16).map_err(|_| MonitorError::BadValue(v.to_string()))?),
+                    (_, None) => return Err(MonitorError::Missing("value")),
+                };
+                let mut out = vec![];
+                for i in 0..=count {
+                    if i > 0 {
+                        at.address = at.address.wrapping_add(at.step());
+                    }
+                    self.last = at;
+                    match value {
+                        Some(v) => write_location(exec, at, v)?,
+                        None => {
+                            let v = read_location(exec, at)?;
+                            out.push(format!("{} {:08X} {:0width$X}", at.space.code(), at.address, v, width = at.digits()));
+                        }
+                    }
+                }
+                Ok(out.join("\n"))
+            }
+            "START" => {
+                let a = args.first().ok_or(MonitorError::Missing("address"))?;
+                let pc = parse_hex(a).ok_or_else(|| MonitorError::BadAddress(a.to_string()))?;
+                initialize(exec);
+                exec.start(pc);
+                Ok(String::new())
+            }
+            "CONTINUE" => {
+                let pc = exec.pc();
+                exec.start(pc);
+                Ok(String::new())
+            }
+            "INITIALIZE" => {
+                initialize(exec);
+                Ok(String::new())
+            }
+            "BOOT" => {
+                let device = match args.first() {
+                    Some(name) => BootDevice::from_name(name).ok_or_else(|| MonitorError::BadAddress(name.to_string()))?,
+                    None => self.boot_device,
+                };
+                initialize(exec);
+                firmware::boot(exec, device).map_err(MonitorError::Boot)?;
+                Ok(String::new())
+            }
+            "HALT" => {
+                if !exec.is_halted() {
+                    exec.halt(HaltReason::External);
+                }
+                Ok(String::new())
+            }
+            _ => Ok(HELP.to_string()),
+        }
+    }
+
+    /// Works out the location from the qualifiers and address, and how many more after it.
+    fn location(&self, qualifiers: &[&str], address: Option<&str>) -> Result<(Location, u32), MonitorError> {
+        let mut at = self.last;
+        let mut space = None;
+        let mut count = 0;
+        for q in qualifiers {
+            let upper = q.to_ascii_uppercase();
+            match upper.as_str() {
+                "/P" => space = Some(Space::Physical),
+                "/V" => space = Some(Space::Virtual),
+                "/G" => space = Some(Space::Register),
+                "/I" => space = Some(Space::Ipr),
+                "/B" => at.size = 1,
+                "/W" => at.size = 2,
+                "/L" => at.size = 4,
+                "/Q" => at.size = 8,
+                _ => {
+                    count = upper.strip_prefix("/N:").and_then(parse_hex).ok_or_else(|| MonitorError::BadQualifier(q.to_string()))?;
+                }
+            }
+        }
+
+        let address = address.unwrap_or("+");
+        match address {
+            "+" => at.address = at.address.wrapping_add(at.step()),
+            "-" => at.address = at.address.wrapping_sub(at.step()),
+            "*" => {}
+            _ => {
+                if let Some(r) = RegID::from_name(address) {
+                    at.space = Space::Register;
+                    at.address = r.id() as u32;
+                } else if address.eq_ignore_ascii_case("PSL") {
+                    at.space = Space::Psl;
+                    at.address = 0;
+                } else {
+                    at.address = parse_hex(address).ok_or_else(|| MonitorError::BadAddress(address.to_string()))?;
+                    if at.space == Space::Psl {
+                        at.space = Space::Physical;
+                    }
+                }
+            }
+        }
+        if let Some(space) = space {
+            at.space = space;
+        }
+        let bad = || MonitorError::BadAddress(address.to_string());
+        match at.space {
+            Space::Register if at.address > 15 => return Err(bad()),
+            Space::Ipr if PrivRegisters::from_u32(at.address).is_none() => return Err(bad()),
+            _ => {}
+        }
+        Ok((at, count))
+    }
+
+    /// Drives the console terminal: runs the CPU until the next event while it's running, and
+    /// when it's halted, takes commands from the terminal. Returns whether the CPU is running,
+    /// so the host knows whether to wait for input.
+    pub fn service(&mut self, exec: &mut ExecutionContext) -> bool {
+        if !self.driving {
+            exec.console().set_halt_character(Some(HALT_CHARACTER));
+            self.driving = true;
+        }
+        if !exec.is_halted() {
+            self.prompting = false;
+            exec.run_until_next_event();
+        }
+        if !exec.is_halted() {
+            return true;
+        }
+
+        if !self.prompting {
+            let reason = exec.halt_reason().unwrap();
+            if let Some(message) = halt_message(reason) {
+                let pc = exec.pc();
+                exec.console().write_str(&format!("\r\n{}\r\n\tPC = {:08X}\r\n", message, pc));
+            }
+            exec.console().write_str(PROMPT);
+            self.prompting = true;
+        }
+        while let Some(b) = exec.console().take_input() {
+            self.key(exec, b);
+            if !exec.is_halted() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Takes a character typed at the prompt.
+    fn key(&mut self, exec: &mut ExecutionContext, b: u8) {
+        let line = match self.editor.key(exec, b) {
+            Some(line) => line,
+            None => return,
+        };
+        let out = match self.command(exec, &line) {
+            Ok(out) => out,
+            Err(e) => e.to_string(),
+        };
+        for l in out.lines() {
+            exec.console().write_str(l);
+            exec.console().write_str("\r\n");
+        }
+        if exec.is_halted() {
+            exec.console().write_str(PROMPT);
+        } else {
+            self.prompting = false;
+        }
+    }
+}
+
+/// What INITIALIZE does, and START and BOOT first: resets the I/O and puts the CPU in its
+/// power-up state.
+fn initialize(exec: &mut ExecutionContext) {
+    exec.write_ipr(PrivRegisters::IORESET, 0);
+    exec.console().reset();
+    exec.set_psl(POWER_UP_PSL);
+}
+
+fn width(size: u32) -> OperandWidth {
+    match size {
+        1 => OperandWidth::Byte,
+        2 => OperandWidth::Word,
+        4 => OperandWidth::Longword,
+        _ => OperandWidth::Quadword,
+    }
+}
+
+fn virtual_error(e: DebugAccessError, address: u32) -> MonitorError {
+    match e {
+        DebugAccessError::Unmapped => MonitorError::Unmapped(address),
+        DebugAccessError::Bus(_) => MonitorError::NoMemory(Space::Virtual, address),
+    }
+}
+
+fn read_location(exec: &mut ExecutionContext, at: Location) -> Result<u64, MonitorError> {
+    let missing = MonitorError::NoMemory(at.space, at.address);
+    Ok(match at.space {
+        Space::Physical => {
+            let bus = exec.bus();
+            let a = at.address;
+            match at.size {
+                1 => bus.read_u8(a).map(u64::from),
+                2 => bus.read_u16(a).map(u64::from),
+                4 => bus.read_u32(a).map(u64::from),
+                _ => bus.read_u32(a).and_then(|lo| Ok((bus.read_u32(a.wrapping_add(4))? as u64) << 32 | lo as u64)),
+            }
+            .map_err(|_| missing)?
+        }
+        Space::Virtual => exec.debug_read(at.address, width(at.size)).map_err(|e| virtual_error(e, at.address))? as u64,
+        Space::Register => exec.reg(RegID::new(at.address as u8)) as u64,
+        Space::Ipr => exec.read_ipr(PrivRegisters::from_u32(at.address).unwrap()) as u64,
+        Space::Psl => exec.psl() as u64,
+    })
+}
+
+fn write_location(exec: &mut ExecutionContext, at: Location, v: u64) -> Result<(), MonitorError> {
+    let missing = MonitorError::NoMemory(at.space, at.address);
+    match at.space {
+        Space::Physical => {
+            let bus = exec.bus();
+            let a = at.address;
+            match at.size {
+                1 => bus.write_u8(a, v as u8),
+                2 => bus.write_u16(a, v as u16),
+                4 => bus.write_u32(a, v as u32),
+                _ => bus.write_u32(a, v as u32).and_then(|_| bus.write_u32(a.wrapping_add(4), (v >> 32) as u32)),
+            }
+            .map_err(|_| missing)?
+        }
+        Space::Virtual => exec.debug_write(at.address, width(at.size), v as u128).map_err(|e| virtual_error(e, at.address))?,
+        Space::Register => exec.set_reg(RegID::new(at.address as u8), v as u32),
+        Space::Ipr => exec.write_ipr(PrivRegisters::from_u32(at.address).unwrap(), v as u32),
+        Space::Psl => exec.set_psl(v as u32),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::sysclk::TimeMode,
+        devices::console::{ChannelSource, MemorySink},
+        machine::{MachineBuilder, Model},
+    };
+
+    #[test]
+    fn monitor_commands() {
+        let sink = MemorySink::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut exec = MachineBuilder::new(Model::Ka630)
+            .time_mode(TimeMode::Virtual)
+            .console_sink(Box::new(sink.clone()))
+            .console_source(Box::new(ChannelSource::new(rx)))
+            .build()
+            .unwrap();
+        let mut monitor = Monitor::new();
+        let run = |monitor: &mut Monitor, exec: &mut ExecutionContext, line: &str| monitor.command(exec, line).unwrap();
+
+        // MOVL #5, R0, then HALT, at 0x1000.
+        assert_eq!(run(&mut monitor, &mut exec, "d/p/l 1000 5005D0"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "e/b 1000"), "P 00001000 D0");
+        assert_eq!(run(&mut monitor, &mut exec, "E"), "P 00001001 05");
+        assert_eq!(run(&mut monitor, &mut exec, "e/w/n:1 1000"), "P 00001000 05D0\nP 00001002 0050");
+        assert_eq!(run(&mut monitor, &mut exec, "EXAM/V/Q 1000"), "V 00001000 00000000005005D0");
+        assert_eq!(run(&mut monitor, &mut exec, "d r1 ABC"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "e/g 1"), "G 00000001 00000ABC");
+        assert_eq!(run(&mut monitor, &mut exec, "e/i 3E"), "I 0000003E 08000000");
+        assert!(matches!(monitor.command(&mut exec, "e/p 7FFFFFF0"), Err(MonitorError::NoMemory(Space::Physical, 0x7FFF_FFF0))));
+        // With memory management on, virtual addresses go through the page tables: here one
+        // system page mapped to physical 1000.
+        assert_eq!(run(&mut monitor, &mut exec, "d/p/l 2000 90000008"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "d/i c 2000"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "d/i d 1"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "d/i 38 1"), "");
+        assert_eq!(run(&mut monitor, &mut exec, "e/v/b 80000000"), "V 80000000 D0");
+        assert!(matches!(monitor.command(&mut exec, "e/v 1000"), Err(MonitorError::Unmapped(0x1000))));
+        assert_eq!(run(&mut monitor, &mut exec, "e/p/b 1000"), "P 00001000 D0");
+        assert_eq!(run(&mut monitor, &mut exec, "d/i 38 0"), "");
+        assert!(matches!(monitor.command(&mut exec, "frob"), Err(MonitorError::UnknownCommand(_))));
+        assert!(matches!(monitor.command(&mut exec, "d/g 10 0"), Err(MonitorError::BadAddress(_))));
+        assert!(run(&mut monitor, &mut exec, "he").starts_with("EXAMINE"));
+
+        // Started from the terminal, it halts back to the prompt.
+        assert!(!monitor.service(&mut exec));
+        assert_eq!(sink.take(), PROMPT.as_bytes());
+        for b in b"s 1000\r" {
+            tx.send(*b).unwrap();
+        }
+        while monitor.service(&mut exec) {}
+        let out = String::from_utf8(sink.take()).unwrap();
+        assert_eq!(out, "s 1000\r\n\r\n?06 HLT INST\r\n\tPC = 00001004\r\n>>> ");
+        assert_eq!(run(&mut monitor, &mut exec, "e r0"), "G 00000000 00000005");
+        assert_eq!(run(&mut monitor, &mut exec, "e psl"), "M 00000000 041F0000");
+        assert_eq!(exec.read_ipr(PrivRegisters::SAVPC), 0x1004);
+
+        // ^P halts a CPU that's spinning, BRB to itself at 0x2000.
+        run(&mut monitor, &mut exec, "d/w 2000 FE11");
+        run(&mut monitor, &mut exec, "d pc 2000");
+        run(&mut monitor, &mut exec, "c");
+        assert!(monitor.service(&mut exec));
+        tx.send(HALT_CHARACTER).unwrap();
+        while monitor.service(&mut exec) {}
+        assert_eq!(exec.halt_reason(), Some(HaltReason::External));
+        assert!(String::from_utf8(sink.take()).unwrap().contains("?02 EXT HLT\r\n\tPC = 00002000"));
+
+        // Rubbing out, and a failed boot.
+        for b in b"bx\x7F\r" {
+            tx.send(*b).unwrap();
+        }
+        assert!(!monitor.service(&mut exec));
+        assert_eq!(sink.take(), b"bx\x08 \x08\r\n?Boot failed, there's no DUA0 to boot from\r\n>>> ");
+    }
+}
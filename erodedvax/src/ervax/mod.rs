@@ -1,2 +1,7 @@
 pub mod cpu;
-pub mod utils;
\ No newline at end of file
+pub mod devices;
+pub mod gdb;
+pub mod loader;
+pub mod machine;
+pub mod netbsd;
+pub mod utils;
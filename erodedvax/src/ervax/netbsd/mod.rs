@@ -0,0 +1,675 @@
+//! Running NetBSD/VAX programs without NetBSD. The program runs in user mode in P0 space, and
+//! its CHMK system calls halt the CPU for the host to carry out, with files kept in a sandbox
+//! directory. Only the calls a simple program needs are here; the rest fail with ENOSYS.
+//!
+//! Arguments are at 4(AP), as the libc stubs leave them. A call returns in R0 and R1 with the
+//! carry clear, or fails with the error number in R0 and the carry set.
+//!
+//! Memory is laid out from the program up: its segments, the heap brk moves, mmap pages handed
+//! out going down, a page holding the SCB, then the stack at the top of memory. Every SCB vector
+//! halts, so an exception ends the program with the signal NetBSD would have sent.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ervax::{
+    cpu::{
+        execution::{vectors, ExecutionContext, HaltReason},
+        registers::PrivRegisters,
+        PrivilegeMode,
+        RegID,
+    },
+    loader::{push_args, Executable, LoadError},
+};
+
+pub mod sandbox;
+
+use sandbox::Sandbox;
+
+/// User mode, coming from user mode, at IPL 0.
+pub const USER_PSL: u32 = 0x03C0_0000;
+pub const PAGE_SIZE: u32 = 4096;
+pub const STACK_SIZE: u32 = 1 << 20;
+/// P0 space, the only part of the address space programs run in.
+pub const P0_END: u32 = 0x4000_0000;
+const PATH_MAX: usize = 1024;
+
+/// System call numbers.
+pub mod calls {
+    /// The call whose number is the first argument.
+    pub const SYSCALL: u32 = 0;
+    pub const EXIT: u32 = 1;
+    pub const READ: u32 = 3;
+    pub const WRITE: u32 = 4;
+    pub const OPEN: u32 = 5;
+    pub const CLOSE: u32 = 6;
+    pub const BREAK: u32 = 17;
+    pub const MUNMAP: u32 = 73;
+    /// gettimeofday with a 32-bit time_t, from before NetBSD 6.
+    pub const GETTIMEOFDAY_50: u32 = 116;
+    pub const MMAP: u32 = 197;
+    /// The call whose number is the first argument, a quadword.
+    pub const QUAD_SYSCALL: u32 = 198;
+    pub const LSEEK: u32 = 199;
+    pub const GETTIMEOFDAY: u32 = 418;
+    pub const FSTAT: u32 = 440;
+}
+
+/// NetBSD error numbers.
+pub mod errno {
+    pub const ENOENT: u32 = 2;
+    pub const EIO: u32 = 5;
+    pub const EBADF: u32 = 9;
+    pub const ENOMEM: u32 = 12;
+    pub const EACCES: u32 = 13;
+    pub const EFAULT: u32 = 14;
+    pub const EEXIST: u32 = 17;
+    pub const ENODEV: u32 = 19;
+    pub const EINVAL: u32 = 22;
+    pub const EMFILE: u32 = 24;
+    pub const ESPIPE: u32 = 29;
+    pub const ENAMETOOLONG: u32 = 63;
+    pub const ENOSYS: u32 = 78;
+}
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANON: u32 = 0x1000;
+const MAX_FILES: usize = 64;
+
+const S_IFCHR: u32 = 0o020000;
+
+/// The signals exceptions turn into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Ill = 4,
+    Trap = 5,
+    Fpe = 8,
+    Bus = 10,
+    Segv = 11,
+}
+
+impl Signal {
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Ill => "SIGILL",
+            Signal::Trap => "SIGTRAP",
+            Signal::Fpe => "SIGFPE",
+            Signal::Bus => "SIGBUS",
+            Signal::Segv => "SIGSEGV",
+        }
+    }
+
+    /// What NetBSD sends for an exception through `vector`.
+    pub fn for_vector(vector: u16) -> Option<Signal> {
+        use vectors::*;
+        Some(match vector {
+            MACHINE_CHECK => Signal::Bus,
+            ACCESS_VIOLATION | TRANSLATION_NOT_VALID => Signal::Segv,
+            TRACE | BREAKPOINT => Signal::Trap,
+            ARITHMETIC => Signal::Fpe,
+            v if v < CHANGE_MODE + 0x10 => Signal::Ill,
+            _ => return None,
+        })
+    }
+}
+
+/// How a program ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Termination {
+    Exited(i32),
+    /// An exception NetBSD would have sent a signal for, at this PC.
+    Signalled(Signal, u32),
+    /// The CPU halted for some other reason, at this PC.
+    Halted(HaltReason, u32),
+}
+
+impl Termination {
+    /// The exit status, as a shell reports it.
+    pub fn status(self) -> i32 {
+        match self {
+            Termination::Exited(status) => status,
+            Termination::Signalled(signal, _) => 128 + signal as i32,
+            Termination::Halted(..) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Termination::Exited(status) => write!(f, "exited with status {}", status),
+            Termination::Signalled(signal, pc) => write!(f, "{} at PC ^X{:X}", signal.name(), pc),
+            Termination::Halted(reason, pc) => write!(f, "the CPU halted at PC ^X{:X}: {:?}", pc, reason),
+        }
+    }
+}
+
+/// What a file descriptor refers to.
+pub enum Descriptor {
+    Input(Box<dyn Read>),
+    Output(Box<dyn Write>),
+    File(File),
+}
+
+fn io_errno(e: &io::Error) -> u32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => errno::ENOENT,
+        io::ErrorKind::PermissionDenied => errno::EACCES,
+        io::ErrorKind::AlreadyExists => errno::EEXIST,
+        io::ErrorKind::InvalidInput => errno::EINVAL,
+        // The first few host error numbers are the same everywhere, bar EAGAIN.
+        _ => match e.raw_os_error() {
+            Some(n) if (1..=34).contains(&n) && n != 11 => n as u32,
+            _ => errno::EIO,
+        },
+    }
+}
+
+#[inline]
+fn round_page(v: u32) -> u32 {
+    v.wrapping_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Whether `len` bytes at `address` are all in RAM, checked before making a host buffer for them.
+fn in_ram(exec: &mut ExecutionContext, address: u32, len: u32) -> bool {
+    address as u64 + len as u64 <= exec.bus().ram_size() as u64
+}
+
+fn read_guest(exec: &mut ExecutionContext, address: u32, len: u32) -> Result<Vec<u8>, u32> {
+    if !in_ram(exec, address, len) {
+        return Err(errno::EFAULT);
+    }
+    let mut bytes = vec![0; len as usize];
+    exec.bus().read_bytes(address, &mut bytes).map_err(|_| errno::EFAULT)?;
+    Ok(bytes)
+}
+
+fn write_guest(exec: &mut ExecutionContext, address: u32, bytes: &[u8]) -> Result<(), u32> {
+    exec.bus().write_bytes(address, bytes).map_err(|_| errno::EFAULT)
+}
+
+/// A NUL terminated path.
+fn read_path(exec: &mut ExecutionContext, address: u32) -> Result<String, u32> {
+    let mut bytes = vec![];
+    for i in 0..PATH_MAX as u32 {
+        match exec.bus().read_u8(address.wrapping_add(i)).map_err(|_| errno::EFAULT)? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            b => bytes.push(b),
+        }
+    }
+    Err(errno::ENAMETOOLONG)
+}
+
+/// What fstat reports, laid out as NetBSD 6's struct stat.
+#[derive(Default)]
+struct Stat {
+    dev: u64,
+    mode: u32,
+    ino: u64,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    /// Access, modification, change and birth times, in seconds and nanoseconds.
+    times: [(i64, i32); 4],
+    size: u64,
+    blocks: u64,
+    blksize: u32,
+}
+
+impl Stat {
+    #[cfg(unix)]
+    fn from_metadata(m: &std::fs::Metadata) -> Stat {
+        use std::os::unix::fs::MetadataExt;
+        let change = (m.ctime(), m.ctime_nsec() as i32);
+        Stat {
+            dev: m.dev(),
+            mode: m.mode(),
+            ino: m.ino(),
+            nlink: m.nlink() as u32,
+            uid: m.uid(),
+            gid: m.gid(),
+            rdev: m.rdev(),
+            times: [(m.atime(), m.atime_nsec() as i32), (m.mtime(), m.mtime_nsec() as i32), change, change],
+            size: m.size(),
+            blocks: m.blocks(),
+            blksize: m.blksize() as u32,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_metadata(m: &std::fs::Metadata) -> Stat {
+        const S_IFDIR: u32 = 0o040000;
+        const S_IFREG: u32 = 0o100000;
+        let kind = if m.is_dir() { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+        Stat { mode: kind, nlink: 1, size: m.len(), blocks: (m.len() + 511) / 512, blksize: 4096, ..Stat::default() }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![];
+        b.extend_from_slice(&self.dev.to_le_bytes());
+        b.extend_from_slice(&self.mode.to_le_bytes());
+        b.extend_from_slice(&self.ino.to_le_bytes());
+        for v in &[self.nlink, self.uid, self.gid] {
+            b.extend_from_slice(&v.to_le_bytes());
+        }
+        b.extend_from_slice(&self.rdev.to_le_bytes());
+        for (sec, nsec) in &self.times {
+            b.extend_from_slice(&sec.to_le_bytes());
+            b.extend_from_slice(&nsec.to_le_bytes());
+        }
+        b.extend_from_slice(&self.size.to_le_bytes());
+        b.extend_from_slice(&self.blocks.to_le_bytes());
+        // Then st_blksize, st_flags, st_gen and two spares.
+        b.extend_from_slice(&self.blksize.to_le_bytes());
+        b.resize(b.len() + 16, 0);
+        b
+    }
+}
+
+/// A program and the host's stand-in for its kernel.
+pub struct Process {
+    sandbox: Sandbox,
+    files: Vec<Option<Descriptor>>,
+    /// Where the heap starts, and where brk has it end.
+    heap_start: u32,
+    brk: u32,
+    /// mmap hands out pages from below here.
+    mmap_next: u32,
+    exit_status: Option<i32>,
+}
+
+impl Process {
+    /// A process with its files in `sandbox`, and the host's standard input, output and error.
+    pub fn new(sandbox: Sandbox) -> Process {
+        Process {
+            sandbox,
+            files: vec![
+                Some(Descriptor::Input(Box::new(io::stdin()))),
+                Some(Descriptor::Output(Box::new(io::stdout()))),
+                Some(Descriptor::Output(Box::new(io::stderr()))),
+            ],
+            heap_start: 0,
+            brk: 0,
+            mmap_next: 0,
+            exit_status: None,
+        }
+    }
+
+    /// Replaces what file descriptor `fd` refers to.
+    pub fn set_descriptor(&mut self, fd: usize, d: Descriptor) {
+        if self.files.len() <= fd {
+            self.files.resize_with(fd + 1, || None);
+        }
+        self.files[fd] = Some(d);
+    }
+
+    #[inline]
+    pub fn brk(&self) -> u32 {
+        self.brk
+    }
+
+    /// Loads the program and sets the CPU up to run it in user mode, with `args` and `env` on its
+    /// stack as NetBSD's execve leaves them.
+    pub fn start(&mut self, exec: &mut ExecutionContext, program: &Executable, args: &[&str], env: &[&str]) -> Result<(), LoadError> {
+        program.load(exec)?;
+        let top = (exec.bus().ram_size().min(P0_END as usize) as u32) & !(PAGE_SIZE - 1);
+        let heap_start = round_page(program.end());
+        let scb = match top.checked_sub(STACK_SIZE + PAGE_SIZE) {
+            Some(scb) if scb >= heap_start => scb,
+            _ => return Err(LoadError::OutsideMemory(heap_start, STACK_SIZE + PAGE_SIZE)),
+        };
+        // A vector's low bits being 3 halts the CPU.
+        let halting: Vec<u8> = (0..PAGE_SIZE / 8).flat_map(|_| 3u32.to_le_bytes()).collect();
+        exec.bus().write_bytes(scb, &halting).map_err(|_| LoadError::OutsideMemory(scb, PAGE_SIZE))?;
+        exec.write_ipr(PrivRegisters::SCBB, scb);
+
+        exec.set_psl(USER_PSL);
+        for r in 0..14 {
+            exec.set_reg(RegID::new(r), 0);
+        }
+        push_args(exec, top, args, env)?;
+        exec.set_host_change_mode(true);
+
+        self.heap_start = heap_start;
+        self.brk = heap_start;
+        self.mmap_next = scb;
+        self.exit_status = None;
+        Ok(())
+    }
+
+    /// Runs the program until it exits or stops.
+    pub fn run(&mut self, exec: &mut ExecutionContext) -> Termination {
+        loop {
+            let reason = exec.run();
+            let pc = exec.pc();
+            match reason {
+                HaltReason::ChangeMode(PrivilegeMode::Kernel, code) => {
+                    self.system_call(exec, code);
+                    if let Some(status) = self.exit_status {
+                        return Termination::Exited(status);
+                    }
+                    exec.start(pc);
+                }
+                HaltReason::ChangeMode(..) => return Termination::Signalled(Signal::Ill, pc),
+                HaltReason::BadVector(v) => match Signal::for_vector(v) {
+                    Some(signal) => return Termination::Signalled(signal, pc),
+                    None => return Termination::Halted(reason, pc),
+                },
+                _ => return Termination::Halted(reason, pc),
+            }
+        }
+    }
+
+    /// Carries out the system call `code`, leaving its results in R0 and R1 and the carry.
+    pub fn system_call(&mut self, exec: &mut ExecutionContext, code: u16) {
+        let args = exec.reg(RegID::AP).wrapping_add(4);
+        match self.call(exec, code as u32, args) {
+            Ok(v) => {
+                exec.set_reg(RegID::new(0), v as u32);
+                exec.set_reg(RegID::new(1), (v >> 32) as u32);
+                exec.set_carry(false);
+            }
+            Err(e) => {
+                exec.set_reg(RegID::new(0), e);
+                exec.set_carry(true);
+            }
+        }
+    }
+
+    fn file(&mut self, fd: u32) -> Result<&mut Descriptor, u32> {
+        self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(errno::EBADF)
+    }
+
+    fn call(&mut self, exec: &mut ExecutionContext, code: u32, args: u32) -> Result<u64, u32> {
+        use calls::*;
+        let arg = |exec: &mut ExecutionContext, n: u32| {
+            exec.bus().read_u32(args.wrapping_add(n * 4)).map_err(|_| errno::EFAULT)
+        };
+
+        match code {
+            SYSCALL | QUAD_SYSCALL => {
+                let skip = if code == SYSCALL { 4 } else { 8 };
+                match arg(exec, 0)? {
+                    SYSCALL | QUAD_SYSCALL => Err(errno::ENOSYS),
+                    code => self.call(exec, code, args.wrapping_add(skip)),
+                }
+            }
+            EXIT => {
+                for d in self.files.iter_mut() {
+                    if let Some(Descriptor::Output(out)) = d {
+                        let _ = out.flush();
+                    }
+                }
+                self.exit_status = Some(arg(exec, 0)? as i32 & 0xFF);
+                Ok(0)
+            }
+            READ => {
+                let (fd, buffer, len) = (arg(exec, 0)?, arg(exec, 1)?, arg(exec, 2)?);
+                // Checks the buffer is there before reading anything into it.
+                let mut bytes = read_guest(exec, buffer, len)?;
+                let n = match self.file(fd)? {
+                    Descriptor::Input(input) => input.read(&mut bytes),
+                    Descriptor::File(f) => f.read(&mut bytes),
+                    Descriptor::Output(_) => return Err(errno::EBADF),
+                }.map_err(|e| io_errno(&e))?;
+                write_guest(exec, buffer, &bytes[..n])?;
+                Ok(n as u64)
+            }
+            WRITE => {
+                let (fd, buffer, len) = (arg(exec, 0)?, arg(exec, 1)?, arg(exec, 2)?);
+                let bytes = read_guest(exec, buffer, len)?;
+                match self.file(fd)? {
+                    Descriptor::Output(out) => out.write_all(&bytes),
+                    Descriptor::File(f) => f.write_all(&bytes),
+                    Descriptor::Input(_) => return Err(errno::EBADF),
+                }.map_err(|e| io_errno(&e))?;
+                Ok(len as u64)
+            }
+            OPEN => {
+                let address = arg(exec, 0)?;
+                let path = read_path(exec, address)?;
+                let (flags, mode) = (arg(exec, 1)?, arg(exec, 2)?);
+                let fd = match self.files.iter().position(Option::is_none) {
+                    Some(fd) => fd,
+                    None if self.files.len() < MAX_FILES => self.files.len(),
+                    None => return Err(errno::EMFILE),
+                };
+                let file = self.sandbox.open(&path, flags, mode).map_err(|e| io_errno(&e))?;
+                self.set_descriptor(fd, Descriptor::File(file));
+                Ok(fd as u64)
+            }
+            CLOSE => {
+                let fd = arg(exec, 0)?;
+                self.file(fd)?;
+                if let Some(Descriptor::Output(mut out)) = self.files[fd as usize].take() {
+                    let _ = out.flush();
+                }
+                Ok(0)
+            }
+            BREAK => {
+                let end = arg(exec, 0)?;
+                if end < self.heap_start {
+                    return Err(errno::EINVAL);
+                }
+                if end > self.mmap_next {
+                    return Err(errno::ENOMEM);
+                }
+                if end > self.brk {
+                    write_guest(exec, self.brk, &vec![0; (end - self.brk) as usize])?;
+                }
+                self.brk = end;
+                Ok(0)
+            }
+            MMAP => {
+                let (address, len, flags, fd) = (arg(exec, 0)?, round_page(arg(exec, 1)?), arg(exec, 3)?, arg(exec, 4)?);
+                let offset = arg(exec, 6)? as u64 | (arg(exec, 7)? as u64) << 32;
+                if len == 0 {
+                    return Err(errno::EINVAL);
+                }
+                let address = if flags & MAP_FIXED != 0 {
+                    if address % PAGE_SIZE != 0 {
+                        return Err(errno::EINVAL);
+                    }
+                    address
+                } else {
+                    match self.mmap_next.checked_sub(len) {
+                        Some(a) if a >= self.brk => a,
+                        _ => return Err(errno::ENOMEM),
+                    }
+                };
+
+                if !in_ram(exec, address, len) {
+                    return Err(errno::ENOMEM);
+                }
+                let mut contents = vec![0; len as usize];
+                if flags & MAP_ANON == 0 {
+                    let f = match self.file(fd)? {
+                        Descriptor::File(f) => f,
+                        _ => return Err(errno::ENODEV),
+                    };
+                    f.seek(SeekFrom::Start(offset)).map_err(|e| io_errno(&e))?;
+                    let mut read = 0;
+                    while read < contents.len() {
+                        match f.read(&mut contents[read..]).map_err(|e| io_errno(&e))? {
+                            0 => break,
+                            n => read += n,
+                        }
+                    }
+                }
+                write_guest(exec, address, &contents).map_err(|_| errno::ENOMEM)?;
+                if flags & MAP_FIXED == 0 {
+                    self.mmap_next = address;
+                }
+                Ok(address as u64)
+            }
+            // The pages aren't taken back.
+            MUNMAP => Ok(0),
+            LSEEK => {
+                let fd = arg(exec, 0)?;
+                let offset = (arg(exec, 2)? as u64 | (arg(exec, 3)? as u64) << 32) as i64;
+                let whence = arg(exec, 4)?;
+                let f = match self.file(fd)? {
+                    Descriptor::File(f) => f,
+                    _ => return Err(errno::ESPIPE),
+                };
+                let from = match whence {
+                    0 if offset >= 0 => SeekFrom::Start(offset as u64),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    _ => return Err(errno::EINVAL),
+                };
+                f.seek(from).map_err(|e| io_errno(&e))
+            }
+            GETTIMEOFDAY | GETTIMEOFDAY_50 => {
+                let (tv, tz) = (arg(exec, 0)?, arg(exec, 1)?);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                if tv != 0 {
+                    let usec = now.subsec_micros().to_le_bytes();
+                    let bytes = if code == GETTIMEOFDAY {
+                        [&now.as_secs().to_le_bytes()[..], &usec].concat()
+                    } else {
+                        [&(now.as_secs() as u32).to_le_bytes()[..], &usec].concat()
+                    };
+                    write_guest(exec, tv, &bytes)?;
+                }
+                if tz != 0 {
+                    write_guest(exec, tz, &[0; 8])?;
+                }
+                Ok(0)
+            }
+            FSTAT => {
+                let (fd, buffer) = (arg(exec, 0)?, arg(exec, 1)?);
+                let stat = match self.file(fd)? {
+                    Descriptor::File(f) => Stat::from_metadata(&f.metadata().map_err(|e| io_errno(&e))?),
+                    _ => Stat { mode: S_IFCHR | 0o620, nlink: 1, blksize: 1024, ..Stat::default() },
+                };
+                write_guest(exec, buffer, &stat.to_bytes())?;
+                Ok(0)
+            }
+            _ => Err(errno::ENOSYS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ervax::{
+        cpu::{bus::VAXBus, instrs::assemble, sysclk::TimeMode},
+        loader::elf,
+    };
+    use std::{cell::RefCell, fs, rc::Rc};
+
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `source`, assembled at 0x1000 with its entry mask first, in a sandbox.
+    fn run(source: &str, dir: &std::path::Path) -> (Termination, String, ExecutionContext) {
+        let code = assemble(source, 0x1000).unwrap();
+        let program = Executable::parse(&elf::tests::image(0x1000, &code.bytes, 0x100, &[])).unwrap();
+        let mut exec = ExecutionContext::with_bus(VAXBus::new(4 << 20), TimeMode::Virtual);
+        let mut process = Process::new(Sandbox::new(dir).unwrap());
+        let out = Captured::default();
+        process.set_descriptor(1, Descriptor::Output(Box::new(out.clone())));
+        process.start(&mut exec, &program, &["test"], &[]).unwrap();
+        assert_eq!(process.brk(), 0x2000);
+        let end = process.run(&mut exec);
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        (end, text, exec)
+    }
+
+    #[test]
+    fn system_calls() {
+        let dir = std::env::temp_dir().join(format!("erodedvax-netbsd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Each stub returns the error number negated, as the carry doesn't survive RET.
+        let stubs = |name: &str, code: u32| format!("
+        {name}: .WORD 0
+                CHMK #{code}
+                BCC {name}_OK
+                MNEGL R0, R0
+        {name}_OK: RET
+        ", name = name, code = code);
+        let source = format!("
+        START:  .WORD 0
+                PUSHL #12
+                PUSHAB HELLO
+                PUSHL #1
+                CALLS #3, WRITE
+                PUSHL #^O644
+                PUSHL #^X601
+                PUSHAB NAME
+                CALLS #3, OPEN
+                MOVL R0, R6
+                PUSHL #5
+                PUSHAB HELLO
+                PUSHL R6
+                CALLS #3, WRITE
+                PUSHL R6
+                CALLS #1, CLOSE
+                PUSHL #0
+                PUSHAB MISSING
+                CALLS #2, OPEN
+                MOVL R0, R7
+                PUSHL #^X3000
+                CALLS #1, BRK
+                MOVL R0, R8
+                PUSHL #0
+                PUSHAB TIME
+                CALLS #2, TOD
+                MNEGL R7, -(SP)
+                CALLS #1, EXIT
+        {}{}{}{}{}{}
+        HELLO:  .ASCII \"hello world\"
+                .BYTE 10
+        NAME:   .ASCIZ \"/out.txt\"
+        MISSING: .ASCIZ \"../nope\"
+        TIME:   .BLKB 12
+        ",
+            stubs("WRITE", calls::WRITE), stubs("OPEN", calls::OPEN), stubs("CLOSE", calls::CLOSE),
+            stubs("BRK", calls::BREAK), stubs("TOD", calls::GETTIMEOFDAY), stubs("EXIT", calls::EXIT),
+        );
+        let (end, out, mut exec) = run(&source, &dir);
+        assert_eq!(end, Termination::Exited(errno::ENOENT as i32));
+        assert_eq!(out, "hello world\n");
+        assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), "hello");
+        assert_eq!(exec.reg(RegID::new(8)), 0, "brk");
+        let time = exec.bus().read_u32(assemble(&source, 0x1000).unwrap().symbols["TIME"]).unwrap();
+        assert!(time > 1_500_000_000);
+
+        // A privileged instruction, as NetBSD would see it.
+        let (end, _, _) = run("START: .WORD 0\nNOP\nHALT", &dir);
+        assert_eq!(end, Termination::Signalled(Signal::Ill, 0x1003));
+        assert_eq!(end.status(), 132);
+
+        // Buffers that run off the end of memory fault before the host makes room for them.
+        let source = format!("
+        START:  .WORD 0
+                PUSHL #-1
+                PUSHAB START
+                PUSHL #1
+                CALLS #3, WRITE
+                MNEGL R0, -(SP)
+                CALLS #1, EXIT
+        {}{}
+        ", stubs("WRITE", calls::WRITE), stubs("EXIT", calls::EXIT));
+        let (end, out, _) = run(&source, &dir);
+        assert_eq!((end, out.as_str()), (Termination::Exited(errno::EFAULT as i32), ""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! The host directory a program's files are kept in. Guest paths are looked up in it as if it
+//! were the root, whether they're absolute or not, and `..` stops at the top. Symbolic links are
+//! followed, but not out of it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// NetBSD open(2) flags.
+pub const O_ACCMODE: u32 = 0x3;
+pub const O_WRONLY: u32 = 0x1;
+pub const O_RDWR: u32 = 0x2;
+pub const O_APPEND: u32 = 0x8;
+pub const O_CREAT: u32 = 0x200;
+pub const O_TRUNC: u32 = 0x400;
+pub const O_EXCL: u32 = 0x800;
+
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: &Path) -> io::Result<Sandbox> {
+        Ok(Sandbox { root: fs::canonicalize(root)? })
+    }
+
+    #[inline]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The host path for a guest path, looked up lexically.
+    pub fn resolve(&self, guest: &str) -> PathBuf {
+        let mut path = PathBuf::new();
+        for c in Path::new(guest).components() {
+            match c {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => {
+                    path.pop();
+                }
+                _ => {}
+            }
+        }
+        self.root.join(path)
+    }
+
+    /// Fails if a symbolic link takes `path` out of the sandbox. A path that doesn't exist yet is
+    /// checked by its directory, and mustn't be a link to something that doesn't exist either,
+    /// which creating it would create wherever the link points. Returns whether it exists.
+    fn check(&self, path: &Path) -> io::Result<bool> {
+        let outside = || io::Error::new(io::ErrorKind::PermissionDenied, "outside the sandbox");
+        let (real, exists) = match fs::canonicalize(path) {
+            Ok(real) => (real, true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if fs::symlink_metadata(path).is_ok() {
+                    return Err(outside());
+                }
+                match path.parent() {
+                    Some(dir) => (fs::canonicalize(dir)?, false),
+                    None => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        if real.starts_with(&self.root) {
+            Ok(exists)
+        } else {
+            Err(outside())
+        }
+    }
+
+    /// Opens a guest path as open(2) would, with NetBSD's flags and creation mode.
+    pub fn open(&self, guest: &str, flags: u32, mode: u32) -> io::Result<File> {
+        let path = self.resolve(guest);
+        let exists = self.check(&path)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode & 0o7777);
+            // Nor may a link appear there between checking and creating.
+            if !exists {
+                options.custom_flags(libc::O_NOFOLLOW);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (mode, exists);
+        options.open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn sandboxed_paths() {
+        let dir = std::env::temp_dir().join(format!("erodedvax-sandbox-{}", std::process::id()));
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/motd"), "hello").unwrap();
+        let sandbox = Sandbox::new(&dir).unwrap();
+
+        assert_eq!(sandbox.resolve("/etc/motd"), sandbox.root().join("etc/motd"));
+        assert_eq!(sandbox.resolve("../../../etc/./motd"), sandbox.root().join("etc/motd"));
+        let mut text = String::new();
+        sandbox.open("/tmp/../etc/motd", 0, 0).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello");
+
+        assert!(sandbox.open("new", O_WRONLY | O_CREAT | O_EXCL, 0o644).is_ok());
+        assert_eq!(sandbox.open("new", O_WRONLY | O_CREAT | O_EXCL, 0o644).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(sandbox.open("missing", 0, 0).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", dir.join("escape")).unwrap();
+            assert_eq!(sandbox.open("/escape/hostname", 0, 0).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            let outside = dir.with_extension("outside");
+            std::os::unix::fs::symlink(&outside, dir.join("dangling")).unwrap();
+            assert_eq!(sandbox.open("dangling", O_WRONLY | O_CREAT, 0o644).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            assert!(!outside.exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
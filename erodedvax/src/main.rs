@@ -5,9 +5,61 @@ extern crate bitfield;
 
 
 mod ervax;
+mod cmd;
 
 pub use ervax::*;
 
+const USAGE: &str = "\
+usage: erodedvax <command> [args]
+
+commands:
+  disasm    disassemble a raw binary, or a slice of a disk image or memory dump
+  difftest  check the decoder against instructions captured from a reference implementation
+  run       run a machine described by a configuration file
+  netbsd    run a NetBSD/VAX program, with the host standing in for the kernel
+  trace     print a binary instruction trace
+
+Run `erodedvax <command> --help` for a command's options.";
+
 fn main() {
-    
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("disasm") if args.iter().any(|a| a == "--help") => {
+            println!("{}", cmd::disasm::USAGE);
+            Ok(())
+        }
+        Some("disasm") => cmd::disasm::run(&args[1..]),
+        Some("difftest") if args.iter().any(|a| a == "--help") => {
+            println!("{}", cmd::difftest::USAGE);
+            Ok(())
+        }
+        Some("difftest") => cmd::difftest::run(&args[1..]),
+        Some("run") if args.iter().any(|a| a == "--help") => {
+            println!("{}", cmd::run::USAGE);
+            Ok(())
+        }
+        Some("run") => cmd::run::run(&args[1..]),
+        // Options after the program are the program's own.
+        Some("netbsd") if args.get(1).is_some_and(|a| a == "--help") => {
+            println!("{}", cmd::netbsd::USAGE);
+            Ok(())
+        }
+        Some("netbsd") => cmd::netbsd::run(&args[1..]),
+        Some("trace") if args.iter().any(|a| a == "--help") => {
+            println!("{}", cmd::trace::USAGE);
+            Ok(())
+        }
+        Some("trace") => cmd::trace::run(&args[1..]),
+        None | Some("help") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(other) => Err(format!("unknown command `{}`\n\n{}", other, USAGE)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("erodedvax: {}", e);
+        std::process::exit(1);
+    }
+}